@@ -143,7 +143,7 @@ pub async fn start_challenge(arkose_token: &str) -> anyhow::Result<Session> {
         session_token,
         funcaptcha: None,
         challenge: None,
-        client: context::get_instance().client(),
+        client: context::get_instance().arkose_client(),
         game_type: 0,
         headers,
     };
@@ -344,7 +344,7 @@ impl Session {
                     )
                 }
 
-                tokio::spawn(async move {
+                crate::context::spawn(async move {
                     if let Err(err) = callback(self.client, self.session_token).await {
                         warn!("funcaptcha callback error: {err}")
                     }
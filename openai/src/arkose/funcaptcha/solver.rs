@@ -112,3 +112,38 @@ pub async fn submit_task(submit_task: SubmitSolver<'_>) -> anyhow::Result<Vec<i3
         }
     }
 }
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+struct BalanceResp {
+    #[serde(rename = "errorId")]
+    error_id: i32,
+    #[serde(rename = "errorDescription")]
+    error_description: Option<String>,
+    balance: f64,
+}
+
+/// Query the balance of the solver client key, fails if the key is invalid
+pub async fn balance(solver: &Solver, client_key: &str) -> anyhow::Result<f64> {
+    let url = match solver {
+        Solver::Yescaptcha => "https://global.yescaptcha.com/getBalance",
+        Solver::Capsolver => "https://api.capsolver.com/getBalance",
+    };
+
+    let resp = context::get_instance()
+        .client()
+        .post(url)
+        .json(&serde_json::json!({ "clientKey": client_key }))
+        .send()
+        .await?
+        .json::<BalanceResp>()
+        .await?;
+
+    if resp.error_id != 0 {
+        anyhow::bail!(
+            "solver balance error: {}",
+            resp.error_description.unwrap_or_default()
+        )
+    }
+    Ok(resp.balance)
+}
@@ -1,8 +1,8 @@
 use crate::arkose::crypto;
-use crate::urldecoding;
+use crate::{now_duration, urldecoding};
 use base64::Engine;
 use moka::sync::Cache;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::path::Path;
 use std::sync::OnceLock;
 use time::format_description::well_known::Rfc3339;
@@ -15,6 +15,17 @@ pub struct RequestEntry {
     pub body: String,
     pub bx: String,
     pub bv: String,
+    pub started_at: i64,
+}
+
+/// HAR file is considered stale after 30 days
+const STALE_SECS: i64 = 3600 * 24 * 30;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Freshness {
+    Fresh,
+    Stale,
 }
 
 pub fn check_from_slice(s: &[u8]) -> anyhow::Result<()> {
@@ -28,6 +39,36 @@ pub fn check_from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<()> {
     check_from_slice(&bytes)
 }
 
+/// Validate HAR file required fields, bda decodability and expiry heuristics
+pub fn validate_from_slice(s: &[u8]) -> anyhow::Result<Freshness> {
+    let entry = parse_from_slice(s)?;
+
+    if entry.url.is_empty() || entry.method.is_empty() {
+        anyhow::bail!("Missing request url or method");
+    }
+
+    if entry.bv.is_empty() {
+        anyhow::bail!("Missing request user-agent header");
+    }
+
+    if entry.bx.is_empty() {
+        anyhow::bail!("Empty bda payload");
+    }
+
+    let now = now_duration()?.as_secs() as i64;
+    if now - entry.started_at > STALE_SECS {
+        return Ok(Freshness::Stale);
+    }
+
+    Ok(Freshness::Fresh)
+}
+
+#[inline]
+pub fn validate_from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Freshness> {
+    let bytes = std::fs::read(path)?;
+    validate_from_slice(&bytes)
+}
+
 #[inline]
 pub fn parse_from_slice(s: &[u8]) -> anyhow::Result<RequestEntry> {
     let har = serde_json::from_slice::<Har>(&s)?;
@@ -124,6 +165,7 @@ fn parse(har: Har) -> anyhow::Result<RequestEntry> {
                         .join("&"),
                     bx: crypto::decrypt(bda, &format!("{bv}{bw}"))?,
                     bv,
+                    started_at: bt,
                 };
                 return Ok(entry);
             }
@@ -0,0 +1,185 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::Ordering;
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+use crate::{context, metrics, warn};
+
+const ATTEMPTS: &str = "ninja_arkose_attempts_total";
+const SUCCESSES: &str = "ninja_arkose_success_total";
+const LATENCY: &str = "ninja_arkose_latency_milliseconds_total";
+
+/// Minimum attempts before the success rate alert takes effect
+const ALERT_MIN_ATTEMPTS: usize = 10;
+
+/// Latest attempts of a source the success rate alert is computed over
+const ALERT_WINDOW: usize = 50;
+
+/// Arkose token source
+#[derive(Clone, Copy)]
+pub enum Source {
+    Solver,
+    Har,
+}
+
+impl Source {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Source::Solver => "solver",
+            Source::Har => "har",
+        }
+    }
+}
+
+/// Recent attempts of the arkose token sources of an instance, and the HAR file names of
+/// their labels. The success rate alert looks at the latest attempts only, so a source
+/// failing after a long healthy run is noticed. The HAR files are labeled by a hash of
+/// their name on `/metrics`, the names are only shown by the admin API
+#[derive(Default)]
+pub(crate) struct Attempts {
+    windows: Mutex<HashMap<String, VecDeque<bool>>>,
+    names: RwLock<HashMap<String, String>>,
+}
+
+impl Attempts {
+    /// Push the outcome to the window of the source, returns its successes and attempts
+    fn push(&self, key: String, success: bool) -> (usize, usize) {
+        let Ok(mut windows) = self.windows.lock() else {
+            return (0, 0);
+        };
+        let window = windows.entry(key).or_default();
+        if window.len() == ALERT_WINDOW {
+            window.pop_front();
+        }
+        window.push_back(success);
+        (
+            window.iter().filter(|success| **success).count(),
+            window.len(),
+        )
+    }
+
+    /// Label of the HAR file, remembered to show its name to the admin
+    fn har_label(&self, name: &str) -> String {
+        let label = format!("har-{:x}", Sha256::digest(name.as_bytes()))[..16].to_owned();
+        if let Ok(mut names) = self.names.write() {
+            names
+                .entry(label.clone())
+                .or_insert_with(|| name.to_owned());
+        }
+        label
+    }
+
+    fn name(&self, label: &str) -> Option<String> {
+        self.names.read().ok()?.get(label).cloned()
+    }
+}
+
+/// Success rate and latency of an arkose token source
+#[derive(serde::Serialize)]
+pub struct SourceStats {
+    pub kind: String,
+    pub name: String,
+    pub attempts: u64,
+    pub successes: u64,
+    pub avg_latency_ms: u64,
+}
+
+/// Stats of all recorded arkose token sources, with the names of the HAR files
+pub fn stats() -> Vec<SourceStats> {
+    let ctx = context::get_instance();
+    let successes = metrics::values(SUCCESSES);
+    let latency = metrics::values(LATENCY);
+    metrics::values(ATTEMPTS)
+        .into_iter()
+        .filter_map(|(labels, attempts)| {
+            // `{kind="solver",name="yescaptcha"}`
+            let (kind, name) = labels
+                .strip_prefix("{kind=\"")?
+                .strip_suffix("\"}")?
+                .split_once("\",name=\"")?;
+            Some(SourceStats {
+                kind: kind.to_owned(),
+                name: ctx
+                    .arkose_attempts()
+                    .name(name)
+                    .unwrap_or_else(|| name.to_owned()),
+                attempts,
+                successes: successes.get(&labels).copied().unwrap_or_default(),
+                avg_latency_ms: latency.get(&labels).copied().unwrap_or_default() / attempts.max(1),
+            })
+        })
+        .collect()
+}
+
+/// Record an arkose token attempt
+pub(crate) fn record(source: Source, name: &str, success: bool, elapsed: Duration) {
+    let ctx = context::get_instance();
+    let attempts = ctx.arkose_attempts();
+    let label = match source {
+        Source::Har => attempts.har_label(name),
+        Source::Solver => name.to_owned(),
+    };
+    let labels = [("kind", source.as_str()), ("name", label.as_str())];
+
+    metrics::inc(ATTEMPTS, "Arkose token attempts", &labels, 1);
+    if success {
+        metrics::inc(SUCCESSES, "Arkose token successes", &labels, 1);
+    }
+    metrics::inc(
+        LATENCY,
+        "Arkose token latency in milliseconds",
+        &labels,
+        elapsed.as_millis() as u64,
+    );
+
+    let (successes, attempts) = attempts.push(format!("{}/{label}", source.as_str()), success);
+    if success || attempts < ALERT_MIN_ATTEMPTS {
+        return;
+    }
+
+    let threshold = ctx.arkose_alert_threshold();
+    let rate = successes as f64 / attempts as f64;
+    if rate < threshold {
+        warn!(
+            "Arkose {} `{name}` success rate {:.2}% is below threshold {:.2}% ({successes}/{attempts} latest attempts)",
+            source.as_str(),
+            rate * 100.0,
+            threshold * 100.0
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window() {
+        let attempts = Attempts::default();
+        for _ in 0..1000 {
+            attempts.push("har/a".to_owned(), true);
+        }
+        // A long healthy run is forgotten once the window is full of failures
+        let mut last = (0, 0);
+        for _ in 0..ALERT_WINDOW {
+            last = attempts.push("har/a".to_owned(), false);
+        }
+        assert_eq!(last, (0, ALERT_WINDOW));
+        assert_eq!(attempts.push("har/b".to_owned(), false), (0, 1));
+    }
+
+    #[test]
+    fn test_har_label() {
+        let attempts = Attempts::default();
+        let label = attempts.har_label("chat.openai.com.har");
+        assert!(label.starts_with("har-") && !label.contains("openai"));
+        assert_eq!(label, attempts.har_label("chat.openai.com.har"));
+        assert_ne!(label, attempts.har_label("other.har"));
+        assert_eq!(
+            attempts.name(&label).as_deref(),
+            Some("chat.openai.com.har")
+        );
+    }
+}
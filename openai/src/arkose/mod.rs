@@ -1,10 +1,13 @@
 pub mod crypto;
 pub mod funcaptcha;
 pub mod har;
+pub mod metrics;
 pub mod murmur;
+pub mod pool;
 
 use base64::engine::general_purpose;
 use std::path::Path;
+use std::time::Instant;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 
@@ -27,7 +30,8 @@ use crate::HEADER_UA;
 use self::funcaptcha::solver::SubmitSolver;
 use self::funcaptcha::ArkoseSolver;
 use self::funcaptcha::Solver;
-#[derive(Hash, PartialEq, Eq, Debug)]
+use self::metrics::Source;
+#[derive(Hash, PartialEq, Eq, Debug, Clone, Copy)]
 pub enum Type {
     GPT3,
     GPT4,
@@ -47,6 +51,15 @@ impl Type {
         Ok(t)
     }
 
+    pub fn name(&self) -> &'static str {
+        match self {
+            Type::GPT3 => "gpt3",
+            Type::GPT4 => "gpt4",
+            Type::Auth => "auth",
+            Type::Platform => "platform",
+        }
+    }
+
     pub fn get_site(&self) -> &'static str {
         match self {
             Type::GPT3 | Type::GPT4 => "https://chat.openai.com",
@@ -275,7 +288,7 @@ async fn get_from_bx_common(
     ];
 
     let resp = context::get_instance()
-        .client()
+        .arkose_client()
         .post(format!("https://{host}/fc/gt2/public_key/{public_key}"))
         .header(header::USER_AGENT, HEADER_UA)
         .header(header::ACCEPT, "*/*")
@@ -324,7 +337,7 @@ async fn get_from_har<P: AsRef<Path>>(path: P) -> anyhow::Result<ArkoseToken> {
         .push_str(&format!("&bda={}", general_purpose::STANDARD.encode(&bda)));
     entry.body.push_str(&format!("&rnd={rnd}"));
 
-    let client = context::get_instance().client();
+    let client = context::get_instance().arkose_client();
 
     let method = Method::from_bytes(entry.method.as_bytes())?;
 
@@ -355,6 +368,19 @@ async fn get_from_har<P: AsRef<Path>>(path: P) -> anyhow::Result<ArkoseToken> {
 /// Get ArkoseLabs token from context (Only support ChatGPT, Platform, Auth)
 #[inline]
 async fn get_from_context(t: Type) -> anyhow::Result<ArkoseToken> {
+    // Take a pre-generated token from the warm pool
+    if let Some(arkose_token) = context::get_instance()
+        .arkose_token_pool()
+        .and_then(|pool| pool.take(t))
+    {
+        return Ok(arkose_token);
+    }
+
+    generate_from_context(t).await
+}
+
+/// Generate ArkoseLabs token from HAR file or solver
+async fn generate_from_context(t: Type) -> anyhow::Result<ArkoseToken> {
     let valid_arkose_token =
         |arkose_token: ArkoseToken, arkose_solver: Option<&'static ArkoseSolver>| async move {
             let get = move || async { Ok(arkose_token) };
@@ -368,11 +394,30 @@ async fn get_from_context(t: Type) -> anyhow::Result<ArkoseToken> {
 
     let hat_path = ctx.arkose_har_path(&t);
     if let Some(file_path) = hat_path.file_path {
+        let har_name = file_path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let start = Instant::now();
         match ArkoseToken::new_from_har(&file_path).await {
             Ok(arkose_token) => {
-                return valid_arkose_token(arkose_token, arkose_solver).await;
+                metrics::record(
+                    Source::Har,
+                    &har_name,
+                    arkose_token.success(),
+                    start.elapsed(),
+                );
+                let result = valid_arkose_token(arkose_token, arkose_solver).await;
+                ctx.arkose_har_report(
+                    &t,
+                    &file_path,
+                    result.as_ref().is_ok_and(|token| token.success()),
+                );
+                return result;
             }
             Err(err) => {
+                metrics::record(Source::Har, &har_name, false, start.elapsed());
+                ctx.arkose_har_report(&t, &file_path, false);
                 warn!(
                     "get arkose token from har file: {} error: {err}",
                     file_path.display()
@@ -408,18 +453,26 @@ where
 
     if arkose_token.success() {
         // Submit token to funcaptcha callback
-        tokio::spawn(funcaptcha::callback(
-            context::get_instance().client(),
+        crate::context::spawn(funcaptcha::callback(
+            context::get_instance().arkose_client(),
             arkose_token.value().to_owned(),
         ));
     } else {
         if let Some(arkose_solver) = arkose_solver {
-            return submit_captcha(
+            let start = Instant::now();
+            let result = submit_captcha(
                 &arkose_solver.solver,
                 &arkose_solver.client_key,
                 arkose_token,
             )
             .await;
+            metrics::record(
+                Source::Solver,
+                &arkose_solver.solver.to_string(),
+                result.is_ok(),
+                start.elapsed(),
+            );
+            return result;
         }
         warn!("arkose token is invalid, but no solver is available.")
     }
@@ -449,7 +502,7 @@ async fn submit_captcha(
                     .image(fun.image.clone())
                     .build();
                 let sender = tx.clone();
-                tokio::spawn(async move {
+                crate::context::spawn(async move {
                     let res = funcaptcha::solver::submit_task(submit_task).await;
                     if let Some(err) = sender.send((i, res)).await.err() {
                         warn!("submit funcaptcha answer error: {err}")
@@ -486,7 +539,7 @@ async fn submit_captcha(
                         .images(images)
                         .build();
                     let sender = tx.clone();
-                    tokio::spawn(async move {
+                    crate::context::spawn(async move {
                         let res = funcaptcha::solver::submit_task(submit_task).await;
                         if let Some(err) = sender.send((i, res)).await.err() {
                             warn!("submit funcaptcha answer error: {err}")
@@ -0,0 +1,137 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tokio::sync::Notify;
+
+use super::{ArkoseToken, Type};
+use crate::{context, debug, warn};
+
+/// Wait time before retrying after a failed token generation
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Warm pool of pre-generated arkose tokens.
+///
+/// A type is only warmed after it has been requested once, so unused
+/// types don't consume solver credits.
+pub struct ArkoseTokenPool {
+    size: usize,
+    ttl: Duration,
+    slots: Mutex<HashMap<Type, VecDeque<(ArkoseToken, Instant)>>>,
+    notify: Notify,
+}
+
+impl ArkoseTokenPool {
+    pub(crate) fn new(size: usize, ttl: Duration) -> Self {
+        Self {
+            size,
+            ttl,
+            slots: Mutex::new(HashMap::new()),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Take a ready token of the given type, if any
+    pub(crate) fn take(&self, t: Type) -> Option<ArkoseToken> {
+        let mut slots = self.slots.lock().expect("Failed to lock arkose token pool");
+        let tokens = slots.entry(t).or_default();
+        tokens.retain(|(_, created)| created.elapsed() < self.ttl);
+        let token = tokens.pop_front().map(|(token, _)| token);
+        drop(slots);
+        self.notify.notify_one();
+        token
+    }
+
+    /// Number of ready tokens for each requested type
+    pub fn ready(&self) -> HashMap<&'static str, usize> {
+        let mut slots = self.slots.lock().expect("Failed to lock arkose token pool");
+        slots
+            .iter_mut()
+            .map(|(t, tokens)| {
+                tokens.retain(|(_, created)| created.elapsed() < self.ttl);
+                (t.name(), tokens.len())
+            })
+            .collect()
+    }
+
+    /// Drop the expired tokens, returns how many were dropped
+    pub(crate) fn sweep(&self) -> usize {
+        let mut slots = self.slots.lock().expect("Failed to lock arkose token pool");
+        slots
+            .values_mut()
+            .map(|tokens| {
+                let len = tokens.len();
+                tokens.retain(|(_, created)| created.elapsed() < self.ttl);
+                len - tokens.len()
+            })
+            .sum()
+    }
+
+    /// Pool size of each type
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Number of tokens missing for each requested type
+    fn missing(&self) -> Vec<(Type, usize)> {
+        let mut slots = self.slots.lock().expect("Failed to lock arkose token pool");
+        slots
+            .iter_mut()
+            .filter_map(|(t, tokens)| {
+                tokens.retain(|(_, created)| created.elapsed() < self.ttl);
+                (tokens.len() < self.size).then(|| (*t, self.size - tokens.len()))
+            })
+            .collect()
+    }
+
+    fn push(&self, t: Type, token: ArkoseToken) {
+        self.slots
+            .lock()
+            .expect("Failed to lock arkose token pool")
+            .entry(t)
+            .or_default()
+            .push_back((token, Instant::now()));
+    }
+}
+
+/// Keep the arkose token pool filled, refreshing as tokens are consumed or expire
+pub(crate) async fn refresh_task() {
+    let ctx = context::get_instance();
+    let Some(pool) = ctx.arkose_token_pool() else {
+        return;
+    };
+
+    loop {
+        let mut failed = false;
+        for (t, count) in pool.missing() {
+            for _ in 0..count {
+                match super::generate_from_context(t).await {
+                    Ok(token) if token.success() => pool.push(t, token),
+                    Ok(_) => {
+                        debug!("Arkose token pool: generated {} token is invalid", t.name());
+                        failed = true;
+                        break;
+                    }
+                    Err(err) => {
+                        warn!(
+                            "Arkose token pool: generate {} token error: {err}",
+                            t.name()
+                        );
+                        failed = true;
+                        break;
+                    }
+                }
+            }
+        }
+
+        if failed {
+            tokio::time::sleep(RETRY_INTERVAL).await;
+            continue;
+        }
+
+        tokio::select! {
+            _ = pool.notify.notified() => {}
+            _ = tokio::time::sleep(pool.ttl / 2) => {}
+        }
+    }
+}
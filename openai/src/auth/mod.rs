@@ -158,6 +158,9 @@ impl AuthClient {
     }
 
     async fn handle_error(status: Option<StatusCode>, err_msg: String) -> AuthError {
+        if err_msg.contains("deactivated") {
+            return AuthError::AccountDeactivated(err_msg);
+        }
         match status {
             Some(
                 status_code @ (StatusCode::UNAUTHORIZED
@@ -224,6 +227,9 @@ impl AuthClient {
         if let Some(error) = url_params.get("error") {
             if let Some(error_description) = url_params.get("error_description") {
                 let msg = format!("{}: {}", error[0], error_description[0]);
+                if msg.contains("deactivated") {
+                    bail!(AuthError::AccountDeactivated(msg))
+                }
                 bail!("{}", msg)
             } else {
                 bail!("{}", error[0])
@@ -405,6 +411,12 @@ impl AuthClientBuilder {
         self
     }
 
+    /// Set whether sockets have `TCP_NODELAY` enabled.
+    pub fn tcp_nodelay(mut self, enabled: bool) -> Self {
+        self.inner = self.inner.tcp_nodelay(enabled);
+        self
+    }
+
     /// Sets the necessary values to mimic the specified impersonate client version.
     pub fn impersonate(mut self, ver: Impersonate) -> Self {
         self.inner = self.inner.impersonate(ver);
@@ -433,6 +445,12 @@ impl AuthClientBuilder {
         self
     }
 
+    /// Override the DNS resolver implementation.
+    pub fn dns_resolver<R: reqwest::dns::Resolve + 'static>(mut self, resolver: Arc<R>) -> Self {
+        self.inner = self.inner.dns_resolver(resolver);
+        self
+    }
+
     pub fn build(self) -> AuthClient {
         let client = self.inner.build().expect("ClientBuilder::build()");
 
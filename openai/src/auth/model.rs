@@ -44,6 +44,29 @@ pub struct AuthAccount {
     #[builder(setter(into, strip_option), default)]
     #[serde(rename = "cf-turnstile-response")]
     pub cf_turnstile_response: Option<String>,
+    #[builder(setter(into, strip_option), default)]
+    #[serde(rename = "h-captcha-response")]
+    pub h_captcha_response: Option<String>,
+    #[builder(setter(into, strip_option), default)]
+    #[serde(rename = "g-recaptcha-response")]
+    pub g_recaptcha_response: Option<String>,
+    /// WebUI "remember me" checkbox
+    #[serde(default)]
+    #[builder(default)]
+    pub remember: bool,
+}
+
+impl AuthAccount {
+    /// Response token of the login page captcha widget, the field is named after the provider
+    pub fn captcha_response(&self) -> Option<&str> {
+        [
+            &self.cf_turnstile_response,
+            &self.h_captcha_response,
+            &self.g_recaptcha_response,
+        ]
+        .into_iter()
+        .find_map(|response| response.as_deref().filter(|r| !r.is_empty()))
+    }
 }
 
 #[derive(Serialize, Deserialize)]
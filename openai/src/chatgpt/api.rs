@@ -55,11 +55,13 @@ pub enum ApiError {
 
 use std::time::Duration;
 
+use reqwest::header::{HeaderMap, HeaderValue};
 use reqwest::{impersonate::Impersonate, Proxy, StatusCode};
 use serde::{de::DeserializeOwned, Serialize};
 use tokio::sync::RwLock;
 
 use super::model::{req, resp};
+use super::{SENTINEL_CHAT_REQUIREMENTS_TOKEN, SENTINEL_PROOF_TOKEN};
 
 use crate::{HEADER_UA, URL_CHATGPT_API};
 
@@ -67,6 +69,7 @@ pub struct ChatGPT {
     api_prefix: String,
     client: reqwest::Client,
     access_token: RwLock<String>,
+    user_agent: String,
 }
 
 impl ChatGPT {
@@ -117,6 +120,30 @@ impl ChatGPT {
         self.response_handle::<U>(resp).await
     }
 
+    /// Request chat requirements and build the sentinel headers for a conversation
+    async fn sentinel_headers(&self) -> ApiResult<HeaderMap> {
+        let requirements = self.post_chat_requirements().await?;
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            SENTINEL_CHAT_REQUIREMENTS_TOKEN,
+            HeaderValue::from_str(&requirements.token)
+                .map_err(|err| ApiError::FailedRequestError(err.to_string()))?,
+        );
+
+        let pow = requirements.proofofwork;
+        if let (true, Some(seed), Some(difficulty)) = (pow.required, pow.seed, pow.difficulty) {
+            let proof = crate::pow::generate_blocking(seed, difficulty, self.user_agent.clone())
+                .await
+                .map_err(|err| ApiError::FailedRequestError(err.to_string()))?;
+            headers.insert(
+                SENTINEL_PROOF_TOKEN,
+                HeaderValue::from_str(&proof)
+                    .map_err(|err| ApiError::FailedRequestError(err.to_string()))?,
+            );
+        }
+        Ok(headers)
+    }
+
     async fn response_handle<U: DeserializeOwned>(&self, resp: reqwest::Response) -> ApiResult<U> {
         match resp.error_for_status_ref() {
             Ok(_) => Ok(resp
@@ -253,9 +280,14 @@ impl ChatGPT {
         &self,
         req: req::GetConvoRequest<'a>,
     ) -> ApiResult<resp::GetConvosResponse> {
+        let archived = if req.is_archived {
+            "&is_archived=true"
+        } else {
+            ""
+        };
         self.request::<resp::GetConvosResponse>(
             format!(
-                "{}/conversations?offset={}&limit={}&order=updated",
+                "{}/conversations?offset={}&limit={}&order=updated{archived}",
                 self.api_prefix, req.offset, req.limit
             ),
             RequestMethod::GET,
@@ -268,11 +300,16 @@ impl ChatGPT {
         &self,
         req: req::PostConvoRequest<'a>,
     ) -> Pin<Box<dyn Stream<Item = ApiResult<resp::PostConvoResponse>> + Send>> {
+        let headers = match self.sentinel_headers().await {
+            Ok(headers) => headers,
+            Err(err) => return Box::pin(futures::stream::once(async move { Err(err) })),
+        };
         let url = format!("{}/conversation", self.api_prefix);
         let resp = self
             .client
             .post(url)
             .bearer_auth(&self.access_token.read().await)
+            .headers(headers)
             .json(&req)
             .eventsource()
             .expect("eventsource error");
@@ -283,11 +320,13 @@ impl ChatGPT {
         &self,
         req: req::PostConvoRequest<'a>,
     ) -> ApiResult<Vec<resp::PostConvoResponse>> {
+        let headers = self.sentinel_headers().await?;
         let url = format!("{}/conversation", self.api_prefix);
         let resp = self
             .client
             .post(url)
             .bearer_auth(&self.access_token.read().await)
+            .headers(headers)
             .json(&req)
             .send()
             .await?;
@@ -351,6 +390,40 @@ impl ChatGPT {
         .await
     }
 
+    /// Archive the conversation, it is hidden from the conversation list
+    pub async fn archive_conversation(
+        &self,
+        conversation_id: &str,
+    ) -> ApiResult<resp::PatchConvoResponse> {
+        self.patch_conversation(
+            req::PatchConvoRequest::builder()
+                .conversation_id(conversation_id)
+                .is_archived(true)
+                .build(),
+        )
+        .await
+    }
+
+    /// Move the archived conversation back to the conversation list
+    pub async fn unarchive_conversation(
+        &self,
+        conversation_id: &str,
+    ) -> ApiResult<resp::PatchConvoResponse> {
+        self.patch_conversation(
+            req::PatchConvoRequest::builder()
+                .conversation_id(conversation_id)
+                .is_archived(false)
+                .build(),
+        )
+        .await
+    }
+
+    /// Archive all the conversations
+    pub async fn archive_conversations(&self) -> ApiResult<resp::PatchConvoResponse> {
+        self.patch_conversations(req::PatchConvoRequest::builder().is_archived(true).build())
+            .await
+    }
+
     pub async fn post_conversation_gen_title<'a>(
         &self,
         req: req::PostConvoGenTitleRequest<'a>,
@@ -378,6 +451,48 @@ impl ChatGPT {
         .await
     }
 
+    /// Create the share link of the conversation, private until it is published by `patch_share`
+    pub async fn create_share<'a>(
+        &self,
+        req: req::CreateShareRequest<'a>,
+    ) -> ApiResult<resp::ShareResponse> {
+        self.request_payload(
+            format!("{}/share/create", self.api_prefix),
+            RequestMethod::POST,
+            &req,
+        )
+        .await
+    }
+
+    pub async fn patch_share<'a>(
+        &self,
+        req: req::PatchShareRequest<'a>,
+    ) -> ApiResult<resp::ShareResponse> {
+        self.request_payload(
+            format!("{}/share/{}", self.api_prefix, req.share_id),
+            RequestMethod::PATCH,
+            &req,
+        )
+        .await
+    }
+
+    pub async fn delete_share(&self, share_id: &str) -> ApiResult<resp::PatchConvoResponse> {
+        self.request(
+            format!("{}/share/{share_id}", self.api_prefix),
+            RequestMethod::DELETE,
+        )
+        .await
+    }
+
+    pub async fn post_chat_requirements(&self) -> ApiResult<resp::ChatRequirementsResponse> {
+        self.request_payload(
+            format!("{}/sentinel/chat-requirements", self.api_prefix),
+            RequestMethod::POST,
+            &serde_json::json!({ "conversation_mode_kind": "primary_assistant" }),
+        )
+        .await
+    }
+
     pub async fn get_conversation_limit(&self) -> ApiResult<resp::GetConvoLimitResponse> {
         self.request(
             format!("{URL_CHATGPT_API}/public-api/conversation_limit"),
@@ -389,11 +504,19 @@ impl ChatGPT {
 
 pub struct ChatGPTBuilder {
     builder: reqwest::ClientBuilder,
+    client: Option<reqwest::Client>,
     api_prefix: String,
     access_token: RwLock<String>,
+    user_agent: String,
 }
 
 impl ChatGPTBuilder {
+    /// Send the requests with an existing client, the client options of the builder are ignored
+    pub fn client(mut self, client: reqwest::Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
     pub fn access_token(mut self, access_token: String) -> Self {
         self.access_token = RwLock::new(access_token);
         self
@@ -438,14 +561,19 @@ impl ChatGPTBuilder {
     /// Sets the `User-Agent` header to be used by this client.
     pub fn user_agent(mut self, value: &str) -> Self {
         self.builder = self.builder.user_agent(value);
+        self.user_agent = value.to_owned();
         self
     }
 
     pub fn build(self) -> ChatGPT {
         ChatGPT {
             api_prefix: self.api_prefix,
-            client: self.builder.build().expect("ClientBuilder::build()"),
+            client: match self.client {
+                Some(client) => client,
+                None => self.builder.build().expect("ClientBuilder::build()"),
+            },
             access_token: self.access_token,
+            user_agent: self.user_agent,
         }
     }
 
@@ -458,8 +586,10 @@ impl ChatGPTBuilder {
 
         ChatGPTBuilder {
             builder,
+            client: None,
             api_prefix: format!("{URL_CHATGPT_API}/backend-api"),
             access_token: RwLock::default(),
+            user_agent: HEADER_UA.to_owned(),
         }
     }
 }
@@ -2,3 +2,8 @@ pub mod model;
 
 #[cfg(feature = "api")]
 pub mod api;
+
+/// Chat requirements token header
+pub const SENTINEL_CHAT_REQUIREMENTS_TOKEN: &str = "openai-sentinel-chat-requirements-token";
+/// Proof-of-work token header
+pub const SENTINEL_PROOF_TOKEN: &str = "openai-sentinel-proof-token";
@@ -0,0 +1,60 @@
+use std::{collections::BTreeMap, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// Requested model names mapped to backend slugs,
+/// e.g. `gpt-4o=gpt-4,gpt-3.5-turbo-16k=text-davinci-002-render-sha`
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ModelAlias(BTreeMap<String, String>);
+
+impl FromStr for ModelAlias {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut alias = BTreeMap::new();
+        for item in s.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            match item.split_once('=') {
+                Some((name, slug)) if !name.trim().is_empty() && !slug.trim().is_empty() => {
+                    alias.insert(name.trim().to_owned(), slug.trim().to_owned());
+                }
+                _ => anyhow::bail!("Invalid model alias `{item}`, expected `name=slug`"),
+            }
+        }
+        Ok(Self(alias))
+    }
+}
+
+impl ModelAlias {
+    /// Backend slug of the requested model, `None` if not aliased
+    pub fn get(&self, model: &str) -> Option<&str> {
+        self.0.get(model).map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model_alias_from_str() {
+        let alias = ModelAlias::from_str(
+            " gpt-4o = gpt-4 ,, gpt-3.5-turbo-16k=text-davinci-002-render-sha,",
+        )
+        .unwrap();
+        assert_eq!(alias.get("gpt-4o"), Some("gpt-4"));
+        assert_eq!(
+            alias.get("gpt-3.5-turbo-16k"),
+            Some("text-davinci-002-render-sha")
+        );
+        assert_eq!(alias.get("gpt-4"), None);
+        assert_eq!(ModelAlias::from_str("").unwrap().get("gpt-4o"), None);
+    }
+
+    #[test]
+    fn test_model_alias_invalid() {
+        assert!(ModelAlias::from_str("gpt-4o").is_err());
+        assert!(ModelAlias::from_str("gpt-4o=").is_err());
+        assert!(ModelAlias::from_str("=gpt-4").is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 use typed_builder::TypedBuilder;
+pub mod alias;
 pub mod req;
 pub mod resp;
 
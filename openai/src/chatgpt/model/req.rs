@@ -34,12 +34,18 @@ pub enum Action {
 
 #[derive(Serialize, TypedBuilder)]
 pub struct PatchConvoRequest<'a> {
+    #[serde(skip_serializing)]
     #[builder(setter(into, strip_option), default)]
     pub conversation_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     #[builder(setter(into, strip_option), default)]
     title: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     #[builder(setter(into, strip_option), default)]
     is_visible: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[builder(setter(into, strip_option), default)]
+    is_archived: Option<bool>,
 }
 
 #[derive(TypedBuilder)]
@@ -50,6 +56,9 @@ pub struct GetConvoRequest<'a> {
     pub offset: u32,
     #[builder(default = 20)]
     pub limit: u32,
+    /// List the archived conversations instead
+    #[builder(default = false)]
+    pub is_archived: bool,
 }
 
 #[derive(Serialize, TypedBuilder)]
@@ -66,6 +75,34 @@ pub struct MessageFeedbackRequest<'a> {
     conversation_id: &'a str,
 }
 
+#[derive(Serialize, TypedBuilder)]
+pub struct CreateShareRequest<'a> {
+    conversation_id: &'a str,
+    /// Last message of the shared conversation
+    current_node_id: &'a str,
+    /// Hide the name of the sharer
+    #[builder(default = true)]
+    is_anonymous: bool,
+}
+
+#[derive(Serialize, TypedBuilder)]
+pub struct PatchShareRequest<'a> {
+    pub share_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[builder(setter(into, strip_option), default)]
+    highlighted_message_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[builder(setter(into, strip_option), default)]
+    title: Option<&'a str>,
+    /// Publish the share link, a created share is private until then
+    #[builder(default = true)]
+    is_public: bool,
+    #[builder(default = true)]
+    is_visible: bool,
+    #[builder(default = true)]
+    is_anonymous: bool,
+}
+
 #[derive(Serialize, Clone)]
 pub enum Rating {
     ThumbsUp,
@@ -120,6 +120,8 @@ pub struct ConvoItems {
     pub update_time: String,
     pub current_node: Option<String>,
     pub mapping: Option<HashMap<String, Mapping>>,
+    #[serde(default)]
+    pub is_archived: bool,
 }
 
 #[derive(Deserialize, Debug)]
@@ -138,6 +140,8 @@ pub struct GetConvoResponse {
     pub update_time: f64,
     pub mapping: HashMap<String, Mapping>,
     pub current_node: Option<String>,
+    #[serde(default)]
+    pub is_archived: bool,
 }
 
 #[derive(Deserialize, Debug)]
@@ -263,6 +267,25 @@ pub struct PatchConvoResponse {
     pub success: bool,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct ShareResponse {
+    pub share_id: String,
+    pub share_url: String,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub is_public: bool,
+    #[serde(default)]
+    pub is_visible: bool,
+    #[serde(default)]
+    pub is_anonymous: bool,
+    pub highlighted_message_id: Option<String>,
+    pub current_node_id: Option<String>,
+    /// The conversation was already shared, the existing share is returned
+    #[serde(default)]
+    pub already_exists: bool,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct PostConvoGenTitleResponse {
     pub title: Option<String>,
@@ -351,3 +374,30 @@ pub struct MessageDisclaimer {
     pub textarea: String,
     pub model_switcher: String,
 }
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Requirement {
+    #[serde(default)]
+    pub required: bool,
+    pub dx: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ProofOfWorkRequirement {
+    #[serde(default)]
+    pub required: bool,
+    pub seed: Option<String>,
+    pub difficulty: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChatRequirementsResponse {
+    pub persona: Option<String>,
+    pub token: String,
+    #[serde(default)]
+    pub arkose: Requirement,
+    #[serde(default)]
+    pub turnstile: Requirement,
+    #[serde(default)]
+    pub proofofwork: ProofOfWorkRequirement,
+}
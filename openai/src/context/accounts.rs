@@ -0,0 +1,293 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use serde::Serialize;
+
+use crate::auth::model::AuthStrategy;
+use crate::now_duration;
+use crate::token::model::AuthenticateToken;
+use crate::warn;
+
+/// Accounts not seen for this long are dropped
+const RETENTION: u64 = 3600 * 24 * 7;
+/// Accounts seen within this window count as active sessions
+const ACTIVE_WINDOW: u64 = 60 * 30;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountState {
+    Active,
+    LoginFailed,
+    Deactivated,
+    /// Disabled through the admin API, its requests are rejected
+    Disabled,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AccountStatus {
+    pub email: String,
+    pub state: AccountState,
+    /// Access token expire timestamp (seconds)
+    pub expires_at: Option<i64>,
+    /// Last request timestamp (seconds)
+    pub last_seen: u64,
+    pub requests: u64,
+    /// Upstream responses with an error status
+    pub errors: u64,
+    /// Upstream responses with 429
+    pub rate_limited: u64,
+    /// Last PUID refresh timestamp (seconds)
+    pub last_puid_refresh: Option<u64>,
+    pub last_error: Option<String>,
+    /// Added through the admin API, ninja holds its credential
+    pub managed: bool,
+}
+
+impl AccountStatus {
+    fn new(email: &str, state: AccountState, now: u64) -> Self {
+        Self {
+            email: email.to_owned(),
+            state,
+            expires_at: None,
+            last_seen: now,
+            requests: 0,
+            errors: 0,
+            rate_limited: 0,
+            last_puid_refresh: None,
+            last_error: None,
+            managed: false,
+        }
+    }
+}
+
+/// Credential of a managed account, used to log in again
+#[derive(Clone)]
+pub enum Credential {
+    Password {
+        password: String,
+        option: AuthStrategy,
+    },
+    RefreshToken(String),
+}
+
+/// Account added through the admin API
+#[derive(Clone)]
+pub struct ManagedAccount {
+    pub credential: Credential,
+    pub token: Option<AuthenticateToken>,
+}
+
+/// Accounts seen by this instance, through login or access token requests
+pub(super) struct AccountTracker {
+    accounts: RwLock<HashMap<String, AccountStatus>>,
+    managed: RwLock<HashMap<String, ManagedAccount>>,
+    /// Emails disabled through the admin API, persisted, the sweep and failures leave them alone
+    disabled: RwLock<HashSet<String>>,
+    path: PathBuf,
+}
+
+fn now() -> u64 {
+    now_duration().map(|d| d.as_secs()).unwrap_or_default()
+}
+
+impl AccountTracker {
+    pub(super) fn load(path: PathBuf) -> Self {
+        let disabled = std::fs::read(&path)
+            .ok()
+            .and_then(|data| {
+                serde_json::from_slice::<HashSet<String>>(&data)
+                    .map_err(|err| {
+                        warn!("Failed to read disabled accounts {}: {err}", path.display())
+                    })
+                    .ok()
+            })
+            .unwrap_or_default();
+        Self {
+            accounts: RwLock::new(HashMap::new()),
+            managed: RwLock::new(HashMap::new()),
+            disabled: RwLock::new(disabled),
+            path,
+        }
+    }
+
+    /// The account as listed, disabled if its email is in the disabled set
+    fn status(&self, mut account: AccountStatus) -> AccountStatus {
+        if self.disabled(&account.email) {
+            account.state = AccountState::Disabled;
+        }
+        account
+    }
+
+    /// Record a request of the account
+    pub(super) fn seen(&self, email: &str, expires_at: Option<i64>) {
+        let now = now();
+        if let Ok(mut accounts) = self.accounts.write() {
+            accounts.retain(|_, account| {
+                account.managed || now.saturating_sub(account.last_seen) < RETENTION
+            });
+            let account = accounts
+                .entry(email.to_owned())
+                .or_insert_with(|| AccountStatus::new(email, AccountState::Active, now));
+            account.state = AccountState::Active;
+            account.expires_at = expires_at.or(account.expires_at);
+            account.last_seen = now;
+            account.requests += 1;
+        }
+    }
+
+    /// Record a failure of the account
+    pub(super) fn failed(&self, email: &str, state: AccountState, error: String) {
+        if let Ok(mut accounts) = self.accounts.write() {
+            let account = accounts
+                .entry(email.to_owned())
+                .or_insert_with(|| AccountStatus::new(email, state, now()));
+            account.state = state;
+            account.last_error = Some(error);
+        }
+    }
+
+    /// Record the upstream response status of a request of the account
+    pub(super) fn responded(&self, email: &str, status: u16) {
+        if status < 400 {
+            return;
+        }
+        if let Ok(mut accounts) = self.accounts.write() {
+            if let Some(account) = accounts.get_mut(email) {
+                account.errors += 1;
+                if status == 429 {
+                    account.rate_limited += 1;
+                }
+            }
+        }
+    }
+
+    /// Record a PUID refresh of the account
+    pub(super) fn puid_refreshed(&self, email: &str) {
+        if let Ok(mut accounts) = self.accounts.write() {
+            if let Some(account) = accounts.get_mut(email) {
+                account.last_puid_refresh = Some(now());
+            }
+        }
+    }
+
+    /// The account, if seen
+    pub(super) fn get(&self, email: &str) -> Option<AccountStatus> {
+        let account = self.accounts.read().ok()?.get(email).cloned()?;
+        Some(self.status(account))
+    }
+
+    /// Whether the account was disabled through the admin API
+    pub(super) fn disabled(&self, email: &str) -> bool {
+        self.disabled
+            .read()
+            .map(|disabled| disabled.contains(email))
+            .unwrap_or_default()
+    }
+
+    /// Disable or enable and persist the account, `false` if it is neither seen nor disabled
+    pub(super) fn set_disabled(&self, email: &str, disabled: bool) -> anyhow::Result<bool> {
+        let known = self
+            .accounts
+            .read()
+            .map(|accounts| accounts.contains_key(email))
+            .unwrap_or_default();
+        let mut set = self
+            .disabled
+            .write()
+            .map_err(|_| anyhow::anyhow!("Failed to write disabled accounts"))?;
+        if !known && !set.contains(email) {
+            return Ok(false);
+        }
+        let changed = match disabled {
+            true => set.insert(email.to_owned()),
+            false => set.remove(email),
+        };
+        if changed {
+            std::fs::write(&self.path, serde_json::to_vec(&*set)?)?;
+        }
+        Ok(true)
+    }
+
+    /// Hold the credential and token of the account logged in through the admin API
+    pub(super) fn manage(&self, email: &str, credential: Credential, token: AuthenticateToken) {
+        let now = now();
+        if let Ok(mut accounts) = self.accounts.write() {
+            let account = accounts
+                .entry(email.to_owned())
+                .or_insert_with(|| AccountStatus::new(email, AccountState::Active, now));
+            account.state = AccountState::Active;
+            account.expires_at = Some(token.expires());
+            account.last_error = None;
+            account.managed = true;
+        }
+        if let Ok(mut managed) = self.managed.write() {
+            managed.insert(
+                email.to_owned(),
+                ManagedAccount {
+                    credential,
+                    token: Some(token),
+                },
+            );
+        }
+    }
+
+    /// The managed account
+    pub(super) fn managed(&self, email: &str) -> Option<ManagedAccount> {
+        self.managed.read().ok()?.get(email).cloned()
+    }
+
+    /// Forget the account and its credential, `false` if it is unknown. A disabled
+    /// account stays disabled once it is seen again
+    pub(super) fn remove(&self, email: &str) -> bool {
+        let removed = self
+            .accounts
+            .write()
+            .map(|mut accounts| accounts.remove(email).is_some())
+            .unwrap_or_default();
+        let managed = self
+            .managed
+            .write()
+            .map(|mut managed| managed.remove(email).is_some())
+            .unwrap_or_default();
+        removed || managed
+    }
+
+    /// Drop the accounts not seen within the retention, returns how many were dropped
+    pub(super) fn sweep(&self) -> usize {
+        let now = now();
+        self.accounts
+            .write()
+            .map(|mut accounts| {
+                let len = accounts.len();
+                accounts.retain(|_, account| {
+                    account.managed || now.saturating_sub(account.last_seen) < RETENTION
+                });
+                len - accounts.len()
+            })
+            .unwrap_or_default()
+    }
+
+    /// All accounts, most recently seen first
+    pub(super) fn list(&self) -> Vec<AccountStatus> {
+        let mut accounts = self
+            .accounts
+            .read()
+            .map(|accounts| accounts.values().cloned().collect::<Vec<_>>())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|account| self.status(account))
+            .collect::<Vec<_>>();
+        accounts.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+        accounts
+    }
+
+    /// Accounts seen within the active window
+    pub(super) fn active(&self) -> Vec<AccountStatus> {
+        let now = now();
+        self.list()
+            .into_iter()
+            .filter(|account| now.saturating_sub(account.last_seen) < ACTIVE_WINDOW)
+            .collect()
+    }
+}
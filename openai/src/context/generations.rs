@@ -0,0 +1,55 @@
+use std::{collections::HashMap, path::PathBuf, sync::RwLock};
+
+use crate::warn;
+
+/// WebUI session generation by account email, the sessions issued before the last
+/// "log out everywhere" of the account carry an older one and stop validating. Kept
+/// in the instance file so the revocation survives restarts
+pub(super) struct SessionGenerations {
+    path: PathBuf,
+    generations: RwLock<HashMap<String, u64>>,
+}
+
+impl SessionGenerations {
+    pub(super) fn load(path: PathBuf) -> Self {
+        let generations = std::fs::read(&path)
+            .ok()
+            .and_then(|data| {
+                serde_json::from_slice::<HashMap<String, u64>>(&data)
+                    .map_err(|err| {
+                        warn!(
+                            "Failed to read session generations {}: {err}",
+                            path.display()
+                        )
+                    })
+                    .ok()
+            })
+            .unwrap_or_default();
+        Self {
+            path,
+            generations: RwLock::new(generations),
+        }
+    }
+
+    /// Current generation of the account, 0 until it logs out everywhere
+    pub(super) fn get(&self, email: &str) -> u64 {
+        self.generations
+            .read()
+            .ok()
+            .and_then(|generations| generations.get(email).copied())
+            .unwrap_or_default()
+    }
+
+    /// Bump and persist the generation of the account, returns the new one
+    pub(super) fn bump(&self, email: &str) -> anyhow::Result<u64> {
+        let mut generations = self
+            .generations
+            .write()
+            .map_err(|_| anyhow::anyhow!("Failed to write session generations"))?;
+        let generation = generations.entry(email.to_owned()).or_default();
+        *generation += 1;
+        let generation = *generation;
+        std::fs::write(&self.path, serde_json::to_vec(&*generations)?)?;
+        Ok(generation)
+    }
+}
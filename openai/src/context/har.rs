@@ -1,37 +1,64 @@
-use crate::{arkose, homedir::home_dir, info, warn};
+use crate::{
+    arkose::{self, har::Freshness},
+    homedir::home_dir,
+    info, warn, webhook,
+};
 use hotwatch::{Event, EventKind, Hotwatch};
+use serde::Serialize;
 use std::{
     collections::HashMap,
     path::{Path, PathBuf},
     sync::{
         atomic::{AtomicUsize, Ordering},
-        OnceLock, RwLock,
+        Arc, RwLock, Weak,
     },
 };
 
-pub(super) static HAR: OnceLock<RwLock<HashMap<arkose::Type, HarProvider>>> = OnceLock::new();
+/// HAR providers of a context, by arkose type
+pub(super) type HarMap = Arc<RwLock<HashMap<arkose::Type, HarProvider>>>;
 
 pub struct HarPath {
     pub dir_path: PathBuf,
     pub file_path: Option<PathBuf>,
 }
 
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "snake_case", tag = "status", content = "reason")]
+pub enum HarStatus {
+    Healthy,
+    Stale,
+    Invalid(String),
+    Disabled(String),
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HarHealth {
+    pub file: String,
+    #[serde(flatten)]
+    pub status: HarStatus,
+    pub consecutive_failures: usize,
+}
+
 #[derive(Debug)]
 pub(super) struct HarProvider {
     /// HAR dir path
     dir_path: PathBuf,
     /// File Hotwatch
-    hotwatch: Hotwatch,
+    hotwatch: Option<Hotwatch>,
     /// HAR file pool
     pool: Vec<String>,
     index: AtomicUsize,
+    /// HAR file health
+    health: HashMap<String, HarHealth>,
+    /// Disable a HAR file after consecutive failures
+    max_failures: usize,
 }
 
 impl HarProvider {
     pub(super) fn new(
-        _type: arkose::Type,
         dir_path: Option<&PathBuf>,
         default_dir_name: &str,
+        max_failures: usize,
     ) -> HarProvider {
         let dir_path = dir_path.cloned().unwrap_or(
             home_dir()
@@ -42,17 +69,24 @@ impl HarProvider {
         init_directory(&dir_path);
 
         let mut pool = Vec::new();
-        Self::init_pool(&dir_path, &mut pool);
+        let mut health = HashMap::new();
+        Self::init_pool(&dir_path, &mut pool, &mut health);
 
         HarProvider {
             pool,
-            hotwatch: watch_har_dir(_type, &dir_path),
+            hotwatch: None,
             dir_path,
             index: AtomicUsize::new(0),
+            health,
+            max_failures,
         }
     }
 
-    fn init_pool(dir_path: impl AsRef<Path>, pool: &mut Vec<String>) {
+    fn init_pool(
+        dir_path: impl AsRef<Path>,
+        pool: &mut Vec<String>,
+        health: &mut HashMap<String, HarHealth>,
+    ) {
         std::fs::read_dir(dir_path.as_ref())
             .expect("Failed to read har directory")
             .filter_map(|entry| entry.ok().map(|e| e.path()))
@@ -64,14 +98,99 @@ impl HarProvider {
             })
             .for_each(|file_path| {
                 if let Some(file_name) = file_path.file_stem() {
-                    pool.push(format!("{}.har", file_name.to_string_lossy()));
+                    let file = format!("{}.har", file_name.to_string_lossy());
+
+                    // Keep disabled HAR file out of the pool until it changes
+                    if let Some(HarStatus::Disabled(_)) = health.get(&file).map(|h| &h.status) {
+                        return;
+                    }
+
+                    let status = match arkose::har::validate_from_file(&file_path) {
+                        Ok(Freshness::Fresh) => HarStatus::Healthy,
+                        Ok(Freshness::Stale) => {
+                            warn!("HAR file: {} may be expired", file_path.display());
+                            HarStatus::Stale
+                        }
+                        Err(err) => {
+                            warn!("Invalid HAR file: {} error: {err}", file_path.display());
+                            HarStatus::Invalid(err.to_string())
+                        }
+                    };
+
+                    if !matches!(status, HarStatus::Invalid(_)) {
+                        pool.push(file.clone());
+                    }
+
+                    let consecutive_failures = health
+                        .get(&file)
+                        .map(|h| h.consecutive_failures)
+                        .unwrap_or_default();
+                    health.insert(
+                        file.clone(),
+                        HarHealth {
+                            file,
+                            status,
+                            consecutive_failures,
+                        },
+                    );
                 }
             });
     }
 
     fn reset_pool(&mut self) {
         self.pool.clear();
-        Self::init_pool(&self.dir_path, &mut self.pool)
+        self.health
+            .retain(|file, _| self.dir_path.join(file).exists());
+        Self::init_pool(&self.dir_path, &mut self.pool, &mut self.health)
+    }
+
+    /// Forget the HAR file health, used when the file changes
+    fn forget(&mut self, path: &Path) {
+        if let Some(file_name) = path.file_name() {
+            self.health.remove(file_name.to_string_lossy().as_ref());
+        }
+    }
+
+    /// Report the HAR file result, disable it after consecutive failures
+    pub(super) fn report(&mut self, path: &Path, success: bool) {
+        let file = match path.file_name() {
+            Some(file_name) => file_name.to_string_lossy().to_string(),
+            None => return,
+        };
+
+        if let Some(health) = self.health.get_mut(&file) {
+            if success {
+                health.consecutive_failures = 0;
+                return;
+            }
+
+            health.consecutive_failures += 1;
+            if self.max_failures > 0 && health.consecutive_failures >= self.max_failures {
+                warn!(
+                    "HAR file: {} disabled after {} consecutive failures",
+                    path.display(),
+                    health.consecutive_failures
+                );
+                health.status = HarStatus::Disabled(format!(
+                    "{} consecutive failures",
+                    health.consecutive_failures
+                ));
+                self.pool.retain(|f| f.ne(&file));
+                if self.pool.is_empty() {
+                    webhook::fire(
+                        webhook::Event::HarExhausted,
+                        serde_json::json!({ "dir": self.dir_path.display().to_string() }),
+                    );
+                }
+            }
+        }
+    }
+
+    /// HAR files health
+    pub(super) fn health(&self) -> Vec<HarHealth> {
+        let mut health = self.health.values().cloned().collect::<Vec<HarHealth>>();
+        health.sort_by(|a, b| a.file.cmp(&b.file));
+        health
     }
 
     pub(super) fn pool(&self) -> HarPath {
@@ -103,6 +222,21 @@ impl HarProvider {
     }
 }
 
+/// Build the HAR map and start watching each HAR directory
+pub(super) fn new_har_map(providers: HashMap<arkose::Type, HarProvider>) -> HarMap {
+    let har_map = Arc::new(RwLock::new(providers));
+    if let Ok(mut providers) = har_map.write() {
+        providers.iter_mut().for_each(|(_type, har)| {
+            har.hotwatch = Some(watch_har_dir(
+                *_type,
+                &har.dir_path,
+                Arc::downgrade(&har_map),
+            ))
+        });
+    }
+    har_map
+}
+
 fn init_directory(path: impl AsRef<Path>) {
     let path = path.as_ref();
 
@@ -112,7 +246,11 @@ fn init_directory(path: impl AsRef<Path>) {
     }
 }
 
-fn watch_har_dir(_type: arkose::Type, path: impl AsRef<Path>) -> Hotwatch {
+fn watch_har_dir(
+    _type: arkose::Type,
+    path: impl AsRef<Path>,
+    har_map: Weak<RwLock<HashMap<arkose::Type, HarProvider>>>,
+) -> Hotwatch {
     let mut hotwatch = Hotwatch::new().expect("hotwatch failed to initialize!");
     hotwatch
         .watch(path.as_ref().display().to_string(), {
@@ -126,12 +264,15 @@ fn watch_har_dir(_type: arkose::Type, path: impl AsRef<Path>) -> Hotwatch {
                             "HAR directory: {watch_path} changes observed: {}",
                             path.display()
                         );
-                        let lock = HAR.get().unwrap();
+                        let Some(lock) = har_map.upgrade() else {
+                            return;
+                        };
                         let mut har_map = lock.write().expect("Failed to get har map");
                         if let Some(har) = har_map.get_mut(&_type) {
                             // clear cache
                             if let Some(path_str) = path.as_path().to_str() {
                                 arkose::har::clear_cache(path_str);
+                                har.forget(path);
                                 har.reset_pool();
                             }
                         }
@@ -146,8 +287,10 @@ fn watch_har_dir(_type: arkose::Type, path: impl AsRef<Path>) -> Hotwatch {
 
 impl Drop for HarProvider {
     fn drop(&mut self) {
-        if let Some(err) = self.hotwatch.unwatch(self.dir_path.as_path()).err() {
-            warn!("hotwatch stop error: {err}")
+        if let Some(hotwatch) = self.hotwatch.as_mut() {
+            if let Some(err) = hotwatch.unwatch(self.dir_path.as_path()).err() {
+                warn!("hotwatch stop error: {err}")
+            }
         }
     }
 }
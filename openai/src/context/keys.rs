@@ -0,0 +1,191 @@
+use std::{collections::HashMap, path::PathBuf, sync::RwLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::warn;
+
+/// Upstream of a client key
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyTarget {
+    /// Account managed through the admin API, its token is refreshed by ninja
+    Account(String),
+    /// Upstream access token or platform API key
+    Token(String),
+}
+
+/// Scheduling class of a client key once the concurrency limit is reached, the waiting
+/// requests of the higher classes are let in more often than the lower ones
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyPriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl KeyPriority {
+    pub const ALL: [KeyPriority; 3] = [KeyPriority::High, KeyPriority::Normal, KeyPriority::Low];
+
+    /// Share of the freed slots
+    pub fn weight(self) -> u32 {
+        match self {
+            KeyPriority::Low => 1,
+            KeyPriority::Normal => 2,
+            KeyPriority::High => 4,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            KeyPriority::Low => "low",
+            KeyPriority::Normal => "normal",
+            KeyPriority::High => "high",
+        }
+    }
+
+    fn is_normal(&self) -> bool {
+        *self == KeyPriority::Normal
+    }
+}
+
+/// Client key of the key map, its upstream and priority,
+/// e.g. `{ account = "team-a@example.com", priority = "high" }`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct KeySpec {
+    #[serde(flatten)]
+    pub target: KeyTarget,
+    #[serde(default, skip_serializing_if = "KeyPriority::is_normal")]
+    pub priority: KeyPriority,
+}
+
+/// Client key as listed by the admin API, the key and token are masked
+#[derive(Serialize, Clone, Debug)]
+pub struct KeyEntry {
+    pub key: String,
+    pub account: Option<String>,
+    pub token: Option<String>,
+    pub priority: KeyPriority,
+    /// Set in the key map file, can't be changed through the admin API
+    pub fixed: bool,
+    /// Unix seconds of the last request with the key since the start
+    pub last_seen: Option<i64>,
+}
+
+/// Client keys mapped to upstream tokens or accounts, so the teams sharing an instance
+/// are kept apart on their own OpenAI accounts. The keys of the key map file are fixed,
+/// the keys added through the admin API are kept in the instance file across restarts
+pub(super) struct KeyMap {
+    path: PathBuf,
+    fixed: HashMap<String, KeySpec>,
+    keys: RwLock<HashMap<String, KeySpec>>,
+    last_seen: RwLock<HashMap<String, i64>>,
+}
+
+impl KeyMap {
+    pub(super) fn load(path: PathBuf, fixed: HashMap<String, KeySpec>) -> Self {
+        let keys = std::fs::read(&path)
+            .ok()
+            .and_then(|data| {
+                serde_json::from_slice::<HashMap<String, KeySpec>>(&data)
+                    .map_err(|err| warn!("Failed to read client keys {}: {err}", path.display()))
+                    .ok()
+            })
+            .unwrap_or_default();
+        Self {
+            path,
+            fixed,
+            keys: RwLock::new(keys),
+            last_seen: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub(super) fn get(&self, key: &str) -> Option<KeySpec> {
+        if let Some(spec) = self.fixed.get(key) {
+            return Some(spec.clone());
+        }
+        self.keys.read().ok()?.get(key).cloned()
+    }
+
+    /// Record a request with the key
+    pub(super) fn touch(&self, key: &str) {
+        let now = crate::now_duration()
+            .map(|now| now.as_secs() as i64)
+            .unwrap_or_default();
+        if let Ok(mut last_seen) = self.last_seen.write() {
+            last_seen.insert(key.to_owned(), now);
+        }
+    }
+
+    pub(super) fn last_seen(&self, key: &str) -> Option<i64> {
+        self.last_seen.read().ok()?.get(key).copied()
+    }
+
+    /// Add or change a key and persist the keys
+    pub(super) fn insert(&self, key: String, spec: KeySpec) -> anyhow::Result<()> {
+        if self.fixed.contains_key(&key) {
+            anyhow::bail!("key {} is fixed in the key map file", mask(&key))
+        }
+        let mut keys = self
+            .keys
+            .write()
+            .map_err(|_| anyhow::anyhow!("Failed to write client keys"))?;
+        keys.insert(key, spec);
+        crate::write_private(&self.path, &serde_json::to_vec(&*keys)?)?;
+        Ok(())
+    }
+
+    /// Remove a key and persist the keys, `false` if it is unknown
+    pub(super) fn remove(&self, key: &str) -> anyhow::Result<bool> {
+        if self.fixed.contains_key(key) {
+            anyhow::bail!("key {} is fixed in the key map file", mask(key))
+        }
+        let mut keys = self
+            .keys
+            .write()
+            .map_err(|_| anyhow::anyhow!("Failed to write client keys"))?;
+        if keys.remove(key).is_none() {
+            return Ok(false);
+        }
+        if let Ok(mut last_seen) = self.last_seen.write() {
+            last_seen.remove(key);
+        }
+        crate::write_private(&self.path, &serde_json::to_vec(&*keys)?)?;
+        Ok(true)
+    }
+
+    pub(super) fn list(&self) -> Vec<KeyEntry> {
+        let entry = |key: &str, spec: &KeySpec, fixed: bool| {
+            let (account, token) = match &spec.target {
+                KeyTarget::Account(account) => (Some(account.clone()), None),
+                KeyTarget::Token(token) => (None, Some(mask(token))),
+            };
+            KeyEntry {
+                key: mask(key),
+                account,
+                token,
+                priority: spec.priority,
+                fixed,
+                last_seen: self.last_seen(key),
+            }
+        };
+        let mut entries = self
+            .fixed
+            .iter()
+            .map(|(key, spec)| entry(key, spec, true))
+            .collect::<Vec<_>>();
+        if let Ok(keys) = self.keys.read() {
+            entries.extend(keys.iter().map(|(key, spec)| entry(key, spec, false)));
+        }
+        entries
+    }
+}
+
+/// `nk-abc...wxyz`
+pub fn mask(key: &str) -> String {
+    match (key.get(..6), key.get(key.len().saturating_sub(4)..)) {
+        (Some(head), Some(tail)) if key.len() > 12 => format!("{head}...{tail}"),
+        _ => "...".to_owned(),
+    }
+}
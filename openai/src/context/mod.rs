@@ -1,57 +1,367 @@
+mod accounts;
+mod generations;
 mod har;
+mod keys;
 mod preauth;
+mod toggles;
+mod websessions;
 
 use std::{
     collections::HashMap,
+    future::Future,
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    path::PathBuf,
-    sync::OnceLock,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::Duration,
 };
 
 use crate::{
-    arkose::{self, funcaptcha::ArkoseSolver},
+    arkose::{self, funcaptcha::ArkoseSolver, pool::ArkoseTokenPool},
     auth::AuthClient,
-    balancer::ClientRoundRobinBalancer,
+    balancer::{AccountBinding, ClientRoundRobinBalancer, Ipv6Strategy},
+    chatgpt::model::alias::ModelAlias,
+    cookies::CookieJars,
+    dns::DohEndpoint,
     error,
+    homedir::home_dir,
+    impersonate::{ImpersonateProfiles, UserAgentPool, UserAgentRotation},
+    metrics,
+    token::model::AuthenticateToken,
+    warn,
+    webhook::Webhook,
 };
 use reqwest::Client;
 use typed_builder::TypedBuilder;
 
+#[cfg(feature = "ldap")]
+use crate::serve::ldap::LoginFailures;
+#[cfg(feature = "plugin")]
+use crate::serve::plugin::Plugins;
+#[cfg(feature = "limit")]
+use crate::serve::RateWindow;
+#[cfg(all(feature = "serve", feature = "template"))]
+use crate::serve::{buildid::BuildId, route::arkose::AssetCache};
+#[cfg(feature = "serve")]
+use crate::serve::{
+    captcha::{self, CaptchaPasses, CaptchaProvider, CaptchaVerifier},
+    cassette::Cassette,
+    clearance::Clearance,
+    coalesce::Flights,
+    contentlog::ContentLog,
+    conversations::ConversationCache,
+    drain::Drain,
+    keypool::ApiKeyPool,
+    logfile::LogRotation,
+    mirror::Mirror,
+    moderation::Moderation,
+    puid::PuidCache,
+    requirements::RequirementsCache,
+    responses::ResponseCache,
+    share::ShareCache,
+    upstream::{UpstreamSet, Upstreams},
+    usage::{self, UsageStore, UsageStrategy},
+};
+
 use self::{
-    har::{HarPath, HarProvider, HAR},
+    accounts::AccountTracker,
+    generations::SessionGenerations,
+    har::{HarHealth, HarMap, HarPath, HarProvider},
+    keys::KeyMap,
     preauth::PreauthCookieProvider,
+    toggles::TogglesStore,
+    websessions::WebSessions,
 };
 
+pub use self::accounts::{AccountState, AccountStatus, Credential, ManagedAccount};
+pub use self::keys::{mask as mask_key, KeyEntry, KeyPriority, KeySpec, KeyTarget};
+pub use self::preauth::PreauthCookie;
+pub use self::toggles::{Toggles, TogglesPatch};
+
+/// Hostnames the forward CONNECT proxy tunnels to by default
+pub const DEFAULT_FORWARD_HOSTS: [&str; 4] = [
+    "openai.com",
+    "*.openai.com",
+    "*.oaistatic.com",
+    "*.oaiusercontent.com",
+];
+
+/// Default server port
+const DEFAULT_PORT: u16 = 7999;
+
+tokio::task_local! {
+    /// Context of the running server instance
+    static CURRENT: Arc<Context>;
+}
+
+/// Initialize the process default context, used outside of a server instance.
+///
+/// Server instances create their own context, `Serve` does not require it.
 /// Use Once to guarantee initialization only once
 pub fn init(args: ContextArgs) {
-    if let Some(_) = CTX.set(Context::new(args)).err() {
+    if let Some(_) = CTX.set(Arc::new(Context::new(args))).err() {
         error!("Failed to initialize context");
     };
 }
 
-/// Get the program context
-pub fn get_instance() -> &'static Context {
-    CTX.get_or_init(|| Context::new(ContextArgs::builder().build()))
+/// Get the context of the current server instance, or the process default
+pub fn get_instance() -> Arc<Context> {
+    CURRENT.try_with(Arc::clone).unwrap_or_else(|_| {
+        CTX.get_or_init(|| Arc::new(Context::new(ContextArgs::builder().build())))
+            .clone()
+    })
+}
+
+/// Run the future with `ctx` as the current context
+pub async fn scope<F: Future>(ctx: Arc<Context>, f: F) -> F::Output {
+    CURRENT.scope(ctx, f).await
+}
+
+/// Run the closure with `ctx` as the current context
+pub fn sync_scope<F: FnOnce() -> R, R>(ctx: Arc<Context>, f: F) -> R {
+    CURRENT.sync_scope(ctx, f)
+}
+
+/// Metrics registry of the running server instance, `None` outside of one
+pub(crate) fn current_metrics() -> Option<Arc<metrics::Registry>> {
+    CURRENT.try_with(|ctx| ctx.metrics.clone()).ok()
+}
+
+fn lowercase(entries: Vec<String>) -> Vec<String> {
+    entries
+        .into_iter()
+        .map(|entry| entry.trim().to_lowercase())
+        .filter(|entry| !entry.is_empty())
+        .collect()
+}
+
+/// An entry is an email address, a domain or a `*.` wildcard of the subdomains of a domain
+fn email_matches(email: &str, entry: &str) -> bool {
+    if entry.contains('@') {
+        return email == entry;
+    }
+    let Some((_, domain)) = email.rsplit_once('@') else {
+        return false;
+    };
+    match entry.strip_prefix("*.") {
+        Some(parent) => domain
+            .strip_suffix(parent)
+            .is_some_and(|sub| sub.ends_with('.')),
+        None => domain == entry,
+    }
+}
+
+/// Per instance state file in the home directory, instances not bound to the
+/// default port get the port as suffix so they do not overwrite each other
+fn instance_path(bind: Option<SocketAddr>, name: &str) -> PathBuf {
+    let name = match bind {
+        Some(addr) if addr.port() != DEFAULT_PORT => format!("{name}_{}", addr.port()),
+        _ => name.to_owned(),
+    };
+    home_dir().unwrap_or(PathBuf::from(".")).join(name)
+}
+
+/// Runtime settings as configured at startup
+fn startup_toggles(args: &ContextArgs) -> Toggles {
+    #[cfg(feature = "limit")]
+    let (tb_enable, tb_capacity, tb_fill_rate) =
+        (args.tb_enable, args.tb_capacity, args.tb_fill_rate);
+    #[cfg(not(feature = "limit"))]
+    let (tb_enable, tb_capacity, tb_fill_rate) = (false, 60, 1);
+    Toggles {
+        disable_ui: args.disable_ui,
+        tb_enable,
+        tb_capacity,
+        tb_fill_rate,
+        cf_turnstile: true,
+        maintenance: false,
+    }
+}
+
+/// Spawn a task that keeps the current context
+pub fn spawn<F>(f: F) -> tokio::task::JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(CURRENT.scope(get_instance(), f))
 }
 
 #[derive(TypedBuilder, Clone, Default)]
 pub struct ContextArgs {
     /// Server bind address
-    #[builder(setter(into), default = Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 7999)))]
+    #[builder(setter(into), default = Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), DEFAULT_PORT)))]
     pub(crate) bind: Option<SocketAddr>,
 
     /// Machine worker pool
     #[builder(setter(into), default = 1)]
     pub(crate) workers: usize,
 
+    /// Log file path, log to stdout if not set
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default)]
+    pub(crate) log_file: Option<PathBuf>,
+
+    /// Log file rotation by time
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default)]
+    pub(crate) log_rotation: LogRotation,
+
+    /// Log file rotation by size (MB), 0 to disable
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default)]
+    pub(crate) log_max_size: u64,
+
+    /// Access log file path, one JSON line per request, rotated like the log file
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default)]
+    pub(crate) access_log: Option<PathBuf>,
+
+    /// Rotated log files to keep, 0 to keep all
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default = 7)]
+    pub(crate) log_max_files: usize,
+
+    /// Usage accounting store, disabled if not set
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default)]
+    pub(crate) usage_store: Option<UsageStrategy>,
+
+    /// Usage accounting redis url
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default = "redis://127.0.0.1:6379".to_string())]
+    pub(crate) usage_redis_url: String,
+
+    /// Usage accounting retention (days)
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default = 90)]
+    pub(crate) usage_retention: u64,
+
+    /// Conversation list cache ttl (seconds), 0 to disable
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default = 0)]
+    pub(crate) conversation_cache_ttl: u64,
+
+    /// Wait for upstream before serving the stale conversation list (seconds)
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default = 3)]
+    pub(crate) conversation_cache_timeout: u64,
+
+    /// Share page data cache ttl (seconds), 0 to disable
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default = 0)]
+    pub(crate) share_cache_ttl: u64,
+
+    /// Model list and account check cache ttl (seconds), 0 to disable
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default = 0)]
+    pub(crate) response_cache_ttl: u64,
+
+    /// Expired entry sweep interval (seconds), 0 to disable
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default = 300)]
+    pub(crate) sweep_interval: u64,
+
+    /// Retention of the stored user data (days), 0 to disable
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default)]
+    pub(crate) data_retention: u64,
+
+    /// Wait for the in-flight event streams on shutdown (seconds), 0 to disable
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default = 60)]
+    pub(crate) shutdown_drain_timeout: u64,
+
+    /// Graceful shutdown timeout (seconds), the connections still open are then closed
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default = 30)]
+    pub(crate) shutdown_timeout: u64,
+
+    /// Platform API keys pooled for the /v1 proxy
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default)]
+    pub(crate) api_key_pool: Vec<String>,
+
+    /// Rewrite plugins of the proxied routes, compiled before the start
+    #[cfg(feature = "plugin")]
+    #[builder(setter(into), default)]
+    pub(crate) plugins: Option<Arc<Plugins>>,
+
+    /// Content moderation of the keyword rules and the endpoint, compiled before the start
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default)]
+    pub(crate) moderation: Option<Arc<Moderation>>,
+
+    /// Shadow traffic mirror to a http(s) upstream origin or a local sink file
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default)]
+    pub(crate) mirror: Option<Arc<Mirror>>,
+
+    /// Cassette of the recorded upstream exchanges, opened before the start
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default)]
+    pub(crate) cassette: Option<Arc<Cassette>>,
+
+    /// Content log of the prompts, opened before the start
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default)]
+    pub(crate) content_log: Option<Arc<ContentLog>>,
+
+    /// Alternate ChatGPT API endpoints (mirrors/gateways), used when the official one fails
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default)]
+    pub(crate) chatgpt_upstream: Vec<String>,
+
+    /// Alternate platform API endpoints (mirrors/gateways), used when the official one fails
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default)]
+    pub(crate) platform_upstream: Vec<String>,
+
     /// Concurrent limit (Enforces a limit on the concurrent number of requests the underlying)
     #[builder(setter(into), default = 65535)]
     pub(crate) concurrent_limit: usize,
 
+    /// Requests waiting for the concurrency limit at most, 0 for unbounded
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default)]
+    pub(crate) queue_depth: usize,
+
+    /// Wait of a request for the concurrency limit at most (seconds), 0 waits until served
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default)]
+    pub(crate) queue_timeout: u64,
+
     /// Disable direct connection
     #[builder(default = false)]
     pub(crate) disable_direct: bool,
 
+    /// Hide the upstream conversations once the response is finished
+    #[builder(default = false)]
+    pub(crate) stateless: bool,
+
+    /// Share one upstream call among identical concurrent GET requests
+    #[builder(default = false)]
+    pub(crate) coalesce_requests: bool,
+
+    /// Requested model names mapped to backend slugs
+    #[builder(setter(into), default)]
+    pub(crate) model_alias: ModelAlias,
+
+    /// SSE keepalive ping interval (seconds), 0 to disable
+    #[builder(setter(into), default = 15)]
+    pub(crate) sse_keepalive: u64,
+
+    /// Maximum duration of a streamed conversation (seconds), 0 to disable
+    #[builder(setter(into), default = 0)]
+    pub(crate) sse_max_duration: u64,
+
+    /// Upstream path stopping the generation when the client disconnects mid-stream
+    #[builder(setter(into), default)]
+    pub(crate) stop_generation_path: Option<String>,
+
+    /// Event types stripped from the proxied event streams
+    #[builder(setter(into), default)]
+    pub(crate) sse_strip: Vec<String>,
+
     /// Enabled Cookie Store
     #[builder(default = false)]
     pub(crate) cookie_store: bool,
@@ -64,6 +374,18 @@ pub struct ContextArgs {
     #[builder(setter(into), default = 90)]
     pub(crate) pool_idle_timeout: usize,
 
+    /// Maximum idle connections per host kept in the pool, unlimited if unset
+    #[builder(setter(into), default)]
+    pub(crate) pool_max_idle_per_host: Option<usize>,
+
+    /// Clients per outbound route (direct or proxy), each with its own connection pool
+    #[builder(setter(into), default = 1)]
+    pub(crate) pool_size: usize,
+
+    /// Disable TCP_NODELAY of the upstream sockets
+    #[builder(default = false)]
+    pub(crate) disable_tcp_nodelay: bool,
+
     /// Client timeout
     #[builder(setter(into), default = 600)]
     pub(crate) timeout: usize,
@@ -72,7 +394,40 @@ pub struct ContextArgs {
     #[builder(setter(into), default = 60)]
     pub(crate) connect_timeout: usize,
 
-    /// Server proxies
+    /// Timeout of the event stream requests until the response headers (seconds), the client
+    /// timeout if 0
+    #[builder(setter(into), default = 0)]
+    pub(crate) stream_timeout: u64,
+
+    /// Longest wait for the next chunk of an upstream event stream (seconds), 0 to disable
+    #[builder(setter(into), default = 0)]
+    pub(crate) stream_read_timeout: u64,
+
+    /// TLS fingerprint profiles of the API clients
+    #[builder(setter(into), default)]
+    pub(crate) impersonate: ImpersonateProfiles,
+
+    /// TLS fingerprint profiles of the auth clients
+    #[builder(setter(into), default)]
+    pub(crate) auth_impersonate: ImpersonateProfiles,
+
+    /// TLS fingerprint profiles of the arkose clients, the API clients are used if empty
+    #[builder(setter(into), default)]
+    pub(crate) arkose_impersonate: ImpersonateProfiles,
+
+    /// User-Agents of the upstream requests, generated from the profiles if empty
+    #[builder(setter(into), default)]
+    pub(crate) user_agents: Vec<String>,
+
+    /// User-Agent rotation, the clients keep the User-Agent of their profile if unset
+    #[builder(setter(into), default)]
+    pub(crate) user_agent_rotation: Option<UserAgentRotation>,
+
+    /// Cloudflare clearance harvester url, FlareSolverr compatible
+    #[builder(setter(into), default)]
+    pub(crate) cf_harvester: Option<String>,
+
+    /// Server proxies (http/https/socks5/socks5h, credentials go in the url userinfo)
     #[builder(setter(into), default)]
     pub(crate) proxies: Vec<String>,
 
@@ -84,6 +439,18 @@ pub struct ContextArgs {
     #[builder(setter(into), default)]
     pub(crate) ipv6_subnet: Option<(std::net::Ipv6Addr, u8)>,
 
+    /// Ipv6 subnet rotation strategy
+    #[builder(setter(into), default)]
+    pub(crate) ipv6_strategy: Ipv6Strategy,
+
+    /// Per-account outbound binding, keyed by account email
+    #[builder(setter(into), default)]
+    pub(crate) account_bindings: HashMap<String, AccountBinding>,
+
+    /// DNS-over-HTTPS endpoint for outbound clients
+    #[builder(setter(into), default)]
+    pub(crate) doh: Option<DohEndpoint>,
+
     /// TLS cert
     #[builder(setter(into), default)]
     pub(crate) tls_cert: Option<PathBuf>,
@@ -96,18 +463,201 @@ pub struct ContextArgs {
     #[builder(setter(into), default)]
     auth_key: Option<String>,
 
+    /// Admin API key
+    #[builder(setter(into), default)]
+    pub(crate) admin_key: Option<String>,
+
+    /// Client keys mapped to upstream tokens or accounts, fixed
+    #[builder(setter(into), default)]
+    pub(crate) key_map: HashMap<String, KeySpec>,
+
+    /// Accept the token of the `api_key` / `access_token` query
+    #[builder(default = false)]
+    pub(crate) query_token: bool,
+
+    /// HTTP Basic auth `user:password` pairs
+    #[builder(setter(into), default)]
+    pub(crate) basic_auth: Vec<String>,
+
+    /// Paths passing without the HTTP Basic auth
+    #[builder(setter(into), default)]
+    pub(crate) basic_auth_exempt: Vec<String>,
+
+    /// System prompts of the new conversations by client key or account
+    #[builder(setter(into), default)]
+    pub(crate) system_prompts: Option<SystemPrompts>,
+
+    /// Prepend the system prompts to the proxied conversations too
+    #[builder(default = false)]
+    pub(crate) system_prompt_proxy: bool,
+
+    /// Webhook urls
+    #[builder(setter(into), default)]
+    pub(crate) webhook: Vec<String>,
+
+    /// Webhook HMAC-SHA256 signing secret
+    #[builder(setter(into), default)]
+    webhook_secret: Option<String>,
+
     /// Disable web ui
     #[builder(setter(into), default = false)]
     pub(crate) disable_ui: bool,
 
-    /// Cloudflare captcha site key
+    /// Disable web ui share pages
+    #[builder(setter(into), default = false)]
+    pub(crate) disable_share: bool,
+
+    /// Url prefix all routes are mounted under
+    #[builder(setter(into), default)]
+    pub(crate) url_prefix: Option<String>,
+
+    /// Web ui templates and static resources override directory
+    #[builder(setter(into), default)]
+    pub(crate) ui_dir: Option<PathBuf>,
+
+    /// WebUI session cookie lifetime (seconds) without "remember me", 0 until the browser is closed,
+    /// `None` until the token expires
+    #[builder(setter(into), default)]
+    pub(crate) session_lifetime: Option<u64>,
+
+    /// Refresh the WebUI session when the token expires within (seconds)
+    #[builder(setter(into), default = 21600)]
+    pub(crate) session_refresh_window: u64,
+
+    /// Absolute WebUI session lifetime (seconds) since the login, 0 for no limit
+    #[builder(setter(into), default = 0)]
+    pub(crate) session_max_age: u64,
+
+    /// Extend the WebUI session cookie on every request instead of on session refresh only
+    #[builder(setter(into), default = false)]
+    pub(crate) session_sliding: bool,
+
+    /// Login page captcha site key
     #[builder(setter(into), default)]
     pub(crate) cf_site_key: Option<String>,
 
-    /// Cloudflare captcha secret key
+    /// Login page captcha secret key
     #[builder(setter(into), default)]
     pub(crate) cf_secret_key: Option<String>,
 
+    /// Login page captcha provider, turnstile / hcaptcha / recaptcha (v3)
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default)]
+    pub(crate) captcha_provider: CaptchaProvider,
+
+    /// Minimum reCAPTCHA v3 score of the login (0.0 ~ 1.0)
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default = 0.5)]
+    pub(crate) recaptcha_min_score: f64,
+
+    /// Skip the login captcha of a client ip that passed it within (seconds), 0 to disable
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default = 0)]
+    pub(crate) captcha_cache_ttl: u64,
+
+    /// Header of the client ip set by the reverse proxy in front
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default)]
+    pub(crate) real_ip_header: Option<String>,
+
+    /// WebUI CSRF cookie key, random if unset
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default)]
+    pub(crate) csrf_key: Option<String>,
+
+    /// WebUI CSRF cookie with SameSite=Strict
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default = false)]
+    pub(crate) csrf_strict: bool,
+
+    /// New WebUI CSRF token for every rendered form
+    #[cfg(feature = "serve")]
+    #[builder(setter(into), default = false)]
+    pub(crate) csrf_rotate: bool,
+
+    /// WebUI login allowed emails or domains, empty allows every account
+    #[builder(setter(into), default)]
+    pub(crate) login_allow: Vec<String>,
+
+    /// WebUI login denied emails or domains
+    #[builder(setter(into), default)]
+    pub(crate) login_deny: Vec<String>,
+
+    /// WebUI OIDC single sign-on issuer url
+    #[builder(setter(into), default)]
+    pub(crate) oidc_issuer: Option<String>,
+
+    /// WebUI OIDC client id
+    #[builder(setter(into), default)]
+    pub(crate) oidc_client_id: Option<String>,
+
+    /// WebUI OIDC client secret
+    #[builder(setter(into), default)]
+    pub(crate) oidc_client_secret: Option<String>,
+
+    /// WebUI OIDC scopes
+    #[builder(setter(into), default = "openid email profile".to_owned())]
+    pub(crate) oidc_scopes: String,
+
+    /// WebUI LDAP login url
+    #[builder(setter(into), default)]
+    pub(crate) ldap_url: Option<String>,
+
+    /// WebUI LDAP bind DN of the user
+    #[builder(setter(into), default)]
+    pub(crate) ldap_user_dn: Option<String>,
+
+    /// WebUI LDAP StartTLS upgrade of `ldap://`
+    #[builder(setter(into), default)]
+    pub(crate) ldap_starttls: bool,
+
+    /// WebUI LDAP search base of the user entry
+    #[builder(setter(into), default)]
+    pub(crate) ldap_base_dn: Option<String>,
+
+    /// WebUI LDAP filter of the user entry
+    #[builder(
+        setter(into),
+        default = "(|(uid={username})(sAMAccountName={username})(userPrincipalName={username}))".to_owned()
+    )]
+    pub(crate) ldap_user_filter: String,
+
+    /// WebUI LDAP group DN to pooled accounts
+    #[builder(setter(into), default)]
+    pub(crate) ldap_groups: HashMap<String, Vec<String>>,
+
+    /// WebUI site title
+    #[builder(setter(into), default)]
+    pub(crate) site_title: Option<String>,
+
+    /// WebUI logo url
+    #[builder(setter(into), default)]
+    pub(crate) logo_url: Option<String>,
+
+    /// WebUI footer text
+    #[builder(setter(into), default)]
+    pub(crate) footer_text: Option<String>,
+
+    /// Disable WebUI security headers
+    #[builder(setter(into), default = false)]
+    pub(crate) disable_security_headers: bool,
+
+    /// WebUI Content-Security-Policy
+    #[builder(setter(into), default)]
+    pub(crate) csp: Option<String>,
+
+    /// WebUI X-Frame-Options
+    #[builder(setter(into), default)]
+    pub(crate) frame_options: Option<String>,
+
+    /// WebUI Referrer-Policy
+    #[builder(setter(into), default)]
+    pub(crate) referrer_policy: Option<String>,
+
+    /// CSP frame-ancestors of the embedded share pages
+    #[builder(setter(into), default)]
+    pub(crate) share_frame_ancestors: Option<String>,
+
     /// Arkose endpoint
     #[builder(setter(into), default)]
     pub(crate) arkose_endpoint: Option<String>,
@@ -136,10 +686,26 @@ pub struct ContextArgs {
     #[builder(setter(into), default)]
     pub(crate) arkose_har_upload_key: Option<String>,
 
+    /// Disable a HAR file after consecutive failures (0 means never)
+    #[builder(setter(into), default = 5)]
+    pub(crate) arkose_har_max_failures: usize,
+
     /// arkoselabs solver
     #[builder(setter(into), default)]
     pub(crate) arkose_solver: Option<ArkoseSolver>,
 
+    /// Arkose solver/HAR success rate alert threshold
+    #[builder(setter(into), default = 0.5)]
+    pub(crate) arkose_alert_threshold: f64,
+
+    /// Arkose token warm pool size per type (0 means disabled)
+    #[builder(setter(into), default = 0)]
+    pub(crate) arkose_pool_size: usize,
+
+    /// Arkose token warm pool token lifetime (seconds)
+    #[builder(setter(into), default = 120)]
+    pub(crate) arkose_pool_ttl: u64,
+
     /// Enable Tokenbucket
     #[cfg(feature = "limit")]
     #[builder(setter(into), default = false)]
@@ -170,15 +736,47 @@ pub struct ContextArgs {
     #[builder(setter(into), default = 86400)]
     pub(crate) tb_expired: u32,
 
+    /// Tokenbucket windows limited together with the capacity and fill rate
+    #[cfg(feature = "limit")]
+    #[builder(setter(into), default)]
+    pub(crate) tb_window: Vec<RateWindow>,
+
+    /// Forward CONNECT proxy bind address
+    #[builder(setter(into), default)]
+    pub(crate) fbind: Option<std::net::SocketAddr>,
+
+    /// Forward CONNECT proxy allowed hostnames
+    #[builder(setter(into), default = DEFAULT_FORWARD_HOSTS.iter().map(ToString::to_string).collect())]
+    pub(crate) fhosts: Vec<String>,
+
+    /// Forward CONNECT proxy `user:password` credentials
+    #[builder(setter(into), default)]
+    pub(crate) fauth: Vec<String>,
+
     /// Preauth MITM server bind address
     #[cfg(feature = "preauth")]
     #[builder(setter(into), default)]
     pub(crate) pbind: Option<std::net::SocketAddr>,
 
-    /// Preauth MITM server upstream proxy
+    /// Preauth MITM server upstream proxies, rotated per connection
+    #[cfg(feature = "preauth")]
+    #[builder(setter(into), default)]
+    pub(crate) pupstream: Vec<String>,
+
+    /// Preauth MITM server intercept hostnames
+    #[cfg(feature = "preauth")]
+    #[builder(setter(into), default = vec![String::from("ios.chat.openai.com")])]
+    pub(crate) pfilter: Vec<String>,
+
+    /// Preauth cookie pool low webhook url
     #[cfg(feature = "preauth")]
     #[builder(setter(into), default)]
-    pub(crate) pupstream: Option<String>,
+    pub(crate) preauth_webhook: Option<String>,
+
+    /// Fire the preauth webhook when the cookie pool size drops below
+    #[cfg(feature = "preauth")]
+    #[builder(setter(into), default = 1)]
+    pub(crate) preauth_webhook_threshold: usize,
 
     /// crate MITM server CA certificate file path
     #[cfg(feature = "preauth")]
@@ -191,56 +789,282 @@ pub struct ContextArgs {
     pub(crate) pkey: PathBuf,
 }
 
-pub struct CfTurnstile {
-    pub site_key: String,
-    pub secret_key: String,
+/// WebUI branding
+pub struct Branding {
+    pub site_title: Option<String>,
+    pub logo_url: Option<String>,
+    pub footer_text: Option<String>,
 }
 
-// Program context
-static CTX: OnceLock<Context> = OnceLock::new();
+/// WebUI OIDC single sign-on relying party
+pub struct Oidc {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub scopes: String,
+    /// Endpoints of the issuer, fetched once from its discovery document
+    pub discovery: tokio::sync::OnceCell<OidcDiscovery>,
+}
+
+/// OpenID Connect discovery document, the endpoints used by the sign-in
+#[derive(serde::Deserialize)]
+pub struct OidcDiscovery {
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: String,
+}
+
+/// HTTP Basic auth of the whole server
+pub struct BasicAuth {
+    /// User and password pairs
+    pub credentials: Vec<(String, String)>,
+    /// Path prefixes passing without the credentials
+    pub exempt: Vec<String>,
+    /// Key of the cookie tags, a restart asks the browsers for the credentials again
+    pub cookie_key: [u8; 32],
+}
+
+/// System prompts prepended to the new conversations, the prompt of the client key
+/// takes precedence over the prompt of the account
+#[derive(serde::Deserialize, Default, Clone)]
+pub struct SystemPrompts {
+    /// Client key to its prompt
+    #[serde(default)]
+    pub keys: HashMap<String, String>,
+    /// Account email to its prompt
+    #[serde(default)]
+    pub accounts: HashMap<String, String>,
+}
+
+/// WebUI LDAP / Active Directory login
+pub struct Ldap {
+    pub url: String,
+    /// Bind DN template of the user
+    pub user_dn: String,
+    /// Search base of the user entry, `None` takes the login name as the email
+    pub base_dn: Option<String>,
+    pub user_filter: String,
+    /// Lowercased group DN to the pooled accounts of its members, empty pools every account
+    pub groups: HashMap<String, Vec<String>>,
+    /// Upgrade the `ldap://` connection with StartTLS before the bind
+    pub starttls: bool,
+    /// Recent failed logins
+    #[cfg(feature = "ldap")]
+    pub failures: LoginFailures,
+}
+
+/// WebUI security headers, `None` uses the default value, empty omits the header
+pub struct SecurityHeaders {
+    pub csp: Option<String>,
+    pub frame_options: Option<String>,
+    pub referrer_policy: Option<String>,
+    /// CSP `frame-ancestors` of the embedded share pages, any site when `None`
+    pub share_frame_ancestors: Option<String>,
+    /// Send `Strict-Transport-Security`, only when serving TLS
+    pub hsts: bool,
+}
+
+// Process default context
+static CTX: OnceLock<Arc<Context>> = OnceLock::new();
 
 pub struct Context {
+    /// Server bind address
+    bind: Option<SocketAddr>,
     /// Requesting client
     client_load: Option<ClientRoundRobinBalancer>,
     /// Requesting oauth client
     auth_client_load: Option<ClientRoundRobinBalancer>,
+    /// Requesting arkose client, the requesting client if no arkose profile is set
+    arkose_client_load: Option<ClientRoundRobinBalancer>,
+    /// User-Agents of the upstream requests
+    user_agents: UserAgentPool,
+    /// Cookie stores of the requesting clients, kept across restarts
+    cookie_jars: Option<Arc<CookieJars>>,
     /// arkoselabs solver
     arkose_solver: Option<ArkoseSolver>,
     /// HAR file upload authenticate key
     arkose_har_upload_key: Option<String>,
     /// Login auth key
     auth_key: Option<String>,
-    /// Cloudflare Turnstile
-    cf_turnstile: Option<CfTurnstile>,
+    /// Admin API key
+    admin_key: Option<String>,
+    /// HTTP Basic auth of the whole server
+    basic_auth: Option<BasicAuth>,
+    /// System prompts of the new conversations
+    system_prompts: Option<SystemPrompts>,
+    /// Prepend the system prompts to the proxied conversations too
+    system_prompt_proxy: bool,
+    /// Event webhooks
+    webhook: Option<Webhook>,
+    /// WebUI login allowed emails or domains
+    login_allow: Vec<String>,
+    /// WebUI login denied emails or domains
+    login_deny: Vec<String>,
+    /// WebUI OIDC single sign-on
+    oidc: Option<Oidc>,
+    /// WebUI LDAP login
+    ldap: Option<Ldap>,
+    /// Login page captcha
+    #[cfg(feature = "serve")]
+    captcha: Option<Box<dyn CaptchaVerifier>>,
+    /// Recent captcha verifications by client ip
+    #[cfg(feature = "serve")]
+    captcha_passes: Option<CaptchaPasses>,
+    /// Header of the client ip set by the reverse proxy in front
+    #[cfg(feature = "serve")]
+    real_ip_header: Option<String>,
+    /// WebUI branding
+    branding: Branding,
+    /// WebUI security headers
+    security_headers: Option<SecurityHeaders>,
+    /// WebUI override directory
+    ui_dir: Option<PathBuf>,
+    /// Url prefix, empty when mounted at the root
+    url_prefix: String,
+    /// WebUI share pages
+    share_pages: bool,
+    /// WebUI session cookie lifetime without "remember me"
+    session_lifetime: Option<u64>,
+    /// Absolute WebUI session lifetime
+    session_max_age: u64,
+    /// Extend the WebUI session cookie on every request
+    session_sliding: bool,
+    /// WebUI session refresh window
+    session_refresh_window: u64,
     /// Arkose endpoint
     arkose_endpoint: Option<String>,
     /// Enable Arkose GPT-3.5 experiment
     arkose_gpt3_experiment: bool,
+    /// Hide the upstream conversations once the response is finished
+    stateless: bool,
+    /// Share one upstream call among identical concurrent GET requests
+    coalesce_requests: bool,
+    /// New WebUI CSRF token for every rendered form
+    #[cfg(feature = "serve")]
+    csrf_rotate: bool,
+    /// Requested model names mapped to backend slugs
+    model_alias: ModelAlias,
+    /// SSE keepalive ping interval
+    sse_keepalive: Option<Duration>,
+    /// Maximum duration of a streamed conversation
+    sse_max_duration: Option<Duration>,
+    /// Timeout of the event stream requests until the response headers
+    stream_timeout: Duration,
+    /// Longest wait for the next chunk of an upstream event stream
+    stream_read_timeout: Option<Duration>,
+    /// Upstream path stopping the generation when the client disconnects mid-stream
+    stop_generation_path: Option<String>,
+    /// Event types stripped from the proxied event streams
+    sse_strip: Vec<String>,
+    /// Arkose solver/HAR success rate alert threshold
+    arkose_alert_threshold: f64,
+    /// Recent attempts of the arkose token sources
+    arkose_attempts: arkose::metrics::Attempts,
+    /// Arkose token warm pool
+    arkose_token_pool: Option<ArkoseTokenPool>,
     /// PreAuth cookie cache
     preauth_provider: Option<PreauthCookieProvider>,
+    /// Arkose HAR providers
+    har: HarMap,
+    /// HAR upload session token secret
+    token_secret: tokio::sync::OnceCell<String>,
+    /// Accounts seen by this instance
+    accounts: AccountTracker,
+    /// Settings changed at runtime
+    toggles: TogglesStore,
+    /// WebUI session generation by account
+    session_generations: SessionGenerations,
+    /// WebUI sessions held by the browsers
+    web_sessions: WebSessions,
+    /// Client keys mapped to upstream tokens or accounts
+    keys: KeyMap,
+    /// Accept the token of the `api_key` / `access_token` query
+    query_token: bool,
+    /// Usage accounting store
+    #[cfg(feature = "serve")]
+    usage: Option<Arc<dyn UsageStore>>,
+    /// Conversation list cache
+    #[cfg(feature = "serve")]
+    conversation_cache: Option<ConversationCache>,
+    /// Share page data cache
+    #[cfg(feature = "serve")]
+    share_cache: Option<ShareCache>,
+    /// Model list and account check cache
+    #[cfg(feature = "serve")]
+    response_cache: Option<ResponseCache>,
+    /// Platform API key pool
+    #[cfg(feature = "serve")]
+    api_key_pool: Option<ApiKeyPool>,
+    /// Rewrite plugins
+    #[cfg(feature = "plugin")]
+    plugins: Option<Arc<Plugins>>,
+    /// Content moderation
+    #[cfg(feature = "serve")]
+    moderation: Option<Arc<Moderation>>,
+    /// Shadow traffic mirror
+    #[cfg(feature = "serve")]
+    mirror: Option<Arc<Mirror>>,
+    /// Upstream exchange record/replay
+    #[cfg(feature = "serve")]
+    cassette: Option<Arc<Cassette>>,
+    /// Prompt audit trail
+    #[cfg(feature = "serve")]
+    content_log: Option<Arc<ContentLog>>,
+    /// Retention of the stored user data (days)
+    #[cfg(feature = "serve")]
+    data_retention: Option<u64>,
+    /// Upstream endpoints with failover
+    #[cfg(feature = "serve")]
+    upstreams: Upstreams,
+    /// Cloudflare clearance of the outbound routes
+    #[cfg(feature = "serve")]
+    clearance: Option<Arc<Clearance>>,
+    /// In-flight event streams and conversations, waited for at shutdown
+    #[cfg(feature = "serve")]
+    drain: Arc<Drain>,
+    /// Metrics of the instance
+    metrics: Arc<metrics::Registry>,
+    /// Coalesced requests in flight
+    #[cfg(feature = "serve")]
+    flights: Arc<Flights>,
+    /// `_puid` cookies of the accounts
+    #[cfg(feature = "serve")]
+    puid_cache: PuidCache,
+    /// Chat requirements of the accounts
+    #[cfg(feature = "serve")]
+    requirements_cache: RequirementsCache,
+    /// Arkose static assets
+    #[cfg(all(feature = "serve", feature = "template"))]
+    arkose_assets: AssetCache,
+    /// ChatGPT frontend build id of the WebUI
+    #[cfg(all(feature = "serve", feature = "template"))]
+    build_id: BuildId,
+    /// WebUI templates, loaded with the routes
+    #[cfg(feature = "serve")]
+    templates: OnceLock<tera::Tera>,
 }
 
 impl Context {
-    fn new(args: ContextArgs) -> Self {
+    pub fn new(args: ContextArgs) -> Self {
         let gpt3_har_provider = HarProvider::new(
-            arkose::Type::GPT3,
             args.arkose_gpt3_har_dir.as_ref(),
             ".gpt3",
+            args.arkose_har_max_failures,
         );
         let gpt4_har_provider = HarProvider::new(
-            arkose::Type::GPT4,
             args.arkose_gpt4_har_dir.as_ref(),
             ".gpt4",
+            args.arkose_har_max_failures,
         );
         let auth_har_provider = HarProvider::new(
-            arkose::Type::Auth,
             args.arkose_auth_har_dir.as_ref(),
             ".auth",
+            args.arkose_har_max_failures,
         );
         let platform_har_provider = HarProvider::new(
-            arkose::Type::Platform,
             args.arkose_platform_har_dir.as_ref(),
             ".platform",
+            args.arkose_har_max_failures,
         );
 
         let mut har_map = HashMap::with_capacity(4);
@@ -248,30 +1072,223 @@ impl Context {
         har_map.insert(arkose::Type::GPT4, gpt4_har_provider);
         har_map.insert(arkose::Type::Auth, auth_har_provider);
         har_map.insert(arkose::Type::Platform, platform_har_provider);
-        HAR.set(std::sync::RwLock::new(har_map))
-            .expect("Failed to set har map");
+
+        let toggles =
+            TogglesStore::new(instance_path(args.bind, ".toggles"), startup_toggles(&args));
+
+        let user_agents = UserAgentPool::new(&args);
+        if user_agents.mixed_families() {
+            warn!("The API client profiles are of several browser families, the rotated User-Agents may not match the fingerprint");
+        }
+
+        // The harvested clearances are kept in the cookie stores
+        let cookie_jars = (args.cookie_store || args.cf_harvester.is_some())
+            .then(|| Arc::new(CookieJars::load(instance_path(args.bind, ".cookies"))));
+        #[cfg(feature = "serve")]
+        let clearance = cookie_jars.clone().map(|jars| {
+            let proxies = args.proxies.iter().cloned().chain(
+                args.account_bindings
+                    .values()
+                    .filter_map(|binding| binding.proxy.clone()),
+            );
+            Arc::new(Clearance::new(jars, args.cf_harvester.clone(), proxies))
+        });
 
         Context {
             client_load: Some(
-                ClientRoundRobinBalancer::new_client(&args)
+                ClientRoundRobinBalancer::new_client(&args, cookie_jars.clone())
                     .expect("Failed to initialize the requesting client"),
             ),
             auth_client_load: Some(
                 ClientRoundRobinBalancer::new_auth_client(&args)
                     .expect("Failed to initialize the requesting oauth client"),
             ),
+            arkose_client_load: (!args.arkose_impersonate.is_empty()).then(|| {
+                ClientRoundRobinBalancer::new_arkose_client(&args, cookie_jars.clone())
+                    .expect("Failed to initialize the requesting arkose client")
+            }),
+            user_agents,
+            cookie_jars,
             arkose_endpoint: args.arkose_endpoint,
             arkose_solver: args.arkose_solver,
             arkose_har_upload_key: args.arkose_har_upload_key,
             arkose_gpt3_experiment: args.arkose_gpt3_experiment,
+            stateless: args.stateless,
+            coalesce_requests: args.coalesce_requests,
+            #[cfg(feature = "serve")]
+            csrf_rotate: args.csrf_rotate,
+            model_alias: args.model_alias,
+            sse_keepalive: (args.sse_keepalive > 0)
+                .then(|| Duration::from_secs(args.sse_keepalive)),
+            sse_max_duration: (args.sse_max_duration > 0)
+                .then(|| Duration::from_secs(args.sse_max_duration)),
+            stream_timeout: Duration::from_secs(match args.stream_timeout {
+                0 => args.timeout as u64,
+                timeout => timeout,
+            }),
+            stream_read_timeout: (args.stream_read_timeout > 0)
+                .then(|| Duration::from_secs(args.stream_read_timeout)),
+            stop_generation_path: args.stop_generation_path,
+            sse_strip: args.sse_strip,
+            arkose_alert_threshold: args.arkose_alert_threshold,
+            arkose_attempts: Default::default(),
+            arkose_token_pool: (args.arkose_pool_size > 0).then(|| {
+                ArkoseTokenPool::new(
+                    args.arkose_pool_size,
+                    Duration::from_secs(args.arkose_pool_ttl),
+                )
+            }),
             auth_key: args.auth_key,
-            cf_turnstile: args.cf_site_key.and_then(|site_key| {
-                args.cf_secret_key.map(|secret_key| CfTurnstile {
-                    site_key,
-                    secret_key,
-                })
+            admin_key: args.admin_key,
+            basic_auth: (!args.basic_auth.is_empty()).then(|| BasicAuth {
+                credentials: args
+                    .basic_auth
+                    .iter()
+                    .filter_map(|pair| pair.split_once(':'))
+                    .map(|(user, password)| (user.to_owned(), password.to_owned()))
+                    .collect(),
+                exempt: args.basic_auth_exempt,
+                cookie_key: rand::random(),
+            }),
+            system_prompts: args.system_prompts.map(|prompts| SystemPrompts {
+                keys: prompts.keys,
+                accounts: prompts
+                    .accounts
+                    .into_iter()
+                    .map(|(account, prompt)| (account.to_lowercase(), prompt))
+                    .collect(),
+            }),
+            system_prompt_proxy: args.system_prompt_proxy,
+            login_allow: lowercase(args.login_allow),
+            login_deny: lowercase(args.login_deny),
+            oidc: args
+                .oidc_issuer
+                .zip(args.oidc_client_id.zip(args.oidc_client_secret))
+                .map(|(issuer, (client_id, client_secret))| Oidc {
+                    issuer: issuer.trim_end_matches('/').to_owned(),
+                    client_id,
+                    client_secret,
+                    scopes: args.oidc_scopes,
+                    discovery: tokio::sync::OnceCell::new(),
+                }),
+            ldap: args
+                .ldap_url
+                .zip(args.ldap_user_dn)
+                .map(|(url, user_dn)| Ldap {
+                    url,
+                    user_dn,
+                    base_dn: args.ldap_base_dn,
+                    user_filter: args.ldap_user_filter,
+                    groups: args
+                        .ldap_groups
+                        .into_iter()
+                        .map(|(group, accounts)| (group.to_lowercase(), lowercase(accounts)))
+                        .collect(),
+                    starttls: args.ldap_starttls,
+                    #[cfg(feature = "ldap")]
+                    failures: LoginFailures::default(),
+                }),
+            webhook: (!args.webhook.is_empty())
+                .then(|| Webhook::new(args.webhook, args.webhook_secret)),
+            session_lifetime: args.session_lifetime,
+            session_max_age: args.session_max_age,
+            session_sliding: args.session_sliding,
+            session_refresh_window: args.session_refresh_window,
+            branding: Branding {
+                site_title: args.site_title,
+                logo_url: args.logo_url,
+                footer_text: args.footer_text,
+            },
+            security_headers: (!args.disable_security_headers).then(|| SecurityHeaders {
+                csp: args.csp,
+                frame_options: args.frame_options,
+                referrer_policy: args.referrer_policy,
+                share_frame_ancestors: args.share_frame_ancestors,
+                hsts: args.tls_cert.is_some() && args.tls_key.is_some(),
             }),
-            preauth_provider: args.pbind.is_some().then(|| PreauthCookieProvider::new()),
+            ui_dir: args.ui_dir,
+            url_prefix: args.url_prefix.unwrap_or_default(),
+            share_pages: cfg!(feature = "template") && !args.disable_ui && !args.disable_share,
+            #[cfg(feature = "serve")]
+            captcha_passes: (args.captcha_cache_ttl > 0)
+                .then(|| CaptchaPasses::new(args.captcha_cache_ttl)),
+            #[cfg(feature = "serve")]
+            real_ip_header: args.real_ip_header,
+            #[cfg(feature = "serve")]
+            captcha: captcha::verifier(
+                args.captcha_provider,
+                args.cf_site_key,
+                args.cf_secret_key,
+                args.recaptcha_min_score,
+            ),
+            har: har::new_har_map(har_map),
+            preauth_provider: args.pbind.is_some().then(|| {
+                PreauthCookieProvider::new(
+                    instance_path(args.bind, ".preauth_cookies"),
+                    args.preauth_webhook,
+                    args.preauth_webhook_threshold,
+                )
+            }),
+            bind: args.bind,
+            token_secret: tokio::sync::OnceCell::new(),
+            accounts: AccountTracker::load(instance_path(args.bind, ".disabled_accounts")),
+            toggles,
+            session_generations: SessionGenerations::load(instance_path(
+                args.bind,
+                ".session_generations",
+            )),
+            web_sessions: WebSessions::load(instance_path(args.bind, ".web_sessions")),
+            keys: KeyMap::load(instance_path(args.bind, ".keys"), args.key_map),
+            query_token: args.query_token,
+            #[cfg(feature = "serve")]
+            usage: args.usage_store.and_then(|strategy| {
+                usage::new_store(strategy, &args.usage_redis_url, args.usage_retention)
+                    .map_err(|err| error!("Failed to initialize the usage store: {err}"))
+                    .ok()
+            }),
+            #[cfg(feature = "serve")]
+            conversation_cache: (args.conversation_cache_ttl > 0).then(|| {
+                ConversationCache::new(args.conversation_cache_ttl, args.conversation_cache_timeout)
+            }),
+            #[cfg(feature = "serve")]
+            share_cache: (args.share_cache_ttl > 0).then(|| ShareCache::new(args.share_cache_ttl)),
+            #[cfg(feature = "serve")]
+            response_cache: (args.response_cache_ttl > 0)
+                .then(|| ResponseCache::new(args.response_cache_ttl)),
+            #[cfg(feature = "serve")]
+            api_key_pool: (!args.api_key_pool.is_empty())
+                .then(|| ApiKeyPool::new(args.api_key_pool)),
+            #[cfg(feature = "plugin")]
+            plugins: args.plugins,
+            #[cfg(feature = "serve")]
+            moderation: args.moderation,
+            #[cfg(feature = "serve")]
+            mirror: args.mirror,
+            #[cfg(feature = "serve")]
+            cassette: args.cassette,
+            #[cfg(feature = "serve")]
+            content_log: args.content_log,
+            #[cfg(feature = "serve")]
+            data_retention: (args.data_retention > 0).then_some(args.data_retention),
+            #[cfg(feature = "serve")]
+            upstreams: Upstreams::new(args.chatgpt_upstream, args.platform_upstream),
+            #[cfg(feature = "serve")]
+            clearance,
+            #[cfg(feature = "serve")]
+            drain: Arc::default(),
+            metrics: Arc::default(),
+            #[cfg(feature = "serve")]
+            flights: Arc::default(),
+            #[cfg(feature = "serve")]
+            puid_cache: PuidCache::default(),
+            #[cfg(feature = "serve")]
+            requirements_cache: RequirementsCache::default(),
+            #[cfg(all(feature = "serve", feature = "template"))]
+            arkose_assets: AssetCache::default(),
+            #[cfg(all(feature = "serve", feature = "template"))]
+            build_id: BuildId::default(),
+            #[cfg(feature = "serve")]
+            templates: OnceLock::new(),
         }
     }
 
@@ -284,6 +1301,42 @@ impl Context {
             .into()
     }
 
+    /// User-Agents of the upstream requests
+    pub fn user_agents(&self) -> &UserAgentPool {
+        &self.user_agents
+    }
+
+    /// Get the reqwest client of the arkose requests
+    pub fn arkose_client(&self) -> Client {
+        match self.arkose_client_load.as_ref() {
+            Some(load) => load.next().into(),
+            None => self.client(),
+        }
+    }
+
+    /// Get the reqwest client, sticky to the account or session with the ipv6 strategy
+    pub fn sticky_client(&self, account: Option<&str>, session: Option<&str>) -> Client {
+        self.client_load
+            .as_ref()
+            .expect("The load balancer client is not initialized")
+            .next_sticky(account, session)
+            .into()
+    }
+
+    /// Like `sticky_client`, with the outbound route of the client, `None` for the per request ipv6 clients
+    pub fn sticky_client_route(
+        &self,
+        account: Option<&str>,
+        session: Option<&str>,
+    ) -> (Client, Option<String>) {
+        let (client, route) = self
+            .client_load
+            .as_ref()
+            .expect("The load balancer client is not initialized")
+            .next_sticky_route(account, session);
+        (client.into(), route)
+    }
+
     /// Get the reqwest auth client
     pub fn auth_client(&self) -> AuthClient {
         self.auth_client_load
@@ -293,6 +1346,15 @@ impl Context {
             .into()
     }
 
+    /// Get the reqwest auth client, sticky to the account with the ipv6 strategy
+    pub fn sticky_auth_client(&self, account: &str) -> AuthClient {
+        self.auth_client_load
+            .as_ref()
+            .expect("The load balancer auth client is not initialized")
+            .next_sticky(Some(account), None)
+            .into()
+    }
+
     /// Get the arkoselabs har file upload authenticate key
     pub fn arkose_har_upload_key(&self) -> Option<&String> {
         self.arkose_har_upload_key.as_ref()
@@ -305,20 +1367,163 @@ impl Context {
 
     /// Get the arkose har file path
     pub fn arkose_har_path(&self, _type: &arkose::Type) -> HarPath {
-        let har_lock = HAR
-            .get()
-            .expect("Failed to get har lock")
-            .read()
-            .expect("Failed to get har map");
+        let har_lock = self.har.read().expect("Failed to get har map");
         har_lock
             .get(_type)
             .map(|h| h.pool())
             .expect("Failed to get har pool")
     }
 
-    /// Cloudflare Turnstile config
-    pub fn cf_turnstile(&self) -> Option<&CfTurnstile> {
-        self.cf_turnstile.as_ref()
+    /// Report the arkose har file result
+    pub fn arkose_har_report(&self, _type: &arkose::Type, file_path: &Path, success: bool) {
+        let mut har_lock = self.har.write().expect("Failed to get har map");
+        if let Some(har) = har_lock.get_mut(_type) {
+            har.report(file_path, success)
+        }
+    }
+
+    /// Get the arkose har files health
+    pub fn arkose_har_health(&self) -> HashMap<&'static str, Vec<HarHealth>> {
+        let har_lock = self.har.read().expect("Failed to get har map");
+        har_lock
+            .iter()
+            .map(|(_type, har)| (_type.name(), har.health()))
+            .collect()
+    }
+
+    /// Login page captcha, `None` if it is not enforced
+    #[cfg(feature = "serve")]
+    pub fn captcha(&self) -> Option<&dyn CaptchaVerifier> {
+        self.captcha
+            .as_deref()
+            .filter(|_| self.toggles.get().cf_turnstile)
+    }
+
+    /// Recent captcha verifications, `None` if they are not kept
+    #[cfg(feature = "serve")]
+    pub fn captcha_passes(&self) -> Option<&CaptchaPasses> {
+        self.captcha_passes.as_ref()
+    }
+
+    /// Header of the client ip set by the reverse proxy in front, `None` to use the connection address
+    #[cfg(feature = "serve")]
+    pub fn real_ip_header(&self) -> Option<&str> {
+        self.real_ip_header.as_deref()
+    }
+
+    /// Get the runtime settings
+    pub fn toggles(&self) -> Toggles {
+        self.toggles.get()
+    }
+
+    /// Change the runtime settings, the changes are persisted
+    pub fn update_toggles(&self, patch: TogglesPatch) -> anyhow::Result<Toggles> {
+        self.toggles.update(patch)
+    }
+
+    /// Current WebUI session generation of the account
+    pub fn session_generation(&self, email: &str) -> u64 {
+        self.session_generations.get(email)
+    }
+
+    /// Invalidate every WebUI session issued to the account so far
+    pub fn bump_session_generation(&self, email: &str) -> anyhow::Result<u64> {
+        self.session_generations.bump(email)
+    }
+
+    /// WebUI sessions held by the browsers
+    pub(crate) fn web_sessions(&self) -> &WebSessions {
+        &self.web_sessions
+    }
+
+    /// Drop the expired WebUI sessions
+    pub fn sweep_web_sessions(&self) -> usize {
+        self.web_sessions.sweep()
+    }
+
+    /// Upstream of the client key, `None` if it is not a client key
+    pub fn key_target(&self, key: &str) -> Option<KeyTarget> {
+        self.keys.get(key).map(|spec| spec.target)
+    }
+
+    /// Priority of the client key, `None` if it is not a client key
+    pub fn key_priority(&self, key: &str) -> Option<KeyPriority> {
+        self.keys.get(key).map(|spec| spec.priority)
+    }
+
+    /// Whether the token of the `api_key` / `access_token` query is accepted
+    pub fn query_token(&self) -> bool {
+        self.query_token
+    }
+
+    /// Record a request with the client key
+    pub fn touch_key(&self, key: &str) {
+        self.keys.touch(key)
+    }
+
+    /// Unix seconds of the last request with the client key since the start
+    pub fn key_last_seen(&self, key: &str) -> Option<i64> {
+        self.keys.last_seen(key)
+    }
+
+    /// Add or change a client key, the keys of the key map file are fixed
+    pub fn set_key(&self, key: String, spec: KeySpec) -> anyhow::Result<()> {
+        self.keys.insert(key, spec)
+    }
+
+    /// Remove a client key, `false` if it is unknown
+    pub fn remove_key(&self, key: &str) -> anyhow::Result<bool> {
+        self.keys.remove(key)
+    }
+
+    /// Get the client keys, masked
+    pub fn keys(&self) -> Vec<KeyEntry> {
+        self.keys.list()
+    }
+
+    /// Get the WebUI branding
+    pub fn branding(&self) -> &Branding {
+        &self.branding
+    }
+
+    /// Get the url prefix, empty when mounted at the root
+    pub fn url_prefix(&self) -> &str {
+        &self.url_prefix
+    }
+
+    /// Whether the WebUI serves the share pages
+    pub fn share_pages(&self) -> bool {
+        self.share_pages && !self.toggles.get().disable_ui
+    }
+
+    /// Get the WebUI override directory
+    pub fn ui_dir(&self) -> Option<&Path> {
+        self.ui_dir.as_deref()
+    }
+
+    /// Get the WebUI security headers
+    pub fn security_headers(&self) -> Option<&SecurityHeaders> {
+        self.security_headers.as_ref()
+    }
+
+    /// Get the WebUI session cookie lifetime without "remember me"
+    pub fn session_lifetime(&self) -> Option<u64> {
+        self.session_lifetime
+    }
+
+    /// Absolute WebUI session lifetime (seconds), 0 for no limit
+    pub fn session_max_age(&self) -> u64 {
+        self.session_max_age
+    }
+
+    /// Whether every request extends the WebUI session cookie
+    pub fn session_sliding(&self) -> bool {
+        self.session_sliding
+    }
+
+    /// Get the WebUI session refresh window
+    pub fn session_refresh_window(&self) -> u64 {
+        self.session_refresh_window
     }
 
     /// Arkoselabs endpoint
@@ -331,6 +1536,173 @@ impl Context {
         self.auth_key.as_ref()
     }
 
+    /// Admin API key
+    pub fn admin_key(&self) -> Option<&String> {
+        self.admin_key.as_ref()
+    }
+
+    /// HTTP Basic auth of the whole server, `None` if it is not configured
+    pub fn basic_auth(&self) -> Option<&BasicAuth> {
+        self.basic_auth.as_ref()
+    }
+
+    /// System prompt of a new conversation of the client key or the token, `proxy` for the
+    /// proxied conversations, which only get it when enabled
+    pub fn system_prompt(&self, key: Option<&str>, token: &str, proxy: bool) -> Option<&str> {
+        let prompts = self
+            .system_prompts
+            .as_ref()
+            .filter(|_| !proxy || self.system_prompt_proxy)?;
+        if let Some(prompt) = key.and_then(|key| prompts.keys.get(key)) {
+            return Some(prompt);
+        }
+        let profile = crate::token::check(token).ok().flatten()?;
+        prompts
+            .accounts
+            .get(&profile.email().to_lowercase())
+            .map(String::as_str)
+    }
+
+    /// Event webhooks
+    pub fn webhook(&self) -> Option<&Webhook> {
+        self.webhook.as_ref()
+    }
+
+    /// Usage accounting store
+    #[cfg(feature = "serve")]
+    pub fn usage(&self) -> Option<&Arc<dyn UsageStore>> {
+        self.usage.as_ref()
+    }
+
+    /// Conversation list cache
+    #[cfg(feature = "serve")]
+    pub fn conversation_cache(&self) -> Option<&ConversationCache> {
+        self.conversation_cache.as_ref()
+    }
+
+    /// Share page data cache
+    #[cfg(feature = "serve")]
+    pub fn share_cache(&self) -> Option<&ShareCache> {
+        self.share_cache.as_ref()
+    }
+
+    /// Model list and account check cache
+    #[cfg(feature = "serve")]
+    pub fn response_cache(&self) -> Option<&ResponseCache> {
+        self.response_cache.as_ref()
+    }
+
+    /// Platform API key pool
+    #[cfg(feature = "serve")]
+    pub fn api_key_pool(&self) -> Option<&ApiKeyPool> {
+        self.api_key_pool.as_ref()
+    }
+
+    /// Rewrite plugins
+    #[cfg(feature = "plugin")]
+    pub fn plugins(&self) -> Option<&Arc<Plugins>> {
+        self.plugins.as_ref()
+    }
+
+    /// Save the cookie stores of the requesting clients
+    pub fn save_cookies(&self) {
+        if let Some(Err(err)) = self.cookie_jars.as_ref().map(|jars| jars.save()) {
+            warn!("Failed to save cookies: {err}")
+        }
+    }
+
+    /// Content moderation
+    #[cfg(feature = "serve")]
+    pub fn moderation(&self) -> Option<&Arc<Moderation>> {
+        self.moderation.as_ref()
+    }
+
+    /// Shadow traffic mirror
+    #[cfg(feature = "serve")]
+    pub fn mirror(&self) -> Option<&Arc<Mirror>> {
+        self.mirror.as_ref()
+    }
+
+    /// Cloudflare clearance of the outbound routes, with the cookie store or a harvester
+    #[cfg(feature = "serve")]
+    pub fn clearance(&self) -> Option<&Arc<Clearance>> {
+        self.clearance.as_ref()
+    }
+
+    /// In-flight event streams and conversations of the instance
+    #[cfg(feature = "serve")]
+    pub(crate) fn drain(&self) -> &Arc<Drain> {
+        &self.drain
+    }
+
+    /// Coalesced requests in flight
+    #[cfg(feature = "serve")]
+    pub(crate) fn flights(&self) -> &Arc<Flights> {
+        &self.flights
+    }
+
+    /// Cached `_puid` cookies of the accounts
+    #[cfg(feature = "serve")]
+    pub(crate) fn puid_cache(&self) -> &PuidCache {
+        &self.puid_cache
+    }
+
+    /// Cached chat requirements of the accounts
+    #[cfg(feature = "serve")]
+    pub(crate) fn requirements_cache(&self) -> &RequirementsCache {
+        &self.requirements_cache
+    }
+
+    /// Cached arkose static assets
+    #[cfg(all(feature = "serve", feature = "template"))]
+    pub(crate) fn arkose_assets(&self) -> &AssetCache {
+        &self.arkose_assets
+    }
+
+    /// ChatGPT frontend build id of the WebUI
+    #[cfg(all(feature = "serve", feature = "template"))]
+    pub(crate) fn build_id(&self) -> &BuildId {
+        &self.build_id
+    }
+
+    /// WebUI templates
+    #[cfg(feature = "serve")]
+    pub(crate) fn templates(&self) -> &OnceLock<tera::Tera> {
+        &self.templates
+    }
+
+    /// Upstream exchange record/replay
+    #[cfg(feature = "serve")]
+    pub fn cassette(&self) -> Option<&Cassette> {
+        self.cassette.as_deref()
+    }
+
+    /// Prompt audit trail
+    #[cfg(feature = "serve")]
+    pub fn content_log(&self) -> Option<&ContentLog> {
+        self.content_log.as_deref()
+    }
+
+    /// Retention of the stored user data (days), `None` if they are kept
+    #[cfg(feature = "serve")]
+    pub fn data_retention(&self) -> Option<u64> {
+        self.data_retention
+    }
+
+    /// Endpoints of the upstream origin
+    #[cfg(feature = "serve")]
+    pub(crate) fn upstream(&self, origin: &str) -> Option<&UpstreamSet> {
+        self.upstreams.of(origin)
+    }
+
+    /// Base url of the upstream origin, the first healthy endpoint
+    #[cfg(feature = "serve")]
+    pub(crate) fn upstream_origin<'a>(&'a self, origin: &'a str) -> &'a str {
+        self.upstream(origin)
+            .map(UpstreamSet::origin)
+            .unwrap_or(origin)
+    }
+
     /// Push a preauth cookie
     #[cfg(feature = "preauth")]
     pub fn push_preauth_cookie(&self, value: &str) {
@@ -343,8 +1715,208 @@ impl Context {
         self.preauth_provider.as_ref().map(|p| p.get()).flatten()
     }
 
+    /// Check the preauth cookie pool
+    #[cfg(feature = "preauth")]
+    pub fn check_preauth_cookies(&self) {
+        self.preauth_provider.as_ref().map(|p| p.check());
+    }
+
+    /// Insert a raw preauth cookie value
+    #[cfg(feature = "preauth")]
+    pub fn insert_preauth_cookie(&self, value: &str) -> anyhow::Result<PreauthCookie> {
+        self.preauth_provider
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("PreAuth is not enabled"))?
+            .insert(value)
+    }
+
+    /// List preauth cookies
+    #[cfg(feature = "preauth")]
+    pub fn list_preauth_cookies(&self) -> Vec<PreauthCookie> {
+        self.preauth_provider
+            .as_ref()
+            .map(|p| p.list())
+            .unwrap_or_default()
+    }
+
+    /// Remove a preauth cookie by device id
+    #[cfg(feature = "preauth")]
+    pub fn remove_preauth_cookie(&self, device_id: &str) -> Option<PreauthCookie> {
+        self.preauth_provider
+            .as_ref()
+            .and_then(|p| p.remove(device_id))
+    }
+
+    /// Remove expired preauth cookies
+    #[cfg(feature = "preauth")]
+    pub fn purge_preauth_cookies(&self) -> usize {
+        self.preauth_provider
+            .as_ref()
+            .map(|p| p.purge())
+            .unwrap_or_default()
+    }
+
     /// Get the arkose gpt3 experiment
     pub fn arkose_gpt3_experiment(&self) -> bool {
         self.arkose_gpt3_experiment
     }
+
+    /// Whether the upstream conversations are hidden once the response is finished
+    pub fn stateless(&self) -> bool {
+        self.stateless
+    }
+
+    /// Whether identical concurrent GET requests share one upstream call
+    pub fn coalesce_requests(&self) -> bool {
+        self.coalesce_requests
+    }
+
+    /// Whether every rendered WebUI form gets a new CSRF token
+    #[cfg(feature = "serve")]
+    pub fn csrf_rotate(&self) -> bool {
+        self.csrf_rotate
+    }
+
+    /// Requested model names mapped to backend slugs
+    pub fn model_alias(&self) -> &ModelAlias {
+        &self.model_alias
+    }
+
+    /// SSE keepalive ping interval, `None` if disabled
+    pub fn sse_keepalive(&self) -> Option<Duration> {
+        self.sse_keepalive
+    }
+
+    /// Maximum duration of a streamed conversation, `None` if unlimited
+    pub fn sse_max_duration(&self) -> Option<Duration> {
+        self.sse_max_duration
+    }
+
+    /// Timeout of the event stream requests until the response headers, their body is read
+    /// apart from the client timeout
+    pub fn stream_timeout(&self) -> Duration {
+        self.stream_timeout
+    }
+
+    /// Longest wait for the next chunk of an upstream event stream, `None` if unlimited
+    pub fn stream_read_timeout(&self) -> Option<Duration> {
+        self.stream_read_timeout
+    }
+
+    /// Upstream path stopping the generation when the client disconnects mid-stream
+    pub fn stop_generation_path(&self) -> Option<&str> {
+        self.stop_generation_path.as_deref()
+    }
+
+    /// Event types stripped from the proxied event streams
+    pub fn sse_strip(&self) -> &[String] {
+        &self.sse_strip
+    }
+
+    /// Get the arkose solver/HAR success rate alert threshold
+    pub fn arkose_alert_threshold(&self) -> f64 {
+        self.arkose_alert_threshold
+    }
+
+    /// Recent attempts of the arkose token sources
+    pub(crate) fn arkose_attempts(&self) -> &arkose::metrics::Attempts {
+        &self.arkose_attempts
+    }
+
+    /// Get the arkose token warm pool
+    pub fn arkose_token_pool(&self) -> Option<&ArkoseTokenPool> {
+        self.arkose_token_pool.as_ref()
+    }
+
+    /// Record a request of the account
+    pub fn record_account(&self, email: &str, expires_at: Option<i64>) {
+        self.accounts.seen(email, expires_at)
+    }
+
+    /// Record a login or refresh failure of the account
+    pub fn record_account_failure(&self, email: &str, state: AccountState, error: String) {
+        self.accounts.failed(email, state, error)
+    }
+
+    /// Record the upstream response status of a request of the account
+    pub fn record_account_response(&self, email: &str, status: u16) {
+        self.accounts.responded(email, status)
+    }
+
+    /// Record a PUID refresh of the account
+    pub fn record_puid_refresh(&self, email: &str) {
+        self.accounts.puid_refreshed(email)
+    }
+
+    /// Get an account seen by this instance
+    pub fn account(&self, email: &str) -> Option<AccountStatus> {
+        self.accounts.get(email)
+    }
+
+    /// Whether the account was disabled through the admin API
+    pub fn account_disabled(&self, email: &str) -> bool {
+        self.accounts.disabled(email)
+    }
+
+    /// WebUI OIDC single sign-on, `None` if it is not configured
+    pub fn oidc(&self) -> Option<&Oidc> {
+        self.oidc.as_ref()
+    }
+
+    /// WebUI LDAP login, `None` if it is not configured
+    pub fn ldap(&self) -> Option<&Ldap> {
+        self.ldap.as_ref()
+    }
+
+    /// Whether the account may log in to the WebUI, a denied entry wins over an allowed one
+    pub fn login_allowed(&self, email: &str) -> bool {
+        let email = email.trim().to_lowercase();
+        let matches = |entries: &[String]| entries.iter().any(|entry| email_matches(&email, entry));
+        !matches(&self.login_deny) && (self.login_allow.is_empty() || matches(&self.login_allow))
+    }
+
+    /// Disable or enable the account, persisted across restarts, `false` if it is unknown
+    pub fn set_account_disabled(&self, email: &str, disabled: bool) -> anyhow::Result<bool> {
+        self.accounts.set_disabled(email, disabled)
+    }
+
+    /// Hold the credential and token of an account logged in through the admin API
+    pub fn manage_account(&self, email: &str, credential: Credential, token: AuthenticateToken) {
+        self.accounts.manage(email, credential, token)
+    }
+
+    /// Get an account added through the admin API
+    pub fn managed_account(&self, email: &str) -> Option<ManagedAccount> {
+        self.accounts.managed(email)
+    }
+
+    /// Forget the account and its credential
+    pub fn remove_account(&self, email: &str) -> bool {
+        self.accounts.remove(email)
+    }
+
+    /// Drop the accounts not seen within the retention
+    pub fn sweep_accounts(&self) -> usize {
+        self.accounts.sweep()
+    }
+
+    /// Get the accounts seen by this instance
+    pub fn accounts(&self) -> Vec<AccountStatus> {
+        self.accounts.list()
+    }
+
+    /// Get the accounts with recent requests
+    pub fn active_accounts(&self) -> Vec<AccountStatus> {
+        self.accounts.active()
+    }
+
+    /// Get the HAR upload session token secret
+    pub(crate) fn token_secret(&self) -> &tokio::sync::OnceCell<String> {
+        &self.token_secret
+    }
+
+    /// Get the state file path of this instance
+    pub(crate) fn instance_path(&self, name: &str) -> PathBuf {
+        instance_path(self.bind, name)
+    }
 }
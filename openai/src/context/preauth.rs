@@ -1,51 +1,139 @@
-use crate::{error, homedir::home_dir, info, now_duration, warn};
-use moka::sync::Cache;
-use std::{path::PathBuf, time::Duration};
+use crate::{error, info, metrics, now_duration, warn};
+use moka::{notification::RemovalCause, sync::Cache, Expiry};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+const HARVESTED: &str = "ninja_preauth_harvested_total";
+const EXPIRED: &str = "ninja_preauth_expired_total";
+const POOL_SIZE: &str = "ninja_preauth_pool_size";
+
+/// Preauth cookie lifetime, counted from the timestamp embedded in the cookie
+const PREAUTH_COOKIE_LIFETIME: u64 = 3600 * 24 - 60;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PreauthCookie {
+    /// Device id
+    pub device_id: String,
+    /// `_preauth_devicecheck` cookie value
+    pub value: String,
+    /// Expire timestamp (seconds)
+    pub expires_at: u64,
+}
+
+impl PreauthCookie {
+    /// Parse from `_preauth_devicecheck` value, e.g. `device_id:timestamp-hash`
+    fn parse(value: &str) -> Option<Self> {
+        let (device_id, timestamp_part) = value.split_once(':')?;
+        let (timestamp, _) = timestamp_part.split_once('-')?;
+        let timestamp = timestamp.parse::<u64>().ok()?;
+        Some(Self {
+            device_id: device_id.to_owned(),
+            value: value.to_owned(),
+            expires_at: timestamp + PREAUTH_COOKIE_LIFETIME,
+        })
+    }
+
+    /// Remaining lifetime, `None` if already expired
+    fn remaining(&self) -> Option<Duration> {
+        match now_duration() {
+            Ok(now) => self
+                .expires_at
+                .checked_sub(now.as_secs())
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            Err(err) => {
+                error!("Failed to get now duration: {}", err);
+                None
+            }
+        }
+    }
+}
+
+/// Expire each cookie at its own expiry timestamp
+struct PreauthCookieExpiry;
+
+impl Expiry<String, PreauthCookie> for PreauthCookieExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &String,
+        value: &PreauthCookie,
+        _current_time: Instant,
+    ) -> Option<Duration> {
+        Some(value.remaining().unwrap_or_default())
+    }
+
+    /// A re-harvested cookie of the same device replaces the entry, with its own expiry
+    fn expire_after_update(
+        &self,
+        _key: &String,
+        value: &PreauthCookie,
+        _current_time: Instant,
+        _current_duration: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(value.remaining().unwrap_or_default())
+    }
+}
 
 pub(super) struct PreauthCookieProvider {
     path: PathBuf,
-    cache: Cache<String, String>,
+    cache: Cache<String, PreauthCookie>,
+    /// Webhook fired when the pool drops below `webhook_threshold`
+    webhook: Option<String>,
+    webhook_threshold: usize,
+    /// Whether the low pool webhook has fired, reset once the pool recovers
+    alerted: AtomicBool,
 }
 
 impl PreauthCookieProvider {
-    pub(super) fn new() -> Self {
-        let path = home_dir()
-            .unwrap_or(PathBuf::from("."))
-            .join(".preauth_cookies");
-
-        // Read from file
+    pub(super) fn new(path: PathBuf, webhook: Option<String>, webhook_threshold: usize) -> Self {
+        // Read from file, one JSON entry per line (legacy files store the raw cookie value)
         let data = std::fs::read(&path)
             .map(|data| {
                 data.split(|&c| c == b'\n')
                     .filter(|s| !s.is_empty())
-                    .map(|s| String::from_utf8_lossy(s).to_string())
-                    .filter(|input| Self::is_invalid(input))
-                    .collect::<Vec<String>>()
+                    .filter_map(|s| {
+                        serde_json::from_slice::<PreauthCookie>(s)
+                            .ok()
+                            .or_else(|| PreauthCookie::parse(&String::from_utf8_lossy(s)))
+                    })
+                    .filter(|cookie| cookie.remaining().is_some())
+                    .collect::<Vec<PreauthCookie>>()
             })
             .unwrap_or_default();
 
-        let cache: Cache<String, String> = Cache::builder()
+        let cache: Cache<String, PreauthCookie> = Cache::builder()
             .max_capacity(1000)
-            .time_to_live(Duration::from_secs(3600 * 24))
+            .expire_after(PreauthCookieExpiry)
+            .eviction_listener(|_, _, cause| {
+                if cause == RemovalCause::Expired {
+                    metrics::inc(EXPIRED, "Expired preauth cookies", &[], 1);
+                }
+            })
             .build();
 
         // Load from file
-        data.iter().for_each(|value| {
+        data.into_iter().for_each(|cookie| {
             info!(
-                "Load preauth cookie from file: {}, value: {value}",
-                path.display()
+                "Load preauth cookie from file: {}, value: {}",
+                path.display(),
+                cookie.value
             );
-            value.find(":").map(|colon_index| {
-                let device_id = &value[..colon_index];
-                cache.insert(device_id.to_owned(), value.to_owned())
-            });
+            cache.insert(cookie.device_id.clone(), cookie)
         });
 
-        if let Some(err) = std::fs::write(&path, data.join("\n").as_bytes()).err() {
-            warn!("Failed to write preauth cookie to file: {err}")
+        let provider = PreauthCookieProvider {
+            cache,
+            path,
+            webhook,
+            webhook_threshold,
+            alerted: AtomicBool::new(false),
         };
-
-        PreauthCookieProvider { cache, path }
+        provider.sync_to_file();
+        provider
     }
 
     pub(super) fn push(&self, value: &str) {
@@ -53,58 +141,138 @@ impl PreauthCookieProvider {
             .split(";")
             .find(|s| s.contains("_preauth_devicecheck"))
             .map(|value| {
-                let preauth_devicecheck = value
-                    .trim()
-                    .trim_start_matches("_preauth_devicecheck=")
-                    .to_owned();
-                preauth_devicecheck.find(":").map(|colon_index| {
-                    let device_id = &preauth_devicecheck[..colon_index];
-                    info!("Push PreAuth Cookie: {preauth_devicecheck}");
-                    self.cache.insert(device_id.to_owned(), preauth_devicecheck);
-                    self.sync_to_file();
-                });
+                let preauth_devicecheck = value.trim().trim_start_matches("_preauth_devicecheck=");
+                match PreauthCookie::parse(preauth_devicecheck) {
+                    Some(cookie) => {
+                        if self
+                            .cache
+                            .get(&cookie.device_id)
+                            .is_some_and(|c| c.value.eq(&cookie.value))
+                        {
+                            return;
+                        }
+                        info!("Push PreAuth Cookie: {preauth_devicecheck}");
+                        metrics::inc(HARVESTED, "Harvested preauth cookies", &[], 1);
+                        self.cache.insert(cookie.device_id.clone(), cookie);
+                        self.sync_to_file();
+                    }
+                    None => warn!("Invalid PreAuth Cookie: {preauth_devicecheck}"),
+                }
             });
     }
 
     pub(super) fn get(&self) -> Option<String> {
         use rand::seq::IteratorRandom;
-        if let Some((_, v)) = self
-            .cache
+        self.cache
             .iter()
-            .filter(|(_, input)| Self::is_invalid(input))
+            .filter(|(_, cookie)| cookie.remaining().is_some())
             .choose(&mut rand::thread_rng())
-        {
-            return Some(v);
+            .map(|(_, cookie)| cookie.value)
+    }
+
+    /// Insert a raw `_preauth_devicecheck` value
+    pub(super) fn insert(&self, value: &str) -> anyhow::Result<PreauthCookie> {
+        let cookie = PreauthCookie::parse(value.trim())
+            .ok_or_else(|| anyhow::anyhow!("Invalid preauth cookie: {value}"))?;
+        if cookie.remaining().is_none() {
+            anyhow::bail!("Preauth cookie has expired: {value}")
+        }
+        self.cache.insert(cookie.device_id.clone(), cookie.clone());
+        self.sync_to_file();
+        Ok(cookie)
+    }
+
+    /// List all cookies, sorted by expiry
+    pub(super) fn list(&self) -> Vec<PreauthCookie> {
+        let mut cookies = self
+            .cache
+            .iter()
+            .map(|(_, cookie)| cookie)
+            .collect::<Vec<PreauthCookie>>();
+        cookies.sort_by_key(|cookie| cookie.expires_at);
+        cookies
+    }
+
+    /// Remove a cookie by device id
+    pub(super) fn remove(&self, device_id: &str) -> Option<PreauthCookie> {
+        let cookie = self.cache.remove(device_id);
+        if cookie.is_some() {
+            self.sync_to_file();
+        }
+        cookie
+    }
+
+    /// Remove all expired cookies, returns the number removed
+    pub(super) fn purge(&self) -> usize {
+        let stale = self
+            .cache
+            .iter()
+            .filter(|(_, cookie)| cookie.remaining().is_none())
+            .map(|(device_id, _)| device_id)
+            .collect::<Vec<_>>();
+        if stale.is_empty() {
+            return 0;
+        }
+        stale
+            .iter()
+            .for_each(|device_id| self.cache.invalidate(device_id.as_str()));
+        self.sync_to_file();
+        stale.len()
+    }
+
+    /// Run pending expirations, update the pool size gauge and fire the
+    /// webhook when the pool drops below the threshold
+    pub(super) fn check(&self) {
+        self.cache.run_pending_tasks();
+        let size = self
+            .cache
+            .iter()
+            .filter(|(_, cookie)| cookie.remaining().is_some())
+            .count();
+        metrics::set(POOL_SIZE, "Available preauth cookies", &[], size as u64);
+
+        if size >= self.webhook_threshold {
+            self.alerted.store(false, Ordering::Relaxed);
+            return;
+        }
+
+        if let Some(webhook) = self.webhook.clone() {
+            if self.alerted.swap(true, Ordering::Relaxed) {
+                return;
+            }
+            warn!(
+                "PreAuth cookie pool size {size} is below threshold {}",
+                self.webhook_threshold
+            );
+            let body = serde_json::json!({
+                "event": "preauth_pool_low",
+                "size": size,
+                "threshold": self.webhook_threshold,
+            });
+            super::spawn(async move {
+                if let Err(err) = super::get_instance()
+                    .client()
+                    .post(&webhook)
+                    .json(&body)
+                    .send()
+                    .await
+                    .and_then(|resp| resp.error_for_status())
+                {
+                    error!("Failed to send preauth webhook: {err}");
+                }
+            });
         }
-        None
     }
 
     fn sync_to_file(&self) {
         let data = self
             .cache
             .iter()
-            .map(|(_, v)| v)
+            .filter_map(|(_, cookie)| serde_json::to_string(&cookie).ok())
             .collect::<Vec<String>>()
             .join("\n");
         let _ = std::fs::write(&self.path, data).map_err(|err| {
             error!("Failed to write preauth cookie to file: {}", err);
         });
     }
-
-    fn is_invalid(input: &str) -> bool {
-        let parts: Vec<&str> = input.split(':').collect();
-        if parts.len() == 2 {
-            let timestamp_part = parts[1];
-            let timestamp_parts: Vec<&str> = timestamp_part.split('-').collect();
-            if timestamp_parts.len() == 2 {
-                let timestamp = timestamp_parts[0];
-                let timestamp = timestamp.parse::<u64>().unwrap_or(0);
-                match now_duration() {
-                    Ok(duration) => return duration.as_secs() - timestamp < (3600 * 24 - 60),
-                    Err(err) => error!("Failed to get now duration: {}", err),
-                }
-            }
-        }
-        false
-    }
 }
@@ -0,0 +1,105 @@
+use std::{path::PathBuf, sync::RwLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::warn;
+
+/// Settings that can be changed at runtime through the admin API
+#[derive(Serialize, Clone, Debug)]
+pub struct Toggles {
+    /// Hide the WebUI, it can only be shown again if it was enabled at startup
+    pub disable_ui: bool,
+    /// Token bucket limiter
+    pub tb_enable: bool,
+    pub tb_capacity: u32,
+    pub tb_fill_rate: u32,
+    /// Enforce the captcha of the login page, if the keys are set
+    pub cf_turnstile: bool,
+    /// Answer every request but the admin ones with 503
+    pub maintenance: bool,
+}
+
+/// Changed settings, also the content of the persisted file
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TogglesPatch {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disable_ui: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tb_enable: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tb_capacity: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tb_fill_rate: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cf_turnstile: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maintenance: Option<bool>,
+}
+
+impl TogglesPatch {
+    fn merge(&mut self, other: TogglesPatch) {
+        self.disable_ui = other.disable_ui.or(self.disable_ui);
+        self.tb_enable = other.tb_enable.or(self.tb_enable);
+        self.tb_capacity = other.tb_capacity.or(self.tb_capacity);
+        self.tb_fill_rate = other.tb_fill_rate.or(self.tb_fill_rate);
+        self.cf_turnstile = other.cf_turnstile.or(self.cf_turnstile);
+        self.maintenance = other.maintenance.or(self.maintenance);
+    }
+
+    fn apply(&self, toggles: &mut Toggles) {
+        toggles.disable_ui = self.disable_ui.unwrap_or(toggles.disable_ui);
+        toggles.tb_enable = self.tb_enable.unwrap_or(toggles.tb_enable);
+        toggles.tb_capacity = self.tb_capacity.unwrap_or(toggles.tb_capacity);
+        toggles.tb_fill_rate = self.tb_fill_rate.unwrap_or(toggles.tb_fill_rate);
+        toggles.cf_turnstile = self.cf_turnstile.unwrap_or(toggles.cf_turnstile);
+        toggles.maintenance = self.maintenance.unwrap_or(toggles.maintenance);
+    }
+}
+
+/// Runtime settings over the startup ones, the changes are kept in the instance toggles file
+pub(super) struct TogglesStore {
+    path: PathBuf,
+    /// Whether the WebUI routes were mounted at startup
+    ui_mounted: bool,
+    /// Startup settings and the changes
+    state: RwLock<(Toggles, TogglesPatch)>,
+}
+
+impl TogglesStore {
+    pub(super) fn new(path: PathBuf, startup: Toggles) -> Self {
+        let patch = std::fs::read(&path)
+            .ok()
+            .and_then(|data| {
+                serde_json::from_slice::<TogglesPatch>(&data)
+                    .map_err(|err| warn!("Failed to read toggles {}: {err}", path.display()))
+                    .ok()
+            })
+            .unwrap_or_default();
+        Self {
+            path,
+            ui_mounted: !startup.disable_ui,
+            state: RwLock::new((startup, patch)),
+        }
+    }
+
+    pub(super) fn get(&self) -> Toggles {
+        let state = self.state.read().expect("Failed to read toggles");
+        let mut toggles = state.0.clone();
+        state.1.apply(&mut toggles);
+        toggles.disable_ui |= !self.ui_mounted;
+        toggles
+    }
+
+    /// Apply and persist the changes
+    pub(super) fn update(&self, patch: TogglesPatch) -> anyhow::Result<Toggles> {
+        if patch.disable_ui == Some(false) && !self.ui_mounted {
+            anyhow::bail!("The WebUI was disabled at startup, restart without --disable-webui")
+        }
+        {
+            let mut state = self.state.write().expect("Failed to write toggles");
+            state.1.merge(patch);
+            std::fs::write(&self.path, serde_json::to_vec_pretty(&state.1)?)?;
+        }
+        Ok(self.get())
+    }
+}
@@ -0,0 +1,124 @@
+use std::{collections::HashMap, path::PathBuf, sync::RwLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{now_duration, warn};
+
+/// Sessions kept at most, the ones expiring first are dropped beyond it
+const MAX_SESSIONS: usize = 10_000;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+struct WebSession {
+    /// Serialized session, opaque to the store
+    data: String,
+    /// Unix seconds the session token expires
+    expires: i64,
+}
+
+/// WebUI sessions by the random id their browser cookie holds, so the cookies of the
+/// accounts a browser holds stay small. Kept in the instance file, readable by the owner
+/// only, so the sessions survive restarts
+pub(crate) struct WebSessions {
+    path: PathBuf,
+    sessions: RwLock<HashMap<String, WebSession>>,
+}
+
+fn now() -> i64 {
+    now_duration()
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+impl WebSessions {
+    pub(super) fn load(path: PathBuf) -> Self {
+        let now = now();
+        let sessions = std::fs::read(&path)
+            .ok()
+            .and_then(|data| {
+                serde_json::from_slice::<HashMap<String, WebSession>>(&data)
+                    .map_err(|err| warn!("Failed to read WebUI sessions {}: {err}", path.display()))
+                    .ok()
+            })
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(_, session)| session.expires > now)
+            .collect();
+        Self {
+            path,
+            sessions: RwLock::new(sessions),
+        }
+    }
+
+    /// Serialized session of the id, `None` if it is unknown or expired
+    pub(crate) fn get(&self, id: &str) -> Option<String> {
+        self.sessions
+            .read()
+            .ok()?
+            .get(id)
+            .filter(|session| session.expires > now())
+            .map(|session| session.data.clone())
+    }
+
+    /// Add or replace the session and persist the sessions if it changed
+    pub(crate) fn insert(&self, id: &str, data: String, expires: i64) -> anyhow::Result<()> {
+        let mut sessions = self
+            .sessions
+            .write()
+            .map_err(|_| anyhow::anyhow!("Failed to write WebUI sessions"))?;
+        let session = WebSession { data, expires };
+        if sessions.get(id) == Some(&session) {
+            return Ok(());
+        }
+        sessions.insert(id.to_owned(), session);
+        if sessions.len() > MAX_SESSIONS {
+            let now = now();
+            sessions.retain(|_, session| session.expires > now);
+            let mut expiring = sessions
+                .iter()
+                .map(|(id, session)| (session.expires, id.clone()))
+                .collect::<Vec<_>>();
+            expiring.sort();
+            let excess = expiring.len().saturating_sub(MAX_SESSIONS);
+            for (_, id) in expiring.into_iter().take(excess) {
+                sessions.remove(&id);
+            }
+        }
+        crate::write_private(&self.path, &serde_json::to_vec(&*sessions)?)?;
+        Ok(())
+    }
+
+    /// Remove the session of a logout and persist the sessions
+    pub(crate) fn remove(&self, id: &str) -> anyhow::Result<()> {
+        let mut sessions = self
+            .sessions
+            .write()
+            .map_err(|_| anyhow::anyhow!("Failed to write WebUI sessions"))?;
+        if sessions.remove(id).is_some() {
+            crate::write_private(&self.path, &serde_json::to_vec(&*sessions)?)?;
+        }
+        Ok(())
+    }
+
+    /// Drop the expired sessions, returns how many were removed
+    pub(super) fn sweep(&self) -> usize {
+        let now = now();
+        let Ok(mut sessions) = self.sessions.write() else {
+            return 0;
+        };
+        let len = sessions.len();
+        sessions.retain(|_, session| session.expires > now);
+        let removed = len - sessions.len();
+        if removed > 0 {
+            if let Err(err) = serde_json::to_vec(&*sessions)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| Ok(crate::write_private(&self.path, &data)?))
+            {
+                warn!(
+                    "Failed to write WebUI sessions {}: {err}",
+                    self.path.display()
+                );
+            }
+        }
+        removed
+    }
+}
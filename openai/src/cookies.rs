@@ -0,0 +1,214 @@
+//! Cookie stores of the upstream clients kept across restarts, one per outbound
+//! route so the Cloudflare clearance stays with the address it was issued to
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use reqwest::{cookie::CookieStore, header::HeaderValue, Url};
+use serde::{Deserialize, Serialize};
+
+use crate::{debug, warn};
+
+/// Cloudflare clearance cookie
+pub const CF_CLEARANCE: &str = "cf_clearance";
+
+/// Saved cookie store of a route
+#[derive(Serialize, Deserialize)]
+struct SavedJar {
+    cookies: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user_agent: Option<String>,
+}
+
+/// Cookie store of the clients of one route
+#[derive(Default)]
+pub struct CookieJar {
+    store: RwLock<cookie_store::CookieStore>,
+    /// User-Agent the `cf_clearance` was issued to, the requests of the route must send it
+    user_agent: RwLock<Option<String>>,
+}
+
+impl CookieJar {
+    fn load(saved: SavedJar) -> anyhow::Result<Self> {
+        let store = cookie_store::CookieStore::load_json(saved.cookies.as_bytes())
+            .map_err(|err| anyhow::anyhow!(err))?;
+        Ok(Self {
+            store: RwLock::new(store),
+            user_agent: RwLock::new(saved.user_agent),
+        })
+    }
+
+    /// Session cookies are kept too, the upstream session outlives the process,
+    /// the expired cookies are dropped
+    fn save(&self) -> anyhow::Result<SavedJar> {
+        let store = cookie_store::CookieStore::from_cookies(
+            self.store
+                .read()
+                .expect("Failed to read cookie store")
+                .iter_unexpired()
+                .map(|cookie| Ok::<_, anyhow::Error>(cookie.clone())),
+            false,
+        )?;
+        let mut data = Vec::new();
+        store
+            .save_incl_expired_and_nonpersistent_json(&mut data)
+            .map_err(|err| anyhow::anyhow!(err))?;
+        Ok(SavedJar {
+            cookies: String::from_utf8(data)?,
+            user_agent: self.user_agent(),
+        })
+    }
+
+    /// Store the `Set-Cookie` values of a Cloudflare clearance and the User-Agent it is bound to
+    pub fn set_clearance<'a>(
+        &self,
+        mut cookies: impl Iterator<Item = &'a HeaderValue>,
+        url: &Url,
+        user_agent: String,
+    ) {
+        self.set_cookies(&mut cookies, url);
+        *self
+            .user_agent
+            .write()
+            .expect("Failed to write cookie store") = Some(user_agent);
+    }
+
+    /// Whether the store has an unexpired `cf_clearance` for the url
+    pub fn has_clearance(&self, url: &Url) -> bool {
+        self.store
+            .read()
+            .expect("Failed to read cookie store")
+            .get_request_values(url)
+            .any(|(name, _)| name == CF_CLEARANCE)
+    }
+
+    /// Drop the `cf_clearance` of the url, Cloudflare challenged it anyway
+    pub fn clear_clearance(&self, url: &Url) {
+        let expired = self
+            .store
+            .read()
+            .expect("Failed to read cookie store")
+            .matches(url)
+            .into_iter()
+            .filter(|cookie| cookie.name() == CF_CLEARANCE)
+            .filter_map(|cookie| {
+                let domain = cookie
+                    .domain()
+                    .map(|domain| format!("; Domain={domain}"))
+                    .unwrap_or_default();
+                let path = cookie.path().unwrap_or("/");
+                HeaderValue::from_str(&format!("{CF_CLEARANCE}=; Path={path}{domain}; Max-Age=0"))
+                    .ok()
+            })
+            .collect::<Vec<_>>();
+        self.set_cookies(&mut expired.iter(), url);
+        *self
+            .user_agent
+            .write()
+            .expect("Failed to write cookie store") = None;
+    }
+
+    /// User-Agent of the clearance, `None` without a clearance
+    pub fn user_agent(&self) -> Option<String> {
+        self.user_agent
+            .read()
+            .expect("Failed to read cookie store")
+            .clone()
+    }
+}
+
+impl CookieStore for CookieJar {
+    fn set_cookies(&self, cookie_headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url) {
+        let cookies = cookie_headers
+            .filter_map(|value| value.to_str().ok())
+            .filter_map(|value| cookie_store::RawCookie::parse(value.to_owned()).ok())
+            .collect::<Vec<_>>();
+        self.store
+            .write()
+            .expect("Failed to write cookie store")
+            .store_response_cookies(cookies.into_iter(), url);
+    }
+
+    fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+        let value = self
+            .store
+            .read()
+            .expect("Failed to read cookie store")
+            .get_request_values(url)
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>()
+            .join("; ");
+        (!value.is_empty())
+            .then(|| HeaderValue::from_str(&value).ok())
+            .flatten()
+    }
+}
+
+/// Cookie stores of the routes, saved as one JSON file
+pub struct CookieJars {
+    path: PathBuf,
+    jars: Mutex<HashMap<String, Arc<CookieJar>>>,
+}
+
+impl CookieJars {
+    /// Load the saved stores, a missing or broken file starts empty
+    pub fn load(path: PathBuf) -> Self {
+        let jars = std::fs::read(&path)
+            .ok()
+            .and_then(|data| {
+                serde_json::from_slice::<HashMap<String, SavedJar>>(&data)
+                    .map_err(|err| warn!("Failed to read cookies {}: {err}", path.display()))
+                    .ok()
+            })
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(route, saved)| match CookieJar::load(saved) {
+                Ok(jar) => Some((route, Arc::new(jar))),
+                Err(err) => {
+                    warn!("Failed to load the cookies of {route}: {err}");
+                    None
+                }
+            })
+            .collect();
+        Self {
+            path,
+            jars: Mutex::new(jars),
+        }
+    }
+
+    /// Store of the route, shared by its clients
+    pub fn jar(&self, route: &str) -> Arc<CookieJar> {
+        self.jars
+            .lock()
+            .expect("Failed to lock cookie stores")
+            .entry(route.to_owned())
+            .or_default()
+            .clone()
+    }
+
+    /// Routes of the stores
+    pub fn routes(&self) -> Vec<String> {
+        self.jars
+            .lock()
+            .expect("Failed to lock cookie stores")
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    /// Write the stores to the file, readable by the owner only as the cookies are credentials
+    pub fn save(&self) -> anyhow::Result<()> {
+        let data = self
+            .jars
+            .lock()
+            .expect("Failed to lock cookie stores")
+            .iter()
+            .map(|(route, jar)| Ok((route.clone(), jar.save()?)))
+            .collect::<anyhow::Result<HashMap<_, _>>>()?;
+        crate::write_private(&self.path, &serde_json::to_vec(&data)?)?;
+        debug!("Cookies saved to {}", self.path.display());
+        Ok(())
+    }
+}
@@ -0,0 +1,203 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    Client,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::debug;
+
+/// Cloudflare JSON API, addressed by IP so the resolver does not depend on system DNS
+const CLOUDFLARE: &str = "https://1.1.1.1/dns-query";
+/// Google JSON API
+const GOOGLE: &str = "https://8.8.8.8/resolve";
+
+/// Minimum cached lifetime of a record
+const MIN_TTL: u64 = 30;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+
+/// DNS-over-HTTPS endpoint, `cloudflare`, `google` or a custom JSON API url
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct DohEndpoint(String);
+
+impl FromStr for DohEndpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cloudflare" => Ok(Self(CLOUDFLARE.to_owned())),
+            "google" => Ok(Self(GOOGLE.to_owned())),
+            _ => {
+                let url = url::Url::parse(s)?;
+                if url.scheme() != "https" {
+                    anyhow::bail!("DoH endpoint must be `cloudflare`, `google` or a https url")
+                }
+                Ok(Self(s.to_owned()))
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for DohEndpoint {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<DohEndpoint> for String {
+    fn from(endpoint: DohEndpoint) -> Self {
+        endpoint.0
+    }
+}
+
+impl ToString for DohEndpoint {
+    fn to_string(&self) -> String {
+        self.0.clone()
+    }
+}
+
+#[derive(Deserialize)]
+struct DohResponse {
+    #[serde(rename = "Status")]
+    status: u16,
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DohAnswer>,
+}
+
+#[derive(Deserialize)]
+struct DohAnswer {
+    #[serde(rename = "type")]
+    kind: u16,
+    #[serde(rename = "TTL")]
+    ttl: u64,
+    data: String,
+}
+
+struct Inner {
+    endpoint: String,
+    /// Lookup clients of the outbound routes, used in turn
+    clients: Vec<Client>,
+    index: AtomicUsize,
+    cache: RwLock<HashMap<String, (Vec<IpAddr>, Instant)>>,
+}
+
+/// Resolve upstream hostnames over DNS-over-HTTPS (JSON API)
+#[derive(Clone)]
+pub struct DohResolver(Arc<Inner>);
+
+impl DohResolver {
+    /// The lookups go out through the same proxies and interface as the upstream
+    /// requests, the direct route is only used when it is not disabled
+    pub fn new(
+        endpoint: &DohEndpoint,
+        proxies: &[String],
+        disable_direct: bool,
+        interface: Option<IpAddr>,
+    ) -> Self {
+        let build = |proxy: Option<&String>| {
+            let mut builder = Client::builder()
+                .connect_timeout(Duration::from_secs(10))
+                .timeout(Duration::from_secs(10))
+                .local_address(interface);
+            if let Some(url) = proxy {
+                builder = builder.proxy(reqwest::Proxy::all(url).expect("Failed to build proxy"));
+            }
+            builder.build().expect("Failed to build DoH client")
+        };
+
+        let mut clients = Vec::with_capacity(proxies.len() + 1);
+        if proxies.is_empty() || !disable_direct {
+            clients.push(build(None));
+        }
+        clients.extend(proxies.iter().map(|proxy| build(Some(proxy))));
+
+        Self(Arc::new(Inner {
+            endpoint: endpoint.0.clone(),
+            clients,
+            index: AtomicUsize::new(0),
+            cache: RwLock::new(HashMap::new()),
+        }))
+    }
+
+    fn client(&self) -> &Client {
+        let index = self.0.index.fetch_add(1, Ordering::Relaxed) % self.0.clients.len();
+        &self.0.clients[index]
+    }
+
+    async fn lookup(&self, name: &str) -> anyhow::Result<Vec<IpAddr>> {
+        if let Some((addrs, expires)) = self.0.cache.read().ok().and_then(|c| c.get(name).cloned())
+        {
+            if expires > Instant::now() {
+                return Ok(addrs);
+            }
+        }
+
+        let mut addrs = Vec::new();
+        let mut ttl = u64::MAX;
+        let client = self.client();
+        for kind in ["A", "AAAA"] {
+            let resp = client
+                .get(&self.0.endpoint)
+                .query(&[("name", name), ("type", kind)])
+                .header(reqwest::header::ACCEPT, "application/dns-json")
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<DohResponse>()
+                .await?;
+
+            if resp.status != 0 {
+                debug!("DoH lookup {name} {kind} status: {}", resp.status);
+                continue;
+            }
+
+            resp.answer
+                .into_iter()
+                .filter(|answer| answer.kind == TYPE_A || answer.kind == TYPE_AAAA)
+                .filter_map(|answer| Some((answer.data.parse::<IpAddr>().ok()?, answer.ttl)))
+                .for_each(|(addr, answer_ttl)| {
+                    ttl = ttl.min(answer_ttl);
+                    addrs.push(addr);
+                });
+        }
+
+        if addrs.is_empty() {
+            anyhow::bail!("DoH lookup {name} returned no address")
+        }
+
+        let expires = Instant::now() + Duration::from_secs(ttl.max(MIN_TTL));
+        if let Ok(mut cache) = self.0.cache.write() {
+            cache.insert(name.to_owned(), (addrs.clone(), expires));
+        }
+        Ok(addrs)
+    }
+}
+
+impl Resolve for DohResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let resolver = self.clone();
+        Box::pin(async move {
+            let addrs = resolver
+                .lookup(name.as_str())
+                .await
+                .map_err(|err| -> Box<dyn std::error::Error + Send + Sync> { err.into() })?;
+            let addrs: Addrs = Box::new(addrs.into_iter().map(|addr| SocketAddr::new(addr, 0)));
+            Ok(addrs)
+        })
+    }
+}
@@ -60,6 +60,8 @@ pub enum AuthError {
     NotSupportedImplementation,
     #[error("failed to get preauth cookie")]
     PreauthCookieNotFound,
+    #[error("account deactivated (error {0:?})")]
+    AccountDeactivated(String),
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -0,0 +1,308 @@
+use std::str::FromStr;
+
+use rand::seq::SliceRandom;
+use reqwest::impersonate::Impersonate;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{context::ContextArgs, random_impersonate, warn};
+
+/// Profiles of the random okhttp fingerprint
+const OKHTTP_PROFILES: [&str; 7] = [
+    "okhttp3_9",
+    "okhttp3_11",
+    "okhttp3_13",
+    "okhttp3_14",
+    "okhttp4_9",
+    "okhttp4_10",
+    "okhttp5",
+];
+
+/// Platforms of the generated desktop browser User-Agents
+const DESKTOP_PLATFORMS: [&str; 3] = [
+    "Windows NT 10.0; Win64; x64",
+    "Macintosh; Intel Mac OS X 10_15_7",
+    "X11; Linux x86_64",
+];
+
+/// TLS/HTTP2 fingerprint profiles of an upstream client, e.g. `chrome120,safari16_5`,
+/// a client picks one at random when it is built, the random okhttp profiles if empty
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(try_from = "Vec<String>", into = "Vec<String>")]
+pub struct ImpersonateProfiles(Vec<String>);
+
+impl FromStr for ImpersonateProfiles {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::try_from(
+            s.split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(ToOwned::to_owned)
+                .collect::<Vec<_>>(),
+        )
+    }
+}
+
+impl TryFrom<Vec<String>> for ImpersonateProfiles {
+    type Error = anyhow::Error;
+
+    fn try_from(names: Vec<String>) -> Result<Self, Self::Error> {
+        let names = names
+            .into_iter()
+            .map(|name| name.to_lowercase())
+            .collect::<Vec<_>>();
+        if let Some(name) = names.iter().find(|name| profile(name).is_none()) {
+            anyhow::bail!("Unsupported impersonate profile `{name}`")
+        }
+        Ok(Self(names))
+    }
+}
+
+impl From<ImpersonateProfiles> for Vec<String> {
+    fn from(profiles: ImpersonateProfiles) -> Self {
+        profiles.0
+    }
+}
+
+impl ImpersonateProfiles {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Profile of a new client and its browser family
+    pub fn pick(&self) -> (Impersonate, &'static str) {
+        self.0
+            .choose(&mut rand::thread_rng())
+            .and_then(|name| Some((profile(name)?, profile_family(name))))
+            .unwrap_or_else(|| (random_impersonate(), "okhttp"))
+    }
+
+    /// Browser families of the profiles
+    fn families(&self) -> Vec<&'static str> {
+        let mut families = match self.0.is_empty() {
+            true => vec!["okhttp"],
+            false => self.0.iter().map(|name| profile_family(name)).collect(),
+        };
+        families.sort_unstable();
+        families.dedup();
+        families
+    }
+
+    /// Current User-Agents matching the profiles
+    fn user_agents(&self) -> Vec<String> {
+        let names = match self.0.is_empty() {
+            true => OKHTTP_PROFILES
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            false => self.0.clone(),
+        };
+        names.iter().flat_map(|name| generate(name)).collect()
+    }
+}
+
+/// How the User-Agents of the pool are assigned
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UserAgentRotation {
+    /// One per client, when the client is built
+    Client,
+    /// Same one for the requests of an account
+    Account,
+    /// New one per request
+    Request,
+}
+
+impl FromStr for UserAgentRotation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(Self::Client),
+            "account" => Ok(Self::Account),
+            "request" => Ok(Self::Request),
+            _ => anyhow::bail!("user agent rotation: {} is not supported", s),
+        }
+    }
+}
+
+/// User-Agents of the upstream requests, each one is only used with a
+/// fingerprint profile of the same browser family
+#[derive(Clone, Default)]
+pub struct UserAgentPool {
+    agents: Vec<(&'static str, String)>,
+    rotation: Option<UserAgentRotation>,
+    /// Families of the API client profiles, the per account or request User-Agents keep to them
+    request_families: Vec<&'static str>,
+}
+
+impl UserAgentPool {
+    /// The configured User-Agents, or generated from the profiles if only the rotation is set
+    pub(crate) fn new(args: &ContextArgs) -> Self {
+        let Some(rotation) = args.user_agent_rotation else {
+            return Self::default();
+        };
+        let agents = match args.user_agents.is_empty() {
+            true => [
+                &args.impersonate,
+                &args.auth_impersonate,
+                &args.arkose_impersonate,
+            ]
+            .into_iter()
+            .flat_map(ImpersonateProfiles::user_agents)
+            .collect(),
+            false => args.user_agents.clone(),
+        };
+        let mut agents = agents
+            .into_iter()
+            .filter_map(|agent| Some((agent_family(&agent)?, agent)))
+            .collect::<Vec<_>>();
+        agents.sort_unstable();
+        agents.dedup();
+        Self {
+            agents,
+            rotation: Some(rotation),
+            request_families: args.impersonate.families(),
+        }
+    }
+
+    /// Whether the per account or request User-Agents may not match the fingerprint of the client
+    pub(crate) fn mixed_families(&self) -> bool {
+        self.rotation
+            .is_some_and(|rotation| rotation != UserAgentRotation::Client)
+            && self.request_families.len() > 1
+    }
+
+    /// User-Agent of a new client with a profile of the family
+    pub(crate) fn for_client(&self, family: &str) -> Option<&str> {
+        if self.rotation != Some(UserAgentRotation::Client) {
+            return None;
+        }
+        self.agents
+            .iter()
+            .filter(|(f, _)| *f == family)
+            .collect::<Vec<_>>()
+            .choose(&mut rand::thread_rng())
+            .map(|(_, agent)| agent.as_str())
+    }
+
+    /// User-Agent of an upstream request, the same one for the account with the account rotation
+    pub fn for_request(&self, account: Option<&str>) -> Option<&str> {
+        let candidates = self
+            .agents
+            .iter()
+            .filter(|(family, _)| self.request_families.contains(family))
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            return None;
+        }
+        let index = match (self.rotation?, account) {
+            (UserAgentRotation::Client, _) => return None,
+            (UserAgentRotation::Account, Some(account)) => {
+                let hash = Sha256::digest(account.as_bytes());
+                u64::from_be_bytes(hash[..8].try_into().ok()?) as usize % candidates.len()
+            }
+            _ => rand::random::<usize>() % candidates.len(),
+        };
+        Some(candidates[index].1.as_str())
+    }
+}
+
+/// Browser family of a profile name
+fn profile_family(name: &str) -> &'static str {
+    match name {
+        name if name.starts_with("chrome") => "chrome",
+        name if name.starts_with("edge") => "edge",
+        name if name.starts_with("safari") => "safari",
+        _ => "okhttp",
+    }
+}
+
+/// Browser family of a User-Agent, `None` if unknown
+fn agent_family(agent: &str) -> Option<&'static str> {
+    if agent.starts_with("okhttp/") {
+        Some("okhttp")
+    } else if agent.contains(" Edg/") {
+        Some("edge")
+    } else if agent.contains("Chrome/") {
+        Some("chrome")
+    } else if agent.contains("Version/") && agent.contains("Safari/") {
+        Some("safari")
+    } else {
+        warn!("Unknown User-Agent browser family, skipped: {agent}");
+        None
+    }
+}
+
+/// User-Agents of the browser version of a profile
+fn generate(name: &str) -> Vec<String> {
+    let version = name
+        .trim_start_matches(|c: char| c.is_ascii_alphabetic())
+        .trim_end_matches("_android");
+    let chrome = |platform: &str, version: &str| {
+        format!("Mozilla/5.0 ({platform}) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/{version}.0.0.0 Safari/537.36")
+    };
+    match profile_family(name) {
+        "chrome" if name.ends_with("_android") => vec![format!(
+            "Mozilla/5.0 (Linux; Android 10; K) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/{version}.0.0.0 Mobile Safari/537.36"
+        )],
+        "chrome" => DESKTOP_PLATFORMS
+            .iter()
+            .map(|platform| chrome(platform, version))
+            .collect(),
+        "edge" => DESKTOP_PLATFORMS[..2]
+            .iter()
+            .map(|platform| format!("{} Edg/{version}.0.0.0", chrome(platform, version)))
+            .collect(),
+        "safari" => vec![format!(
+            "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/{} Safari/605.1.15",
+            version.replace('_', ".")
+        )],
+        _ => match name {
+            "okhttp_android13" => vec!["okhttp/4.11.0".to_owned()],
+            _ => vec![format!("okhttp/{}.0", version.replace('_', "."))],
+        },
+    }
+}
+
+fn profile(name: &str) -> Option<Impersonate> {
+    let impersonate = match name {
+        "chrome99" => Impersonate::Chrome99,
+        "chrome100" => Impersonate::Chrome100,
+        "chrome101" => Impersonate::Chrome101,
+        "chrome104" => Impersonate::Chrome104,
+        "chrome105" => Impersonate::Chrome105,
+        "chrome106" => Impersonate::Chrome106,
+        "chrome108" => Impersonate::Chrome108,
+        "chrome109" => Impersonate::Chrome109,
+        "chrome114" => Impersonate::Chrome114,
+        "chrome116" => Impersonate::Chrome116,
+        "chrome117" => Impersonate::Chrome117,
+        "chrome118" => Impersonate::Chrome118,
+        "chrome119" => Impersonate::Chrome119,
+        "chrome120" => Impersonate::Chrome120,
+        "chrome99_android" => Impersonate::Chrome99Android,
+        "safari12" => Impersonate::Safari12,
+        "safari15_3" => Impersonate::Safari15_3,
+        "safari15_5" => Impersonate::Safari15_5,
+        "safari15_6_1" => Impersonate::Safari15_6_1,
+        "safari16" => Impersonate::Safari16,
+        "safari16_5" => Impersonate::Safari16_5,
+        "safari17_2_1" => Impersonate::Safari17_2_1,
+        "edge99" => Impersonate::Edge99,
+        "edge101" => Impersonate::Edge101,
+        "edge120" => Impersonate::Edge120,
+        "okhttp3_9" => Impersonate::OkHttp3_9,
+        "okhttp3_11" => Impersonate::OkHttp3_11,
+        "okhttp3_13" => Impersonate::OkHttp3_13,
+        "okhttp3_14" => Impersonate::OkHttp3_14,
+        "okhttp4_9" => Impersonate::OkHttp4_9,
+        "okhttp4_10" => Impersonate::OkHttp4_10,
+        "okhttp5" => Impersonate::OkHttp5,
+        "okhttp_android13" => Impersonate::OkHttpAndroid13,
+        _ => return None,
+    };
+    Some(impersonate)
+}
@@ -9,15 +9,21 @@ pub mod auth;
 pub mod balancer;
 pub mod chatgpt;
 pub mod context;
+pub mod cookies;
+pub mod dns;
 pub mod error;
 pub mod eventsource;
 pub mod homedir;
+pub mod impersonate;
 pub mod log;
+pub mod metrics;
 pub mod platform;
+pub mod pow;
 pub mod token;
 pub mod unescape;
 pub mod urldecoding;
 pub mod uuid;
+pub mod webhook;
 
 #[cfg(feature = "serve")]
 pub mod serve;
@@ -69,3 +75,27 @@ pub fn generate_random_string(len: usize) -> String {
         .map(|x| CHARSET[x as usize % CHARSET.len()] as char)
         .collect()
 }
+
+/// Replace the file with data only the owner can read, written to a temporary
+/// file first so a crash never leaves it half written
+pub(crate) fn write_private(path: &std::path::Path, data: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}.tmp", generate_random_string(8)));
+    let tmp = path.with_file_name(name);
+    let write = || -> std::io::Result<()> {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)
+    };
+    write().map_err(|err| {
+        let _ = std::fs::remove_file(&tmp);
+        err
+    })
+}
@@ -0,0 +1,171 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+
+/// Metric family, all series of the same metric name
+struct Family {
+    help: &'static str,
+    kind: &'static str,
+    series: BTreeMap<String, Arc<AtomicU64>>,
+}
+
+/// Metric families of a server instance
+#[derive(Default)]
+pub struct Registry(RwLock<BTreeMap<&'static str, Family>>);
+
+/// Registry of the process, used outside of a server instance
+static DEFAULT: OnceLock<Arc<Registry>> = OnceLock::new();
+
+/// Registry of the current server instance
+fn registry() -> Arc<Registry> {
+    crate::context::current_metrics()
+        .unwrap_or_else(|| DEFAULT.get_or_init(Default::default).clone())
+}
+
+/// Format labels to prometheus label set, e.g. `{kind="solver",name="yescaptcha"}`
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels = labels
+        .iter()
+        .map(|(k, v)| format!("{k}=\"{}\"", v.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect::<Vec<String>>()
+        .join(",");
+    format!("{{{labels}}}")
+}
+
+fn series(
+    name: &'static str,
+    help: &'static str,
+    kind: &'static str,
+    labels: &[(&str, &str)],
+) -> Arc<AtomicU64> {
+    series_of_key(name, help, kind, format_labels(labels))
+}
+
+/// Series by its key, the text rendered after the metric name
+fn series_of_key(
+    name: &'static str,
+    help: &'static str,
+    kind: &'static str,
+    key: String,
+) -> Arc<AtomicU64> {
+    let registry = registry();
+    if let Some(value) = registry
+        .0
+        .read()
+        .expect("Failed to read metrics registry")
+        .get(name)
+        .and_then(|f| f.series.get(&key))
+    {
+        return value.clone();
+    }
+
+    registry
+        .0
+        .write()
+        .expect("Failed to write metrics registry")
+        .entry(name)
+        .or_insert_with(|| Family {
+            help,
+            kind,
+            series: BTreeMap::new(),
+        })
+        .series
+        .entry(key)
+        .or_default()
+        .clone()
+}
+
+/// Get or register a counter
+pub fn counter(name: &'static str, help: &'static str, labels: &[(&str, &str)]) -> Arc<AtomicU64> {
+    series(name, help, "counter", labels)
+}
+
+/// Get or register a gauge
+pub fn gauge(name: &'static str, help: &'static str, labels: &[(&str, &str)]) -> Arc<AtomicU64> {
+    series(name, help, "gauge", labels)
+}
+
+/// Increment a counter by `value`
+pub fn inc(name: &'static str, help: &'static str, labels: &[(&str, &str)], value: u64) {
+    counter(name, help, labels).fetch_add(value, Ordering::Relaxed);
+}
+
+/// Set a gauge to `value`
+pub fn set(name: &'static str, help: &'static str, labels: &[(&str, &str)], value: u64) {
+    gauge(name, help, labels).store(value, Ordering::Relaxed);
+}
+
+/// Record `value` into a histogram with the bucket upper bounds, rendered as
+/// `<name>_bucket{le=...}`, `<name>_sum` and `<name>_count`
+pub fn observe(
+    name: &'static str,
+    help: &'static str,
+    buckets: &[u64],
+    labels: &[(&str, &str)],
+    value: u64,
+) {
+    let bucket = |le: &str| {
+        let mut labels = labels.to_vec();
+        labels.push(("le", le));
+        format!("_bucket{}", format_labels(&labels))
+    };
+    // Buckets below the value are registered too, so they render as zero
+    for le in buckets {
+        let series = series_of_key(name, help, "histogram", bucket(&le.to_string()));
+        if value <= *le {
+            series.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    series_of_key(name, help, "histogram", bucket("+Inf")).fetch_add(1, Ordering::Relaxed);
+    let labels = format_labels(labels);
+    series_of_key(name, help, "histogram", format!("_sum{labels}"))
+        .fetch_add(value, Ordering::Relaxed);
+    series_of_key(name, help, "histogram", format!("_count{labels}"))
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+/// All series values of a metric, keyed by the formatted label set
+pub fn values(name: &str) -> BTreeMap<String, u64> {
+    let registry = registry();
+    let values = registry
+        .0
+        .read()
+        .expect("Failed to read metrics registry")
+        .get(name)
+        .map(|family| {
+            family
+                .series
+                .iter()
+                .map(|(labels, value)| (labels.clone(), value.load(Ordering::Relaxed)))
+                .collect()
+        })
+        .unwrap_or_default();
+    values
+}
+
+/// Current value of a series, zero if not registered
+pub fn value(name: &str, labels: &[(&str, &str)]) -> u64 {
+    values(name)
+        .get(&format_labels(labels))
+        .copied()
+        .unwrap_or_default()
+}
+
+/// Render all metrics in prometheus text exposition format
+pub fn render() -> String {
+    let mut out = String::new();
+    let registry = registry();
+    let registry = registry.0.read().expect("Failed to read metrics registry");
+    for (name, family) in registry.iter() {
+        let _ = writeln!(out, "# HELP {name} {}", family.help);
+        let _ = writeln!(out, "# TYPE {name} {}", family.kind);
+        for (labels, value) in family.series.iter() {
+            let _ = writeln!(out, "{name}{labels} {}", value.load(Ordering::Relaxed));
+        }
+    }
+    out
+}
@@ -0,0 +1,90 @@
+use base64::{engine::general_purpose, Engine};
+use rand::seq::SliceRandom;
+use sha3::{Digest, Sha3_512};
+
+/// Proof-of-work token prefix
+const POW_PREFIX: &str = "gAAAAAB";
+
+/// Fallback token prefix when no answer is found
+const POW_FALLBACK_PREFIX: &str = "gAAAAABwQ8Lk5FbGpA2NcR9dShT6gYjU7VxZ4D";
+
+/// Maximum attempts to find an answer
+const MAX_ATTEMPTS: usize = 500_000;
+
+const SCREENS: [u32; 3] = [3008, 4010, 6000];
+const SCREEN_SCALES: [u32; 3] = [1, 2, 4];
+
+/// Browser `jsHeapSizeLimit`
+const HEAP_SIZE_LIMIT: u64 = 4294705152;
+
+/// [`generate`] on the blocking pool, the search may take a few hundred milliseconds
+pub async fn generate_blocking(
+    seed: String,
+    difficulty: String,
+    user_agent: String,
+) -> anyhow::Result<String> {
+    tokio::task::spawn_blocking(move || generate(&seed, &difficulty, &user_agent))
+        .await
+        .map_err(Into::into)
+}
+
+/// Compute the sentinel proof-of-work token from chat-requirements `seed` and `difficulty`
+pub fn generate(seed: &str, difficulty: &str, user_agent: &str) -> String {
+    let mut rng = rand::thread_rng();
+    let screen = SCREENS.choose(&mut rng).copied().unwrap_or(SCREENS[0])
+        * SCREEN_SCALES.choose(&mut rng).copied().unwrap_or(1);
+    let date = js_date();
+
+    for nonce in 0..MAX_ATTEMPTS {
+        let config = serde_json::json!([screen, date, HEAP_SIZE_LIMIT, nonce, user_agent]);
+        let answer = general_purpose::STANDARD.encode(config.to_string());
+        if check(seed, &answer, difficulty) {
+            return format!("{POW_PREFIX}{answer}");
+        }
+    }
+
+    format!(
+        "{POW_FALLBACK_PREFIX}{}",
+        general_purpose::STANDARD.encode(format!("\"{seed}\""))
+    )
+}
+
+/// Check the sha3-512 hex digest prefix of `seed + answer` is not greater than `difficulty`
+fn check(seed: &str, answer: &str, difficulty: &str) -> bool {
+    let mut hasher = Sha3_512::new();
+    hasher.update(seed.as_bytes());
+    hasher.update(answer.as_bytes());
+    let hash = hasher.finalize();
+
+    let prefix = hash
+        .iter()
+        .take((difficulty.len() + 1) / 2)
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+    prefix[..difficulty.len().min(prefix.len())].le(difficulty)
+}
+
+/// Format current time like javascript `Date.toString()`
+fn js_date() -> String {
+    let now = time::format_description::parse(
+        "[weekday repr:short] [month repr:short] [day] [year] [hour]:[minute]:[second]",
+    )
+    .ok()
+    .and_then(|format| time::OffsetDateTime::now_utc().format(&format).ok())
+    .unwrap_or_default();
+    format!("{now} GMT+0000 (Coordinated Universal Time)")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        let seed = "0.42";
+        let difficulty = "0fffff";
+        let token = generate(seed, difficulty, crate::HEADER_UA);
+        assert!(token.starts_with(POW_PREFIX));
+        assert!(check(seed, &token[POW_PREFIX.len()..], difficulty));
+    }
+}
@@ -0,0 +1,90 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use anyhow::anyhow;
+
+use crate::{context, info, warn, URL_CHATGPT_API};
+
+/// Build id of the bundled WebUI, used until the upstream one is discovered
+const DEFAULT_BUILD_ID: &str = "eFlZtDCQUjuHAccnRY3au";
+const REFRESH_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// ChatGPT frontend build id of an instance, followed through its own client
+#[derive(Default)]
+pub(crate) struct BuildId {
+    id: RwLock<Option<String>>,
+    refreshing: AtomicBool,
+}
+
+/// Current ChatGPT frontend build id
+pub(super) fn build_id() -> String {
+    context::get_instance()
+        .build_id()
+        .id
+        .read()
+        .ok()
+        .and_then(|id| id.clone())
+        .unwrap_or_else(|| DEFAULT_BUILD_ID.to_owned())
+}
+
+/// Keep the build id of the current instance in sync with the upstream frontend
+pub(super) async fn refresh_task() {
+    let ctx = context::get_instance();
+    if ctx.build_id().refreshing.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    loop {
+        match fetch_build_id().await {
+            Ok(id) => {
+                if let Ok(mut build_id) = ctx.build_id().id.write() {
+                    if build_id.as_deref() != Some(id.as_str()) {
+                        info!("ChatGPT frontend build id: {id}");
+                        *build_id = Some(id);
+                    }
+                }
+            }
+            Err(err) => warn!("Fetch ChatGPT frontend build id error: {err}"),
+        }
+        tokio::time::sleep(REFRESH_INTERVAL).await;
+    }
+}
+
+async fn fetch_build_id() -> anyhow::Result<String> {
+    let html = context::get_instance()
+        .client()
+        .get(URL_CHATGPT_API)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    parse_build_id(&html).ok_or_else(|| anyhow!("buildId not found in the page"))
+}
+
+/// Extract the `buildId` of the page `__NEXT_DATA__`
+fn parse_build_id(html: &str) -> Option<String> {
+    const KEY: &str = "\"buildId\":\"";
+    let start = html.find(KEY)? + KEY.len();
+    let id = html[start..].split('"').next()?;
+    (!id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+    .then(|| id.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_build_id() {
+        let html = r#"<script id="__NEXT_DATA__" type="application/json">{"props":{},"buildId":"Wv-2_WZnCyA2p6Lx","isFallback":false}</script>"#;
+        assert_eq!(parse_build_id(html), Some("Wv-2_WZnCyA2p6Lx".to_owned()));
+        assert_eq!(parse_build_id(r#"{"buildId":""}"#), None);
+        assert_eq!(parse_build_id(r#"{"buildId":"../x"}"#), None);
+        assert_eq!(parse_build_id("<html></html>"), None);
+    }
+}
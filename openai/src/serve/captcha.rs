@@ -0,0 +1,341 @@
+//! Captcha of the WebUI login page, Cloudflare Turnstile, hCaptcha or reCAPTCHA v3
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use axum::http::HeaderMap;
+use serde::{Deserialize, Serialize};
+
+use super::error::ResponseError;
+use crate::context;
+
+/// Action of the reCAPTCHA v3 token of the login page
+const RECAPTCHA_ACTION: &str = "login";
+
+/// Logins a verification lets skip the widget before the next solve
+const MAX_PASS_LOGINS: u32 = 5;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptchaProvider {
+    #[default]
+    Turnstile,
+    Hcaptcha,
+    Recaptcha,
+}
+
+impl std::str::FromStr for CaptchaProvider {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "turnstile" => Ok(Self::Turnstile),
+            "hcaptcha" => Ok(Self::Hcaptcha),
+            "recaptcha" => Ok(Self::Recaptcha),
+            _ => anyhow::bail!("captcha provider: {} is not supported", s),
+        }
+    }
+}
+
+impl CaptchaProvider {
+    /// Name of the provider in the templates
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Turnstile => "turnstile",
+            Self::Hcaptcha => "hcaptcha",
+            Self::Recaptcha => "recaptcha",
+        }
+    }
+}
+
+/// Verifies the response token of the captcha widget of the login page
+#[async_trait::async_trait]
+pub trait CaptchaVerifier: Send + Sync {
+    fn provider(&self) -> CaptchaProvider;
+
+    fn site_key(&self) -> &str;
+
+    async fn verify(&self, remote_ip: &IpAddr, response: &str) -> anyhow::Result<()>;
+}
+
+/// Answer of the siteverify endpoints, the score and action are reCAPTCHA v3 only
+#[derive(Deserialize)]
+struct SiteVerify {
+    success: bool,
+    #[serde(default, rename = "error-codes")]
+    error_codes: Vec<String>,
+    score: Option<f64>,
+    action: Option<String>,
+}
+
+/// Post the response token to the siteverify endpoint of the provider
+async fn site_verify(url: &str, form: &[(&str, &str)]) -> anyhow::Result<SiteVerify> {
+    let verify = context::get_instance()
+        .client()
+        .post(url)
+        .form(form)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<SiteVerify>()
+        .await?;
+    if !verify.success {
+        anyhow::bail!("captcha rejected: {}", verify.error_codes.join(", "))
+    }
+    Ok(verify)
+}
+
+pub struct Turnstile {
+    pub site_key: String,
+    pub secret_key: String,
+}
+
+#[async_trait::async_trait]
+impl CaptchaVerifier for Turnstile {
+    fn provider(&self) -> CaptchaProvider {
+        CaptchaProvider::Turnstile
+    }
+
+    fn site_key(&self) -> &str {
+        &self.site_key
+    }
+
+    async fn verify(&self, remote_ip: &IpAddr, response: &str) -> anyhow::Result<()> {
+        site_verify(
+            "https://challenges.cloudflare.com/turnstile/v0/siteverify",
+            &[
+                ("secret", &self.secret_key),
+                ("response", response),
+                ("remoteip", &remote_ip.to_string()),
+                ("idempotency_key", &crate::uuid::uuid()),
+            ],
+        )
+        .await
+        .map(|_| ())
+    }
+}
+
+pub struct HCaptcha {
+    pub site_key: String,
+    pub secret_key: String,
+}
+
+#[async_trait::async_trait]
+impl CaptchaVerifier for HCaptcha {
+    fn provider(&self) -> CaptchaProvider {
+        CaptchaProvider::Hcaptcha
+    }
+
+    fn site_key(&self) -> &str {
+        &self.site_key
+    }
+
+    async fn verify(&self, remote_ip: &IpAddr, response: &str) -> anyhow::Result<()> {
+        site_verify(
+            "https://api.hcaptcha.com/siteverify",
+            &[
+                ("secret", &self.secret_key),
+                ("response", response),
+                ("remoteip", &remote_ip.to_string()),
+                ("sitekey", &self.site_key),
+            ],
+        )
+        .await
+        .map(|_| ())
+    }
+}
+
+/// reCAPTCHA v3, invisible, the token of the login action must score at least the minimum
+pub struct ReCaptcha {
+    pub site_key: String,
+    pub secret_key: String,
+    pub min_score: f64,
+}
+
+#[async_trait::async_trait]
+impl CaptchaVerifier for ReCaptcha {
+    fn provider(&self) -> CaptchaProvider {
+        CaptchaProvider::Recaptcha
+    }
+
+    fn site_key(&self) -> &str {
+        &self.site_key
+    }
+
+    async fn verify(&self, remote_ip: &IpAddr, response: &str) -> anyhow::Result<()> {
+        let verify = site_verify(
+            "https://www.google.com/recaptcha/api/siteverify",
+            &[
+                ("secret", &self.secret_key),
+                ("response", response),
+                ("remoteip", &remote_ip.to_string()),
+            ],
+        )
+        .await?;
+        if verify.action.as_deref() != Some(RECAPTCHA_ACTION) {
+            anyhow::bail!("captcha action mismatch")
+        }
+        match verify.score {
+            Some(score) if score >= self.min_score => Ok(()),
+            score => anyhow::bail!("captcha score {} too low", score.unwrap_or_default()),
+        }
+    }
+}
+
+/// Verifier of the configured provider, `None` without both keys
+pub fn verifier(
+    provider: CaptchaProvider,
+    site_key: Option<String>,
+    secret_key: Option<String>,
+    min_score: f64,
+) -> Option<Box<dyn CaptchaVerifier>> {
+    let (site_key, secret_key) = site_key.zip(secret_key)?;
+    Some(match provider {
+        CaptchaProvider::Turnstile => Box::new(Turnstile {
+            site_key,
+            secret_key,
+        }),
+        CaptchaProvider::Hcaptcha => Box::new(HCaptcha {
+            site_key,
+            secret_key,
+        }),
+        CaptchaProvider::Recaptcha => Box::new(ReCaptcha {
+            site_key,
+            secret_key,
+            min_score,
+        }),
+    })
+}
+
+/// Successful logins by client ip, the next logins of the ip within the ttl skip the widget,
+/// at most [`MAX_PASS_LOGINS`] of them
+pub struct CaptchaPasses {
+    ttl: Duration,
+    passes: RwLock<HashMap<IpAddr, (Instant, u32)>>,
+}
+
+impl CaptchaPasses {
+    pub fn new(ttl: u64) -> Self {
+        Self {
+            ttl: Duration::from_secs(ttl),
+            passes: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn passed(&self, addr: &IpAddr) -> bool {
+        self.passes
+            .read()
+            .ok()
+            .and_then(|passes| passes.get(addr).copied())
+            .is_some_and(|(verified, left)| left > 0 && verified.elapsed() < self.ttl)
+    }
+
+    /// Spend one of the logins of the pass of the ip, false if it has none left
+    fn consume(&self, addr: &IpAddr) -> bool {
+        let Ok(mut passes) = self.passes.write() else {
+            return false;
+        };
+        match passes.get_mut(addr) {
+            Some((verified, left)) if *left > 0 && verified.elapsed() < self.ttl => {
+                *left -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn insert(&self, addr: IpAddr) {
+        if let Ok(mut passes) = self.passes.write() {
+            passes.insert(addr, (Instant::now(), MAX_PASS_LOGINS));
+        }
+    }
+
+    /// Drop the expired and used up passes, returns how many were dropped
+    pub(crate) fn sweep(&self) -> usize {
+        let Ok(mut passes) = self.passes.write() else {
+            return 0;
+        };
+        let len = passes.len();
+        passes.retain(|_, (verified, left)| *left > 0 && verified.elapsed() < self.ttl);
+        len - passes.len()
+    }
+}
+
+/// Address of the client of the request, read from the `--real-ip-header` set by the
+/// reverse proxy in front, the connection address otherwise. Of a list such as
+/// `X-Forwarded-For` the last entry is taken, the one the proxy appended. Unspecified if
+/// the router is served without the connection info, e.g. mounted by an app
+pub(super) fn client_ip(addr: Option<&SocketAddr>, headers: &HeaderMap) -> IpAddr {
+    context::get_instance()
+        .real_ip_header()
+        .and_then(|name| headers.get(name))
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|ip| ip.trim().parse().ok())
+        .or_else(|| addr.map(SocketAddr::ip))
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+}
+
+/// Whether the client ip has passed the captcha recently, the login page then omits the widget
+pub(super) fn passed(addr: &IpAddr) -> bool {
+    context::get_instance()
+        .captcha_passes()
+        .is_some_and(|passes| passes.passed(addr))
+}
+
+/// Remember a successful login of the client ip, its next logins skip the captcha.
+/// An unknown address gets no pass, it would be shared by every client
+pub(super) fn record_pass(addr: &IpAddr) {
+    if addr.is_unspecified() {
+        return;
+    }
+    let ctx = context::get_instance();
+    if let (Some(_), Some(passes)) = (ctx.captcha(), ctx.captcha_passes()) {
+        passes.insert(*addr);
+    }
+}
+
+/// Check the captcha response of the login form, if a captcha is enforced
+pub(super) async fn captcha_check(
+    addr: &IpAddr,
+    response: Option<&str>,
+) -> Result<(), ResponseError> {
+    let ctx = context::get_instance();
+    let Some(verifier) = ctx.captcha() else {
+        return Ok(());
+    };
+    if ctx
+        .captcha_passes()
+        .is_some_and(|passes| passes.consume(addr))
+    {
+        return Ok(());
+    }
+    let response = response.filter(|r| !r.is_empty()).ok_or_else(|| {
+        ResponseError::BadRequest(anyhow::anyhow!("Missing captcha response".to_owned()))
+    })?;
+    verifier
+        .verify(addr, response)
+        .await
+        .map_err(ResponseError::BadRequest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pass_logins() {
+        let passes = CaptchaPasses::new(60);
+        let addr: IpAddr = "127.0.0.1".parse().unwrap();
+        assert!(!passes.consume(&addr));
+        passes.insert(addr);
+        for _ in 0..MAX_PASS_LOGINS {
+            assert!(passes.passed(&addr));
+            assert!(passes.consume(&addr));
+        }
+        assert!(!passes.passed(&addr));
+        assert!(!passes.consume(&addr));
+        assert_eq!(passes.sweep(), 1);
+    }
+}
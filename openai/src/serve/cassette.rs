@@ -0,0 +1,294 @@
+//! Record/replay of the upstream exchanges, the tests and CI of downstream apps
+//! run against the recorded cassette without real accounts
+use std::path::PathBuf;
+
+use axum::body::Bytes;
+use axum::http::{self, StatusCode};
+use base64::{engine::general_purpose, Engine};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::error::ResponseError;
+use crate::debug;
+
+/// Response headers not recorded, the recorded body is complete and decoded
+const SKIPPED_HEADERS: [&str; 3] = ["transfer-encoding", "content-length", "connection"];
+
+/// Response headers left out of the recordings, the session cookies of the account
+const SECRET_HEADERS: [&str; 1] = ["set-cookie"];
+
+/// JSON fields of the recorded bodies replaced by `REDACTED`, e.g. of `/api/auth/session`
+const SECRET_FIELDS: [&str; 10] = [
+    "accessToken",
+    "access_token",
+    "refreshToken",
+    "refresh_token",
+    "idToken",
+    "id_token",
+    "sessionToken",
+    "session_token",
+    "password",
+    "api_key",
+];
+
+const REDACTED: &str = "REDACTED";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CassetteMode {
+    /// Proxy to the upstream and save the exchanges
+    Record,
+    /// Answer from the saved exchanges, the upstream is never requested
+    #[default]
+    Replay,
+}
+
+impl std::str::FromStr for CassetteMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "record" => Ok(Self::Record),
+            "replay" => Ok(Self::Replay),
+            _ => anyhow::bail!("cassette mode: {} is not supported", s),
+        }
+    }
+}
+
+/// One recorded exchange, a JSON file of the cassette directory
+#[derive(Serialize, Deserialize)]
+struct Recording {
+    method: String,
+    path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_body: Option<String>,
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// Text body
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body: Option<String>,
+    /// Binary body
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body_base64: Option<String>,
+}
+
+/// Request of the client, recorded by method, path and a hash of the body
+pub(super) struct Exchange {
+    method: String,
+    path: String,
+    request_body: Option<Bytes>,
+    /// File name prefix shared by the requests of the route
+    prefix: String,
+    name: String,
+}
+
+pub struct Cassette {
+    dir: PathBuf,
+    mode: CassetteMode,
+    /// Replay the latest recording of the route to a request whose body changed
+    fallback: bool,
+}
+
+impl Cassette {
+    pub fn new(dir: PathBuf, mode: CassetteMode, fallback: bool) -> anyhow::Result<Self> {
+        match mode {
+            CassetteMode::Record => std::fs::create_dir_all(&dir)?,
+            CassetteMode::Replay if !dir.is_dir() => {
+                anyhow::bail!("Cassette directory {} not exists", dir.display())
+            }
+            CassetteMode::Replay => {}
+        }
+        Ok(Self {
+            dir,
+            mode,
+            fallback,
+        })
+    }
+
+    pub(super) fn replaying(&self) -> bool {
+        self.mode == CassetteMode::Replay
+    }
+
+    /// Exchange of the client request, before the tokens and sentinel headers are added
+    pub(super) fn exchange(
+        &self,
+        method: &http::Method,
+        path: &str,
+        body: Option<&Bytes>,
+    ) -> Exchange {
+        let slug = path
+            .split('?')
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .take(96)
+            .collect::<String>();
+        let prefix = format!("{}{}-", method.as_str(), slug);
+
+        let mut hasher = Sha256::new();
+        hasher.update(method.as_str());
+        hasher.update(path);
+        if let Some(body) = body {
+            hasher.update(body);
+        }
+        let hash = hasher
+            .finalize()
+            .iter()
+            .take(8)
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+
+        Exchange {
+            method: method.to_string(),
+            path: path.to_owned(),
+            request_body: body.cloned(),
+            name: format!("{prefix}{hash}.json"),
+            prefix,
+        }
+    }
+
+    /// Recorded response of the exchange, with the fallback the latest recording of
+    /// the route answers requests whose body changed, e.g. by generated message ids
+    pub(super) async fn replay(
+        &self,
+        exchange: &Exchange,
+    ) -> Result<reqwest::Response, ResponseError> {
+        let path = match self.dir.join(&exchange.name) {
+            path if path.is_file() => Some(path),
+            _ if self.fallback => self.latest(&exchange.prefix),
+            _ => None,
+        };
+        let Some(path) = path else {
+            return Err(ResponseError::new(
+                format!(
+                    "No cassette recording of {} {}",
+                    exchange.method, exchange.path
+                ),
+                StatusCode::BAD_GATEWAY,
+            ));
+        };
+        debug!("Cassette replay {}", path.display());
+
+        let recording = serde_json::from_slice::<Recording>(&tokio::fs::read(&path).await?)?;
+        let body = match (recording.body, recording.body_base64) {
+            (Some(body), _) => body.into_bytes(),
+            (None, Some(body)) => general_purpose::STANDARD.decode(body)?,
+            (None, None) => Vec::new(),
+        };
+        let mut builder = http::Response::builder().status(recording.status);
+        for (name, value) in recording.headers {
+            builder = builder.header(name, value);
+        }
+        Ok(reqwest::Response::from(
+            builder
+                .body(body)
+                .map_err(ResponseError::InternalServerError)?,
+        ))
+    }
+
+    /// Save the exchange, the body is read completely so the response is not streamed while recording
+    pub(super) async fn record(
+        &self,
+        exchange: Exchange,
+        resp: reqwest::Response,
+    ) -> Result<reqwest::Response, ResponseError> {
+        let status = resp.status();
+        let headers = resp.headers().clone();
+        let body = resp.bytes().await?;
+
+        let (text, binary) = match std::str::from_utf8(&body) {
+            Ok(text) => (Some(redact(text)), None),
+            Err(_) => (None, Some(general_purpose::STANDARD.encode(&body))),
+        };
+        let recording = Recording {
+            method: exchange.method,
+            path: exchange.path,
+            request_body: exchange
+                .request_body
+                .map(|body| redact(&String::from_utf8_lossy(&body))),
+            status: status.as_u16(),
+            headers: headers
+                .iter()
+                .filter(|(name, _)| !SKIPPED_HEADERS.contains(&name.as_str()))
+                .filter(|(name, _)| !SECRET_HEADERS.contains(&name.as_str()))
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_owned()))
+                })
+                .collect(),
+            body: text,
+            body_base64: binary,
+        };
+        let path = self.dir.join(&exchange.name);
+        tokio::fs::write(&path, serde_json::to_vec_pretty(&recording)?).await?;
+        debug!("Cassette record {}", path.display());
+
+        let mut builder = http::Response::builder().status(status);
+        for (name, value) in headers
+            .iter()
+            .filter(|(name, _)| !SKIPPED_HEADERS.contains(&name.as_str()))
+        {
+            builder = builder.header(name, value);
+        }
+        Ok(reqwest::Response::from(
+            builder
+                .body(body)
+                .map_err(ResponseError::InternalServerError)?,
+        ))
+    }
+
+    /// Latest recording of the route
+    fn latest(&self, prefix: &str) -> Option<PathBuf> {
+        std::fs::read_dir(&self.dir)
+            .ok()?
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(prefix))
+            .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+            .max_by_key(|(modified, _)| *modified)
+            .map(|(_, path)| path)
+    }
+}
+
+/// Replace the secret fields of a JSON body, other bodies are recorded as is
+fn redact(text: &str) -> String {
+    fn walk(value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Object(map) => map.iter_mut().for_each(|(key, value)| {
+                if SECRET_FIELDS.contains(&key.as_str()) && !value.is_null() {
+                    *value = serde_json::Value::String(REDACTED.to_owned());
+                } else {
+                    walk(value)
+                }
+            }),
+            serde_json::Value::Array(values) => values.iter_mut().for_each(walk),
+            _ => {}
+        }
+    }
+
+    match serde_json::from_str::<serde_json::Value>(text) {
+        Ok(mut value) if value.is_object() || value.is_array() => {
+            walk(&mut value);
+            value.to_string()
+        }
+        _ => text.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        let body = r#"{"user":{"email":"a@b.c"},"accessToken":"eyJ","expires":"2024"}"#;
+        let redacted = redact(body);
+        assert!(!redacted.contains("eyJ"));
+        assert!(redacted.contains(r#""accessToken":"REDACTED""#));
+        assert!(redacted.contains("a@b.c"));
+
+        let body = r#"[{"refresh_token":"rt"}]"#;
+        assert_eq!(redact(body), r#"[{"refresh_token":"REDACTED"}]"#);
+
+        assert_eq!(redact("data: {}\n\n"), "data: {}\n\n");
+    }
+}
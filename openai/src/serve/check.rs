@@ -0,0 +1,18 @@
+//! Checks of the external services, used to validate the configuration without starting the server
+use crate::arkose::funcaptcha::{solver, ArkoseSolver};
+
+/// Connect to the token bucket redis and `PING` it
+#[cfg(feature = "limit")]
+pub async fn redis(url: &str) -> anyhow::Result<()> {
+    let client = redis::Client::open(url)?;
+    let mut con = client.get_async_connection().await?;
+    redis::cmd("PING")
+        .query_async::<_, String>(&mut con)
+        .await?;
+    Ok(())
+}
+
+/// Verify the solver client key, returns the balance
+pub async fn solver(arkose_solver: &ArkoseSolver) -> anyhow::Result<f64> {
+    solver::balance(&arkose_solver.solver, &arkose_solver.client_key).await
+}
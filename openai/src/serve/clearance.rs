@@ -0,0 +1,276 @@
+//! Cloudflare clearance of the outbound routes, a `cf_clearance` is obtained per route
+//! from a FlareSolverr compatible harvester (a headless browser) when a challenge is
+//! detected, or pushed by an external harvester, and kept in the cookie store of the route
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use reqwest::header::HeaderValue;
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::cookies::{CookieJars, CF_CLEARANCE};
+use crate::{context, info, metrics, now_duration, warn, URL_CHATGPT_API};
+
+const CHALLENGES: &str = "ninja_cf_challenges_total";
+const CLEARANCES: &str = "ninja_cf_clearance_total";
+
+/// Seconds before the clearance of a route is harvested again
+const HARVEST_COOLDOWN: u64 = 60;
+
+/// Milliseconds the harvester may take to solve the challenge
+const HARVEST_TIMEOUT: u64 = 60000;
+
+#[derive(Deserialize)]
+struct HarvesterResponse {
+    status: String,
+    #[serde(default)]
+    message: String,
+    solution: Option<Solution>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Solution {
+    cookies: Vec<HarvestedCookie>,
+    user_agent: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HarvestedCookie {
+    name: String,
+    value: String,
+    domain: String,
+    #[serde(default)]
+    path: Option<String>,
+    /// Unix seconds, negative for session cookies
+    #[serde(default)]
+    expires: Option<f64>,
+}
+
+impl HarvestedCookie {
+    fn set_cookie(&self, now: u64) -> Option<HeaderValue> {
+        let mut value = format!(
+            "{}={}; Domain={}; Path={}; Secure",
+            self.name,
+            self.value,
+            self.domain,
+            self.path.as_deref().unwrap_or("/")
+        );
+        if let Some(expires) = self.expires.filter(|expires| *expires > 0.0) {
+            value.push_str(&format!(
+                "; Max-Age={}",
+                (expires as u64).saturating_sub(now)
+            ));
+        }
+        HeaderValue::from_str(&value).ok()
+    }
+}
+
+/// Clearance pushed by an external harvester
+#[derive(Deserialize)]
+pub(super) struct PushedClearance {
+    /// Outbound route, see `GET /admin/cf-clearance`
+    #[serde(default = "direct")]
+    pub(super) route: String,
+    pub(super) cf_clearance: String,
+    /// User-Agent of the browser that solved the challenge
+    pub(super) user_agent: String,
+    /// Seconds the clearance is valid
+    #[serde(default = "max_age")]
+    pub(super) max_age: u64,
+}
+
+fn direct() -> String {
+    "direct".to_owned()
+}
+
+fn max_age() -> u64 {
+    1800
+}
+
+pub struct Clearance {
+    jars: Arc<CookieJars>,
+    /// Harvester url
+    harvester: Option<String>,
+    client: Client,
+    /// Proxy urls of the routes, the routes hide the proxy password
+    proxies: HashMap<String, String>,
+    /// Unix seconds of the last harvest of a route
+    harvested: Mutex<HashMap<String, u64>>,
+}
+
+impl Clearance {
+    pub(crate) fn new(
+        jars: Arc<CookieJars>,
+        harvester: Option<String>,
+        proxies: impl Iterator<Item = String>,
+    ) -> Self {
+        Self {
+            jars,
+            harvester,
+            client: Client::builder()
+                .timeout(Duration::from_millis(HARVEST_TIMEOUT + 10000))
+                .build()
+                .expect("Failed to build harvester client"),
+            proxies: proxies
+                .map(|proxy| (crate::balancer::mask_proxy_url(&proxy), proxy))
+                .collect(),
+            harvested: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// User-Agent the unexpired clearance of the route was issued to
+    pub(super) fn user_agent(&self, route: &str) -> Option<String> {
+        let url = Url::parse(URL_CHATGPT_API).expect("Invalid upstream url");
+        let jar = self.jars.jar(route);
+        jar.has_clearance(&url).then(|| jar.user_agent()).flatten()
+    }
+
+    /// Routes and whether they hold a clearance
+    pub(super) fn routes(&self) -> serde_json::Value {
+        let url = Url::parse(URL_CHATGPT_API).expect("Invalid upstream url");
+        let routes = self
+            .jars
+            .routes()
+            .into_iter()
+            .map(|route| {
+                let jar = self.jars.jar(&route);
+                json!({
+                    "route": route,
+                    "clearance": jar.has_clearance(&url),
+                    "user_agent": jar.user_agent(),
+                })
+            })
+            .collect::<Vec<_>>();
+        json!({ "harvester": self.harvester.is_some(), "routes": routes })
+    }
+
+    /// The clearance of the route was challenged anyway, drop it and harvest a new one in the background
+    fn stale(self: &Arc<Self>, route: &str) {
+        let url = Url::parse(URL_CHATGPT_API).expect("Invalid upstream url");
+        self.jars.jar(route).clear_clearance(&url);
+        if self.harvester.is_none() {
+            return;
+        }
+        let now = now_duration().map(|d| d.as_secs()).unwrap_or_default();
+        {
+            let mut harvested = self.harvested.lock().expect("Failed to lock harvests");
+            match harvested.get(route) {
+                Some(last) if now < last + HARVEST_COOLDOWN => return,
+                _ => harvested.insert(route.to_owned(), now),
+            };
+        }
+
+        let clearance = self.clone();
+        let route = route.to_owned();
+        context::spawn(async move {
+            let result = clearance.harvest(&route).await;
+            if let Err(ref err) = result {
+                warn!("Failed to harvest the clearance of {route}: {err}");
+            }
+            record("harvester", result.is_ok());
+        });
+    }
+
+    /// Ask the harvester to solve the challenge through the proxy of the route
+    async fn harvest(&self, route: &str) -> anyhow::Result<()> {
+        let harvester = self.harvester.as_deref().unwrap_or_default();
+        let mut body = json!({
+            "cmd": "request.get",
+            "url": format!("{URL_CHATGPT_API}/"),
+            "maxTimeout": HARVEST_TIMEOUT,
+        });
+        if let Some(proxy) = self.proxies.get(route) {
+            body["proxy"] = json!({ "url": proxy });
+        }
+        let resp = self
+            .client
+            .post(harvester)
+            .json(&body)
+            .send()
+            .await?
+            .json::<HarvesterResponse>()
+            .await?;
+        let solution = match (resp.status.as_str(), resp.solution) {
+            ("ok", Some(solution)) => solution,
+            _ => anyhow::bail!("harvester error: {}", resp.message),
+        };
+        if !solution.cookies.iter().any(|c| c.name == CF_CLEARANCE) {
+            anyhow::bail!("harvester solution without {CF_CLEARANCE}")
+        }
+
+        let now = now_duration()?.as_secs();
+        let cookies = solution
+            .cookies
+            .iter()
+            .filter_map(|cookie| cookie.set_cookie(now))
+            .collect::<Vec<_>>();
+        self.set(route, &cookies, solution.user_agent);
+        info!("Harvested the clearance of {route}");
+        Ok(())
+    }
+
+    /// Store a clearance pushed by an external harvester
+    pub(super) fn push(&self, pushed: PushedClearance) -> anyhow::Result<()> {
+        let cookie = HeaderValue::from_str(&format!(
+            "{CF_CLEARANCE}={}; Domain=.openai.com; Path=/; Max-Age={}; Secure; HttpOnly",
+            pushed.cf_clearance, pushed.max_age
+        ))?;
+        self.set(&pushed.route, &[cookie], pushed.user_agent);
+        record("push", true);
+        Ok(())
+    }
+
+    fn set(&self, route: &str, cookies: &[HeaderValue], user_agent: String) {
+        let url = Url::parse(URL_CHATGPT_API).expect("Invalid upstream url");
+        self.jars
+            .jar(route)
+            .set_clearance(cookies.iter(), &url, user_agent);
+        // Keep it right away, a clearance is costly to obtain
+        if let Err(err) = self.jars.save() {
+            warn!("Failed to save cookies: {err}")
+        }
+    }
+}
+
+/// A challenge was answered to a request of the route
+pub(super) fn challenged(clearance: Option<&Arc<Clearance>>, route: Option<&str>) {
+    metrics::inc(CHALLENGES, "Cloudflare challenges of the upstream", &[], 1);
+    if let Some((clearance, route)) = clearance.zip(route) {
+        clearance.stale(route);
+    }
+}
+
+fn record(source: &str, ok: bool) {
+    metrics::inc(
+        CLEARANCES,
+        "Cloudflare clearances obtained by source and result",
+        &[
+            ("source", source),
+            ("result", if ok { "ok" } else { "error" }),
+        ],
+        1,
+    );
+}
+
+/// Whether the upstream response is a Cloudflare challenge instead of the API answer
+pub(super) fn is_challenge(resp: &reqwest::Response) -> bool {
+    let headers = resp.headers();
+    if headers
+        .get("cf-mitigated")
+        .is_some_and(|value| value == "challenge")
+    {
+        return true;
+    }
+    matches!(resp.status().as_u16(), 403 | 503)
+        && headers
+            .get(reqwest::header::SERVER)
+            .is_some_and(|value| value == "cloudflare")
+        && headers
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("text/html"))
+}
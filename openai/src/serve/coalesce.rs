@@ -0,0 +1,98 @@
+//! Single-flight of identical concurrent GET requests, the WebUI and the clients poll the
+//! same endpoints, e.g. the conversation list, so the requests of the same path, account
+//! and authorization in flight together share one upstream call
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use axum::body::Bytes;
+use futures::future::{BoxFuture, FutureExt, Shared, WeakShared};
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+
+use super::convert::rebuild_response;
+use super::error::ResponseError;
+use super::extract::{RequestExtractor, SendRequestExt};
+use crate::{context, metrics};
+
+const COALESCED: &str = "ninja_coalesced_requests_total";
+
+type Fetch = BoxFuture<'static, Result<(StatusCode, HeaderMap, Bytes), ResponseError>>;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Request key -> id and flight of an instance, the flight lives as long as a request waits for it
+#[derive(Default)]
+pub(crate) struct Flights(Mutex<HashMap<String, (u64, WeakShared<Fetch>)>>);
+
+/// Removes the flight once it landed or all its requests are gone
+struct Landing {
+    flights: Arc<Flights>,
+    key: String,
+    id: u64,
+}
+
+impl Drop for Landing {
+    fn drop(&mut self) {
+        if let Ok(mut flights) = self.flights.0.lock() {
+            if flights.get(&self.key).map(|(id, _)| *id) == Some(self.id) {
+                flights.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// Send the request, or join the identical request already in flight
+pub(super) async fn send_request(
+    key: String,
+    origin: &'static str,
+    client: reqwest::Client,
+    req: RequestExtractor,
+) -> Result<reqwest::Response, ResponseError> {
+    let (status, headers, body) = flight(key, origin, client, req).await?;
+    rebuild_response(status, &headers, body)
+}
+
+/// Flight of the request, a new one unless an identical request is in flight
+fn flight(
+    key: String,
+    origin: &'static str,
+    client: reqwest::Client,
+    req: RequestExtractor,
+) -> Shared<Fetch> {
+    let instance = context::get_instance().flights().clone();
+    let mut flights = instance
+        .0
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(flight) = flights.get(&key).and_then(|(_, flight)| flight.upgrade()) {
+        metrics::inc(
+            COALESCED,
+            "Requests joined to an identical one in flight",
+            &[],
+            1,
+        );
+        return flight;
+    }
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let landing = Landing {
+        flights: instance.clone(),
+        key: key.clone(),
+        id,
+    };
+    let fetch: Fetch = async move {
+        let _landing = landing;
+        let resp = client.send_request(origin, req).await?;
+        let status = resp.status();
+        let headers = resp.headers().clone();
+        let body = resp.bytes().await?;
+        Ok((status, headers, body))
+    }
+    .boxed();
+    let flight = fetch.shared();
+    if let Some(weak) = flight.downgrade() {
+        flights.insert(key, (id, weak));
+    }
+    flight
+}
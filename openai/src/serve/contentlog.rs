@@ -0,0 +1,297 @@
+//! Opt-in content log, an audit trail of the prompts sent through the instance. One JSON
+//! record per prompt in a daily file of the log directory, the fields are redacted by the
+//! rules before they are written, and the files older than the retention are deleted
+use std::borrow::Cow;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{context, now_duration, warn};
+
+/// Built-in rules of the `prompt` field, emails and keys or tokens
+const EMAIL_PATTERN: &str = r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}";
+const KEY_PATTERN: &str =
+    r"\b(?:sk|nk|sess)-[A-Za-z0-9_-]{16,}|\beyJ[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+";
+
+const PROMPT_FIELD: &str = "prompt";
+
+/// Redaction rules file, `[[rule]]` tables
+#[derive(Deserialize, Default)]
+pub struct RedactRules {
+    #[serde(default)]
+    pub rule: Vec<RedactRule>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct RedactRule {
+    /// Regex of the redacted text
+    pub pattern: String,
+    #[serde(default = "default_replace")]
+    pub replace: String,
+    /// Fields of the record the rule applies to, `prompt` when empty
+    #[serde(default)]
+    pub fields: Vec<String>,
+}
+
+fn default_replace() -> String {
+    "[REDACTED]".to_owned()
+}
+
+struct Rule {
+    pattern: Regex,
+    replace: String,
+    fields: Vec<String>,
+}
+
+/// Record of a prompt
+#[derive(Serialize)]
+struct ContentRecord<'a> {
+    timestamp: u64,
+    /// Email of the upstream token
+    user: Option<String>,
+    /// Masked client key
+    key: Option<String>,
+    path: String,
+    model: Option<String>,
+    conversation_id: Option<String>,
+    prompt: Cow<'a, str>,
+}
+
+pub struct ContentLog {
+    dir: PathBuf,
+    /// Zero keeps the files
+    retention: Duration,
+    rules: Vec<Rule>,
+    /// Day of the open file
+    file: Mutex<Option<(u64, File)>>,
+}
+
+impl ContentLog {
+    /// The built-in rules redact the emails and keys of the prompts before `rules`
+    pub fn new(dir: PathBuf, retention_days: u64, rules: Vec<RedactRule>) -> anyhow::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let builtin = [EMAIL_PATTERN, KEY_PATTERN].map(|pattern| RedactRule {
+            pattern: pattern.to_owned(),
+            replace: default_replace(),
+            fields: Vec::new(),
+        });
+        let rules = builtin
+            .into_iter()
+            .chain(rules)
+            .map(|rule| {
+                Ok(Rule {
+                    pattern: Regex::new(&rule.pattern)?,
+                    replace: rule.replace,
+                    fields: match rule.fields.is_empty() {
+                        true => vec![PROMPT_FIELD.to_owned()],
+                        false => rule.fields,
+                    },
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self {
+            dir,
+            retention: Duration::from_secs(retention_days * 86400),
+            rules,
+            file: Mutex::new(None),
+        })
+    }
+
+    /// Check the patterns of the rules
+    pub fn check(rules: &[RedactRule]) -> anyhow::Result<()> {
+        rules
+            .iter()
+            .try_for_each(|rule| Regex::new(&rule.pattern).map(|_| ()))
+            .map_err(anyhow::Error::from)
+    }
+
+    fn redact(&self, field: &str, text: &mut String) {
+        for rule in self
+            .rules
+            .iter()
+            .filter(|rule| rule.fields.iter().any(|f| f == field))
+        {
+            if let Cow::Owned(redacted) = rule.pattern.replace_all(text, rule.replace.as_str()) {
+                *text = redacted;
+            }
+        }
+    }
+
+    /// Record the prompt of a request body sent upstream, bodies without a prompt are skipped
+    pub(crate) fn record_body(&self, path: &str, token: &str, key: Option<&str>, body: &Value) {
+        if let Some(prompt) = prompt_text(body) {
+            self.record(
+                path,
+                token,
+                key,
+                body["model"].as_str(),
+                body["conversation_id"].as_str(),
+                &prompt,
+            );
+        }
+    }
+
+    /// Record a prompt of the token and the client key if any
+    fn record(
+        &self,
+        path: &str,
+        token: &str,
+        key: Option<&str>,
+        model: Option<&str>,
+        conversation_id: Option<&str>,
+        prompt: &str,
+    ) {
+        let user = crate::token::check(token.trim_start_matches("Bearer "))
+            .ok()
+            .flatten()
+            .map(|profile| profile.email().to_owned());
+        let mut record = ContentRecord {
+            timestamp: now_duration().map(|d| d.as_secs()).unwrap_or_default(),
+            user,
+            key: key.map(context::mask_key),
+            path: path.to_owned(),
+            model: model.map(ToOwned::to_owned),
+            conversation_id: conversation_id.map(ToOwned::to_owned),
+            prompt: Cow::Borrowed(prompt),
+        };
+        let mut redacted = record.prompt.to_string();
+        self.redact(PROMPT_FIELD, &mut redacted);
+        record.prompt = Cow::Owned(redacted);
+        for (field, value) in [
+            ("user", record.user.as_mut()),
+            ("key", record.key.as_mut()),
+            ("path", Some(&mut record.path)),
+            ("model", record.model.as_mut()),
+            ("conversation_id", record.conversation_id.as_mut()),
+        ] {
+            if let Some(value) = value {
+                self.redact(field, value);
+            }
+        }
+
+        let result = serde_json::to_vec(&record)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| self.write(record.timestamp, &line));
+        if let Err(err) = result {
+            warn!("Content log write error: {err}");
+        }
+    }
+
+    /// Append the line to the file of the day, a new day deletes the expired files
+    fn write(&self, timestamp: u64, line: &[u8]) -> anyhow::Result<()> {
+        let day = timestamp / 86400;
+        let mut file = self
+            .file
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Failed to lock the content log"))?;
+        if file.as_ref().map(|(open, _)| *open) != Some(day) {
+            let date = time::OffsetDateTime::from_unix_timestamp(timestamp as i64)?.date();
+            let path = self.dir.join(format!("content-{date}.jsonl"));
+            *file = Some((
+                day,
+                OpenOptions::new().create(true).append(true).open(path)?,
+            ));
+            self.purge(None);
+        }
+        if let Some((_, file)) = file.as_mut() {
+            file.write_all(line)?;
+            file.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    /// Delete the files last written before the retention, or before `max` if it is shorter,
+    /// returns how many were deleted
+    pub(crate) fn purge(&self, max: Option<Duration>) -> usize {
+        let retention = match (self.retention.is_zero(), max) {
+            (true, None) => return 0,
+            (true, Some(max)) => max,
+            (false, max) => max.map_or(self.retention, |max| max.min(self.retention)),
+        };
+        self.files()
+            .into_iter()
+            .filter(|path| {
+                fs::metadata(path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+                    .and_then(|modified| modified.elapsed().ok())
+                    .is_some_and(|age| age > retention)
+            })
+            .filter(|path| fs::remove_file(path).is_ok())
+            .count()
+    }
+
+    /// Delete the records of the account email, returns how many were deleted
+    pub(crate) fn delete(&self, user: &str) -> anyhow::Result<usize> {
+        // Appends wait for the files to be rewritten
+        let _file = self
+            .file
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Failed to lock the content log"))?;
+        let mut removed = 0;
+        for path in self.files() {
+            let data = fs::read_to_string(&path)?;
+            let before = removed;
+            let lines = data
+                .lines()
+                .filter(|line| {
+                    let matched = serde_json::from_str::<Value>(line)
+                        .is_ok_and(|record| record["user"].as_str() == Some(user));
+                    removed += matched as usize;
+                    !matched
+                })
+                .collect::<Vec<_>>();
+            if removed > before {
+                let mut data = lines.join("\n");
+                if !data.is_empty() {
+                    data.push('\n');
+                }
+                fs::write(&path, data)?;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Files of the log directory
+    fn files(&self) -> Vec<PathBuf> {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+        entries
+            .flatten()
+            .filter(|entry| {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                name.starts_with("content-") && name.ends_with(".jsonl")
+            })
+            .map(|entry| entry.path())
+            .collect()
+    }
+}
+
+/// Text of the prompt of a request body, the messages of the ChatGPT API (`content.parts`)
+/// and of the platform API (`content` text or parts), or the legacy `prompt`
+fn prompt_text(body: &Value) -> Option<String> {
+    let mut texts = Vec::new();
+    for message in body["messages"].as_array().into_iter().flatten() {
+        match &message["content"] {
+            Value::String(text) => texts.push(text.as_str()),
+            Value::Array(parts) => texts.extend(parts.iter().filter_map(|p| p["text"].as_str())),
+            content => texts.extend(
+                content["parts"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(Value::as_str),
+            ),
+        }
+    }
+    texts.extend(body["prompt"].as_str());
+    (!texts.is_empty()).then(|| texts.join("\n"))
+}
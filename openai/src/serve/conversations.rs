@@ -0,0 +1,199 @@
+//! Conversation list cache, keeps the WebUI sidebar and search responsive when upstream is slow
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use axum::body::{Body, Bytes};
+use axum::http::{header, Method, Response, StatusCode, Uri};
+
+use super::error::ResponseError;
+use super::extract::{RequestExtractor, SendRequestExt};
+use crate::{context, URL_CHATGPT_API};
+
+/// Stale lists older than this are not served
+const MAX_STALE: Duration = Duration::from_secs(3600);
+
+pub(super) const HEADER_CACHE: &str = "X-Ninja-Cache";
+
+#[derive(Default)]
+struct AccountLists {
+    /// Bumped by writes, lists fetched before are not stored
+    generation: u64,
+    /// Path and query -> fetched time and body
+    lists: HashMap<String, (Instant, Bytes)>,
+}
+
+pub struct ConversationCache {
+    ttl: Duration,
+    /// Wait for upstream before serving the stale list
+    timeout: Duration,
+    accounts: RwLock<HashMap<String, AccountLists>>,
+}
+
+impl ConversationCache {
+    pub fn new(ttl: u64, timeout: u64) -> Self {
+        Self {
+            ttl: Duration::from_secs(ttl),
+            timeout: Duration::from_secs(timeout),
+            accounts: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, account: &str, key: &str) -> Option<(Instant, Bytes)> {
+        let accounts = self.accounts.read().ok()?;
+        accounts
+            .get(account)?
+            .lists
+            .get(key)
+            .filter(|(fetched, _)| fetched.elapsed() < MAX_STALE)
+            .cloned()
+    }
+
+    fn generation(&self, account: &str) -> u64 {
+        self.accounts
+            .read()
+            .ok()
+            .and_then(|accounts| accounts.get(account).map(|lists| lists.generation))
+            .unwrap_or_default()
+    }
+
+    fn insert(&self, account: &str, key: &str, generation: u64, body: Bytes) {
+        if let Ok(mut accounts) = self.accounts.write() {
+            accounts.retain(|_, account| {
+                account
+                    .lists
+                    .retain(|_, (fetched, _)| fetched.elapsed() < MAX_STALE);
+                !account.lists.is_empty() || account.generation > 0
+            });
+            let lists = accounts.entry(account.to_owned()).or_default();
+            if lists.generation == generation {
+                lists.lists.insert(key.to_owned(), (Instant::now(), body));
+            }
+        }
+    }
+
+    /// Drop the stale lists, returns how many were dropped
+    pub(crate) fn sweep(&self) -> usize {
+        let Ok(mut accounts) = self.accounts.write() else {
+            return 0;
+        };
+        let mut removed = 0;
+        accounts.retain(|_, account| {
+            let len = account.lists.len();
+            account
+                .lists
+                .retain(|_, (fetched, _)| fetched.elapsed() < MAX_STALE);
+            removed += len - account.lists.len();
+            !account.lists.is_empty() || account.generation > 0
+        });
+        removed
+    }
+
+    /// Drop the cached lists of the account, returns how many were dropped
+    pub(crate) fn invalidate(&self, account: &str) -> usize {
+        let Ok(mut accounts) = self.accounts.write() else {
+            return 0;
+        };
+        let lists = accounts.entry(account.to_owned()).or_default();
+        lists.generation += 1;
+        let removed = lists.lists.len();
+        lists.lists.clear();
+        removed
+    }
+}
+
+/// What the cache does with a backend api request
+pub(super) enum CacheAction {
+    /// Conversation list or search, keyed by path and query
+    List(String),
+    /// Write to conversations, invalidates the lists
+    Invalidate,
+    Pass,
+}
+
+impl CacheAction {
+    pub(super) fn of(method: &Method, uri: &Uri) -> Self {
+        let path = uri.path();
+        if method == Method::GET {
+            // `/backend-api/conversations` and `/backend-api/conversations/search`
+            match path.trim_end_matches('/') {
+                "/backend-api/conversations" | "/backend-api/conversations/search" => {
+                    CacheAction::List(
+                        uri.path_and_query()
+                            .map(|v| v.as_str())
+                            .unwrap_or(path)
+                            .to_owned(),
+                    )
+                }
+                _ => CacheAction::Pass,
+            }
+        } else if path.starts_with("/backend-api/conversation") {
+            CacheAction::Invalidate
+        } else {
+            CacheAction::Pass
+        }
+    }
+}
+
+fn list_response(status: StatusCode, body: Bytes, cache: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(HEADER_CACHE, cache)
+        .body(Body::from(body))
+        .expect("An error occurred while building the response")
+}
+
+/// Fetch the list from upstream and cache it if the account was not written meanwhile
+async fn fetch(
+    account: String,
+    key: String,
+    generation: u64,
+    client: reqwest::Client,
+    req: RequestExtractor,
+) -> Result<(StatusCode, Bytes), ResponseError> {
+    let resp = client.send_request(URL_CHATGPT_API, req).await?;
+    let status = resp.status();
+    let body = resp.bytes().await?;
+    if status.is_success() {
+        if let Some(cache) = context::get_instance().conversation_cache() {
+            cache.insert(&account, &key, generation, body.clone());
+        }
+    }
+    Ok((status, body))
+}
+
+/// Serve the list from the cache within the ttl, otherwise from upstream,
+/// falls back to the stale list when upstream is slow or failing
+pub(super) async fn cached_list(
+    cache: &ConversationCache,
+    account: String,
+    key: String,
+    client: reqwest::Client,
+    req: RequestExtractor,
+) -> Result<Response<Body>, ResponseError> {
+    let cached = cache.get(&account, &key);
+    if let Some((fetched, body)) = cached.as_ref() {
+        if fetched.elapsed() < cache.ttl {
+            return Ok(list_response(StatusCode::OK, body.clone(), "hit"));
+        }
+    }
+
+    let generation = cache.generation(&account);
+    let mut task = context::spawn(fetch(account, key, generation, client, req));
+
+    let Some((_, stale)) = cached else {
+        return match task.await {
+            Ok(result) => result.map(|(status, body)| list_response(status, body, "miss")),
+            Err(err) => Err(ResponseError::InternalServerError(err)),
+        };
+    };
+
+    // The refresh goes on in the background after the timeout
+    match tokio::time::timeout(cache.timeout, &mut task).await {
+        Ok(Ok(Ok((status, body)))) if !status.is_server_error() => {
+            Ok(list_response(status, body, "miss"))
+        }
+        _ => Ok(list_response(StatusCode::OK, stale, "stale")),
+    }
+}
@@ -1,6 +1,7 @@
 use std::time::UNIX_EPOCH;
 
-use crate::debug;
+use crate::chatgpt::{SENTINEL_CHAT_REQUIREMENTS_TOKEN, SENTINEL_PROOF_TOKEN};
+use crate::{context, debug};
 use axum::body::StreamBody;
 use axum::http::header;
 use axum::response::{IntoResponse, Response};
@@ -8,7 +9,21 @@ use axum_extra::extract::cookie::Cookie;
 use axum_extra::extract::{cookie, CookieJar};
 use reqwest::header::HeaderMap;
 
+use super::clearance;
+use super::deadline::{self, DeadlineStream, ReadTimeoutStream};
+use super::drain::TrackedStream;
 use super::error::ResponseError;
+use super::keepalive::KeepAliveStream;
+use super::middleware::access_log::UpstreamStatus;
+#[cfg(feature = "plugin")]
+use super::plugin::ResponseRewrite;
+#[cfg(not(feature = "plugin"))]
+use super::rewrite::ResponseRewrite;
+use super::rewrite::RewriteStream;
+use super::stateless::{CleanupStream, ConversationCleanup};
+use super::stop::{StopGeneration, StopStream};
+use super::strip::EventStrip;
+use super::usage::{UsageStream, UsageTracker};
 
 /// Request headers convert
 pub(super) fn header_convert(
@@ -29,6 +44,11 @@ pub(super) fn header_convert(
     if let Some(content_type) = h.get(header::CONTENT_TYPE) {
         headers.insert(header::CONTENT_TYPE, content_type.clone());
     }
+    for name in [SENTINEL_CHAT_REQUIREMENTS_TOKEN, SENTINEL_PROOF_TOKEN] {
+        if let Some(value) = h.get(name) {
+            headers.insert(name, value.clone());
+        }
+    }
     headers.insert(header::ORIGIN, header::HeaderValue::from_static(origin));
     headers.insert(header::REFERER, header::HeaderValue::from_static(origin));
 
@@ -55,16 +75,77 @@ pub(super) fn header_convert(
     Ok(headers)
 }
 
-/// Response convert
+/// Response headers not kept by a response rebuilt from its read body, the body is complete and decoded
+const REBUILT_SKIPPED_HEADERS: [&str; 4] = [
+    "transfer-encoding",
+    "content-length",
+    "content-encoding",
+    "connection",
+];
+
+/// Upstream response rebuilt after its body was read
+pub(super) fn rebuild_response(
+    status: reqwest::StatusCode,
+    headers: &HeaderMap,
+    body: axum::body::Bytes,
+) -> Result<reqwest::Response, ResponseError> {
+    let mut builder = axum::http::Response::builder().status(status);
+    for (name, value) in headers
+        .iter()
+        .filter(|(name, _)| !REBUILT_SKIPPED_HEADERS.contains(&name.as_str()))
+    {
+        builder = builder.header(name, value);
+    }
+    Ok(reqwest::Response::from(
+        builder
+            .body(body)
+            .map_err(ResponseError::InternalServerError)?,
+    ))
+}
+
+/// Response convert, the body feeds the usage tracker, the conversation cleanup and the stop
+/// of the generation if any, then is rewritten by the plugins and redacted by the moderation.
+/// Event streams are stripped of the unwanted events, get keepalive pings and are closed after
+/// the maximum duration
 pub(super) fn response_convert(
     resp: reqwest::Response,
+    usage: Option<UsageTracker>,
+    mut cleanup: Option<ConversationCleanup>,
+    mut stop: Option<StopGeneration>,
+    rewrite: Option<ResponseRewrite>,
+    strip: Option<EventStrip>,
 ) -> Result<impl IntoResponse, ResponseError> {
-    let mut builder = Response::builder().status(resp.status());
-    for kv in resp
+    let content_type = resp
         .headers()
-        .into_iter()
-        .filter(|(k, _)| k.as_str().to_lowercase().ne("set-cookie"))
-    {
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(ToOwned::to_owned);
+    // The challenge page can not be solved by the clients of the proxy
+    if clearance::is_challenge(&resp) {
+        return Err(ResponseError::new(
+            "Upstream Cloudflare challenge, please try again later".to_owned(),
+            resp.status(),
+        ));
+    }
+
+    let rewrite = rewrite.and_then(|r| r.start(resp.status().as_u16(), content_type.as_deref()));
+    let redactor = context::get_instance()
+        .moderation()
+        .and_then(|moderation| moderation.redactor(content_type.as_deref()));
+    let event_stream = content_type
+        .as_deref()
+        .map(|v| v.starts_with("text/event-stream"))
+        .unwrap_or_default();
+    let strip = strip.filter(|_| event_stream);
+    // The length changes with the rewrite
+    let rewritten = rewrite.is_some() || redactor.is_some() || strip.is_some();
+
+    let mut builder = Response::builder()
+        .status(resp.status())
+        .extension(UpstreamStatus(resp.status()));
+    for kv in resp.headers().into_iter().filter(|(k, _)| {
+        k.as_str().to_lowercase().ne("set-cookie") && !(rewritten && k == &header::CONTENT_LENGTH)
+    }) {
         builder = builder.header(kv.0, kv.1);
     }
 
@@ -91,8 +172,47 @@ pub(super) fn response_convert(
             builder = builder.header(axum::http::header::SET_COOKIE, cookie.to_string());
         }
     }
+    if let Some(cleanup) = cleanup.as_mut() {
+        cleanup.served_by(resp.url());
+    }
+    if let Some(stop) = stop.as_mut() {
+        stop.served_by(resp.url());
+    }
+    let body = UsageStream::new(
+        Box::pin(resp.bytes_stream()),
+        content_type.as_deref(),
+        usage,
+    );
+    let body = CleanupStream::new(body, cleanup);
+    let body = StopStream::new(body, stop);
+    let body = RewriteStream::new(body, rewrite);
+    let body = RewriteStream::new(body, redactor);
+    let body = RewriteStream::new(body, strip);
+    let max = event_stream
+        .then(|| context::get_instance().sse_max_duration())
+        .flatten();
+    let body = DeadlineStream::new(
+        body,
+        max,
+        Ok(deadline::backend_error_event(deadline::exceeded(max))),
+    );
+    let read = event_stream
+        .then(|| context::get_instance().stream_read_timeout())
+        .flatten();
+    let body = ReadTimeoutStream::new(
+        body,
+        read,
+        Some(Ok(deadline::backend_error_event(deadline::idle(read)))),
+    );
+    let body = KeepAliveStream::new(
+        body,
+        event_stream
+            .then(|| context::get_instance().sse_keepalive())
+            .flatten(),
+    );
+    let body = TrackedStream::new(body, event_stream);
     Ok(builder
-        .body(StreamBody::new(resp.bytes_stream()))
+        .body(StreamBody::new(body))
         .map_err(ResponseError::InternalServerError)?)
 }
 
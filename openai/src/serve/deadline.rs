@@ -0,0 +1,140 @@
+//! SSE stream time caps, a stuck upstream stream is closed with a final error event
+//! instead of holding the connection for as long as the upstream keeps it open. The event
+//! stream requests are timed apart from the client timeout, by the stream timeout until the
+//! response headers, then by the read timeout between the chunks and the maximum duration
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use futures_core::Stream;
+use tokio::time::{Instant, Sleep};
+
+use super::error::ResponseError;
+use crate::context;
+
+/// Request timeout of the event stream requests in place of the client timeout, the
+/// sleep of a deadline past the clock is the far future
+pub(crate) const NO_TIMEOUT: Duration = Duration::MAX;
+
+/// Stream ended with `last` once `max` has passed since it started
+pub(crate) struct DeadlineStream<S: Stream> {
+    inner: S,
+    deadline: Option<Pin<Box<Sleep>>>,
+    last: Option<S::Item>,
+}
+
+impl<S: Stream> DeadlineStream<S> {
+    /// Pass through without `max`
+    pub(crate) fn new(inner: S, max: Option<Duration>, last: S::Item) -> Self {
+        Self {
+            inner,
+            deadline: max.map(|max| Box::pin(tokio::time::sleep(max))),
+            last: Some(last),
+        }
+    }
+}
+
+impl<S: Stream + Unpin> Stream for DeadlineStream<S>
+where
+    S::Item: Unpin,
+{
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let Some(deadline) = this.deadline.as_mut() else {
+            return Pin::new(&mut this.inner).poll_next(cx);
+        };
+        if deadline.as_mut().poll(cx).is_ready() {
+            // The final event, then the end of the stream
+            return Poll::Ready(this.last.take());
+        }
+        Pin::new(&mut this.inner).poll_next(cx)
+    }
+}
+
+/// Stream ended with `last` once no item came for `read`
+pub(crate) struct ReadTimeoutStream<S: Stream> {
+    inner: S,
+    read: Option<(Duration, Pin<Box<Sleep>>)>,
+    last: Option<S::Item>,
+}
+
+impl<S: Stream> ReadTimeoutStream<S> {
+    /// Pass through without `read`, ended without a final item if `last` is `None`
+    pub(crate) fn new(inner: S, read: Option<Duration>, last: Option<S::Item>) -> Self {
+        Self {
+            inner,
+            read: read.map(|read| (read, Box::pin(tokio::time::sleep(read)))),
+            last,
+        }
+    }
+}
+
+impl<S: Stream + Unpin> Stream for ReadTimeoutStream<S>
+where
+    S::Item: Unpin,
+{
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let Some((read, sleep)) = this.read.as_mut() else {
+            return Pin::new(&mut this.inner).poll_next(cx);
+        };
+        match Pin::new(&mut this.inner).poll_next(cx) {
+            Poll::Ready(item) => {
+                sleep.as_mut().reset(Instant::now() + *read);
+                Poll::Ready(item)
+            }
+            Poll::Pending if sleep.as_mut().poll(cx).is_ready() => {
+                // The final item, then the end of the stream
+                this.read = None;
+                Poll::Ready(this.last.take())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Send an event stream request with the `NO_TIMEOUT` request timeout, timed by the stream
+/// timeout until the response headers only
+pub(crate) async fn stream_response<F>(send: F) -> Result<reqwest::Response, ResponseError>
+where
+    F: Future<Output = reqwest::Result<reqwest::Response>>,
+{
+    let timeout = context::get_instance().stream_timeout();
+    match tokio::time::timeout(timeout, send).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(ResponseError::GatewayTimeout(anyhow!(
+            "The upstream did not answer the stream within {} seconds",
+            timeout.as_secs()
+        ))),
+    }
+}
+
+/// Final event of a proxied ChatGPT stream, ended at a line boundary whatever was sent before
+pub(crate) fn backend_error_event(error: String) -> axum::body::Bytes {
+    let event = serde_json::json!({
+        "message": null,
+        "conversation_id": null,
+        "error": error,
+    });
+    format!("\n\ndata: {event}\n\n").into()
+}
+
+pub(crate) fn exceeded(max: Option<Duration>) -> String {
+    format!(
+        "The stream exceeded the maximum duration of {} seconds",
+        max.unwrap_or_default().as_secs()
+    )
+}
+
+pub(crate) fn idle(read: Option<Duration>) -> String {
+    format!(
+        "The upstream stream sent nothing for {} seconds",
+        read.unwrap_or_default().as_secs()
+    )
+}
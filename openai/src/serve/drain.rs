@@ -0,0 +1,94 @@
+//! In-flight event streams and WebSocket conversations of an instance, the shutdown
+//! stops taking new requests and waits for them before closing the connections
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_core::Stream;
+use tokio::sync::Notify;
+
+use crate::context;
+
+#[derive(Default)]
+pub struct Drain {
+    active: AtomicUsize,
+    drained: Notify,
+    /// Set once the shutdown started, the new requests are answered with 503
+    draining: AtomicBool,
+}
+
+impl Drain {
+    /// Active event streams and conversations
+    pub(super) fn active(&self) -> usize {
+        self.active.load(Ordering::Acquire)
+    }
+
+    /// Whether the shutdown started
+    pub(super) fn draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    /// Stop taking new requests
+    pub(super) fn start(&self) {
+        self.draining.store(true, Ordering::Release);
+    }
+
+    /// Wait until the active event streams are finished, false if the timeout elapsed first
+    pub(super) async fn drain(&self, timeout: Duration) -> bool {
+        tokio::time::timeout(timeout, async {
+            loop {
+                let drained = self.drained.notified();
+                if self.active() == 0 {
+                    break;
+                }
+                drained.await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    /// Count as active until the guard is dropped
+    pub(super) fn track(self: &Arc<Self>) -> DrainGuard {
+        self.active.fetch_add(1, Ordering::AcqRel);
+        DrainGuard(self.clone())
+    }
+}
+
+/// Active stream or conversation of the instance
+pub(super) struct DrainGuard(Arc<Drain>);
+
+impl Drop for DrainGuard {
+    fn drop(&mut self) {
+        if self.0.active.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.drained.notify_waiters();
+        }
+    }
+}
+
+/// Response body stream counted as active until it is dropped
+pub(crate) struct TrackedStream<S> {
+    inner: S,
+    _guard: Option<DrainGuard>,
+}
+
+impl<S> TrackedStream<S> {
+    /// Counted by the current instance only when `tracked`, pass through otherwise
+    pub(crate) fn new(inner: S, tracked: bool) -> Self {
+        let guard = tracked.then(|| context::get_instance().drain().track());
+        Self {
+            inner,
+            _guard: guard,
+        }
+    }
+}
+
+impl<S: Stream + Unpin> Stream for TrackedStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
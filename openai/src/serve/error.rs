@@ -6,6 +6,7 @@ use axum::Json;
 use serde_json::json;
 
 // Make our own error that wraps `anyhow::Error`.
+#[derive(Clone)]
 pub struct ResponseError {
     msg: Option<String>,
     code: StatusCode,
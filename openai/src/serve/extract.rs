@@ -1,4 +1,5 @@
 use std::str::FromStr;
+use std::time::Instant;
 
 use axum::body::Bytes;
 use axum::response::{IntoResponse, Response};
@@ -13,20 +14,89 @@ use http::{HeaderMap, Uri};
 use serde_json::{json, Value};
 
 use crate::arkose::Type;
-use crate::{arkose, context};
-
-use super::convert::header_convert;
+use crate::chatgpt::model::Role;
+use crate::chatgpt::{SENTINEL_CHAT_REQUIREMENTS_TOKEN, SENTINEL_PROOF_TOKEN};
+use crate::{arkose, context, metrics, warn, HEADER_UA};
+
+use super::clearance;
+use super::conversations::CacheAction;
+use super::convert::{header_convert, rebuild_response};
+use super::deadline;
 use super::error::ResponseError;
+use super::middleware::ClientKey;
+#[cfg(feature = "plugin")]
+use super::plugin::ResponseRewrite;
 use super::puid::{get_or_init_puid, reduce_cache_key};
+use super::requirements::{get_or_init_requirements, invalidate_requirements, sentinel_tokens};
+use super::responses::ResponseCache;
+use super::share::ShareWrite;
+use super::stateless::ConversationCleanup;
+use super::stop::StopGeneration;
+use super::strip::EventStrip;
+use super::usage::UsageTracker;
 use super::EMPTY;
 
+const UPSTREAM_LATENCY: &str = "ninja_upstream_latency_milliseconds";
+const UPSTREAM_RESPONSES: &str = "ninja_upstream_responses_total";
+const CHALLENGE_RETRIES: &str = "ninja_cf_retries_total";
+const TOKEN_RETRIES: &str = "ninja_token_retries_total";
+
+/// Upstream latency histogram buckets (milliseconds)
+const LATENCY_BUCKETS: [u64; 10] = [50, 100, 250, 500, 1000, 2500, 5000, 10000, 30000, 60000];
+
 /// Extractor for request parts.
+#[derive(Clone)]
 pub(super) struct RequestExtractor {
     uri: Uri,
     method: http::Method,
     headers: http::HeaderMap,
     jar: CookieJar,
     body: Option<Bytes>,
+    /// Outbound route of the client sending the request
+    route: Option<String>,
+    /// Token rejected by the upstream, the request is sent again with fresh tokens
+    rejected: Option<RejectedToken>,
+    /// Client key the authorization was mapped from
+    client_key: Option<String>,
+}
+
+/// Token the upstream rejected a conversation request for
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum RejectedToken {
+    Arkose,
+    Sentinel,
+}
+
+impl RejectedToken {
+    /// Rejected token of a 4xx payload of the upstream
+    fn of(status: reqwest::StatusCode, body: &[u8]) -> Option<Self> {
+        if !matches!(status.as_u16(), 400 | 403 | 418 | 422) {
+            return None;
+        }
+        let body = String::from_utf8_lossy(body).to_lowercase();
+        if body.contains("arkose") {
+            Some(Self::Arkose)
+        } else if [
+            "sentinel",
+            "chat requirements",
+            "chat-requirements",
+            "proof",
+        ]
+        .iter()
+        .any(|word| body.contains(word))
+        {
+            Some(Self::Sentinel)
+        } else {
+            None
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Arkose => "arkose",
+            Self::Sentinel => "sentinel",
+        }
+    }
 }
 
 #[async_trait]
@@ -40,6 +110,10 @@ where
 
     async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
         let (parts, body) = req.into_parts();
+        let client_key = parts
+            .extensions
+            .get::<ClientKey>()
+            .map(|ClientKey(key)| key.clone());
 
         let body = if parts
             .headers
@@ -71,10 +145,266 @@ where
             jar: CookieJar::from_headers(&parts.headers),
             headers: parts.headers,
             body,
+            route: None,
+            rejected: None,
+            client_key,
         })
     }
 }
 
+impl RequestExtractor {
+    pub(super) fn new(
+        uri: Uri,
+        method: http::Method,
+        headers: HeaderMap,
+        body: Option<Bytes>,
+    ) -> Self {
+        Self {
+            uri,
+            method,
+            jar: CookieJar::from_headers(&headers),
+            headers,
+            body,
+            route: None,
+            rejected: None,
+            client_key: None,
+        }
+    }
+
+    /// Set the outbound route of the client sending the request
+    pub(super) fn set_route(&mut self, route: Option<String>) {
+        self.route = route;
+    }
+
+    /// Conversation request of the client, the arkose and sentinel tokens are attached to it
+    fn is_conversation(&self) -> bool {
+        self.method == http::Method::POST
+            && self.uri.path().trim_end_matches('/') == "/backend-api/conversation"
+    }
+
+    /// Event stream request, a conversation or a platform API request with `stream`
+    fn is_stream(&self) -> bool {
+        self.is_conversation()
+            || (self.method == http::Method::POST
+                && self
+                    .body
+                    .as_ref()
+                    .and_then(|body| serde_json::from_slice::<Value>(body).ok())
+                    .is_some_and(|body| body["stream"] == true))
+    }
+
+    /// The request without the rejected tokens, fresh ones are attached when it is sent again
+    fn without_tokens(mut self, rejected: RejectedToken) -> Self {
+        self.headers.remove(SENTINEL_CHAT_REQUIREMENTS_TOKEN);
+        self.headers.remove(SENTINEL_PROOF_TOKEN);
+        if let Some(body) = self.body.as_ref() {
+            if let Ok(mut json) = serde_json::from_slice::<Value>(body) {
+                if let Some(body) = json.as_object_mut() {
+                    body.remove("arkose_token");
+                }
+                if let Ok(body) = serde_json::to_vec(&json) {
+                    self.body = Some(Bytes::from(body));
+                }
+            }
+        }
+        // The requirements of the account may have changed
+        if let Some(cache_id) = extract_authorization(&self.headers)
+            .ok()
+            .and_then(|token| reduce_cache_key(token).ok())
+        {
+            invalidate_requirements(&cache_id);
+        }
+        self.rejected = Some(rejected);
+        self
+    }
+
+    /// Account key of the request authorization
+    fn account_key(&self) -> Option<String> {
+        extract_authorization(&self.headers).ok().map(|token| {
+            reduce_cache_key(token)
+                .unwrap_or_else(|_| token.trim_start_matches("Bearer ").to_owned())
+        })
+    }
+
+    /// Account and conversation keys used to pick a sticky outgoing address
+    pub(super) fn sticky_keys(&self) -> (Option<String>, Option<String>) {
+        let account = self.account_key();
+
+        // e.g. `/backend-api/conversation/{conversation_id}` or `conversation_id` in body
+        let session = self
+            .uri
+            .path()
+            .split_once("/backend-api/conversation/")
+            .map(|(_, id)| id.trim_end_matches('/'))
+            .filter(|id| id.len() == 36 && !id.contains('/'))
+            .map(ToOwned::to_owned)
+            .or_else(|| {
+                self.body
+                    .as_ref()
+                    .and_then(|body| serde_json::from_slice::<Value>(body).ok())
+                    .and_then(|json| json.get("conversation_id")?.as_str().map(ToOwned::to_owned))
+            });
+
+        (account, session)
+    }
+
+    /// Start tracking the usage of the request if usage accounting is enabled
+    pub(super) fn usage_tracker(&self) -> Option<UsageTracker> {
+        let store = context::get_instance().usage()?.clone();
+        let token = extract_authorization(&self.headers).ok()?;
+        Some(UsageTracker::new(
+            store,
+            token,
+            self.client_key.as_deref(),
+            self.body.as_deref(),
+        ))
+    }
+
+    /// Cleanup of the conversation created or continued by the request in stateless mode
+    pub(super) fn conversation_cleanup(
+        &self,
+        client: &reqwest::Client,
+    ) -> Option<ConversationCleanup> {
+        let is_conversation = self.method == http::Method::POST
+            && self.uri.path().trim_end_matches('/') == "/backend-api/conversation";
+        if !is_conversation || !context::get_instance().stateless() {
+            return None;
+        }
+        let token = extract_authorization(&self.headers).ok()?;
+        Some(ConversationCleanup::new(client.clone(), token))
+    }
+
+    /// Prepend the system prompt of the client key or the account to a new conversation,
+    /// the continued ones got it with their first message
+    pub(super) fn inject_system_prompt(&mut self) {
+        if !self.is_conversation() {
+            return;
+        }
+        let Ok(token) = extract_authorization(&self.headers) else {
+            return;
+        };
+        let ctx = context::get_instance();
+        let Some(prompt) = ctx.system_prompt(self.client_key.as_deref(), token, true) else {
+            return;
+        };
+        let Some(mut value) = self
+            .body
+            .as_ref()
+            .and_then(|body| serde_json::from_slice::<Value>(body).ok())
+        else {
+            return;
+        };
+        if value["conversation_id"].is_string() {
+            return;
+        }
+        let Some(messages) = value["messages"].as_array_mut() else {
+            return;
+        };
+        messages.insert(
+            0,
+            json!({
+                "id": crate::uuid::uuid(),
+                "author": { "role": Role::Critic },
+                "content": { "content_type": "text", "parts": [prompt] },
+            }),
+        );
+        if let Ok(body) = serde_json::to_vec(&value) {
+            self.body = Some(Bytes::from(body));
+        }
+    }
+
+    /// Stop of the generation of the conversation request if the client disconnects
+    pub(super) fn stop_generation(&self, client: &reqwest::Client) -> Option<StopGeneration> {
+        let is_conversation = self.method == http::Method::POST
+            && self.uri.path().trim_end_matches('/') == "/backend-api/conversation";
+        if !is_conversation {
+            return None;
+        }
+        let token = extract_authorization(&self.headers).ok()?;
+        StopGeneration::new(client.clone(), token)
+    }
+
+    /// Events stripped from the event stream of the response
+    pub(super) fn event_strip(&self) -> Option<EventStrip> {
+        EventStrip::of(&self.headers)
+    }
+
+    /// How the conversation list cache handles the request
+    pub(super) fn conversation_cache_action(&self) -> CacheAction {
+        CacheAction::of(&self.method, &self.uri)
+    }
+
+    /// Key of an identical concurrent GET, same path, account and authorization,
+    /// event streams are not shared
+    pub(super) fn coalesce_key(&self) -> Option<String> {
+        let streams = self
+            .headers
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.contains("text/event-stream"));
+        if self.method != http::Method::GET || streams {
+            return None;
+        }
+        let token = extract_authorization(&self.headers).ok()?;
+        let account = self.account_key()?;
+        let path_and_query = self
+            .uri
+            .path_and_query()
+            .map(|v| v.as_str())
+            .unwrap_or(self.uri.path());
+        Some(format!("{account}\n{token}\n{path_and_query}"))
+    }
+
+    /// Key of the request in the model list and account check cache
+    pub(super) fn response_cache_key(&self) -> Option<String> {
+        ResponseCache::key(&self.method, &self.uri)
+    }
+
+    /// Write to the share links of the request
+    pub(super) fn share_write(&self) -> Option<ShareWrite> {
+        ShareWrite::of(&self.method, self.uri.path(), &self.headers)
+    }
+
+    /// Response rewrite of the plugins of the route
+    #[cfg(feature = "plugin")]
+    pub(super) fn response_rewrite(&self) -> Option<ResponseRewrite> {
+        context::get_instance()
+            .plugins()?
+            .response_rewrite(self.method.as_str(), self.uri.path())
+    }
+
+    #[cfg(not(feature = "plugin"))]
+    pub(super) fn response_rewrite(&self) -> Option<super::rewrite::ResponseRewrite> {
+        None
+    }
+
+    /// Request with a client key of the key map
+    pub(super) fn has_client_key(&self) -> bool {
+        self.client_key.is_some()
+    }
+
+    /// Platform API request without its own `sk-` or `sess-` key, served by the API key pool
+    /// if it has a client key
+    pub(super) fn uses_api_key_pool(&self) -> bool {
+        self.uri.path().starts_with("/v1/")
+            && extract_authorization(&self.headers)
+                .map(|token| {
+                    let token = token.trim_start_matches("Bearer ");
+                    !token.starts_with("sk-") && !token.starts_with("sess-")
+                })
+                .unwrap_or_default()
+    }
+
+    /// Replace the authorization with a key of the API key pool
+    pub(super) fn set_api_key(&mut self, key: &str) -> Result<(), ResponseError> {
+        let value = header::HeaderValue::from_str(&format!("Bearer {key}"))
+            .map_err(ResponseError::InternalServerError)?;
+        self.headers.remove("X-Authorization");
+        self.headers.insert(header::AUTHORIZATION, value);
+        Ok(())
+    }
+}
+
 #[async_trait]
 pub(super) trait SendRequestExt {
     async fn send_request(
@@ -100,24 +430,246 @@ impl SendRequestExt for reqwest::Client {
         // Build url
         let url = format!("{origin}{path_and_query}");
 
+        // Answer from the cassette, keyed by the request of the client
+        let ctx = context::get_instance();
+        let cassette = ctx.cassette().map(|cassette| {
+            let exchange = cassette.exchange(&req.method, path_and_query, req.body.as_ref());
+            (cassette, exchange)
+        });
+        if let Some((cassette, exchange)) = cassette.as_ref() {
+            if cassette.replaying() {
+                return cassette.replay(exchange).await;
+            }
+        }
+
+        // Keep the request of the client, a rejected token is retried once with fresh ones
+        let original = (req.rejected.is_none() && req.is_conversation()).then(|| req.clone());
+
         // Handle request
         handle_request(&mut req).await?;
 
         // Handle dashboard request
         handle_dashboard_request(&mut req).await?;
 
-        // Build request
+        // Rewrite the body by the plugins of the route
+        #[cfg(feature = "plugin")]
+        if let (Some(plugins), Some(body)) = (ctx.plugins(), req.body.as_ref()) {
+            if let Some(body) = plugins.rewrite_request(req.method.as_str(), req.uri.path(), body) {
+                req.body = Some(body);
+            }
+        }
+
+        // Moderate the prompt, after the plugins as it is sent upstream
+        if let (Some(moderation), Some(body)) = (ctx.moderation(), req.body.as_ref()) {
+            if let Ok(mut value) = serde_json::from_slice::<Value>(body) {
+                if moderation.check_request(&mut value).await? {
+                    req.body = Some(Bytes::from(serde_json::to_vec(&value)?));
+                }
+            }
+        }
+
+        // Content log of the prompt as it is sent upstream, once for the retried requests
+        if let (Some(log), Some(body)) = (ctx.content_log(), req.body.as_ref()) {
+            if req.rejected.is_none() && req.method == http::Method::POST {
+                if let Ok(value) = serde_json::from_slice::<Value>(body) {
+                    let token = extract_authorization(&req.headers).unwrap_or_default();
+                    log.record_body(req.uri.path(), token, req.client_key.as_deref(), &value);
+                }
+            }
+        }
+
+        // Build request, a clearance of the route is only valid with its User-Agent
+        let class = path_class(req.uri.path());
+        let stream = req.is_stream();
+        let rotated = ctx
+            .user_agents()
+            .for_request(req.account_key().as_deref())
+            .map(ToOwned::to_owned);
+        let route = req.route.take();
+        let sticky = req.sticky_keys();
+        let user_agent = route_user_agent(route.as_deref()).or_else(|| rotated.clone());
         let mut builder =
             self.request(req.method, url)
                 .headers(header_convert(&req.headers, &req.jar, origin)?);
+        if let Some(user_agent) = user_agent {
+            builder = builder.header(header::USER_AGENT, user_agent);
+        }
         if let Some(body) = req.body {
             builder = builder.body(body);
         }
-        // Send request
-        Ok(builder.send().await?)
+        // The body of an event stream is read apart from the client timeout
+        if stream {
+            builder = builder.timeout(deadline::NO_TIMEOUT);
+        }
+        let request = builder.build()?;
+
+        // Shadow the request, the mirror does not affect the response
+        if let Some(mirror) = ctx.mirror() {
+            mirror.mirror(self, &request);
+        }
+
+        // Send request, timed until the upstream response headers
+        let start = Instant::now();
+        let retry = request.try_clone();
+        let result = execute(self, origin, request, stream).await;
+        let status = match &result {
+            Ok(resp) => resp.status().as_u16().to_string(),
+            Err(_) => "error".to_owned(),
+        };
+        let labels = [("path", class), ("status", status.as_str())];
+        metrics::observe(
+            UPSTREAM_LATENCY,
+            "Upstream response latency by path class",
+            &LATENCY_BUCKETS,
+            &labels[..1],
+            start.elapsed().as_millis() as u64,
+        );
+        metrics::inc(
+            UPSTREAM_RESPONSES,
+            "Upstream responses by path class and status code",
+            &labels,
+            1,
+        );
+
+        // A Cloudflare challenge is retried once through the next route
+        let mut resp = result?;
+        if clearance::is_challenge(&resp) {
+            clearance::challenged(ctx.clearance(), route.as_deref());
+            if let Some(retry) = retry {
+                resp = retry_challenged(origin, retry, sticky, rotated, stream).await?;
+            }
+        }
+
+        // An arkose or sentinel token rejected by the upstream is obtained again once
+        if let Some(original) = original.filter(|_| resp.status().is_client_error()) {
+            let status = resp.status();
+            let headers = resp.headers().clone();
+            let body = resp.bytes().await?;
+            match RejectedToken::of(status, &body) {
+                Some(rejected) => {
+                    metrics::inc(
+                        TOKEN_RETRIES,
+                        "Conversation requests retried with fresh tokens by the rejected token",
+                        &[("token", rejected.as_str())],
+                        1,
+                    );
+                    warn!(
+                        "Upstream rejected the {} token, retrying",
+                        rejected.as_str()
+                    );
+                    return self
+                        .send_request(origin, original.without_tokens(rejected))
+                        .await;
+                }
+                None => resp = rebuild_response(status, &headers, body)?,
+            }
+        }
+        match cassette {
+            Some((cassette, exchange)) => cassette.record(exchange, resp).await,
+            None => Ok(resp),
+        }
     }
 }
 
+/// Send the request through the upstream endpoints of the origin
+/// Send the request to the upstream endpoints, an event stream request is timed by the
+/// stream timeout until the response headers
+async fn execute(
+    client: &reqwest::Client,
+    origin: &str,
+    request: reqwest::Request,
+    stream: bool,
+) -> Result<reqwest::Response, ResponseError> {
+    let send = async {
+        match context::get_instance().upstream(origin) {
+            Some(upstream) => upstream.execute(client, request).await,
+            None => client.execute(request).await,
+        }
+    };
+    match stream {
+        true => deadline::stream_response(send).await,
+        false => Ok(send.await?),
+    }
+}
+
+/// User-Agent of the clearance of the route, the clearance is only valid with it
+fn route_user_agent(route: Option<&str>) -> Option<String> {
+    context::get_instance()
+        .clearance()
+        .zip(route)
+        .and_then(|(clearance, route)| clearance.user_agent(route))
+}
+
+/// Send a challenged request again through the next route of the sticky client, the
+/// challenged clearance was dropped and is harvested again. A pinned account or a sticky
+/// ipv6 address of the account or conversation keeps its route, the others move on
+async fn retry_challenged(
+    origin: &str,
+    mut request: reqwest::Request,
+    (account, session): (Option<String>, Option<String>),
+    rotated: Option<String>,
+    stream: bool,
+) -> Result<reqwest::Response, ResponseError> {
+    let ctx = context::get_instance();
+    let (client, route) = ctx.sticky_client_route(account.as_deref(), session.as_deref());
+    match route_user_agent(route.as_deref()).or(rotated) {
+        Some(user_agent) => {
+            let value = header::HeaderValue::from_str(&user_agent)
+                .map_err(ResponseError::InternalServerError)?;
+            request.headers_mut().insert(header::USER_AGENT, value);
+        }
+        None => {
+            request.headers_mut().remove(header::USER_AGENT);
+        }
+    }
+
+    let resp = execute(&client, origin, request, stream).await?;
+    let challenged = clearance::is_challenge(&resp);
+    if challenged {
+        clearance::challenged(ctx.clearance(), route.as_deref());
+    }
+    metrics::inc(
+        CHALLENGE_RETRIES,
+        "Cloudflare challenged requests retried through the next route",
+        &[("result", if challenged { "challenged" } else { "passed" })],
+        1,
+    );
+    Ok(resp)
+}
+
+/// Path classes of the metrics, the label values stay bounded whatever the clients request
+const PATH_CLASSES: [&str; 16] = [
+    "/backend-api/conversation",
+    "/backend-api/conversations",
+    "/backend-api/models",
+    "/backend-api/accounts",
+    "/backend-api/me",
+    "/backend-api/files",
+    "/backend-api/sentinel",
+    "/backend-api/share",
+    "/backend-api/gizmos",
+    "/backend-api/settings",
+    "/public-api/conversation_limit",
+    "/v1/chat",
+    "/v1/completions",
+    "/v1/embeddings",
+    "/v1/models",
+    "/dashboard/billing",
+];
+
+/// Path class of the metrics, the allowed class the path starts with or `other`,
+/// e.g. `/backend-api/conversation/<id>` is `/backend-api/conversation`
+fn path_class(path: &str) -> &'static str {
+    PATH_CLASSES
+        .iter()
+        .find(|class| {
+            path.strip_prefix(**class)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+        .copied()
+        .unwrap_or("other")
+}
+
 /// Check if the request has puid
 pub(super) fn has_puid(headers: &HeaderMap) -> Result<bool, ResponseError> {
     if let Some(hv) = headers.get(header::COOKIE) {
@@ -164,23 +716,34 @@ async fn handle_request(req: &mut RequestExtractor) -> Result<(), ResponseError>
         .ok_or(ResponseError::BadRequest(anyhow::anyhow!("Body is empty")))?;
 
     // If model is not exist, then return error
-    let model = body
+    let requested = body
         .get("model")
         .and_then(|m| m.as_str())
         .ok_or(ResponseError::BadRequest(anyhow::anyhow!(
             "Model is not exist in body!"
         )))?;
 
-    // If puid is exist, then return
-    if !has_puid(&req.headers)? {
-        // extract token from Authorization header
-        let token = extract_authorization(&req.headers)?;
+    // Rewrite the model alias to the backend slug
+    let model = match context::get_instance().model_alias().get(requested) {
+        Some(slug) => slug.to_owned(),
+        None => requested.to_owned(),
+    };
+    let mut modified = model != requested;
+    if modified {
+        body.insert("model".to_owned(), json!(model));
+    }
+    let model = model.as_str();
+
+    // extract token from Authorization header
+    let token = extract_authorization(&req.headers)?.to_owned();
 
-        // Exstract the token from the Authorization header
-        let cache_id = reduce_cache_key(token)?;
+    // Exstract the token from the Authorization header
+    let cache_id = reduce_cache_key(&token)?;
 
+    // If puid is exist, then return
+    if !has_puid(&req.headers)? {
         // Get or init puid
-        let puid = get_or_init_puid(token, model, cache_id).await?;
+        let puid = get_or_init_puid(&token, model, cache_id.clone()).await?;
 
         if let Some(puid) = puid {
             req.headers.insert(
@@ -194,8 +757,36 @@ async fn handle_request(req: &mut RequestExtractor) -> Result<(), ResponseError>
     // Parse model
     let model = arkose::GPTModel::from_str(model).map_err(ResponseError::BadRequest)?;
 
-    // If model is gpt3 or gpt4, then add arkose_token
-    if (context::get_instance().arkose_gpt3_experiment() && model.is_gpt3()) || model.is_gpt4() {
+    // Attach sentinel tokens if the client did not provide them
+    if req.uri.path().ends_with("/backend-api/conversation")
+        && !req.headers.contains_key(SENTINEL_CHAT_REQUIREMENTS_TOKEN)
+    {
+        let user_agent = req
+            .headers
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or(HEADER_UA);
+        match sentinel_tokens(&token, &cache_id, user_agent).await {
+            Ok(tokens) => {
+                req.headers.insert(
+                    SENTINEL_CHAT_REQUIREMENTS_TOKEN,
+                    header::HeaderValue::from_str(&tokens.requirements)
+                        .map_err(ResponseError::BadRequest)?,
+                );
+                if let Some(proof) = tokens.proof {
+                    req.headers.insert(
+                        SENTINEL_PROOF_TOKEN,
+                        header::HeaderValue::from_str(&proof).map_err(ResponseError::BadRequest)?,
+                    );
+                }
+            }
+            Err(err) => warn!("Get sentinel tokens error: {err}"),
+        }
+    }
+
+    // If the account requires arkose for the model, then add arkose_token
+    let requirements = get_or_init_requirements(&token, &model, &cache_id).await;
+    if requirements.arkose || req.rejected == Some(RejectedToken::Arkose) {
         let condition = match body.get("arkose_token") {
             Some(s) => {
                 let s = s.as_str().unwrap_or(EMPTY);
@@ -207,14 +798,16 @@ async fn handle_request(req: &mut RequestExtractor) -> Result<(), ResponseError>
         if condition {
             let arkose_token = arkose::ArkoseToken::new_from_context(model.into()).await?;
             body.insert("arkose_token".to_owned(), json!(arkose_token));
-            // Updaye Modify bytes
-            req.body = Some(Bytes::from(
-                serde_json::to_vec(&json).map_err(ResponseError::BadRequest)?,
-            ));
+            modified = true;
         }
     }
 
-    drop(json);
+    // Updaye Modify bytes
+    if modified {
+        req.body = Some(Bytes::from(
+            serde_json::to_vec(&json).map_err(ResponseError::BadRequest)?,
+        ));
+    }
 
     Ok(())
 }
@@ -254,3 +847,23 @@ async fn handle_dashboard_request(req: &mut RequestExtractor) -> Result<(), Resp
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_class() {
+        assert_eq!(
+            path_class("/backend-api/conversation/6f0c1e2a-8d7b"),
+            "/backend-api/conversation"
+        );
+        assert_eq!(
+            path_class("/backend-api/conversations"),
+            "/backend-api/conversations"
+        );
+        assert_eq!(path_class("/v1/chat/completions"), "/v1/chat");
+        assert_eq!(path_class("/backend-api/conversationx"), "other");
+        assert_eq!(path_class("/random/a1b2c3d4e5f6a7b8c9"), "other");
+    }
+}
@@ -0,0 +1,214 @@
+use std::net::SocketAddr;
+
+use base64::Engine;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{debug, info, warn};
+
+/// Maximum size of the proxy request head
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+const PROXY_AUTH_REQUIRED: &[u8] = b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+    Proxy-Authenticate: Basic realm=\"ninja\"\r\n\
+    Content-Length: 0\r\n\r\n";
+
+/// Plain HTTP CONNECT forward proxy, only tunnels to the allowed hosts.
+///
+/// Native apps can point their HTTP proxy at it without rewriting urls. With `auth`
+/// (`user:password` pairs) the clients must send a matching `Proxy-Authorization`.
+pub(super) async fn forward_proxy(
+    bind: SocketAddr,
+    hosts: Vec<String>,
+    auth: Vec<String>,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(bind).await?;
+    info!("Forward proxy listen on: http://{bind}");
+    info!("Forward proxy allowed hosts: {}", hosts.join(", "));
+
+    crate::context::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    let hosts = hosts.clone();
+                    let auth = auth.clone();
+                    crate::context::spawn(async move {
+                        if let Err(err) = handle(stream, &hosts, &auth).await {
+                            debug!("Forward proxy connection {addr} error: {err}");
+                        }
+                    });
+                }
+                Err(err) => warn!("Forward proxy accept error: {err}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn handle(mut client: TcpStream, hosts: &[String], auth: &[String]) -> anyhow::Result<()> {
+    // Read the request head
+    let mut buf = Vec::with_capacity(1024);
+    let head_len = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if buf.len() > MAX_HEAD_SIZE {
+            anyhow::bail!("request head too large")
+        }
+        let mut chunk = [0u8; 1024];
+        let n = client.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("client closed")
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&buf[..head_len]).into_owned();
+    let mut parts = head.lines().next().unwrap_or_default().split_whitespace();
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) => (method, target, version),
+        _ => anyhow::bail!("invalid request line"),
+    };
+
+    if !auth.is_empty() && !authorized(&head, auth) {
+        client.write_all(PROXY_AUTH_REQUIRED).await?;
+        return Ok(());
+    }
+
+    // `CONNECT host:port` tunnels as is, `GET http://host/path` is rewritten to origin-form
+    let (host, port, head) = if method.eq_ignore_ascii_case("CONNECT") {
+        let (host, port) = target
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+            .ok_or_else(|| anyhow::anyhow!("invalid CONNECT target: {target}"))?;
+        (host.to_owned(), port, None)
+    } else {
+        let url = url::Url::parse(target)?;
+        if url.scheme() != "http" {
+            anyhow::bail!("unsupported proxy target: {target}")
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow::anyhow!("missing host: {target}"))?
+            .to_owned();
+        let path = match url.query() {
+            Some(query) => format!("{}?{query}", url.path()),
+            None => url.path().to_owned(),
+        };
+        // The proxy credentials stay with the proxy
+        let rest = head
+            .split_inclusive("\r\n")
+            .skip(1)
+            .filter(|line| !is_proxy_authorization(line))
+            .collect::<String>();
+        let head = format!("{method} {path} {version}\r\n{rest}");
+        (host, url.port().unwrap_or(80), Some(head))
+    };
+
+    if !allowed(&host, hosts) {
+        warn!("Forward proxy rejected host: {host}");
+        client
+            .write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n")
+            .await?;
+        return Ok(());
+    }
+
+    let mut upstream = match TcpStream::connect((host.as_str(), port)).await {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            client
+                .write_all(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n")
+                .await?;
+            return Err(err.into());
+        }
+    };
+
+    match head {
+        Some(head) => upstream.write_all(head.as_bytes()).await?,
+        None => {
+            client
+                .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                .await?
+        }
+    }
+
+    // Bytes read past the head belong to the tunnel
+    let leftover = &buf[head_len..];
+    if !leftover.is_empty() {
+        upstream.write_all(leftover).await?;
+    }
+
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+fn is_proxy_authorization(line: &str) -> bool {
+    line.split_once(':')
+        .is_some_and(|(name, _)| name.trim().eq_ignore_ascii_case("proxy-authorization"))
+}
+
+/// Whether the request head carries the Basic credentials of one of the `user:password` pairs
+fn authorized(head: &str, auth: &[String]) -> bool {
+    head.lines()
+        .skip(1)
+        .filter(|line| is_proxy_authorization(line))
+        .filter_map(|line| line.split_once(':').map(|(_, value)| value.trim()))
+        .filter_map(|value| value.strip_prefix("Basic "))
+        .filter_map(|encoded| {
+            base64::engine::general_purpose::STANDARD
+                .decode(encoded.trim())
+                .ok()
+        })
+        .any(|credential| auth.iter().any(|pair| pair.as_bytes() == credential))
+}
+
+/// Match the host against the allow list, `*.example.com` matches any subdomain
+fn allowed(host: &str, hosts: &[String]) -> bool {
+    let host = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_lowercase();
+    hosts.iter().any(|pattern| {
+        let pattern = pattern.to_lowercase();
+        match pattern.strip_prefix("*.") {
+            Some(domain) => host
+                .strip_suffix(domain)
+                .is_some_and(|prefix| prefix.ends_with('.')),
+            None => host == pattern,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed() {
+        let hosts = vec!["chat.openai.com".to_owned(), "*.oaistatic.com".to_owned()];
+        assert!(allowed("chat.openai.com", &hosts));
+        assert!(allowed("Chat.OpenAI.com", &hosts));
+        assert!(allowed("cdn.oaistatic.com", &hosts));
+        assert!(allowed("a.b.oaistatic.com", &hosts));
+        assert!(!allowed("oaistatic.com", &hosts));
+        assert!(!allowed("evil-oaistatic.com", &hosts));
+        assert!(!allowed("openai.com", &hosts));
+        assert!(!allowed("chat.openai.com.evil.com", &hosts));
+        assert!(allowed("[::1]", &["::1".to_owned()]));
+    }
+
+    #[test]
+    fn test_authorized() {
+        let auth = vec!["user:pass".to_owned()];
+        let head = "CONNECT chat.openai.com:443 HTTP/1.1\r\n\
+            proxy-authorization: Basic dXNlcjpwYXNz\r\n\r\n";
+        assert!(authorized(head, &auth));
+        assert!(!authorized(head, &["user:other".to_owned()]));
+        assert!(!authorized(
+            "CONNECT chat.openai.com:443 HTTP/1.1\r\n\r\n",
+            &auth
+        ));
+    }
+}
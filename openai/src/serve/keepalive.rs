@@ -0,0 +1,67 @@
+//! SSE keepalive, comment lines sent while the upstream is quiet
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use axum::body::Bytes;
+use futures_core::Stream;
+use tokio::time::{Instant, Sleep};
+
+/// Comment line, ignored by SSE clients
+const PING: &[u8] = b": ping\n";
+
+/// Response body stream sending a ping after `interval` without upstream data
+pub(crate) struct KeepAliveStream<S> {
+    inner: S,
+    keepalive: Option<(Duration, Pin<Box<Sleep>>)>,
+    /// Comments are only valid at the start of a line
+    line_start: bool,
+}
+
+impl<S> KeepAliveStream<S> {
+    /// Pass through without interval
+    pub(crate) fn new(inner: S, interval: Option<Duration>) -> Self {
+        Self {
+            inner,
+            keepalive: interval.map(|interval| (interval, Box::pin(tokio::time::sleep(interval)))),
+            line_start: true,
+        }
+    }
+}
+
+impl<S, E> Stream for KeepAliveStream<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_next(cx);
+        let Some((interval, sleep)) = this.keepalive.as_mut() else {
+            return poll;
+        };
+
+        match poll {
+            Poll::Ready(Some(Ok(chunk))) => {
+                if !chunk.is_empty() {
+                    this.line_start = chunk.ends_with(b"\n");
+                }
+                sleep.as_mut().reset(Instant::now() + *interval);
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Poll::Ready(item) => Poll::Ready(item),
+            Poll::Pending => {
+                while sleep.as_mut().poll(cx).is_ready() {
+                    sleep.as_mut().reset(Instant::now() + *interval);
+                    // Wait for the next interval in the middle of a line
+                    if this.line_start {
+                        return Poll::Ready(Some(Ok(Bytes::from_static(PING))));
+                    }
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
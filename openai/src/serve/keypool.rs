@@ -0,0 +1,288 @@
+//! Platform API key pool, aggregates the quota of several `sk-` keys for the /v1 proxy.
+//! Only the requests with a client key of the key map are served by the pool
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use axum::http::{HeaderMap, StatusCode};
+use serde::Serialize;
+use serde_json::Value;
+
+use super::error::ResponseError;
+use super::extract::{RequestExtractor, SendRequestExt};
+use crate::context::mask_key as mask;
+use crate::{warn, webhook, URL_PLATFORM_API};
+
+/// Keys tried for a request, the next key is used when one is rate limited or removed
+const MAX_ATTEMPTS: usize = 3;
+
+/// Cooldown of a rate limited key without a reset header
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(20);
+
+#[derive(Default)]
+struct KeyState {
+    /// Remaining requests and tokens reported by the last response
+    remaining_requests: Option<u64>,
+    remaining_tokens: Option<u64>,
+    /// Not used until then after a 429
+    cooldown_until: Option<Instant>,
+    /// Removal reason, removed keys are kept for the stats
+    removed: Option<String>,
+}
+
+struct PooledKey {
+    key: String,
+    requests: AtomicU64,
+    rate_limited: AtomicU64,
+    state: RwLock<KeyState>,
+}
+
+impl PooledKey {
+    fn is_removed(&self) -> bool {
+        self.state
+            .read()
+            .map(|state| state.removed.is_some())
+            .unwrap_or_default()
+    }
+
+    fn cooldown_until(&self) -> Option<Instant> {
+        self.state
+            .read()
+            .ok()?
+            .cooldown_until
+            .filter(|until| *until > Instant::now())
+    }
+}
+
+#[derive(Serialize)]
+pub struct KeyStat {
+    /// Masked key, e.g. `sk-pro...abcd`
+    key: String,
+    requests: u64,
+    rate_limited: u64,
+    remaining_requests: Option<u64>,
+    remaining_tokens: Option<u64>,
+    /// Seconds left of the cooldown
+    cooldown: Option<u64>,
+    removed: Option<String>,
+}
+
+pub struct ApiKeyPool {
+    keys: Vec<Arc<PooledKey>>,
+    next: AtomicUsize,
+}
+
+impl ApiKeyPool {
+    pub fn new(keys: Vec<String>) -> Self {
+        Self {
+            keys: keys
+                .into_iter()
+                .map(|key| {
+                    Arc::new(PooledKey {
+                        key,
+                        requests: AtomicU64::new(0),
+                        rate_limited: AtomicU64::new(0),
+                        state: RwLock::new(KeyState::default()),
+                    })
+                })
+                .collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Round-robin over the keys not removed, skipping the ones cooling down
+    /// unless all of them are
+    fn next(&self) -> Option<Arc<PooledKey>> {
+        let len = self.keys.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut cooling: Option<(Instant, &Arc<PooledKey>)> = None;
+        for i in 0..len {
+            let key = &self.keys[(start + i) % len];
+            if key.is_removed() {
+                continue;
+            }
+            match key.cooldown_until() {
+                None => return Some(key.clone()),
+                Some(until) if cooling.map_or(true, |(first, _)| until < first) => {
+                    cooling = Some((until, key))
+                }
+                Some(_) => {}
+            }
+        }
+        cooling.map(|(_, key)| key.clone())
+    }
+
+    /// Track the rate limit headers of the response
+    fn observe(&self, key: &PooledKey, status: StatusCode, headers: &HeaderMap) {
+        key.requests.fetch_add(1, Ordering::Relaxed);
+        let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+        if let Ok(mut state) = key.state.write() {
+            if let Some(remaining) = header("x-ratelimit-remaining-requests") {
+                state.remaining_requests = remaining.parse().ok();
+            }
+            if let Some(remaining) = header("x-ratelimit-remaining-tokens") {
+                state.remaining_tokens = remaining.parse().ok();
+            }
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                key.rate_limited.fetch_add(1, Ordering::Relaxed);
+                let cooldown = ["x-ratelimit-reset-requests", "x-ratelimit-reset-tokens"]
+                    .into_iter()
+                    .filter_map(|name| header(name).and_then(parse_reset))
+                    .max()
+                    .unwrap_or(DEFAULT_COOLDOWN);
+                state.cooldown_until = Some(Instant::now() + cooldown);
+            }
+        }
+    }
+
+    fn remove(&self, key: &PooledKey, reason: &str) {
+        if let Ok(mut state) = key.state.write() {
+            if state.removed.is_some() {
+                return;
+            }
+            state.removed = Some(reason.to_owned());
+        }
+        let masked = mask(&key.key);
+        warn!("API key {masked} removed from the pool: {reason}");
+        webhook::fire(
+            webhook::Event::ApiKeyRemoved,
+            serde_json::json!({ "key": masked, "reason": reason }),
+        );
+    }
+
+    /// Keys left in the pool
+    pub fn active(&self) -> usize {
+        self.keys.iter().filter(|key| !key.is_removed()).count()
+    }
+
+    pub fn stats(&self) -> Vec<KeyStat> {
+        self.keys
+            .iter()
+            .map(|key| {
+                let state = key.state.read().ok();
+                let state = state.as_deref();
+                KeyStat {
+                    key: mask(&key.key),
+                    requests: key.requests.load(Ordering::Relaxed),
+                    rate_limited: key.rate_limited.load(Ordering::Relaxed),
+                    remaining_requests: state.and_then(|s| s.remaining_requests),
+                    remaining_tokens: state.and_then(|s| s.remaining_tokens),
+                    cooldown: key
+                        .cooldown_until()
+                        .map(|until| (until - Instant::now()).as_secs()),
+                    removed: state.and_then(|s| s.removed.clone()),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Reset duration of the rate limit headers, e.g. `20ms`, `1s`, `6m0s` or `1h2m3.5s`
+fn parse_reset(value: &str) -> Option<Duration> {
+    let mut total = 0f64;
+    let mut rest = value.trim();
+    while !rest.is_empty() {
+        let split = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let (number, tail) = rest.split_at(split);
+        let number = number.parse::<f64>().ok()?;
+        let unit_len = tail
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_len);
+        total += number
+            * match unit {
+                "ms" => 0.001,
+                "s" => 1.0,
+                "m" => 60.0,
+                "h" => 3600.0,
+                _ => return None,
+            };
+        rest = tail;
+    }
+    Some(Duration::from_secs_f64(total))
+}
+
+/// Whether the error body of the response is `insufficient_quota`
+fn is_insufficient_quota(body: &[u8]) -> bool {
+    serde_json::from_slice::<Value>(body)
+        .map(|value| value["error"]["code"] == "insufficient_quota")
+        .unwrap_or_default()
+}
+
+/// Send the request with the keys of the pool, a key is removed on 401 or `insufficient_quota`
+/// and the request is retried with the next key, as it is when a key is rate limited
+pub(super) async fn send_request(
+    pool: &ApiKeyPool,
+    client: &reqwest::Client,
+    req: RequestExtractor,
+) -> Result<reqwest::Response, ResponseError> {
+    let mut tried: Vec<Arc<PooledKey>> = Vec::with_capacity(MAX_ATTEMPTS);
+    let mut last = None;
+    while tried.len() < MAX_ATTEMPTS {
+        let Some(key) = pool
+            .next()
+            .filter(|key| !tried.iter().any(|t| Arc::ptr_eq(t, key)))
+        else {
+            break;
+        };
+        let mut pooled = req.clone();
+        pooled.set_api_key(&key.key)?;
+        let resp = client.send_request(URL_PLATFORM_API, pooled).await?;
+        let status = resp.status();
+        pool.observe(&key, status, resp.headers());
+        if status != StatusCode::UNAUTHORIZED && status != StatusCode::TOO_MANY_REQUESTS {
+            return Ok(resp);
+        }
+
+        // Error bodies are small, buffered to tell a spent quota from a rate limit
+        let mut builder = axum::http::Response::builder().status(status);
+        if let Some(headers) = builder.headers_mut() {
+            headers.extend(resp.headers().clone());
+        }
+        let body = resp.bytes().await?;
+        if status == StatusCode::UNAUTHORIZED {
+            pool.remove(&key, "invalid api key");
+        } else if is_insufficient_quota(&body) {
+            pool.remove(&key, "insufficient quota");
+        }
+        last = Some(builder.body(body).map_err(ResponseError::BadGateway)?);
+        tried.push(key);
+    }
+
+    // The error of the last key tried
+    last.map(Into::into).ok_or_else(|| {
+        ResponseError::ServiceUnavailable(anyhow::anyhow!("No API key left in the pool"))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_reset() {
+        assert_eq!(parse_reset("20ms"), Some(Duration::from_millis(20)));
+        assert_eq!(parse_reset("1s"), Some(Duration::from_secs(1)));
+        assert_eq!(parse_reset("6m0s"), Some(Duration::from_secs(360)));
+        assert_eq!(
+            parse_reset("1h2m3.5s"),
+            Some(Duration::from_secs_f64(3723.5))
+        );
+        assert_eq!(parse_reset(""), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_parse_reset_invalid() {
+        assert_eq!(parse_reset("5x"), None);
+        assert_eq!(parse_reset("s"), None);
+        assert_eq!(parse_reset("1.2.3s"), None);
+    }
+
+    #[test]
+    fn test_mask() {
+        assert_eq!(mask("sk-proj-0123456789abcd"), "sk-pro...abcd");
+        assert_eq!(mask("short"), "...");
+    }
+}
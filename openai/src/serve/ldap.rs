@@ -0,0 +1,246 @@
+//! LDAP / Active Directory login of the WebUI and `/auth/ldap/token`, the user binds with the
+//! directory password and gets the token of an account managed through the admin API,
+//! picked among the pooled accounts of its directory groups
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use ldap3::{dn_escape, ldap_escape, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+
+use super::error::ResponseError;
+use super::pooled::{pooled_account, pooled_token};
+use crate::context;
+use crate::token::model::AuthenticateToken;
+
+/// Seconds the directory has to answer each operation
+const LDAP_TIMEOUT: u64 = 10;
+
+/// Failed logins of a login name or a client ip before it is refused for the window
+const MAX_FAILURES: u32 = 5;
+
+/// Seconds the failed logins are counted for
+const FAILURE_WINDOW: u64 = 60 * 15;
+
+/// Failed logins by login name and by client ip, the directory is not asked for either
+/// once it failed [`MAX_FAILURES`] times within the window
+#[derive(Default)]
+pub struct LoginFailures {
+    failures: RwLock<HashMap<String, (Instant, u32)>>,
+}
+
+impl LoginFailures {
+    fn keys(username: &str, addr: &IpAddr) -> [String; 2] {
+        [
+            format!("user:{}", username.to_lowercase()),
+            format!("ip:{addr}"),
+        ]
+    }
+
+    fn blocked(&self, username: &str, addr: &IpAddr) -> bool {
+        let window = Duration::from_secs(FAILURE_WINDOW);
+        let Ok(failures) = self.failures.read() else {
+            return false;
+        };
+        Self::keys(username, addr).iter().any(|key| {
+            failures
+                .get(key)
+                .is_some_and(|(since, count)| *count >= MAX_FAILURES && since.elapsed() < window)
+        })
+    }
+
+    fn failed(&self, username: &str, addr: &IpAddr) {
+        let window = Duration::from_secs(FAILURE_WINDOW);
+        if let Ok(mut failures) = self.failures.write() {
+            for key in Self::keys(username, addr) {
+                let failure = failures.entry(key).or_insert((Instant::now(), 0));
+                if failure.0.elapsed() >= window {
+                    *failure = (Instant::now(), 0);
+                }
+                failure.1 += 1;
+            }
+        }
+    }
+
+    fn succeeded(&self, username: &str) {
+        if let Ok(mut failures) = self.failures.write() {
+            failures.remove(&format!("user:{}", username.to_lowercase()));
+        }
+    }
+
+    /// Drop the failures past the window, returns how many were dropped
+    pub(crate) fn sweep(&self) -> usize {
+        let window = Duration::from_secs(FAILURE_WINDOW);
+        let Ok(mut failures) = self.failures.write() else {
+            return 0;
+        };
+        let len = failures.len();
+        failures.retain(|_, (since, _)| since.elapsed() < window);
+        len - failures.len()
+    }
+}
+
+/// User authenticated by the directory
+struct LdapUser {
+    /// Lowercased email
+    email: String,
+    /// Lowercased DNs of the groups the user is a member of
+    groups: Vec<String>,
+}
+
+/// Bind as the user, then read the email and the groups of its entry
+async fn authenticate(username: &str, password: &str) -> anyhow::Result<LdapUser> {
+    let ctx = context::get_instance();
+    let ldap = ctx
+        .ldap()
+        .ok_or_else(|| anyhow!("LDAP is not configured"))?;
+    // An empty password is an unauthenticated bind, which many servers accept
+    if username.is_empty() || password.is_empty() {
+        anyhow::bail!("username and password are required")
+    }
+
+    let timeout = Duration::from_secs(LDAP_TIMEOUT);
+    let settings = LdapConnSettings::new()
+        .set_conn_timeout(timeout)
+        .set_starttls(ldap.starttls);
+    let (conn, mut client) = LdapConnAsync::with_settings(settings, &ldap.url).await?;
+    ldap3::drive!(conn);
+
+    let dn = ldap.user_dn.replace("{username}", &dn_escape(username));
+    client
+        .with_timeout(timeout)
+        .simple_bind(&dn, password)
+        .await?
+        .success()
+        .map_err(|_| anyhow!("invalid username or password"))?;
+
+    let user = match ldap.base_dn.as_deref() {
+        Some(base_dn) => {
+            let filter = ldap
+                .user_filter
+                .replace("{username}", &ldap_escape(username));
+            let (entries, _) = client
+                .with_timeout(timeout)
+                .search(
+                    base_dn,
+                    Scope::Subtree,
+                    &filter,
+                    vec!["mail", "userPrincipalName", "memberOf"],
+                )
+                .await?
+                .success()?;
+            let entry = entries
+                .into_iter()
+                .next()
+                .map(SearchEntry::construct)
+                .ok_or_else(|| anyhow!("no directory entry for {username}"))?;
+            let attr = |name: &str| entry.attrs.get(name).and_then(|values| values.first());
+            let email = attr("mail")
+                .or_else(|| attr("userPrincipalName"))
+                .ok_or_else(|| anyhow!("no email in the directory entry of {username}"))?;
+            LdapUser {
+                email: email.to_lowercase(),
+                groups: entry
+                    .attrs
+                    .get("memberOf")
+                    .into_iter()
+                    .flatten()
+                    .map(|group| group.to_lowercase())
+                    .collect(),
+            }
+        }
+        None if username.contains('@') => LdapUser {
+            email: username.to_lowercase(),
+            groups: Vec::new(),
+        },
+        None => anyhow::bail!("no email for {username}, set the LDAP base DN"),
+    };
+    let _ = client.unbind().await;
+    Ok(user)
+}
+
+/// Pooled account of the user, among the accounts of its mapped groups when groups are mapped
+fn account(user: &LdapUser) -> anyhow::Result<String> {
+    let ctx = context::get_instance();
+    let groups = &ctx
+        .ldap()
+        .ok_or_else(|| anyhow!("LDAP is not configured"))?
+        .groups;
+    let account = match groups.is_empty() {
+        true => pooled_account(&user.email, None),
+        false => {
+            let among = user
+                .groups
+                .iter()
+                .filter_map(|group| groups.get(group))
+                .flatten()
+                .cloned()
+                .collect::<Vec<_>>();
+            if among.is_empty() {
+                anyhow::bail!("{} is in no group with pooled accounts", user.email)
+            }
+            pooled_account(&user.email, Some(&among))
+        }
+    };
+    account.ok_or_else(|| anyhow!("no pooled account available"))
+}
+
+/// Refuse the login name or the client ip that failed too many times recently
+pub(crate) fn throttle(username: &str, addr: &IpAddr) -> Result<(), ResponseError> {
+    let ctx = context::get_instance();
+    match ctx
+        .ldap()
+        .is_some_and(|ldap| ldap.failures.blocked(username, addr))
+    {
+        true => Err(ResponseError::TooManyRequests(anyhow!(
+            "Too many failed logins, try again later"
+        ))),
+        false => Ok(()),
+    }
+}
+
+/// Log in the directory user, returns its email and the token of its pooled account
+pub(crate) async fn login(
+    username: &str,
+    password: &str,
+    addr: &IpAddr,
+) -> anyhow::Result<(String, AuthenticateToken)> {
+    let ctx = context::get_instance();
+    let failures = &ctx
+        .ldap()
+        .ok_or_else(|| anyhow!("LDAP is not configured"))?
+        .failures;
+    let user = authenticate(username, password).await.map_err(|err| {
+        failures.failed(username, addr);
+        err
+    })?;
+    failures.succeeded(username);
+    if !context::get_instance().login_allowed(&user.email) {
+        anyhow::bail!("{} is not allowed to log in", user.email)
+    }
+    let token = pooled_token(&account(&user)?).await?;
+    crate::info!("LDAP user {} signed in to {}", user.email, token.email());
+    Ok((user.email, token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_login_failures() {
+        let failures = LoginFailures::default();
+        let addr: IpAddr = "10.0.0.1".parse().unwrap();
+        for _ in 0..MAX_FAILURES {
+            assert!(!failures.blocked("Alice", &addr));
+            failures.failed("Alice", &addr);
+        }
+        assert!(failures.blocked("alice", &addr));
+        // The ip is refused for another name, the name from another ip
+        assert!(failures.blocked("bob", &addr));
+        assert!(failures.blocked("alice", &"10.0.0.2".parse().unwrap()));
+        failures.succeeded("alice");
+        assert!(!failures.blocked("alice", &"10.0.0.2".parse().unwrap()));
+    }
+}
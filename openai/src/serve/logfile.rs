@@ -0,0 +1,274 @@
+//! Log file output with time and size based rotation
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::now_duration;
+
+/// When the log file is rotated by time (UTC)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogRotation {
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+impl FromStr for LogRotation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hourly" => Ok(Self::Hourly),
+            "daily" => Ok(Self::Daily),
+            "never" => Ok(Self::Never),
+            _ => anyhow::bail!("Only support `hourly`, `daily` and `never`"),
+        }
+    }
+}
+
+impl ToString for LogRotation {
+    fn to_string(&self) -> String {
+        match self {
+            Self::Hourly => "hourly".to_string(),
+            Self::Daily => "daily".to_string(),
+            Self::Never => "never".to_string(),
+        }
+    }
+}
+
+impl LogRotation {
+    /// Index of the rotation period the timestamp falls in
+    fn period(&self, secs: u64) -> u64 {
+        match self {
+            Self::Hourly => secs / 3600,
+            Self::Daily => secs / 86400,
+            Self::Never => 0,
+        }
+    }
+}
+
+/// Log file writer, the current file is renamed to `<file>.<yyyymmdd-HHMMSS>`
+/// when the period changes or it exceeds `max_size`, keeps the newest `max_files` rotated files
+pub struct RollingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    period: u64,
+    rotation: LogRotation,
+    /// Max size in bytes, 0 disables size based rotation
+    max_size: u64,
+    /// Rotated files to keep, 0 keeps all
+    max_files: usize,
+}
+
+fn now_secs() -> u64 {
+    now_duration().map(|d| d.as_secs()).unwrap_or_default()
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl RollingFile {
+    pub fn new(
+        path: PathBuf,
+        rotation: LogRotation,
+        max_size: u64,
+        max_files: usize,
+    ) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+
+        let file = open(&path)?;
+        let metadata = file.metadata()?;
+        // Continue the existing file in the period it was last written
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or_else(now_secs);
+
+        Ok(Self {
+            period: rotation.period(modified),
+            size: metadata.len(),
+            path,
+            file,
+            rotation,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn should_rotate(&self, now: u64, len: usize) -> bool {
+        self.size > 0
+            && (self.rotation.period(now) != self.period
+                || (self.max_size > 0 && self.size + len as u64 > self.max_size))
+    }
+
+    fn rotate(&mut self, now: u64) -> io::Result<()> {
+        self.file.flush()?;
+
+        let dt = time::OffsetDateTime::from_unix_timestamp(now as i64)
+            .unwrap_or(time::OffsetDateTime::UNIX_EPOCH);
+        let stamp = format!(
+            "{:04}{:02}{:02}-{:02}{:02}{:02}",
+            dt.year(),
+            dt.month() as u8,
+            dt.day(),
+            dt.hour(),
+            dt.minute(),
+            dt.second()
+        );
+        let mut rotated = self.rotated_path(&stamp);
+        let mut n = 1;
+        while rotated.exists() {
+            rotated = self.rotated_path(&format!("{stamp}-{n}"));
+            n += 1;
+        }
+
+        fs::rename(&self.path, rotated)?;
+        self.file = open(&self.path)?;
+        self.size = 0;
+        self.period = self.rotation.period(now);
+        self.cleanup();
+        Ok(())
+    }
+
+    fn rotated_path(&self, suffix: &str) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".{suffix}"));
+        self.path.with_file_name(name)
+    }
+
+    /// Remove the oldest rotated files beyond `max_files`
+    fn cleanup(&self) {
+        if self.max_files == 0 {
+            return;
+        }
+
+        let prefix = format!(
+            "{}.",
+            self.path.file_name().unwrap_or_default().to_string_lossy()
+        );
+        let dir = match self.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+
+        let mut rotated = entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name()
+                    .map(|name| name.to_string_lossy().starts_with(&prefix))
+                    .unwrap_or_default()
+            })
+            .collect::<Vec<_>>();
+
+        // The timestamp suffix sorts oldest first
+        rotated.sort();
+        let excess = rotated.len().saturating_sub(self.max_files);
+        for path in rotated.into_iter().take(excess) {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl Write for RollingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let now = now_secs();
+        if self.should_rotate(now, buf.len()) {
+            if let Err(err) = self.rotate(now) {
+                eprintln!("Failed to rotate log file {}: {err}", self.path.display());
+                // Keep writing the current file, retry in the next period or size
+                self.period = self.rotation.period(now);
+                self.size = 0;
+            }
+        }
+
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rolling_file(name: &str, max_size: u64, max_files: usize) -> RollingFile {
+        let dir = std::env::temp_dir().join(format!("ninja-logfile-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        RollingFile::new(
+            dir.join("ninja.log"),
+            LogRotation::Daily,
+            max_size,
+            max_files,
+        )
+        .unwrap()
+    }
+
+    fn rotated(file: &RollingFile) -> usize {
+        fs::read_dir(file.path.parent().unwrap()).unwrap().count() - 1
+    }
+
+    #[test]
+    fn test_rotation_period() {
+        assert_eq!(LogRotation::Hourly.period(7200), 2);
+        assert_eq!(LogRotation::Daily.period(86400 * 3 + 1), 3);
+        assert_eq!(LogRotation::Never.period(86400 * 3), 0);
+        assert_eq!(
+            "hourly".parse::<LogRotation>().unwrap(),
+            LogRotation::Hourly
+        );
+        assert!("weekly".parse::<LogRotation>().is_err());
+    }
+
+    #[test]
+    fn test_should_rotate() {
+        let mut file = rolling_file("should", 10, 0);
+        let now = file.period * 86400;
+        // An empty file is never rotated
+        assert!(!file.should_rotate(now + 86400, 100));
+
+        file.size = 5;
+        assert!(!file.should_rotate(now, 5));
+        assert!(file.should_rotate(now, 6));
+        assert!(file.should_rotate(now + 86400, 1));
+
+        file.max_size = 0;
+        assert!(!file.should_rotate(now, 100));
+    }
+
+    #[test]
+    fn test_rotate_keeps_max_files() {
+        let mut file = rolling_file("rotate", 0, 2);
+        let now = file.period * 86400;
+        for day in 1..=4 {
+            file.write_all(b"line\n").unwrap();
+            file.rotate(now + day * 86400).unwrap();
+            assert_eq!(file.size, 0);
+            assert_eq!(file.period, file.rotation.period(now + day * 86400));
+        }
+        assert_eq!(rotated(&file), 2);
+        let _ = fs::remove_dir_all(file.path.parent().unwrap());
+    }
+}
@@ -0,0 +1,108 @@
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use axum::extract::{ConnectInfo, State};
+use axum::http::{header, Request, StatusCode};
+use axum::{middleware::Next, response::Response};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use time::format_description::well_known::Rfc3339;
+
+use crate::serve::logfile::RollingFile;
+use crate::warn;
+
+/// Status of the upstream response, set by the handlers proxying to upstream
+#[derive(Clone, Copy)]
+pub(crate) struct UpstreamStatus(pub(crate) StatusCode);
+
+/// Access log sink, one JSON line per request
+pub(crate) struct AccessLog(Mutex<RollingFile>);
+
+impl AccessLog {
+    pub(crate) fn new(file: RollingFile) -> Self {
+        Self(Mutex::new(file))
+    }
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    time: String,
+    method: &'a str,
+    path: &'a str,
+    status: u16,
+    latency_ms: u64,
+    client_ip: Option<String>,
+    /// Hash prefix of the bearer token, identifies the caller without leaking the token
+    token_hash: Option<String>,
+    upstream_status: Option<u16>,
+}
+
+/// Hash prefix of the bearer token, identifies the caller without leaking the token
+pub(crate) fn hash_token(value: &str) -> String {
+    let token = value.strip_prefix("Bearer ").unwrap_or(value).trim();
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .take(8)
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Hash prefix of the `Authorization` or `X-Authorization` bearer token
+fn token_hash<B>(request: &Request<B>) -> Option<String> {
+    request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .or_else(|| request.headers().get("X-Authorization"))?
+        .to_str()
+        .ok()
+        .filter(|value| !value.trim_start_matches("Bearer ").trim().is_empty())
+        .map(hash_token)
+}
+
+pub(crate) async fn access_log_middleware<B>(
+    State(log): State<Arc<AccessLog>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let start = Instant::now();
+    let method = request.method().clone();
+    let path = request.uri().path().to_owned();
+    let client_ip = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string());
+    let token_hash = token_hash(&request);
+
+    let resp = next.run(request).await;
+
+    let record = Record {
+        time: time::OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default(),
+        method: method.as_str(),
+        path: &path,
+        status: resp.status().as_u16(),
+        latency_ms: start.elapsed().as_millis() as u64,
+        client_ip,
+        token_hash,
+        upstream_status: resp
+            .extensions()
+            .get::<UpstreamStatus>()
+            .map(|UpstreamStatus(status)| status.as_u16()),
+    };
+
+    match serde_json::to_string(&record) {
+        Ok(line) => {
+            if let Ok(mut file) = log.0.lock() {
+                if let Err(err) = writeln!(file, "{line}") {
+                    warn!("Failed to write access log: {err}");
+                }
+            }
+        }
+        Err(err) => warn!("Failed to serialize access log: {err}"),
+    }
+
+    resp
+}
@@ -0,0 +1,101 @@
+//! HTTP Basic auth gate of the whole server, a simple protection of a personal instance.
+//! A browser that passed the gate gets a cookie, so the requests of the WebUI that carry
+//! a bearer token in place of the Basic credentials pass as well
+use axum::body::{self, Empty};
+use axum::headers::authorization::Basic;
+use axum::headers::{Authorization, HeaderMapExt};
+use axum::http::{header, HeaderMap, HeaderValue, Request, StatusCode};
+use axum::{middleware::Next, response::Response};
+use axum_extra::extract::cookie::{Cookie, SameSite};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::context::{self, BasicAuth};
+
+/// Cookie of a browser that passed the gate, the tag of its credential
+const BASIC_AUTH_COOKIE: &str = "ninja_basic_auth";
+
+const CHALLENGE: &str = "Basic realm=\"ninja\", charset=\"UTF-8\"";
+
+pub async fn basic_auth_middleware<B>(request: Request<B>, next: Next<B>) -> Response {
+    let ctx = context::get_instance();
+    let Some(basic_auth) = ctx.basic_auth() else {
+        return next.run(request).await;
+    };
+
+    let path = request.uri().path();
+    let path = path.strip_prefix(ctx.url_prefix()).unwrap_or(path);
+    if basic_auth
+        .exempt
+        .iter()
+        .any(|prefix| path.starts_with(prefix.as_str()))
+        || has_cookie(request.headers(), basic_auth)
+    {
+        return next.run(request).await;
+    }
+
+    let user = request
+        .headers()
+        .typed_get::<Authorization<Basic>>()
+        .and_then(|basic| {
+            basic_auth
+                .credentials
+                .iter()
+                .find(|(user, password)| user == basic.username() && password == basic.password())
+        });
+    match user {
+        Some((user, password)) => {
+            let mut resp = next.run(request).await;
+            let cookie = Cookie::build(BASIC_AUTH_COOKIE, tag(basic_auth, user, password))
+                .path("/")
+                .same_site(SameSite::Lax)
+                .http_only(true)
+                .finish();
+            if let Ok(value) = HeaderValue::from_str(&cookie.to_string()) {
+                resp.headers_mut().append(header::SET_COOKIE, value);
+            }
+            resp
+        }
+        None => Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(header::WWW_AUTHENTICATE, CHALLENGE)
+            .body(body::boxed(Empty::new()))
+            .expect("Failed to build the basic auth challenge"),
+    }
+}
+
+/// Whether the request carries the cookie of a browser that passed the gate
+fn has_cookie(headers: &HeaderMap, basic_auth: &BasicAuth) -> bool {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| Cookie::parse(pair.trim()).ok())
+        .filter(|cookie| cookie.name() == BASIC_AUTH_COOKIE)
+        .filter_map(|cookie| {
+            base64::engine::general_purpose::URL_SAFE_NO_PAD
+                .decode(cookie.value())
+                .ok()
+        })
+        .any(|tag| {
+            basic_auth
+                .credentials
+                .iter()
+                .any(|(user, password)| mac(basic_auth, user, password).verify_slice(&tag).is_ok())
+        })
+}
+
+/// Tag of the credential, the cookie is void once the password changes
+fn tag(basic_auth: &BasicAuth, user: &str, password: &str) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .encode(mac(basic_auth, user, password).finalize().into_bytes())
+}
+
+fn mac(basic_auth: &BasicAuth, user: &str, password: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&basic_auth.cookie_key)
+        .expect("HMAC can take key of any size");
+    mac.update(format!("{user}:{password}").as_bytes());
+    mac
+}
@@ -5,9 +5,18 @@ use axum::{
     Form,
 };
 use axum_csrf::CsrfToken;
-use http::{Method, Request, StatusCode};
+use http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode};
 
-use crate::{auth::model::AuthAccount, warn};
+use crate::{context, warn};
+
+/// Cookie of the CSRF token
+pub const CSRF_COOKIE: &str = "Csrf_Token";
+
+/// Token field of the protected forms, the other fields are left to the handler
+#[derive(serde::Deserialize)]
+struct CsrfForm {
+    csrf_token: Option<String>,
+}
 
 /// Can only be done with the feature layer enabled
 pub async fn auth_middleware(
@@ -17,14 +26,14 @@ pub async fn auth_middleware(
     next: Next<BoxBody>,
 ) -> Result<Response, StatusCode> {
     if method == Method::POST {
-        let (parts, body) = request.into_parts();
+        let (mut parts, body) = request.into_parts();
         let bytes = hyper::body::to_bytes(body)
             .await
             .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
         let value = serde_urlencoded::from_bytes(&bytes)
             .map_err(|_| -> StatusCode { StatusCode::INTERNAL_SERVER_ERROR })?;
-        let payload: Form<AuthAccount> = Form(value);
+        let payload: Form<CsrfForm> = Form(value);
         match payload.0.csrf_token {
             Some(csrf_token) => {
                 if token.verify(&csrf_token).is_err() {
@@ -37,8 +46,59 @@ pub async fn auth_middleware(
                 return Err(StatusCode::UNAUTHORIZED);
             }
         }
+        // The token is spent, a form rendered again by the handler gets a new one
+        if context::get_instance().csrf_rotate() {
+            strip_csrf_cookie(&mut parts.headers);
+        }
         request = Request::from_parts(parts, body::boxed(Full::from(bytes)));
     }
 
     Ok(next.run(request).await)
 }
+
+/// Per-form token rotation: a rendered form gets a new token, and the token of a
+/// submitted form is dropped unless the response issues a new one
+pub async fn rotate_middleware<B>(mut request: Request<B>, next: Next<B>) -> Response {
+    if !context::get_instance().csrf_rotate() {
+        return next.run(request).await;
+    }
+
+    let method = request.method().clone();
+    if method == Method::GET {
+        strip_csrf_cookie(request.headers_mut());
+    }
+    let mut resp = next.run(request).await;
+    if method == Method::POST && !sets_csrf_cookie(resp.headers()) {
+        if let Ok(value) = HeaderValue::from_str(&format!("{CSRF_COOKIE}=; Path=/; Max-Age=0")) {
+            resp.headers_mut().append(header::SET_COOKIE, value);
+        }
+    }
+    resp
+}
+
+/// Drop the CSRF cookie of the request, the token extractor then issues a new token
+fn strip_csrf_cookie(headers: &mut HeaderMap) {
+    let cookies = headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .map(str::trim)
+        .filter(|cookie| !cookie.is_empty() && cookie.split('=').next() != Some(CSRF_COOKIE))
+        .collect::<Vec<_>>()
+        .join("; ");
+    headers.remove(header::COOKIE);
+    if let Ok(value) = HeaderValue::from_str(&cookies) {
+        if !cookies.is_empty() {
+            headers.insert(header::COOKIE, value);
+        }
+    }
+}
+
+fn sets_csrf_cookie(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.split('=').next() == Some(CSRF_COOKIE))
+}
@@ -1,15 +1,67 @@
+pub mod access_log;
+pub mod basic_auth;
 pub mod csrf;
+pub mod scheduler;
 #[cfg(feature = "limit")]
 pub mod tokenbucket;
 
 use anyhow::anyhow;
 use axum::http::header;
-use axum::{http::Request, middleware::Next, response::Response};
+use axum::http::HeaderValue;
+use axum::http::Uri;
+use axum::response::IntoResponse;
+use axum::{extract::State, http::Request, middleware::Next, response::Response};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::sync::Arc;
+
+use crate::context::{self, Context, KeyTarget};
+use crate::warn;
 
 use super::error::ResponseError;
 
+/// Client key of the request, its usage is accounted to the key instead of the upstream token
+#[derive(Clone)]
+pub(super) struct ClientKey(pub(super) String);
+
+/// Query parameter of the token, for EventSource clients that can't set headers
+const API_KEY_QUERY: &str = "api_key";
+/// Query parameter of the token of the WebSocket handshake, browsers can't set headers on it
+const ACCESS_TOKEN_QUERY: &str = "access_token";
+const WS_CONVERSATION: &str = "/ws/conversation";
+
+/// Token of the request query, taken off the uri
+#[derive(Clone)]
+pub(super) struct QueryToken(pub(super) String);
+
+impl QueryToken {
+    /// Token of the request, only when the query tokens are enabled
+    pub(super) fn of<B>(request: &Request<B>) -> Option<&str> {
+        context::get_instance()
+            .query_token()
+            .then(|| request.extensions().get::<QueryToken>())
+            .flatten()
+            .map(|token| token.0.as_str())
+    }
+}
+
+/// Take the `?api_key=` (and `?access_token=` of `/ws/conversation`) token off the uri
+/// before the trace layer and the access log record it, it is never sent upstream either
+pub(super) async fn query_token_middleware<B>(mut request: Request<B>, next: Next<B>) -> Response {
+    let ws = request.uri().path().ends_with(WS_CONVERSATION);
+    let token = take_query_param(request.uri_mut(), API_KEY_QUERY);
+    let token = match ws {
+        true => take_query_param(request.uri_mut(), ACCESS_TOKEN_QUERY).or(token),
+        false => token,
+    };
+    if let Some(token) = token {
+        request.extensions_mut().insert(QueryToken(token));
+    }
+    next.run(request).await
+}
+
 pub(super) async fn token_authorization_middleware<B>(
-    request: Request<B>,
+    mut request: Request<B>,
     next: Next<B>,
 ) -> Result<Response, ResponseError> {
     let ok = ["/backend-api/public"];
@@ -18,6 +70,43 @@ pub(super) async fn token_authorization_middleware<B>(
         return Ok(next.run(request).await);
     };
 
+    // Several SDKs can't set a bearer header on SSE requests, the token of `X-Api-Key`
+    // or `?api_key=` (with `--query-token`) is handed on as the `Authorization` header
+    if !request.headers().contains_key(header::AUTHORIZATION)
+        && !request.headers().contains_key("X-Authorization")
+    {
+        if let Some(token) = take_api_key(&mut request) {
+            request.headers_mut().insert(header::AUTHORIZATION, token);
+        }
+    }
+
+    // A client key is swapped for the upstream token it is mapped to
+    let ctx = context::get_instance();
+    let key = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .or_else(|| request.headers().get("X-Authorization"))
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim_start_matches("Bearer ").to_owned());
+    if let Some((key, target)) =
+        key.and_then(|key| ctx.key_target(&key).map(|target| (key, target)))
+    {
+        let token = match target {
+            KeyTarget::Account(account) => super::pooled::pooled_token(&account)
+                .await
+                .map_err(ResponseError::BadGateway)?
+                .access_token()
+                .to_owned(),
+            KeyTarget::Token(token) => token,
+        };
+        let value = HeaderValue::from_str(&format!("Bearer {token}"))
+            .map_err(ResponseError::InternalServerError)?;
+        request.headers_mut().remove("X-Authorization");
+        request.headers_mut().insert(header::AUTHORIZATION, value);
+        ctx.touch_key(&key);
+        request.extensions_mut().insert(ClientKey(key));
+    }
+
     // support Pandora WebUI passing X-Authorization header
     let authorization = match request.headers().get(header::AUTHORIZATION) {
         Some(v) => Some(v),
@@ -26,7 +115,18 @@ pub(super) async fn token_authorization_middleware<B>(
 
     match authorization {
         Some(token) => match crate::token::check_for_u8(token.as_bytes()) {
-            Ok(_) => Ok(next.run(request).await),
+            Ok(profile) => {
+                if let Some(profile) = profile {
+                    if ctx.account_disabled(profile.email()) {
+                        return Err(ResponseError::Forbidden(anyhow!(
+                            "account {} is disabled",
+                            profile.email()
+                        )));
+                    }
+                    ctx.record_account(profile.email(), Some(profile.exp));
+                }
+                Ok(next.run(request).await)
+            }
             Err(err) => Err(ResponseError::Unauthorized(err)),
         },
         None => Err(ResponseError::Unauthorized(anyhow!(
@@ -35,22 +135,322 @@ pub(super) async fn token_authorization_middleware<B>(
     }
 }
 
+/// Bearer token of the `X-Api-Key` header or the `api_key` query, taken off the request
+fn take_api_key<B>(request: &mut Request<B>) -> Option<HeaderValue> {
+    let token = match request.headers_mut().remove("X-Api-Key") {
+        Some(value) => value.to_str().ok()?.to_owned(),
+        None => QueryToken::of(request)?.to_owned(),
+    };
+    let token = token.trim().trim_start_matches("Bearer ");
+    if token.is_empty() {
+        return None;
+    }
+    HeaderValue::from_str(&format!("Bearer {token}")).ok()
+}
+
+/// Remove the query parameter from the uri, returns its value
+fn take_query_param(uri: &mut Uri, param: &str) -> Option<String> {
+    let params = serde_urlencoded::from_str::<Vec<(String, String)>>(uri.query()?).ok()?;
+    let (keys, rest): (Vec<_>, Vec<_>) = params.into_iter().partition(|(name, _)| name == param);
+    let (_, token) = keys.into_iter().next()?;
+
+    let path_and_query = match rest.is_empty() {
+        true => uri.path().to_owned(),
+        false => format!(
+            "{}?{}",
+            uri.path(),
+            serde_urlencoded::to_string(&rest).ok()?
+        ),
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().ok()?);
+    *uri = Uri::from_parts(parts).ok()?;
+    Some(token)
+}
+
+/// Handle the request with the context of its server instance
+pub(super) async fn context_middleware<B>(
+    State(ctx): State<Arc<Context>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    context::scope(ctx, next.run(request)).await
+}
+
+/// Prefix the redirect locations with the url prefix
+pub(super) async fn url_prefix_middleware<B>(request: Request<B>, next: Next<B>) -> Response {
+    let mut resp = next.run(request).await;
+
+    let ctx = context::get_instance();
+    let location = resp
+        .headers()
+        .get(header::LOCATION)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| v.starts_with('/') && !v.starts_with("//"))
+        .map(|v| match v {
+            "/" => ctx.url_prefix().to_owned(),
+            _ => format!("{}{v}", ctx.url_prefix()),
+        })
+        .and_then(|v| HeaderValue::from_str(&v).ok());
+    if let Some(location) = location {
+        resp.headers_mut().insert(header::LOCATION, location);
+    }
+    resp
+}
+
+/// Default WebUI Content-Security-Policy, the pages load scripts and styles from the OpenAI CDN
+/// and inline scripts, and may talk to a configured API prefix or Arkose endpoint
+const DEFAULT_CSP: &str = "default-src 'self'; \
+    script-src 'self' 'unsafe-inline' 'unsafe-eval' https:; \
+    style-src 'self' 'unsafe-inline' https:; \
+    img-src 'self' data: blob: https:; \
+    font-src 'self' data: https:; \
+    connect-src 'self' https: wss:; \
+    frame-src 'self' https:; \
+    worker-src 'self' blob:; \
+    object-src 'none'; \
+    base-uri 'self'; \
+    form-action 'self'";
+const DEFAULT_FRAME_OPTIONS: &str = "DENY";
+/// Embedded share pages, served without X-Frame-Options
+const SHARE_EMBED_PATH: &str = "/share/e/";
+const DEFAULT_REFERRER_POLICY: &str = "strict-origin-when-cross-origin";
+const HSTS: &str = "max-age=31536000";
+
+/// Set the security headers of WebUI responses
+pub(super) async fn security_headers_middleware<B>(request: Request<B>, next: Next<B>) -> Response {
+    let ctx = context::get_instance();
+    let path = request.uri().path();
+    let embed = path
+        .strip_prefix(ctx.url_prefix())
+        .unwrap_or(path)
+        .starts_with(SHARE_EMBED_PATH);
+    let mut resp = next.run(request).await;
+
+    let Some(security) = ctx.security_headers() else {
+        return resp;
+    };
+
+    // The embedded share pages are framed by other sites, the CSP decides which
+    let csp = security.csp.as_deref().unwrap_or(DEFAULT_CSP);
+    let csp = match security.share_frame_ancestors.as_deref() {
+        Some(ancestors) if embed && !csp.is_empty() && !csp.contains("frame-ancestors") => {
+            format!("{csp}; frame-ancestors {ancestors}")
+        }
+        _ => csp.to_owned(),
+    };
+    let frame_options = match embed {
+        true => "",
+        false => security
+            .frame_options
+            .as_deref()
+            .unwrap_or(DEFAULT_FRAME_OPTIONS),
+    };
+
+    let headers = resp.headers_mut();
+    let values = [
+        (header::CONTENT_SECURITY_POLICY, csp.as_str()),
+        (header::X_FRAME_OPTIONS, frame_options),
+        (
+            header::REFERRER_POLICY,
+            security
+                .referrer_policy
+                .as_deref()
+                .unwrap_or(DEFAULT_REFERRER_POLICY),
+        ),
+        (
+            header::STRICT_TRANSPORT_SECURITY,
+            if security.hsts { HSTS } else { "" },
+        ),
+    ];
+    for (name, value) in values {
+        if value.is_empty() || headers.contains_key(&name) {
+            continue;
+        }
+        match HeaderValue::from_str(value) {
+            Ok(value) => {
+                headers.insert(name, value);
+            }
+            Err(err) => warn!("Invalid security header {name}: {err}"),
+        }
+    }
+    resp
+}
+
+/// Answer every request but the admin ones with 503 in maintenance mode
+pub(super) async fn maintenance_middleware<B>(
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, ResponseError> {
+    if context::get_instance().toggles().maintenance && !request.uri().path().starts_with("/admin")
+    {
+        return Err(ResponseError::ServiceUnavailable(anyhow!(
+            "Under maintenance, please try again later"
+        )));
+    }
+    Ok(next.run(request).await)
+}
+
+/// Answer the new requests with 503 once the shutdown started, the load balancer
+/// moves them to another instance while the in-flight streams are drained
+pub(super) async fn draining_middleware<B>(
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, ResponseError> {
+    if context::get_instance().drain().draining() {
+        return Err(ResponseError::ServiceUnavailable(anyhow!(
+            "Server is shutting down, please try again later"
+        )));
+    }
+    Ok(next.run(request).await)
+}
+
+/// Hide the WebUI routes while the WebUI is disabled at runtime
+pub(super) async fn webui_toggle_middleware<B>(
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, ResponseError> {
+    if context::get_instance().toggles().disable_ui {
+        return Err(ResponseError::NotFound(anyhow!("WebUI is disabled")));
+    }
+    Ok(next.run(request).await)
+}
+
+pub(super) async fn admin_authorization_middleware<B>(
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, ResponseError> {
+    let ctx = crate::context::get_instance();
+    let admin_key = ctx
+        .admin_key()
+        .ok_or(ResponseError::NotFound(anyhow!("Admin API is disabled")))?;
+
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim_start_matches("Bearer "))
+        .ok_or(ResponseError::Unauthorized(anyhow!("Admin key required!")))?;
+
+    if !admin_key_matches(admin_key, token) {
+        return Err(ResponseError::Unauthorized(anyhow!("Admin key error!")));
+    }
+
+    Ok(next.run(request).await)
+}
+
+/// Compare the tags of the keys, the time taken tells nothing of the matching prefix
+fn admin_key_matches(admin_key: &str, token: &str) -> bool {
+    let mac = |value: &str| {
+        let mut mac = Hmac::<Sha256>::new_from_slice(admin_key.as_bytes())
+            .expect("HMAC can take key of any size");
+        mac.update(value.as_bytes());
+        mac
+    };
+    mac(token)
+        .verify_slice(&mac(admin_key).finalize().into_bytes())
+        .is_ok()
+}
+
+/// Concurrency limit, the requests over the limit wait by the priority of their client key,
+/// the ones the queue turns away get a 429 with the estimated `Retry-After`
+pub(super) async fn concurrency_limit_middleware<B>(
+    State(scheduler): State<Arc<scheduler::Scheduler>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let priority = ["Authorization", "X-Authorization", "X-Api-Key"]
+        .into_iter()
+        .find_map(|name| request.headers().get(name))
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| QueryToken::of(&request))
+        .and_then(|v| context::get_instance().key_priority(v.trim().trim_start_matches("Bearer ")))
+        .unwrap_or_default();
+    match scheduler.acquire(priority).await {
+        Ok(_permit) => next.run(request).await,
+        Err(rejected) => {
+            let mut response =
+                ResponseError::TooManyRequests(anyhow!("Too many requests, the server is busy"))
+                    .into_response();
+            response.headers_mut().insert(
+                header::RETRY_AFTER,
+                HeaderValue::from(rejected.retry_after.as_secs()),
+            );
+            response
+        }
+    }
+}
+
 #[cfg(feature = "limit")]
 use tokenbucket::{TokenBucket, TokenBucketLimitContext};
 
 #[cfg(feature = "limit")]
 pub(super) async fn token_bucket_limit_middleware<B>(
     axum::extract::State(limit): axum::extract::State<std::sync::Arc<TokenBucketLimitContext>>,
-    axum::extract::ConnectInfo(socket_addr): axum::extract::ConnectInfo<std::net::SocketAddr>,
+    // Missing when the router is mounted by an app served without the connection info
+    connect_info: Option<axum::extract::ConnectInfo<std::net::SocketAddr>>,
     request: Request<B>,
     next: Next<B>,
 ) -> Result<Response, ResponseError> {
-    let addr = socket_addr.ip();
-    match limit.acquire(addr).await {
+    // The client key, or the token once the authorization passed
+    let key = request
+        .extensions()
+        .get::<ClientKey>()
+        .map(|ClientKey(key)| key.as_str())
+        .or_else(|| {
+            request
+                .headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.trim_start_matches("Bearer "))
+        });
+    let addr = connect_info.map(|axum::extract::ConnectInfo(addr)| addr.ip());
+    let key = tokenbucket::bucket_key(key, addr);
+    let toggles = context::get_instance().toggles();
+    let acquired = match toggles.tb_enable {
+        true => {
+            let limits = limit.limits(toggles.tb_capacity, toggles.tb_fill_rate);
+            limit.acquire(&key, &limits).await
+        }
+        false => Ok(true),
+    };
+    match acquired {
         Ok(condition) => match condition {
-            true => Ok(next.run(request).await),
-            false => Err(ResponseError::TooManyRequests(anyhow!("Too Many Requests"))),
+            true => {
+                crate::metrics::inc(
+                    tokenbucket::ALLOWED,
+                    "Requests allowed by the rate limiter",
+                    &[],
+                    1,
+                );
+                Ok(next.run(request).await)
+            }
+            false => {
+                crate::metrics::inc(
+                    tokenbucket::LIMITED,
+                    "Requests rejected by the rate limiter",
+                    &[],
+                    1,
+                );
+                if let Some(webhook) = crate::context::get_instance().webhook() {
+                    webhook.record_rate_limited();
+                }
+                Err(ResponseError::TooManyRequests(anyhow!("Too Many Requests")))
+            }
         },
         Err(err) => Err(ResponseError::InternalServerError(err)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admin_key_matches() {
+        assert!(admin_key_matches("secret", "secret"));
+        assert!(!admin_key_matches("secret", "secre"));
+        assert!(!admin_key_matches("secret", "secret1"));
+        assert!(!admin_key_matches("secret", ""));
+    }
+}
@@ -0,0 +1,211 @@
+//! Concurrency limit with priority classes. The requests over the limit wait in a queue per
+//! priority of their client key, and the freed slots go to the queues by weighted round-robin,
+//! so the high priority keys are let in first without starving the low priority ones. The
+//! queue is bounded in depth and wait, the requests over it are turned away with a retry time
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use tokio::sync::oneshot;
+
+use crate::context::KeyPriority;
+use crate::metrics;
+
+pub(crate) const QUEUED: &str = "ninja_queued_requests_total";
+const REJECTED: &str = "ninja_queue_rejected_total";
+
+/// Weight of the last request in the average running time
+const HOLD_SMOOTHING: f64 = 0.1;
+
+pub struct Scheduler {
+    limit: usize,
+    /// Waiting requests at most, 0 for unbounded
+    depth: usize,
+    /// Wait of a request at most
+    timeout: Option<Duration>,
+    state: Mutex<State>,
+}
+
+/// Request turned away by the queue, with the estimated wait before a retry
+pub struct Rejected {
+    pub retry_after: Duration,
+}
+
+#[derive(Default)]
+struct State {
+    running: usize,
+    /// Requests in the queues, without the cancelled ones
+    waiting: usize,
+    /// Average running time of the requests, seconds
+    hold: f64,
+    /// Waiting requests, a queue per priority in the order of `KeyPriority::ALL`
+    queues: [VecDeque<oneshot::Sender<()>>; 3],
+    /// Slots left to the queues in the current round
+    credits: [u32; 3],
+}
+
+impl State {
+    /// Next waiting request by weighted round-robin
+    fn next(&mut self) -> Option<oneshot::Sender<()>> {
+        if self.queues.iter().all(VecDeque::is_empty) {
+            return None;
+        }
+        loop {
+            for (credits, queue) in self.credits.iter_mut().zip(self.queues.iter_mut()) {
+                if *credits > 0 && !queue.is_empty() {
+                    *credits -= 1;
+                    return queue.pop_front();
+                }
+            }
+            // A new round
+            self.credits = KeyPriority::ALL.map(KeyPriority::weight);
+        }
+    }
+
+    /// Estimated wait of a new request, the queue ahead of it spread over the slots
+    fn retry_after(&self, limit: usize) -> Duration {
+        let rounds = (self.waiting + 1) as f64 / limit.max(1) as f64;
+        Duration::from_secs((rounds * self.hold).ceil().max(1.0) as u64)
+    }
+}
+
+fn index(priority: KeyPriority) -> usize {
+    KeyPriority::ALL
+        .iter()
+        .position(|p| *p == priority)
+        .unwrap_or_default()
+}
+
+/// Slot of a running request, handed over to the next waiting request once dropped
+pub struct Permit {
+    scheduler: Arc<Scheduler>,
+    since: Instant,
+}
+
+impl Permit {
+    fn new(scheduler: &Arc<Scheduler>) -> Self {
+        Self {
+            scheduler: scheduler.clone(),
+            since: Instant::now(),
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let held = self.since.elapsed().as_secs_f64();
+        {
+            let mut state = self.scheduler.state();
+            state.hold = match state.hold > 0.0 {
+                true => state.hold + (held - state.hold) * HOLD_SMOOTHING,
+                false => held,
+            };
+        }
+        self.scheduler.release();
+    }
+}
+
+/// Waiting request, gives back the slot handed over after it was cancelled
+struct Waiting {
+    scheduler: Arc<Scheduler>,
+    rx: Option<oneshot::Receiver<()>>,
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        {
+            let mut state = self.scheduler.state();
+            state.waiting = state.waiting.saturating_sub(1);
+        }
+        if let Some(mut rx) = self.rx.take() {
+            rx.close();
+            if rx.try_recv().is_ok() {
+                self.scheduler.release();
+            }
+        }
+    }
+}
+
+impl Scheduler {
+    pub fn new(limit: usize, depth: usize, timeout: Option<Duration>) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            depth,
+            timeout,
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Wait for a slot, in the queue of the priority once the limit is reached
+    pub async fn acquire(self: &Arc<Self>, priority: KeyPriority) -> Result<Permit, Rejected> {
+        let rx = {
+            let mut state = self.state();
+            if state.running < self.limit && state.queues.iter().all(VecDeque::is_empty) {
+                state.running += 1;
+                return Ok(Permit::new(self));
+            }
+            if self.depth > 0 && state.waiting >= self.depth {
+                return Err(self.reject(&state, "full"));
+            }
+            let (tx, rx) = oneshot::channel();
+            state.queues[index(priority)].push_back(tx);
+            state.waiting += 1;
+            rx
+        };
+        metrics::inc(
+            QUEUED,
+            "Requests queued by the concurrency limit",
+            &[("priority", priority.as_str())],
+            1,
+        );
+
+        let mut waiting = Waiting {
+            scheduler: self.clone(),
+            rx: Some(rx),
+        };
+        if let Some(rx) = waiting.rx.as_mut() {
+            // The senders only go with the scheduler
+            let granted = match self.timeout {
+                Some(timeout) => tokio::time::timeout(timeout, rx).await.is_ok(),
+                None => rx.await.is_ok(),
+            };
+            if !granted {
+                // The slot handed over in the meantime is given back with the waiting
+                let rejected = self.reject(&self.state(), "timeout");
+                return Err(rejected);
+            }
+        }
+        waiting.rx = None;
+        Ok(Permit::new(self))
+    }
+
+    fn reject(&self, state: &State, reason: &'static str) -> Rejected {
+        metrics::inc(
+            REJECTED,
+            "Requests turned away by the concurrency limit queue",
+            &[("reason", reason)],
+            1,
+        );
+        Rejected {
+            retry_after: state.retry_after(self.limit),
+        }
+    }
+
+    /// Hand the slot over to the next waiting request, or free it
+    fn release(&self) {
+        let mut state = self.state();
+        while let Some(tx) = state.next() {
+            // The request is gone unless it receives the slot
+            if tx.send(()).is_ok() {
+                return;
+            }
+        }
+        state.running = state.running.saturating_sub(1);
+    }
+}
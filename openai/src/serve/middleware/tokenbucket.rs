@@ -2,19 +2,114 @@ use moka::sync::Cache;
 use redis::RedisResult;
 use redis_macros::{FromRedisValue, ToRedisArgs};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::net::IpAddr;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+pub(crate) const ALLOWED: &str = "ninja_rate_limit_allowed_total";
+pub(crate) const LIMITED: &str = "ninja_rate_limit_limited_total";
+
 fn now_timestamp() -> u64 {
     let now_duration = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .expect("Time went backwards");
-    now_duration.as_secs()
+    now_duration.as_millis() as u64
+}
+
+/// Bucket of `capacity` tokens filled at `fill_rate` tokens per second
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limit {
+    pub capacity: u32,
+    pub fill_rate: f64,
+}
+
+/// Limit of a window, `requests` per `period` seconds, e.g. `10/min`, `200/hour` or `5/10s`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct RateWindow {
+    pub requests: u32,
+    pub period: u64,
+}
+
+impl From<RateWindow> for Limit {
+    fn from(window: RateWindow) -> Self {
+        Self {
+            capacity: window.requests,
+            fill_rate: window.requests as f64 / window.period as f64,
+        }
+    }
+}
+
+impl std::str::FromStr for RateWindow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (requests, period) = s
+            .split_once('/')
+            .ok_or_else(|| anyhow::anyhow!("rate window: {s} is not `<requests>/<period>`"))?;
+        let requests = requests.trim().parse::<u32>()?;
+        let period = period.trim();
+        let unit = |unit: &str| match unit {
+            "" | "s" | "sec" | "second" => Some(1),
+            "m" | "min" | "minute" => Some(60),
+            "h" | "hour" => Some(3600),
+            "d" | "day" => Some(86400),
+            _ => None,
+        };
+        // e.g. `min` or `10s`
+        let digits = period.len()
+            - period
+                .trim_start_matches(|c: char| c.is_ascii_digit())
+                .len();
+        let count = match digits {
+            0 => 1,
+            _ => period[..digits].parse::<u64>()?,
+        };
+        let period = unit(&period[digits..])
+            .map(|unit| count * unit)
+            .ok_or_else(|| anyhow::anyhow!("rate window: unknown period of {s}"))?;
+        if requests == 0 || period == 0 {
+            anyhow::bail!("rate window: {s} must allow requests over a period");
+        }
+        Ok(Self { requests, period })
+    }
+}
+
+impl TryFrom<String> for RateWindow {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<RateWindow> for String {
+    fn from(window: RateWindow) -> Self {
+        format!("{}/{}s", window.requests, window.period)
+    }
+}
+
+/// Key of the buckets of a client, its key (or token) for an authenticated request so the
+/// clients behind a reverse proxy are kept apart and a key is limited from every address,
+/// the client address otherwise. The key is hashed, the buckets may be kept in redis
+pub fn bucket_key(key: Option<&str>, addr: Option<IpAddr>) -> String {
+    match (key, addr) {
+        (Some(key), _) => format!("key:{:x}", Sha256::digest(key.as_bytes())),
+        (None, Some(addr)) => format!("ip:{addr}"),
+        (None, None) => "anonymous".to_owned(),
+    }
 }
 
 #[async_trait::async_trait]
 pub trait TokenBucket: Send + Sync {
-    async fn acquire(&self, ip: IpAddr) -> anyhow::Result<bool>;
+    /// Take a token of every bucket of the limits of the client, none is taken unless all of them have one
+    async fn acquire(&self, key: &str, limits: &[Limit]) -> anyhow::Result<bool>;
+
+    /// Drop the expired buckets, returns how many were dropped.
+    /// Redis expires the buckets by itself
+    async fn sweep(&self) -> anyhow::Result<usize> {
+        Ok(0)
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -44,75 +139,80 @@ impl std::str::FromStr for Strategy {
 
 #[derive(Serialize, Deserialize, FromRedisValue, ToRedisArgs, Debug, Clone)]
 struct BucketState {
-    tokens: u32,
+    /// Tokens of the bucket of each limit
+    tokens: Vec<f64>,
+    /// Milliseconds
     last_time: u64,
 }
 
+impl BucketState {
+    fn new(limits: &[Limit], now_timestamp: u64) -> Self {
+        Self {
+            tokens: limits.iter().map(|limit| limit.capacity as f64).collect(),
+            last_time: now_timestamp,
+        }
+    }
+
+    /// Fill the buckets and take a token of each, `false` if one of them is empty
+    fn acquire(&mut self, limits: &[Limit], now_timestamp: u64) -> bool {
+        // The limits changed, e.g. by the admin API
+        if self.tokens.len() != limits.len() {
+            *self = Self::new(limits, now_timestamp);
+        }
+        let elapsed = now_timestamp.saturating_sub(self.last_time) as f64 / 1000.0;
+        for (tokens, limit) in self.tokens.iter_mut().zip(limits) {
+            *tokens = (*tokens + elapsed * limit.fill_rate).min(limit.capacity as f64);
+        }
+        self.last_time = now_timestamp;
+
+        let acquired = self.tokens.iter().all(|tokens| *tokens >= 1.0);
+        if acquired {
+            self.tokens.iter_mut().for_each(|tokens| *tokens -= 1.0);
+        }
+        acquired
+    }
+}
+
 pub struct MemTokenBucket {
-    enable: bool,
-    /// token bucket capacity `capacity`
-    capacity: u32,
-    /// token bucket fill rate `fill_rate`
-    fill_rate: u32,
-    /// ip -> token backet
-    buckets: moka::sync::Cache<IpAddr, BucketState>,
+    /// client -> token backet
+    buckets: moka::sync::Cache<String, BucketState>,
 }
 
 impl MemTokenBucket {
-    pub fn new(enable: bool, capacity: u32, fill_rate: u32, expired: u32) -> Self {
-        let buckets: Cache<IpAddr, BucketState> = Cache::builder()
+    pub fn new(expired: u32) -> Self {
+        let buckets: Cache<String, BucketState> = Cache::builder()
             .max_capacity(65535)
             .time_to_idle(Duration::from_secs(expired as u64))
             .build();
-        Self {
-            enable,
-            capacity,
-            fill_rate,
-            buckets,
-        }
+        Self { buckets }
     }
 }
 
 #[async_trait::async_trait]
 impl TokenBucket for MemTokenBucket {
-    async fn acquire(&self, ip: IpAddr) -> anyhow::Result<bool> {
-        if !self.enable {
-            return Ok(true);
-        }
-
+    async fn acquire(&self, key: &str, limits: &[Limit]) -> anyhow::Result<bool> {
         let now_timestamp = now_timestamp();
 
         let mut bucket = self
             .buckets
-            .entry(ip)
-            .or_insert(BucketState {
-                tokens: self.capacity,
-                last_time: now_timestamp,
-            })
+            .entry_by_ref(key)
+            .or_insert(BucketState::new(limits, now_timestamp))
             .into_value();
 
-        let elapsed = now_timestamp - bucket.last_time;
-        let tokens_to_add = (elapsed as u32) * self.fill_rate;
-        bucket.tokens = (bucket.tokens + tokens_to_add).min(self.capacity);
-        bucket.last_time = now_timestamp;
-
-        if bucket.tokens > 0 {
-            bucket.tokens -= 1;
-            self.buckets.insert(ip, bucket);
-            Ok(true)
-        } else {
-            Ok(false)
-        }
+        let acquired = bucket.acquire(limits, now_timestamp);
+        self.buckets.insert(key.to_owned(), bucket);
+        Ok(acquired)
+    }
+
+    async fn sweep(&self) -> anyhow::Result<usize> {
+        let len = self.buckets.entry_count();
+        self.buckets.run_pending_tasks();
+        Ok(len.saturating_sub(self.buckets.entry_count()) as usize)
     }
 }
 
 #[derive(Clone, typed_builder::TypedBuilder)]
 pub struct RedisTokenBucket {
-    enable: bool,
-    /// token bucket capacity `capacity`
-    capacity: u32,
-    /// token bucket fill rate `fill_rate`
-    fill_rate: u32,
     /// token bucket expired
     expired: u32,
     /// redis client
@@ -120,78 +220,165 @@ pub struct RedisTokenBucket {
 }
 
 impl RedisTokenBucket {
-    pub fn new(
-        enable: bool,
-        capacity: u32,
-        fill_rate: u32,
-        expired: u32,
-        node: String,
-    ) -> RedisResult<Self> {
+    pub fn new(expired: u32, node: String) -> RedisResult<Self> {
         // connect to redis
         let client = redis::Client::open(node)?;
-        Ok(Self {
-            enable,
-            capacity,
-            fill_rate,
-            client,
-            expired,
-        })
+        Ok(Self { client, expired })
     }
 }
 
 #[async_trait::async_trait]
 impl TokenBucket for RedisTokenBucket {
-    async fn acquire(&self, ip: IpAddr) -> anyhow::Result<bool> {
+    async fn acquire(&self, key: &str, limits: &[Limit]) -> anyhow::Result<bool> {
         use redis::AsyncCommands;
-        if !self.enable {
-            return Ok(true);
-        }
         let mut con = self.client.get_async_connection().await?;
         let now_timestamp = now_timestamp();
         let mut bucket: BucketState = con
-            .get_ex(ip.to_string(), redis::Expiry::EX(self.expired as usize))
+            .get_ex(key, redis::Expiry::EX(self.expired as usize))
             .await
-            .unwrap_or(BucketState {
-                tokens: self.capacity,
-                last_time: now_timestamp,
-            });
-
-        let elapsed = now_timestamp - bucket.last_time;
-        let tokens_to_add = (elapsed as u32) * self.fill_rate;
-        bucket.tokens = (bucket.tokens + tokens_to_add).min(self.capacity);
-        bucket.last_time = now_timestamp;
-
-        if bucket.tokens > 0 {
-            bucket.tokens -= 1;
-            con.set_ex(ip.to_string(), bucket, self.expired as usize)
-                .await?;
-            Ok(true)
-        } else {
-            Ok(false)
-        }
+            .unwrap_or(BucketState::new(limits, now_timestamp));
+
+        let acquired = bucket.acquire(limits, now_timestamp);
+        con.set_ex(key, bucket, self.expired as usize).await?;
+        Ok(acquired)
     }
 }
 
-pub struct TokenBucketLimitContext(Box<dyn TokenBucket>);
+pub struct TokenBucketLimitContext(Box<dyn TokenBucket>, Vec<RateWindow>);
 
-impl From<(Strategy, bool, u32, u32, u32, String)> for TokenBucketLimitContext {
-    fn from(value: (Strategy, bool, u32, u32, u32, String)) -> Self {
+impl From<(Strategy, u32, String, Vec<RateWindow>)> for TokenBucketLimitContext {
+    fn from(value: (Strategy, u32, String, Vec<RateWindow>)) -> Self {
+        let expired = expired(value.1, &value.3);
         let strategy = match value.0 {
-            Strategy::Mem => Self(Box::new(MemTokenBucket::new(
-                value.1, value.2, value.3, value.4,
-            ))),
-            Strategy::Redis => Self(Box::new(
-                RedisTokenBucket::new(value.1, value.2, value.3, value.4, value.5)
-                    .expect("redis token bucket init failed"),
-            )),
+            Strategy::Mem => Self(Box::new(MemTokenBucket::new(expired)), value.3),
+            Strategy::Redis => Self(
+                Box::new(
+                    RedisTokenBucket::new(expired, value.2)
+                        .expect("redis token bucket init failed"),
+                ),
+                value.3,
+            ),
         };
         strategy
     }
 }
 
+/// Expiry of the buckets, at least the longest window, a bucket dropped earlier
+/// would be full again before its window is over
+fn expired(expired: u32, windows: &[RateWindow]) -> u32 {
+    windows
+        .iter()
+        .map(|window| window.period.min(u32::MAX as u64) as u32)
+        .fold(expired, u32::max)
+}
+
+impl TokenBucketLimitContext {
+    /// The bucket of `capacity` and `fill_rate`, and the buckets of the windows
+    pub fn limits(&self, capacity: u32, fill_rate: u32) -> Vec<Limit> {
+        std::iter::once(Limit {
+            capacity,
+            fill_rate: fill_rate as f64,
+        })
+        .chain(self.1.iter().copied().map(Limit::from))
+        .collect()
+    }
+}
+
 #[async_trait::async_trait]
 impl TokenBucket for TokenBucketLimitContext {
-    async fn acquire(&self, ip: IpAddr) -> anyhow::Result<bool> {
-        Ok(self.0.acquire(ip).await?)
+    async fn acquire(&self, key: &str, limits: &[Limit]) -> anyhow::Result<bool> {
+        Ok(self.0.acquire(key, limits).await?)
+    }
+
+    async fn sweep(&self) -> anyhow::Result<usize> {
+        self.0.sweep().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_window_from_str() {
+        let window = |s: &str| s.parse::<RateWindow>().ok();
+        let expected = |requests, period| Some(RateWindow { requests, period });
+        assert_eq!(window("10/min"), expected(10, 60));
+        assert_eq!(window("200/hour"), expected(200, 3600));
+        assert_eq!(window("5/10s"), expected(5, 10));
+        assert_eq!(window("3/2h"), expected(3, 7200));
+        assert_eq!(window(" 7 / d "), expected(7, 86400));
+        assert_eq!(window("1/"), expected(1, 1));
+        assert_eq!(window("10"), None);
+        assert_eq!(window("0/min"), None);
+        assert_eq!(window("10/0s"), None);
+        assert_eq!(window("10/week"), None);
+        assert_eq!(window("x/min"), None);
+    }
+
+    #[test]
+    fn test_bucket_state_acquire() {
+        // 2 requests at once, 3 per minute
+        let limits = [
+            Limit {
+                capacity: 2,
+                fill_rate: 1.0,
+            },
+            Limit::from(RateWindow {
+                requests: 3,
+                period: 60,
+            }),
+        ];
+        let mut bucket = BucketState::new(&limits, 0);
+        assert!(bucket.acquire(&limits, 0));
+        assert!(bucket.acquire(&limits, 0));
+        assert!(!bucket.acquire(&limits, 0));
+
+        // The first bucket is filled again, the window only has a token left
+        assert!(bucket.acquire(&limits, 2000));
+        assert!(!bucket.acquire(&limits, 4000));
+        // A token is not taken from the other buckets when one of them is empty
+        assert!(bucket.tokens[0] >= 1.0);
+
+        // The window is filled again once its period is over
+        assert!(bucket.acquire(&limits, 64000));
+        assert!(bucket.acquire(&limits, 64000));
+
+        // Changed limits start over with full buckets
+        let limits = &limits[..1];
+        let mut bucket = BucketState {
+            tokens: vec![0.0, 0.0],
+            last_time: 64000,
+        };
+        assert!(bucket.acquire(limits, 64000));
+        assert_eq!(bucket.tokens, vec![1.0]);
+    }
+
+    #[test]
+    fn test_bucket_key() {
+        let addr = "203.0.113.7".parse::<IpAddr>().ok();
+        let key = bucket_key(Some("nk-team-a"), addr);
+        assert!(key.starts_with("key:") && !key.contains("nk-team-a"));
+        // The same key shares its buckets from every address
+        assert_eq!(key, bucket_key(Some("nk-team-a"), None));
+        assert_ne!(key, bucket_key(Some("nk-team-b"), addr));
+        assert_eq!(bucket_key(None, addr), "ip:203.0.113.7");
+    }
+
+    #[test]
+    fn test_expired() {
+        let windows = [
+            RateWindow {
+                requests: 10,
+                period: 60,
+            },
+            RateWindow {
+                requests: 100,
+                period: 86400 * 7,
+            },
+        ];
+        assert_eq!(expired(86400, &[]), 86400);
+        assert_eq!(expired(86400, &windows[..1]), 86400);
+        assert_eq!(expired(86400, &windows), 86400 * 7);
     }
 }
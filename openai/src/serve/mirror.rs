@@ -0,0 +1,184 @@
+//! Shadow traffic, a share of the proxied requests is also sent to a second
+//! upstream or written to a local sink, the primary response is not affected.
+//! The credentials of the requests never leave for the mirror
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use rand::Rng;
+use reqwest::{Client, Request, Url};
+use serde_json::{json, Value};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::{context, debug, metrics, now_duration, warn};
+
+const MIRRORED: &str = "ninja_mirror_requests_total";
+
+/// Headers left out of the mirrored requests
+const REDACTED_HEADERS: [&str; 7] = [
+    "authorization",
+    "x-authorization",
+    "cookie",
+    "x-api-key",
+    "proxy-authorization",
+    "openai-sentinel-chat-requirements-token",
+    "openai-sentinel-proof-token",
+];
+
+/// Body fields left out of the mirrored requests
+const REDACTED_FIELDS: [&str; 1] = ["arkose_token"];
+
+enum Target {
+    /// Second upstream origin, e.g. `https://staging.example.com`
+    Upstream(Url),
+    /// Local file, one JSON record per mirrored request
+    Sink(PathBuf, Mutex<()>),
+}
+
+/// Shadow traffic mirror
+pub struct Mirror {
+    target: Target,
+    ratio: f64,
+    /// Mirror the non-idempotent requests too, e.g. the POST of the conversations
+    all_methods: bool,
+}
+
+impl Mirror {
+    /// `target` is a http(s) origin or a file path, `ratio` the mirrored share of the requests (0.0 ~ 1.0)
+    pub fn new(target: &str, ratio: f64, all_methods: bool) -> anyhow::Result<Self> {
+        let target = match Url::parse(target) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => Target::Upstream(url),
+            Ok(url) if url.scheme() == "file" => Target::Sink(
+                url.to_file_path()
+                    .map_err(|_| anyhow::anyhow!("Invalid mirror file url: {target}"))?,
+                Mutex::new(()),
+            ),
+            _ => Target::Sink(PathBuf::from(target), Mutex::new(())),
+        };
+        Ok(Self {
+            target,
+            ratio,
+            all_methods,
+        })
+    }
+
+    /// Mirror the request in the background if it is sampled, requests with a streamed body are skipped,
+    /// and so are the non-idempotent ones unless all methods are mirrored
+    pub(super) fn mirror(self: &Arc<Self>, client: &Client, req: &Request) {
+        if !self.all_methods && !req.method().is_idempotent() {
+            return;
+        }
+        if self.ratio <= 0.0 || !rand::thread_rng().gen_bool(self.ratio.min(1.0)) {
+            return;
+        }
+        let Some(mut req) = req.try_clone() else {
+            return;
+        };
+        redact(&mut req);
+
+        match &self.target {
+            Target::Upstream(origin) => {
+                let mut url = origin.clone();
+                url.set_path(req.url().path());
+                url.set_query(req.url().query());
+                *req.url_mut() = url;
+                let client = client.clone();
+                context::spawn(async move {
+                    let status = match client.execute(req).await {
+                        // Read the whole body, the upstream sees the same exchange as the primary
+                        Ok(resp) => {
+                            let status = resp.status();
+                            let _ = resp.bytes().await;
+                            status.as_u16().to_string()
+                        }
+                        Err(err) => {
+                            debug!("Mirror request error: {err}");
+                            "error".to_owned()
+                        }
+                    };
+                    metrics::inc(
+                        MIRRORED,
+                        "Mirrored requests by target and status code",
+                        &[("target", "upstream"), ("status", status.as_str())],
+                        1,
+                    );
+                });
+            }
+            Target::Sink(..) => {
+                let mirror = self.clone();
+                context::spawn(async move {
+                    if let Err(err) = mirror.write_sink(&req).await {
+                        warn!("Mirror sink write error: {err}");
+                        return;
+                    }
+                    metrics::inc(
+                        MIRRORED,
+                        "Mirrored requests by target and status code",
+                        &[("target", "sink"), ("status", "written")],
+                        1,
+                    );
+                });
+            }
+        }
+    }
+
+    /// Append the request to the sink file
+    async fn write_sink(&self, req: &Request) -> anyhow::Result<()> {
+        let Target::Sink(path, lock) = &self.target else {
+            return Ok(());
+        };
+        let headers = req
+            .headers()
+            .iter()
+            .map(|(name, value)| {
+                (
+                    name.to_string(),
+                    Value::from(String::from_utf8_lossy(value.as_bytes())),
+                )
+            })
+            .collect::<serde_json::Map<_, _>>();
+        let body = req
+            .body()
+            .and_then(|body| body.as_bytes())
+            .map(String::from_utf8_lossy);
+        let mut line = serde_json::to_vec(&json!({
+            "timestamp": now_duration()?.as_secs(),
+            "method": req.method().as_str(),
+            "path": req.url().path(),
+            "query": req.url().query(),
+            "headers": headers,
+            "body": body,
+        }))?;
+        line.push(b'\n');
+
+        let _guard = lock.lock().await;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        file.write_all(&line).await?;
+        Ok(())
+    }
+}
+
+/// Leave the credentials out of the mirrored request, the headers and the token fields of a JSON body
+fn redact(req: &mut Request) {
+    for name in REDACTED_HEADERS {
+        req.headers_mut().remove(name);
+    }
+    let body = req
+        .body()
+        .and_then(|body| body.as_bytes())
+        .and_then(|body| serde_json::from_slice::<Value>(body).ok());
+    if let Some(Value::Object(mut body)) = body {
+        if REDACTED_FIELDS
+            .iter()
+            .any(|field| body.remove(*field).is_some())
+        {
+            if let Ok(body) = serde_json::to_vec(&body) {
+                *req.body_mut() = Some(body.into());
+            }
+        }
+    }
+}
@@ -1,35 +1,79 @@
+#[cfg(feature = "template")]
+pub(crate) mod buildid;
+pub mod captcha;
+pub mod cassette;
+pub mod check;
+pub mod clearance;
+pub(crate) mod coalesce;
+pub mod contentlog;
+pub mod conversations;
 mod convert;
+mod deadline;
+pub(crate) mod drain;
 mod error;
 mod extract;
+mod forward;
+mod keepalive;
+pub mod keypool;
+#[cfg(feature = "ldap")]
+pub mod ldap;
+pub mod logfile;
 mod middleware;
+pub mod mirror;
+pub mod moderation;
+#[cfg(feature = "plugin")]
+pub mod plugin;
+mod pooled;
 #[cfg(feature = "preauth")]
 pub mod preauth;
-mod puid;
+pub(crate) mod puid;
+pub(crate) mod requirements;
+pub mod responses;
+mod rewrite;
 #[cfg(feature = "template")]
-mod route;
+pub(crate) mod route;
+pub mod share;
 mod signal;
-mod turnstile;
+mod stateless;
+mod stop;
+mod strip;
+mod sweep;
+#[cfg(all(target_os = "linux", feature = "systemd"))]
+mod systemd;
+mod tokens;
+pub(crate) mod upstream;
+pub mod usage;
+mod websocket;
+
+#[cfg(feature = "limit")]
+pub use self::middleware::tokenbucket::RateWindow;
 
 use anyhow::anyhow;
 use axum::body::Body;
+#[cfg(feature = "ldap")]
+use axum::extract::ConnectInfo;
 use axum::headers::authorization::Bearer;
 use axum::headers::Authorization;
-use axum::http::Response;
+use axum::http::{HeaderMap, Response};
 use axum::response::IntoResponse;
 use axum::routing::{any, get, post};
 use axum::{Json, TypedHeader};
 use axum_server::{AddrIncomingConfig, Handle};
 
+use self::conversations::CacheAction;
 use self::convert::response_convert;
 use self::extract::SendRequestExt;
+use crate::arkose;
 use crate::auth::model::{AccessToken, AuthAccount, RefreshToken, SessionAccessToken};
 use crate::auth::provide::AuthProvider;
 use crate::auth::API_AUTH_SESSION_COOKIE_KEY;
-use crate::context::{self, ContextArgs};
+use crate::context::{self, AccountState, Context, ContextArgs, Credential};
+use crate::error::AuthError;
 use crate::serve::error::ResponseError;
 use crate::serve::middleware::tokenbucket::{Strategy, TokenBucketLimitContext};
+use crate::token::model::AuthenticateToken;
 use crate::{info, warn};
-use crate::{URL_CHATGPT_API, URL_PLATFORM_API};
+use crate::{webhook, URL_CHATGPT_API, URL_PLATFORM_API};
 use axum::http::header;
 use axum_extra::extract::{cookie, CookieJar};
 use axum_server::tls_rustls::RustlsConfig;
@@ -55,15 +99,52 @@ fn print_boot_message(inner: &ContextArgs) {
 
     if let Some((ref ipv6, len)) = inner.ipv6_subnet {
         info!("Ipv6 subnet: {ipv6}/{len}");
+        info!("Ipv6 strategy: {}", inner.ipv6_strategy.to_string());
     } else {
         info!("Keepalive {} seconds", inner.tcp_keepalive);
         info!("Timeout {} seconds", inner.timeout);
         info!("Connect timeout {} seconds", inner.connect_timeout);
+        if inner.stream_timeout > 0 {
+            info!("Stream timeout {} seconds", inner.stream_timeout);
+        }
+        if inner.stream_read_timeout > 0 {
+            info!("Stream read timeout {} seconds", inner.stream_read_timeout);
+        }
         if inner.disable_direct {
             info!("Disable direct connection");
         }
     }
 
+    if let Some(ref doh) = inner.doh {
+        info!("DNS-over-HTTPS: {}", doh.to_string());
+    }
+
+    if !inner.account_bindings.is_empty() {
+        info!("Account bindings: {}", inner.account_bindings.len());
+    }
+
+    if !inner.api_key_pool.is_empty() {
+        info!("API key pool: {}", inner.api_key_pool.len());
+    }
+
+    #[cfg(feature = "plugin")]
+    if let Some(plugins) = inner.plugins.as_ref() {
+        info!("Plugins: {}", plugins.len());
+    }
+
+    #[cfg(not(feature = "tiktoken"))]
+    if inner.usage_store.is_some() {
+        warn!("Usage accounting estimates the tokens as 4 characters each, build with the tiktoken feature to count them");
+    }
+
+    if let Some(moderation) = inner.moderation.as_ref() {
+        info!(
+            "Moderation: {} rules, endpoint {}",
+            moderation.rules(),
+            moderation.endpoint().unwrap_or("disabled")
+        );
+    }
+
     inner.arkose_solver.as_ref().map(|solver| {
         info!("ArkoseLabs solver: {:?}", solver.solver);
     });
@@ -86,81 +167,69 @@ impl Serve {
     }
 
     pub fn run(self) -> anyhow::Result<()> {
+        // Log to the rotated file if set, otherwise stdout
+        let file_layer = match self.0.log_file.clone() {
+            Some(path) => {
+                let file = logfile::RollingFile::new(
+                    path,
+                    self.0.log_rotation,
+                    self.0.log_max_size * 1024 * 1024,
+                    self.0.log_max_files,
+                )?;
+                Some(
+                    tracing_subscriber::fmt::layer()
+                        .with_ansi(false)
+                        .with_writer(std::sync::Mutex::new(file)),
+                )
+            }
+            None => None,
+        };
+        let stdout_layer = file_layer.is_none().then(tracing_subscriber::fmt::layer);
+
         tracing_subscriber::registry()
             .with(
                 tracing_subscriber::EnvFilter::try_from_default_env()
                     .unwrap_or_else(|_| "RUST_LOG=warn".into()),
             )
-            .with(tracing_subscriber::fmt::layer())
-            .init();
-
-        print_boot_message(&self.0);
+            .with(file_layer)
+            .with(stdout_layer)
+            .try_init()
+            .ok();
 
-        // init context
-        context::init(self.0.clone());
-
-        let global_layer = tower::ServiceBuilder::new()
-            .layer(
-                tower_http::trace::TraceLayer::new_for_http()
-                    .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
-                    .on_response(trace::DefaultOnResponse::new().level(Level::INFO))
-                    .on_request(trace::DefaultOnRequest::new().level(Level::INFO))
-                    .on_failure(trace::DefaultOnFailure::new().level(Level::WARN)),
-            )
-            .layer(tower::limit::ConcurrencyLimitLayer::new(
-                self.0.concurrent_limit,
-            ))
-            .layer(
-                tower_http::cors::CorsLayer::new()
-                    .allow_credentials(true)
-                    .allow_headers(tower_http::cors::AllowHeaders::mirror_request())
-                    .allow_methods(tower_http::cors::AllowMethods::mirror_request())
-                    .allow_origin(tower_http::cors::AllowOrigin::mirror_request()),
-            )
-            .layer(axum::error_handling::HandleErrorLayer::new(
-                |_: axum::BoxError| async { axum::http::StatusCode::REQUEST_TIMEOUT },
-            ))
-            .layer(tower::timeout::TimeoutLayer::new(Duration::from_secs(
-                self.0.timeout as u64,
-            )))
-            .layer(axum::extract::DefaultBodyLimit::max(200 * 1024 * 1024));
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_io()
+            .enable_time()
+            .worker_threads(self.0.workers)
+            .build()?;
 
-        let app_layer = {
-            let limit_context = TokenBucketLimitContext::from((
-                Strategy::from_str(self.0.tb_store_strategy.as_str())?,
-                self.0.tb_enable,
-                self.0.tb_capacity,
-                self.0.tb_fill_rate,
-                self.0.tb_expired,
-                self.0.tb_redis_url.clone(),
-            ));
+        runtime.block_on(self.serve())
+    }
 
-            tower::ServiceBuilder::new()
-                .layer(axum::middleware::from_fn(
-                    middleware::token_authorization_middleware,
-                ))
-                .layer(axum::middleware::from_fn_with_state(
-                    Arc::new(limit_context),
-                    middleware::token_bucket_limit_middleware,
-                ))
-        };
+    /// Build the router with its own context, to be mounted in an existing axum app.
+    ///
+    /// The background tasks of the router (store sweep, arkose token pool refill, WebUI
+    /// build id refresh) are returned apart, start them with [`BackgroundTasks::spawn`] on the
+    /// runtime of the app, the caches and stores grow without bound otherwise. The listeners
+    /// of `serve` (preauth and forward proxies, signals, systemd) are not part of the router.
+    ///
+    /// The client address of the rate limiter, the captcha and the LDAP throttle is taken from
+    /// `ConnectInfo<SocketAddr>`, serve the app with `into_make_service_with_connect_info`
+    /// (or set `--real-ip-header`), the requests are limited as one client otherwise.
+    pub fn into_router(self) -> anyhow::Result<(axum::Router, BackgroundTasks)> {
+        let ctx = Arc::new(Context::new(self.0.clone()));
+        let limit_context = self.limit_context()?;
+        let router = self.router(ctx.clone(), limit_context.clone())?;
+        Ok((router, self.background_tasks(ctx, limit_context)))
+    }
 
-        let router = axum::Router::new()
-            // official dashboard api endpoint
-            .route("/dashboard/*path", any(official_proxy))
-            // official v1 api endpoint
-            .route("/v1/*path", any(official_proxy))
-            // unofficial backend api endpoint
-            .route("/backend-api/*path", any(unofficial_proxy))
-            .route_layer(app_layer)
-            // unofficial public api endpoint
-            .route("/public-api/*path", any(unofficial_proxy))
-            .route("/auth/token", post(post_access_token))
-            .route("/auth/refresh_token", post(post_refresh_token))
-            .route("/auth/revoke_token", post(post_revoke_token))
-            .route("/api/auth/session", get(get_session));
+    /// Run the server on the current tokio runtime until it is shut down
+    pub async fn serve(self) -> anyhow::Result<()> {
+        print_boot_message(&self.0);
 
-        let router = route::config(router, &self.0).layer(global_layer);
+        let ctx = Arc::new(Context::new(self.0.clone()));
+        let limit_context = self.limit_context()?;
+        let router = self.router(ctx.clone(), limit_context.clone())?;
+        let background = self.background_tasks(ctx.clone(), limit_context);
 
         let http_config = HttpConfig::new()
             .http1_keep_alive(true)
@@ -175,13 +244,7 @@ impl Serve {
             .tcp_keepalive(Some(Duration::from_secs(self.0.tcp_keepalive as u64)))
             .build();
 
-        let runtime = tokio::runtime::Builder::new_multi_thread()
-            .enable_io()
-            .enable_time()
-            .worker_threads(self.0.workers)
-            .build()?;
-
-        runtime.block_on(async move {
+        context::scope(ctx, async move {
             let (tx, rx) = tokio::sync::mpsc::channel::<()>(1);
             // PreAuth mitm proxy
             #[cfg(feature = "preauth")]
@@ -189,6 +252,7 @@ impl Serve {
                 if let Some(err) = preauth::mitm_proxy(
                     pbind,
                     self.0.pupstream.clone(),
+                    self.0.pfilter.clone(),
                     self.0.pcert.clone(),
                     self.0.pkey.clone(),
                     rx,
@@ -200,14 +264,39 @@ impl Serve {
                 }
             }
 
+            // Forward CONNECT proxy
+            if let Some(fbind) = self.0.fbind {
+                if let Err(err) =
+                    forward::forward_proxy(fbind, self.0.fhosts.clone(), self.0.fauth.clone()).await
+                {
+                    crate::error!("Forward proxy error: {}", err);
+                }
+            }
+
             // Signal the server to shutdown using Handle.
             let handle = Handle::new();
 
             // Spawn a task to gracefully shutdown server.
-            tokio::spawn(signal::graceful_shutdown(handle.clone()));
+            context::spawn(signal::graceful_shutdown(
+                handle.clone(),
+                Duration::from_secs(self.0.shutdown_drain_timeout),
+                Duration::from_secs(self.0.shutdown_timeout),
+            ));
+
+            // Spawn the tasks keeping the stores swept and the arkose token pool filled.
+            background.spawn();
+
+            // Notify systemd once the server is listening, and keep its watchdog fed.
+            #[cfg(all(target_os = "linux", feature = "systemd"))]
+            context::spawn(systemd::notify_task(
+                handle.clone(),
+                self.0.tls_cert.is_some() && self.0.tls_key.is_some(),
+            ));
 
-            // Spawn a task to check wan address.
-            tokio::spawn(check_wan_address());
+            webhook::fire(
+                webhook::Event::ServerStart,
+                serde_json::json!({ "bind": self.0.bind }),
+            );
 
             let result = match (self.0.tls_cert, self.0.tls_key) {
                 (Some(cert), Some(key)) => {
@@ -236,15 +325,224 @@ impl Serve {
                 warn!("Http Server error: {}", err);
             }
 
+            #[cfg(all(target_os = "linux", feature = "systemd"))]
+            systemd::stopping();
+
             if let Some(err) = tx.send(()).await.err() {
                 warn!("Send shutdown signal error: {}", err);
             }
 
+            // A hung webhook receiver must not hold up the shutdown
+            if let Some(webhook) = context::get_instance().webhook() {
+                let stop = webhook.send(webhook::Event::ServerStop, serde_json::json!({}));
+                if tokio::time::timeout(Duration::from_secs(5), stop)
+                    .await
+                    .is_err()
+                {
+                    warn!("Webhook server stop event timed out");
+                }
+            }
+
             tokio::time::sleep(Duration::from_secs(1)).await;
-        });
+        })
+        .await;
 
         Ok(())
     }
+
+    fn background_tasks(
+        &self,
+        ctx: Arc<Context>,
+        limit_context: Arc<TokenBucketLimitContext>,
+    ) -> BackgroundTasks {
+        BackgroundTasks {
+            ctx,
+            limit_context,
+            sweep_interval: self.0.sweep_interval,
+            #[cfg(feature = "template")]
+            ui: !self.0.disable_ui,
+        }
+    }
+
+    /// Token bucket limiter of the api routes, shared with the sweep task
+    fn limit_context(&self) -> anyhow::Result<Arc<TokenBucketLimitContext>> {
+        Ok(Arc::new(TokenBucketLimitContext::from((
+            Strategy::from_str(self.0.tb_store_strategy.as_str())?,
+            self.0.tb_expired,
+            self.0.tb_redis_url.clone(),
+            self.0.tb_window.clone(),
+        ))))
+    }
+
+    fn router(
+        &self,
+        ctx: Arc<Context>,
+        limit_context: Arc<TokenBucketLimitContext>,
+    ) -> anyhow::Result<axum::Router> {
+        let global_layer = tower::ServiceBuilder::new()
+            .layer(
+                tower_http::trace::TraceLayer::new_for_http()
+                    .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
+                    .on_response(trace::DefaultOnResponse::new().level(Level::INFO))
+                    .on_request(trace::DefaultOnRequest::new().level(Level::INFO))
+                    .on_failure(trace::DefaultOnFailure::new().level(Level::WARN)),
+            )
+            .layer(axum::middleware::from_fn_with_state(
+                middleware::scheduler::Scheduler::new(
+                    self.0.concurrent_limit,
+                    self.0.queue_depth,
+                    (self.0.queue_timeout > 0).then(|| Duration::from_secs(self.0.queue_timeout)),
+                ),
+                middleware::concurrency_limit_middleware,
+            ))
+            .layer(
+                tower_http::cors::CorsLayer::new()
+                    .allow_credentials(true)
+                    .allow_headers(tower_http::cors::AllowHeaders::mirror_request())
+                    .allow_methods(tower_http::cors::AllowMethods::mirror_request())
+                    .allow_origin(tower_http::cors::AllowOrigin::mirror_request()),
+            )
+            .layer(axum::error_handling::HandleErrorLayer::new(
+                |_: axum::BoxError| async { axum::http::StatusCode::REQUEST_TIMEOUT },
+            ))
+            // The handlers of the event streams wait for the response headers by the stream timeout
+            .layer(tower::timeout::TimeoutLayer::new(Duration::from_secs(
+                (self.0.timeout as u64).max(self.0.stream_timeout),
+            )))
+            .layer(axum::extract::DefaultBodyLimit::max(200 * 1024 * 1024));
+
+        let app_layer = tower::ServiceBuilder::new()
+            .layer(axum::middleware::from_fn(
+                middleware::token_authorization_middleware,
+            ))
+            .layer(axum::middleware::from_fn_with_state(
+                limit_context,
+                middleware::token_bucket_limit_middleware,
+            ));
+
+        let router = axum::Router::new()
+            // official dashboard api endpoint
+            .route("/dashboard/*path", any(official_proxy))
+            // official v1 api endpoint
+            .route("/v1/*path", any(official_proxy))
+            // unofficial backend api endpoint
+            .route("/backend-api/*path", any(unofficial_proxy))
+            // conversation WebSocket, limited like the conversation requests
+            .route("/ws/conversation", get(websocket::ws_conversation))
+            .route_layer(app_layer)
+            // unofficial public api endpoint
+            .route("/public-api/*path", any(unofficial_proxy))
+            .route("/auth/token", post(post_access_token))
+            .route("/auth/refresh_token", post(post_refresh_token))
+            .route("/auth/revoke_token", post(post_revoke_token))
+            .route("/api/auth/session", get(get_session))
+            .route("/metrics", get(get_metrics));
+
+        #[cfg(feature = "ldap")]
+        let router = router.route("/auth/ldap/token", post(post_ldap_token));
+
+        let router = context::sync_scope(ctx.clone(), || route::config(router, &self.0)).layer(
+            axum::middleware::from_fn(middleware::maintenance_middleware),
+        );
+
+        // Mount all routes under the url prefix
+        let router = match self.0.url_prefix.as_deref() {
+            Some(prefix) => axum::Router::new()
+                .route(
+                    // `/` is rewritten to the prefix itself
+                    &format!("{prefix}/"),
+                    get(|| async {
+                        Response::builder()
+                            .status(axum::http::StatusCode::FOUND)
+                            .header(header::LOCATION, "/")
+                            .body(Body::empty())
+                            .expect("An error occurred while redirecting")
+                    }),
+                )
+                .nest(prefix, router)
+                .layer(axum::middleware::from_fn(middleware::url_prefix_middleware)),
+            None => router,
+        };
+
+        // Basic auth gate in front of every route, preflight requests are answered by the cors layer
+        let router = router
+            .layer(axum::middleware::from_fn(
+                middleware::basic_auth::basic_auth_middleware,
+            ))
+            .layer(global_layer);
+
+        // Access log sits outside the global layer to record timeouts as well
+        let router = match self.0.access_log.clone() {
+            Some(path) => {
+                let file = logfile::RollingFile::new(
+                    path,
+                    self.0.log_rotation,
+                    self.0.log_max_size * 1024 * 1024,
+                    self.0.log_max_files,
+                )?;
+                router.layer(axum::middleware::from_fn_with_state(
+                    Arc::new(middleware::access_log::AccessLog::new(file)),
+                    middleware::access_log::access_log_middleware,
+                ))
+            }
+            None => router,
+        };
+
+        // The query tokens are taken off before the access log and the trace layer
+        let router = router
+            .layer(axum::middleware::from_fn(
+                middleware::query_token_middleware,
+            ))
+            .layer(axum::middleware::from_fn(middleware::draining_middleware))
+            .layer(axum::middleware::from_fn_with_state(
+                ctx,
+                middleware::context_middleware,
+            ));
+        Ok(router)
+    }
+}
+
+/// Background tasks of a server instance, started by `serve`, or by the app mounting
+/// the router of [`Serve::into_router`]
+#[must_use = "the background tasks only run once spawned"]
+pub struct BackgroundTasks {
+    ctx: Arc<Context>,
+    limit_context: Arc<TokenBucketLimitContext>,
+    sweep_interval: u64,
+    #[cfg(feature = "template")]
+    ui: bool,
+}
+
+impl BackgroundTasks {
+    /// Spawn the tasks on the current tokio runtime with the context of the instance,
+    /// they run until their handles are aborted or the runtime shuts down
+    pub fn spawn(self) -> Vec<tokio::task::JoinHandle<()>> {
+        context::sync_scope(self.ctx, || {
+            let mut tasks = Vec::new();
+
+            // Check the wan address.
+            tasks.push(context::spawn(check_wan_address()));
+
+            // Keep the arkose token pool filled.
+            tasks.push(context::spawn(arkose::pool::refresh_task()));
+
+            // Remove the expired entries of the stores.
+            if self.sweep_interval > 0 {
+                tasks.push(context::spawn(sweep::sweep_task(
+                    Duration::from_secs(self.sweep_interval),
+                    self.limit_context,
+                )));
+            }
+
+            // Follow the upstream WebUI build id.
+            #[cfg(feature = "template")]
+            if self.ui {
+                tasks.push(context::spawn(buildid::refresh_task()));
+            }
+
+            tasks
+        })
+    }
 }
 
 /// GET /api/auth/session
@@ -266,12 +564,21 @@ async fn get_session(jar: CookieJar) -> Result<impl IntoResponse, ResponseError>
     }
 }
 
+/// GET /metrics
+async fn get_metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        crate::metrics::render(),
+    )
+}
+
 /// POST /auth/token
 async fn post_access_token(
     bearer: Option<TypedHeader<Authorization<Bearer>>>,
     mut account: axum::Form<AuthAccount>,
 ) -> Result<impl IntoResponse, ResponseError> {
-    if let Some(key) = context::get_instance().auth_key() {
+    let ctx = context::get_instance();
+    if let Some(key) = ctx.auth_key() {
         let bearer = bearer.ok_or(ResponseError::Unauthorized(anyhow!(
             "Login Authentication Key required!"
         )))?;
@@ -291,6 +598,43 @@ async fn post_access_token(
     }
 }
 
+/// POST /auth/ldap/token, the token of the pooled account of the directory user
+#[cfg(feature = "ldap")]
+async fn post_ldap_token(
+    addr: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    account: axum::Form<AuthAccount>,
+) -> Result<impl IntoResponse, ResponseError> {
+    let ctx = context::get_instance();
+    if ctx.ldap().is_none() {
+        return Err(ResponseError::NotFound(anyhow!("LDAP is not configured")));
+    }
+    if let Some(key) = ctx.auth_key() {
+        let bearer = bearer.ok_or(ResponseError::Unauthorized(anyhow!(
+            "Login Authentication Key required!"
+        )))?;
+        if bearer.token().ne(key) {
+            return Err(ResponseError::Unauthorized(anyhow!(
+                "Authentication Key error!"
+            )));
+        }
+    }
+
+    let addr = captcha::client_ip(addr.as_ref().map(|ConnectInfo(addr)| addr), &headers);
+    ldap::throttle(&account.username, &addr)?;
+    let (user, token) = ldap::login(&account.username, &account.password, &addr)
+        .await
+        .map_err(ResponseError::Unauthorized)?;
+    // The refresh token of the pooled account stays with ninja
+    Ok(Json(serde_json::json!({
+        "access_token": token.access_token(),
+        "expires": token.expires(),
+        "account": token.email(),
+        "user": user,
+    })))
+}
+
 /// POST /auth/refresh_token
 async fn post_refresh_token(
     TypedHeader(bearer): TypedHeader<Authorization<Bearer>>,
@@ -298,7 +642,13 @@ async fn post_refresh_token(
     let ctx = context::get_instance();
     match ctx.auth_client().do_refresh_token(bearer.token()).await {
         Ok(refresh_token) => Ok(Json(refresh_token)),
-        Err(err) => Err(ResponseError::BadRequest(err)),
+        Err(err) => {
+            webhook::fire(
+                webhook::Event::RefreshFailed,
+                serde_json::json!({ "error": err.to_string() }),
+            );
+            Err(ResponseError::BadRequest(err))
+        }
     }
 }
 
@@ -323,29 +673,170 @@ async fn post_revoke_token(
 /// platform API match path /v1/{tail.*}
 /// reference: https://platform.openai.com/docs/api-reference
 async fn official_proxy(
-    req: extract::RequestExtractor,
+    mut req: extract::RequestExtractor,
 ) -> Result<impl IntoResponse, ResponseError> {
-    let resp = context::get_instance()
-        .client()
-        .send_request(URL_PLATFORM_API, req)
-        .await?;
-    response_convert(resp)
+    let (account, session) = req.sticky_keys();
+    let usage = req.usage_tracker();
+    let rewrite = req.response_rewrite();
+    let ctx = context::get_instance();
+    let (client, route) = ctx.sticky_client_route(account.as_deref(), session.as_deref());
+    req.set_route(route);
+    let resp = match ctx.api_key_pool().filter(|_| req.uses_api_key_pool()) {
+        // Any valid ChatGPT token would spend the pooled keys otherwise
+        Some(_) if !req.has_client_key() => {
+            return Err(ResponseError::Forbidden(anyhow!(
+                "A client key is required to use the API key pool"
+            )))
+        }
+        Some(pool) => keypool::send_request(pool, &client, req).await?,
+        None => match req.coalesce_key().filter(|_| ctx.coalesce_requests()) {
+            Some(key) => coalesce::send_request(key, URL_PLATFORM_API, client, req).await?,
+            None => client.send_request(URL_PLATFORM_API, req).await?,
+        },
+    };
+    if let Some(account) = account.as_deref() {
+        ctx.record_account_response(account, resp.status().as_u16());
+    }
+    response_convert(resp, usage, None, None, rewrite, None)
 }
 
 /// reference: doc/http.rest
 async fn unofficial_proxy(
-    req: extract::RequestExtractor,
+    mut req: extract::RequestExtractor,
 ) -> Result<impl IntoResponse, ResponseError> {
-    let resp = context::get_instance()
-        .client()
-        .send_request(URL_CHATGPT_API, req)
-        .await?;
-    response_convert(resp)
+    let (account, session) = req.sticky_keys();
+    let ctx = context::get_instance();
+    let (client, route) = ctx.sticky_client_route(account.as_deref(), session.as_deref());
+    req.set_route(route);
+
+    if let Some((cache, account)) = ctx.response_cache().zip(account.clone()) {
+        if let Some(key) = req.response_cache_key() {
+            return responses::cached(cache, account, key, client, req)
+                .await
+                .map(IntoResponse::into_response);
+        }
+    }
+
+    let cache = ctx.conversation_cache().zip(account.clone());
+    let mut invalidate = None;
+    if let Some((cache, account)) = cache {
+        match req.conversation_cache_action() {
+            CacheAction::List(key) => {
+                return conversations::cached_list(cache, account, key, client, req)
+                    .await
+                    .map(IntoResponse::into_response);
+            }
+            CacheAction::Invalidate => {
+                cache.invalidate(&account);
+                invalidate = Some((cache, account));
+            }
+            CacheAction::Pass => {}
+        }
+    }
+
+    req.inject_system_prompt();
+    let usage = req.usage_tracker();
+    let cleanup = req.conversation_cleanup(&client);
+    let stop = req.stop_generation(&client);
+    let rewrite = req.response_rewrite();
+    let share = req.share_write();
+    let strip = req.event_strip();
+    let resp = match req.coalesce_key().filter(|_| ctx.coalesce_requests()) {
+        Some(key) => coalesce::send_request(key, URL_CHATGPT_API, client, req).await?,
+        None => client.send_request(URL_CHATGPT_API, req).await?,
+    };
+    if let Some(account) = account.as_deref() {
+        ctx.record_account_response(account, resp.status().as_u16());
+    }
+    let resp = match share {
+        Some(share) => {
+            share.invalidate();
+            share.rewrite(resp).await?
+        }
+        None => resp,
+    };
+    // Drop the lists fetched while the write was in flight
+    if let Some((cache, account)) = invalidate {
+        cache.invalidate(&account);
+    }
+    response_convert(resp, usage, cleanup, stop, rewrite, strip).map(IntoResponse::into_response)
 }
 
 pub(crate) async fn try_login(account: &axum::Form<AuthAccount>) -> anyhow::Result<AccessToken> {
     let ctx = context::get_instance();
-    ctx.auth_client().do_access_token(&account).await
+    if ctx.account_disabled(&account.username) {
+        anyhow::bail!("account {} is disabled", account.username)
+    }
+    let result = ctx
+        .sticky_auth_client(&account.username)
+        .do_access_token(&account)
+        .await;
+    match result {
+        Ok(_) => ctx.record_account(&account.username, None),
+        Err(ref err)
+            if matches!(
+                err.downcast_ref::<AuthError>(),
+                Some(AuthError::AccountDeactivated(_))
+            ) =>
+        {
+            ctx.record_account_failure(
+                &account.username,
+                AccountState::Deactivated,
+                err.to_string(),
+            );
+            webhook::fire(
+                webhook::Event::AccountDeactivated,
+                serde_json::json!({ "account": account.username, "error": err.to_string() }),
+            );
+        }
+        Err(ref err) => ctx.record_account_failure(
+            &account.username,
+            AccountState::LoginFailed,
+            err.to_string(),
+        ),
+    }
+    result
+}
+
+/// Log in an account managed through the admin API with its credential, returns its email
+pub(crate) async fn login_managed(
+    email: Option<&str>,
+    credential: Credential,
+) -> anyhow::Result<String> {
+    let ctx = context::get_instance();
+    let token = match &credential {
+        Credential::Password { password, option } => {
+            let email =
+                email.ok_or_else(|| anyhow!("email is required to log in with a password"))?;
+            let account = AuthAccount::builder()
+                .username(email.to_owned())
+                .password(password.to_owned())
+                .option(option.clone())
+                .build();
+            AuthenticateToken::try_from(try_login(&axum::Form(account)).await?)?
+        }
+        Credential::RefreshToken(refresh_token) => {
+            let result = ctx.auth_client().do_refresh_token(refresh_token).await;
+            if let (Some(email), Err(err)) = (email, result.as_ref()) {
+                ctx.record_account_failure(email, AccountState::LoginFailed, err.to_string());
+            }
+            AuthenticateToken::try_from(result?)?
+        }
+    };
+
+    let email = token.email().to_owned();
+    if ctx.account_disabled(&email) {
+        anyhow::bail!("account {email} is disabled")
+    }
+    // The refresh token may be rotated
+    let credential = match (credential, token.refresh_token()) {
+        (Credential::RefreshToken(_), Some(refresh_token)) if !refresh_token.is_empty() => {
+            Credential::RefreshToken(refresh_token.to_owned())
+        }
+        (credential, _) => credential,
+    };
+    ctx.manage_account(&email, credential, token);
+    Ok(email)
 }
 
 impl TryInto<Response<Body>> for SessionAccessToken {
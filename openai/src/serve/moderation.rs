@@ -0,0 +1,245 @@
+//! Content moderation, prompts are checked before they are sent upstream
+//! and outputs are redacted before they reach the client
+use std::sync::Arc;
+
+use axum::body::Bytes;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::error::ResponseError;
+use super::rewrite::BodyRewrite;
+use crate::{context, warn};
+
+/// Matched text is replaced with
+const REDACTED: &str = "***";
+
+/// Keys of the request body holding the prompt
+const PROMPT_KEYS: [&str; 3] = ["messages", "prompt", "input"];
+
+/// Keys of the message objects holding text
+const TEXT_KEYS: [&str; 3] = ["content", "parts", "text"];
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationAction {
+    /// Reject the request
+    #[default]
+    Reject,
+    /// Replace the matched text of the prompt
+    Redact,
+}
+
+impl std::str::FromStr for ModerationAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "redact" => Ok(Self::Redact),
+            _ => anyhow::bail!("moderation action: {} is not supported", s),
+        }
+    }
+}
+
+pub struct Moderation {
+    rules: Vec<Regex>,
+    /// OpenAI compatible moderations endpoint and its key
+    endpoint: Option<(String, Option<String>)>,
+    action: ModerationAction,
+}
+
+impl Moderation {
+    pub fn new(
+        rules: &[String],
+        endpoint: Option<String>,
+        key: Option<String>,
+        action: ModerationAction,
+    ) -> anyhow::Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                Regex::new(rule).map_err(|err| anyhow::anyhow!("moderation rule {rule}: {err}"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self {
+            rules,
+            endpoint: endpoint.map(|endpoint| (endpoint, key)),
+            action,
+        })
+    }
+
+    /// Number of the keyword rules
+    pub fn rules(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// OpenAI compatible moderations endpoint
+    pub fn endpoint(&self) -> Option<&str> {
+        self.endpoint
+            .as_ref()
+            .map(|(endpoint, _)| endpoint.as_str())
+    }
+
+    fn redact(&self, text: &str) -> Option<String> {
+        let mut redacted = None;
+        for rule in &self.rules {
+            let current = redacted.as_deref().unwrap_or(text);
+            if rule.is_match(current) {
+                redacted = Some(rule.replace_all(current, REDACTED).into_owned());
+            }
+        }
+        redacted
+    }
+
+    /// Check the prompt of the request body, the keyword rules reject or redact it
+    /// and the endpoint rejects flagged prompts. `true` if the body was redacted
+    pub(super) async fn check_request(&self, body: &mut Value) -> Result<bool, ResponseError> {
+        let mut texts = Vec::new();
+        if let Value::Object(map) = body {
+            for (key, value) in map.iter_mut() {
+                if PROMPT_KEYS.contains(&key.as_str()) {
+                    collect_texts(value, key != "messages", &mut texts);
+                }
+            }
+        }
+        if texts.is_empty() {
+            return Ok(false);
+        }
+
+        let mut redacted = false;
+        for text in texts.iter_mut() {
+            if let Some(replaced) = self.redact(text.as_str()) {
+                if self.action == ModerationAction::Reject {
+                    return Err(rejected());
+                }
+                **text = replaced;
+                redacted = true;
+            }
+        }
+
+        if let Some((endpoint, key)) = self.endpoint.as_ref() {
+            let input = texts
+                .iter()
+                .map(|text| text.as_str())
+                .collect::<Vec<_>>()
+                .join("\n");
+            if flagged(endpoint, key.as_deref(), &input).await? {
+                return Err(rejected());
+            }
+        }
+        Ok(redacted)
+    }
+
+    /// Output redaction with the keyword rules, `None` if there is none for the content type
+    pub(super) fn redactor(self: &Arc<Self>, content_type: Option<&str>) -> Option<Redactor> {
+        let content_type = content_type.unwrap_or_default();
+        let event_stream = content_type.starts_with("text/event-stream");
+        if self.rules.is_empty() || !(event_stream || content_type.contains("json")) {
+            return None;
+        }
+        Some(Redactor {
+            moderation: self.clone(),
+            event_stream,
+        })
+    }
+}
+
+fn rejected() -> ResponseError {
+    ResponseError::BadRequest(anyhow::anyhow!("Content rejected by moderation"))
+}
+
+/// Strings of the value, inside `TEXT_KEYS` of objects
+fn collect_texts<'a>(value: &'a mut Value, text: bool, texts: &mut Vec<&'a mut String>) {
+    match value {
+        Value::String(s) => {
+            if text {
+                texts.push(s)
+            }
+        }
+        Value::Array(values) => values
+            .iter_mut()
+            .for_each(|value| collect_texts(value, text, texts)),
+        Value::Object(map) => map.iter_mut().for_each(|(key, value)| {
+            collect_texts(value, TEXT_KEYS.contains(&key.as_str()), texts)
+        }),
+        _ => {}
+    }
+}
+
+/// Ask the moderations endpoint, the request is rejected when it fails
+async fn flagged(endpoint: &str, key: Option<&str>, input: &str) -> Result<bool, ResponseError> {
+    let mut builder = context::get_instance()
+        .client()
+        .post(endpoint)
+        .json(&serde_json::json!({ "input": input }));
+    if let Some(key) = key {
+        builder = builder.bearer_auth(key);
+    }
+    let value = builder
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .map_err(|err| {
+            warn!("Moderation endpoint error: {err}");
+            ResponseError::ServiceUnavailable(anyhow::anyhow!("Moderation is unavailable"))
+        })?
+        .json::<Value>()
+        .await
+        .map_err(ResponseError::ServiceUnavailable)?;
+    Ok(value["results"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .any(|result| result["flagged"] == true))
+}
+
+/// Redact every string of the JSON value, `true` if any was
+fn redact_value(moderation: &Moderation, value: &mut Value) -> bool {
+    match value {
+        Value::String(s) => match moderation.redact(s) {
+            Some(redacted) => {
+                *s = redacted;
+                true
+            }
+            None => false,
+        },
+        Value::Array(values) => values.iter_mut().fold(false, |redacted, value| {
+            redact_value(moderation, value) || redacted
+        }),
+        Value::Object(map) => map.values_mut().fold(false, |redacted, value| {
+            redact_value(moderation, value) || redacted
+        }),
+        _ => false,
+    }
+}
+
+/// Output redaction, content already sent can not be rejected
+pub(crate) struct Redactor {
+    moderation: Arc<Moderation>,
+    event_stream: bool,
+}
+
+impl BodyRewrite for Redactor {
+    fn event_stream(&self) -> bool {
+        self.event_stream
+    }
+
+    fn event(&mut self, data: &str) -> Option<String> {
+        if let Ok(mut value) = serde_json::from_str::<Value>(data) {
+            if redact_value(&self.moderation, &mut value) {
+                return Some(value.to_string());
+            }
+        }
+        Some(data.to_owned())
+    }
+
+    fn body(&mut self, body: &[u8]) -> Bytes {
+        if let Ok(mut value) = serde_json::from_slice::<Value>(body) {
+            if redact_value(&self.moderation, &mut value) {
+                return Bytes::from(value.to_string());
+            }
+        }
+        Bytes::copy_from_slice(body)
+    }
+}
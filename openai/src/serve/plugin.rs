@@ -0,0 +1,228 @@
+//! Rewrite plugins, Rhai scripts inspecting and changing proxied request and response bodies
+//!
+//! A script defines any of the functions below, each gets a map and returns the new body,
+//! or `()` to keep it unchanged. JSON bodies are passed as maps, other bodies as strings.
+//! - `on_request(req)`, `#{method, path, body}` of the request sent upstream
+//! - `on_response(resp)`, `#{method, path, status, body}` of a response that is not an event stream
+//! - `on_event(event)`, `#{method, path, status, data}` of each event stream `data:` line,
+//!   an empty string drops the event
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use axum::body::Bytes;
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::rewrite::BodyRewrite;
+use crate::warn;
+
+/// Operations a script may run per call, stops runaway loops
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+const ON_REQUEST: &str = "on_request";
+const ON_RESPONSE: &str = "on_response";
+const ON_EVENT: &str = "on_event";
+
+/// `[[plugin]]` entry of the plugins file
+#[derive(Deserialize, Clone, Debug)]
+pub struct PluginConfig {
+    /// Path prefix of the proxied requests, e.g. `/backend-api/conversation`
+    pub route: String,
+    /// Rhai script file
+    pub script: PathBuf,
+}
+
+struct Plugin {
+    route: String,
+    script: PathBuf,
+    ast: AST,
+}
+
+impl Plugin {
+    fn has(&self, name: &str) -> bool {
+        self.ast.iter_functions().any(|f| f.name == name)
+    }
+}
+
+pub struct Plugins {
+    engine: Engine,
+    plugins: Vec<Plugin>,
+}
+
+impl Plugins {
+    /// Compile the scripts, they run in the order of the file
+    pub fn new(configs: &[PluginConfig]) -> anyhow::Result<Self> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let plugins = configs
+            .iter()
+            .map(|config| {
+                let ast = engine
+                    .compile_file(config.script.clone())
+                    .map_err(|err| anyhow::anyhow!("plugin {}: {err}", config.script.display()))?;
+                Ok(Plugin {
+                    route: config.route.clone(),
+                    script: config.script.clone(),
+                    ast,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self { engine, plugins })
+    }
+
+    pub fn len(&self) -> usize {
+        self.plugins.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.plugins.is_empty()
+    }
+
+    /// Indexes of the plugins of the route with the function
+    fn matching(&self, path: &str, name: &str) -> Vec<usize> {
+        self.plugins
+            .iter()
+            .enumerate()
+            .filter(|(_, plugin)| path.starts_with(&plugin.route) && plugin.has(name))
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Call the function of the plugin with the value set at `key` of the map,
+    /// script errors are logged and keep the value unchanged
+    fn call(&self, index: usize, name: &str, mut arg: Map, key: &str, value: Value) -> Value {
+        let plugin = &self.plugins[index];
+        let result = rhai::serde::to_dynamic(&value).and_then(|dynamic| {
+            arg.insert(key.into(), dynamic);
+            let result =
+                self.engine
+                    .call_fn::<Dynamic>(&mut Scope::new(), &plugin.ast, name, (arg,))?;
+            if result.is_unit() {
+                return Ok(None);
+            }
+            rhai::serde::from_dynamic::<Value>(&result).map(Some)
+        });
+        match result {
+            Ok(result) => result.unwrap_or(value),
+            Err(err) => {
+                warn!("plugin {} {name}: {err}", plugin.script.display());
+                value
+            }
+        }
+    }
+
+    /// Run the `on_request` of the route, `None` if there is none
+    pub(super) fn rewrite_request(&self, method: &str, path: &str, body: &[u8]) -> Option<Bytes> {
+        let matched = self.matching(path, ON_REQUEST);
+        if matched.is_empty() {
+            return None;
+        }
+        let mut value = parse_body(body);
+        for index in matched {
+            value = self.call(index, ON_REQUEST, args(method, path, None), "body", value);
+        }
+        Some(into_body(value))
+    }
+
+    /// Response rewrite of the route, `None` if no plugin handles responses
+    pub(super) fn response_rewrite(
+        self: &Arc<Self>,
+        method: &str,
+        path: &str,
+    ) -> Option<ResponseRewrite> {
+        let on_response = self.matching(path, ON_RESPONSE);
+        let on_event = self.matching(path, ON_EVENT);
+        if on_response.is_empty() && on_event.is_empty() {
+            return None;
+        }
+        Some(ResponseRewrite {
+            plugins: self.clone(),
+            method: method.to_owned(),
+            path: path.to_owned(),
+            status: 0,
+            on_response,
+            on_event,
+            event_stream: false,
+        })
+    }
+}
+
+fn args(method: &str, path: &str, status: Option<u16>) -> Map {
+    let mut map = Map::new();
+    map.insert("method".into(), method.into());
+    map.insert("path".into(), path.into());
+    if let Some(status) = status {
+        map.insert("status".into(), (status as i64).into());
+    }
+    map
+}
+
+fn parse_body(body: &[u8]) -> Value {
+    serde_json::from_slice(body)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(body).into_owned()))
+}
+
+fn into_body(value: Value) -> Bytes {
+    match value {
+        Value::String(text) => Bytes::from(text),
+        value => Bytes::from(value.to_string()),
+    }
+}
+
+/// Rewrite of the response of a request, the events of an event stream one by one,
+/// other bodies once buffered
+pub(crate) struct ResponseRewrite {
+    plugins: Arc<Plugins>,
+    method: String,
+    path: String,
+    status: u16,
+    on_response: Vec<usize>,
+    on_event: Vec<usize>,
+    event_stream: bool,
+}
+
+impl ResponseRewrite {
+    /// `None` if no plugin handles the content type, the body is then passed through
+    pub(super) fn start(mut self, status: u16, content_type: Option<&str>) -> Option<Self> {
+        self.status = status;
+        self.event_stream = content_type
+            .map(|v| v.starts_with("text/event-stream"))
+            .unwrap_or_default();
+        let handled = if self.event_stream {
+            &self.on_event
+        } else {
+            &self.on_response
+        };
+        (!handled.is_empty()).then_some(self)
+    }
+}
+
+impl BodyRewrite for ResponseRewrite {
+    fn event_stream(&self) -> bool {
+        self.event_stream
+    }
+
+    fn event(&mut self, data: &str) -> Option<String> {
+        // `[DONE]` is passed as a string
+        let mut value = parse_body(data.as_bytes());
+        for &index in &self.on_event {
+            let arg = args(&self.method, &self.path, Some(self.status));
+            value = self.plugins.call(index, ON_EVENT, arg, "data", value);
+        }
+        match value {
+            Value::String(text) if text.is_empty() => None,
+            Value::String(text) => Some(text),
+            value => Some(value.to_string()),
+        }
+    }
+
+    fn body(&mut self, body: &[u8]) -> Bytes {
+        let mut value = parse_body(body);
+        for &index in &self.on_response {
+            let arg = args(&self.method, &self.path, Some(self.status));
+            value = self.plugins.call(index, ON_RESPONSE, arg, "body", value);
+        }
+        into_body(value)
+    }
+}
@@ -0,0 +1,48 @@
+//! Accounts managed through the admin API handed out to the users of the single sign-on
+//! logins (OIDC, LDAP), who get a session of an account without ever seeing its credential
+use anyhow::anyhow;
+use sha2::{Digest, Sha256};
+
+use crate::context::{self, AccountState};
+use crate::now_duration;
+use crate::token::model::AuthenticateToken;
+
+/// Managed account of the user, the same one as long as the pool does not change,
+/// `among` restricts the pool to the given accounts
+pub(crate) fn pooled_account(user: &str, among: Option<&[String]>) -> Option<String> {
+    let mut accounts = context::get_instance()
+        .accounts()
+        .into_iter()
+        .filter(|account| account.managed && account.state == AccountState::Active)
+        .map(|account| account.email)
+        .filter(|email| among.map_or(true, |among| among.contains(email)))
+        .collect::<Vec<_>>();
+    if accounts.is_empty() {
+        return None;
+    }
+    accounts.sort();
+    let digest = Sha256::digest(user.as_bytes());
+    let hash = u64::from_be_bytes(digest[..8].try_into().ok()?);
+    accounts
+        .get((hash % accounts.len() as u64) as usize)
+        .cloned()
+}
+
+/// Token of the managed account, logged in again with its credential when it is about to expire
+pub(crate) async fn pooled_token(account: &str) -> anyhow::Result<AuthenticateToken> {
+    let ctx = context::get_instance();
+    let managed = ctx
+        .managed_account(account)
+        .ok_or_else(|| anyhow!("account {account} is not managed"))?;
+    let now = now_duration()?.as_secs() as i64;
+    let refresh_window = ctx.session_refresh_window() as i64;
+    match managed.token {
+        Some(token) if token.expires() - now > refresh_window => Ok(token),
+        _ => {
+            super::login_managed(Some(account), managed.credential).await?;
+            ctx.managed_account(account)
+                .and_then(|managed| managed.token)
+                .ok_or_else(|| anyhow!("account {account} has no token"))
+        }
+    }
+}
@@ -1,25 +1,151 @@
+use base64::Engine;
 use rcgen::Certificate;
 
-use crate::{error, serve::preauth::proxy::CertificateAuthority};
+use crate::{info, serve::preauth::proxy::CertificateAuthority};
 
-use std::fs;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
-pub fn gen_ca() -> Certificate {
-    let cert = CertificateAuthority::gen_ca().expect("preauth generate cert");
-    let cert_crt = cert.serialize_pem().unwrap();
+const CERT_FILE: &str = "cert.crt";
+const KEY_FILE: &str = "key.pem";
 
-    fs::create_dir("ca").unwrap();
+/// CA certificate export format
+#[derive(Clone, Copy, Debug)]
+pub enum CaFormat {
+    Pem,
+    Der,
+    /// Apple configuration profile
+    Mobileconfig,
+}
 
-    println!("{}", cert_crt);
-    if let Err(err) = fs::write("ca/cert.crt", cert_crt) {
-        error!("cert file write failed: {}", err);
+impl CaFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            CaFormat::Pem => "crt",
+            CaFormat::Der => "cer",
+            CaFormat::Mobileconfig => "mobileconfig",
+        }
+    }
+}
+
+impl FromStr for CaFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pem" => Ok(CaFormat::Pem),
+            "der" => Ok(CaFormat::Der),
+            "mobileconfig" => Ok(CaFormat::Mobileconfig),
+            _ => anyhow::bail!("Unsupported CA format: {s}"),
+        }
+    }
+}
+
+/// Generate the MITM CA certificate and private key into `dir`.
+///
+/// Existing files are kept unless `rotate` is set, in which case they are
+/// renamed with a timestamp suffix before the new CA is written.
+pub fn gen_ca(dir: &Path, rotate: bool) -> anyhow::Result<Certificate> {
+    let cert_path = dir.join(CERT_FILE);
+    let key_path = dir.join(KEY_FILE);
+
+    if cert_path.exists() || key_path.exists() {
+        if !rotate {
+            anyhow::bail!(
+                "CA already exists in {}, use --rotate to replace it",
+                dir.display()
+            )
+        }
+        let suffix = crate::now_duration()?.as_secs();
+        for path in [&cert_path, &key_path] {
+            if path.exists() {
+                let backup = PathBuf::from(format!("{}.{suffix}.bak", path.display()));
+                fs::rename(path, &backup)?;
+                info!("Backup {} to {}", path.display(), backup.display());
+            }
+        }
     }
 
+    let cert = CertificateAuthority::gen_ca()?;
+    fs::create_dir_all(dir)?;
+
+    let cert_crt = cert.serialize_pem()?;
+    println!("{}", cert_crt);
+    fs::write(&cert_path, cert_crt)?;
+
     let private_key = cert.serialize_private_key_pem();
     println!("{}", private_key);
-    if let Err(err) = fs::write("ca/key.pem", private_key) {
-        error!("private key file write failed: {}", err);
-    }
+    fs::write(&key_path, private_key)?;
+
+    Ok(cert)
+}
+
+/// Export the MITM CA certificate in the given format.
+///
+/// Writes to `out`, or next to the certificate with the format extension.
+pub fn export_ca(cert: &Path, format: CaFormat, out: Option<PathBuf>) -> anyhow::Result<PathBuf> {
+    let pem = fs::read(cert)?;
+    let der = rustls_pemfile::certs(&mut pem.as_slice())?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("No certificate found in {}", cert.display()))?;
+
+    let data = match format {
+        CaFormat::Pem => pem,
+        CaFormat::Der => der,
+        CaFormat::Mobileconfig => mobileconfig(&der).into_bytes(),
+    };
+
+    let out = out.unwrap_or_else(|| cert.with_extension(format.extension()));
+    fs::write(&out, data)?;
+    println!("Export CA certificate to {}", out.display());
+    Ok(out)
+}
 
-    cert
+/// Build an Apple configuration profile that installs the root certificate
+fn mobileconfig(der: &[u8]) -> String {
+    let payload = base64::engine::general_purpose::STANDARD.encode(der);
+    let cert_uuid = crate::uuid::uuid().to_uppercase();
+    let profile_uuid = crate::uuid::uuid().to_uppercase();
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>PayloadContent</key>
+    <array>
+        <dict>
+            <key>PayloadCertificateFileName</key>
+            <string>{CERT_FILE}</string>
+            <key>PayloadContent</key>
+            <data>{payload}</data>
+            <key>PayloadDisplayName</key>
+            <string>PreAuth-MITM</string>
+            <key>PayloadIdentifier</key>
+            <string>com.ninja.preauth.cert.{cert_uuid}</string>
+            <key>PayloadType</key>
+            <string>com.apple.security.root</string>
+            <key>PayloadUUID</key>
+            <string>{cert_uuid}</string>
+            <key>PayloadVersion</key>
+            <integer>1</integer>
+        </dict>
+    </array>
+    <key>PayloadDisplayName</key>
+    <string>PreAuth-MITM CA</string>
+    <key>PayloadIdentifier</key>
+    <string>com.ninja.preauth.{profile_uuid}</string>
+    <key>PayloadType</key>
+    <string>Configuration</string>
+    <key>PayloadUUID</key>
+    <string>{profile_uuid}</string>
+    <key>PayloadVersion</key>
+    <integer>1</integer>
+</dict>
+</plist>
+"#
+    )
 }
@@ -1,4 +1,4 @@
-use std::{fs, net::SocketAddr, path::PathBuf};
+use std::{fs, net::SocketAddr, path::PathBuf, time::Duration};
 
 use anyhow::Context;
 use http::{header, Request, Response};
@@ -14,9 +14,13 @@ use log::info;
 pub mod cagen;
 mod proxy;
 
+/// Preauth cookie pool check interval
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
 pub(super) async fn mitm_proxy(
     bind: SocketAddr,
-    upstream_proxy: Option<String>,
+    upstream_proxies: Vec<String>,
+    filters: Vec<String>,
     cert: PathBuf,
     key: PathBuf,
     graceful_shutdown: tokio::sync::mpsc::Receiver<()>,
@@ -42,22 +46,33 @@ pub(super) async fn mitm_proxy(
     .context("Failed to create Certificate Authority")?;
 
     info!("PreAuth Http MITM Proxy listen on: http://{bind}");
+    info!("PreAuth Http MITM Proxy intercept: {}", filters.join(", "));
 
     let http_handler = PreAuthHanlder;
 
     let proxy = proxy::Proxy::builder()
         .ca(ca.clone())
         .listen_addr(bind)
-        .upstream_proxy(upstream_proxy)
-        .mitm_filters(vec![String::from("ios.chat.openai.com")])
+        .upstream_proxies(upstream_proxies)
+        .mitm_filters(filters)
         .handler(http_handler.clone())
         .graceful_shutdown(graceful_shutdown)
         .build();
 
-    tokio::spawn(proxy.start_proxy());
+    crate::context::spawn(proxy.start_proxy());
+    crate::context::spawn(check_preauth_cookies());
     Ok(())
 }
 
+/// Periodically check the preauth cookie pool
+async fn check_preauth_cookies() {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        context::get_instance().check_preauth_cookies();
+    }
+}
+
 #[derive(Clone)]
 struct PreAuthHanlder;
 
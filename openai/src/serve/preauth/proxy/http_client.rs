@@ -1,18 +1,109 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
 use http::{response::Builder, Request, Response};
 use hyper::Body;
 use reqwest::impersonate::Impersonate;
 
 use super::error::Error;
+use crate::balancer::mask_proxy_url;
+use crate::{info, warn};
+
+/// Upstream proxy health check interval
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Upstream proxy health check url
+const HEALTH_CHECK_URL: &str = "https://ios.chat.openai.com";
+
+struct Upstream {
+    proxy: Option<String>,
+    client: HttpClient,
+    healthy: AtomicBool,
+}
+
+/// Upstream proxies, rotated per connection
+pub struct UpstreamPool {
+    upstreams: Vec<Upstream>,
+    index: AtomicUsize,
+}
+
+impl UpstreamPool {
+    pub fn new(proxies: Vec<String>) -> Self {
+        let proxies = if proxies.is_empty() {
+            vec![None]
+        } else {
+            proxies.into_iter().map(Some).collect()
+        };
+        let upstreams = proxies
+            .into_iter()
+            .map(|proxy| Upstream {
+                client: HttpClient::new(proxy.clone()),
+                proxy,
+                healthy: AtomicBool::new(true),
+            })
+            .collect();
+        Self {
+            upstreams,
+            index: AtomicUsize::new(0),
+        }
+    }
+
+    /// Pick the next healthy upstream client, falls back to round-robin when all are unhealthy
+    pub fn next(&self) -> HttpClient {
+        let len = self.upstreams.len();
+        let start = self.index.fetch_add(1, Ordering::Relaxed);
+        (0..len)
+            .map(|i| &self.upstreams[(start + i) % len])
+            .find(|upstream| upstream.healthy.load(Ordering::Relaxed))
+            .unwrap_or(&self.upstreams[start % len])
+            .client
+            .clone()
+    }
+
+    /// Periodically check the upstream proxies
+    pub async fn health_check(self: Arc<Self>) {
+        if self.upstreams.len() < 2 {
+            return;
+        }
+        let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            for upstream in self.upstreams.iter() {
+                let healthy = upstream
+                    .client
+                    .inner
+                    .head(HEALTH_CHECK_URL)
+                    .timeout(Duration::from_secs(10))
+                    .send()
+                    .await
+                    .is_ok();
+                let proxy = mask_proxy_url(upstream.proxy.as_deref().unwrap_or_default());
+                if upstream.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+                    match healthy {
+                        true => info!("PreAuth upstream proxy {proxy} recovered"),
+                        false => warn!("PreAuth upstream proxy {proxy} is unhealthy"),
+                    }
+                }
+            }
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct HttpClient {
     inner: reqwest::Client,
+    proxy: Option<String>,
 }
 
 impl HttpClient {
     pub fn new(proxy: Option<String>) -> Self {
         let mut builder = reqwest::Client::builder();
-        if let Some(p) = proxy {
+        if let Some(p) = proxy.as_deref() {
             builder = builder.proxy(reqwest::Proxy::all(p).expect("faild build proxy"));
         }
         let inner = builder
@@ -21,7 +112,12 @@ impl HttpClient {
             .danger_accept_invalid_certs(true)
             .build()
             .expect("faild build reqwest client");
-        Self { inner }
+        Self { inner, proxy }
+    }
+
+    /// Upstream proxy of the client, the WebSocket tunnels go through it as well
+    pub(super) fn proxy(&self) -> Option<&str> {
+        self.proxy.as_deref()
     }
 
     pub(super) async fn request(&self, req: Request<Body>) -> Result<Response<Body>, Error> {
@@ -6,6 +6,7 @@ use super::{
         read_sni_host_name_from_client_hello, HandshakeRecordReader, PrefixedReaderWriter,
         RecordingBufReader,
     },
+    websocket,
 };
 use http::{header, uri::Scheme, HeaderValue, Uri};
 use hyper::{
@@ -98,6 +99,20 @@ where
             RequestOrResponse::Response(res) => return Ok(res),
         };
 
+        // Tunnel WebSocket upgrades as-is
+        if websocket::is_websocket(&req) {
+            return match websocket::passthrough(req, self.client.proxy()).await {
+                Ok(res) => Ok(res),
+                Err(err) => {
+                    warn!("websocket passthrough failed: {err:?}");
+                    Ok(Response::builder()
+                        .status(http::StatusCode::BAD_GATEWAY)
+                        .body(Body::empty())
+                        .expect("failed build response"))
+                }
+            };
+        }
+
         {
             let header_mut = req.headers_mut();
             header_mut.remove(http::header::HOST);
@@ -137,7 +152,7 @@ where
     async fn process_connect(self, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
         // Filter mitm
         if self.mitm_filter.filter_req(&req).await {
-            tokio::task::spawn(async move {
+            crate::context::spawn(async move {
                 let authority = req
                     .uri()
                     .authority()
@@ -152,7 +167,7 @@ where
                 };
             });
         } else {
-            tokio::task::spawn(async move {
+            crate::context::spawn(async move {
                 let remote_addr = host_addr(req.uri()).unwrap();
                 match hyper::upgrade::on(req).await {
                     Ok(upgraded) => {
@@ -198,7 +213,7 @@ where
         // If the hostname is not in the filter list, then just tunnel the connection.
         if !self.mitm_filter.filter(&sni_hostname).await {
             let remote_addr = format!("{sni_hostname}:443");
-            tokio::task::spawn(async move { tunnel(client_stream, remote_addr).await });
+            crate::context::spawn(async move { tunnel(client_stream, remote_addr).await });
             return;
         }
 
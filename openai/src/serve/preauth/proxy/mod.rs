@@ -12,7 +12,7 @@ pub use tokio_rustls;
 
 use crate::info;
 
-use self::http_client::HttpClient;
+use self::http_client::UpstreamPool;
 
 mod ca;
 mod error;
@@ -20,6 +20,7 @@ pub mod handler;
 mod http_client;
 pub mod mitm;
 mod sni_reader;
+mod websocket;
 
 #[derive(TypedBuilder)]
 pub struct Proxy<H>
@@ -31,7 +32,7 @@ where
     /// A future that once resolved will cause the proxy server to shut down.
     /// The certificate authority to use.
     pub ca: CertificateAuthority,
-    pub upstream_proxy: Option<String>,
+    pub upstream_proxies: Vec<String>,
     pub mitm_filters: Vec<String>,
     pub handler: H,
     graceful_shutdown: tokio::sync::mpsc::Receiver<()>,
@@ -42,14 +43,15 @@ where
     H: HttpHandler,
 {
     pub async fn start_proxy(mut self) -> Result<(), Error> {
-        let client = HttpClient::new(self.upstream_proxy);
+        let upstream = Arc::new(UpstreamPool::new(self.upstream_proxies));
+        crate::context::spawn(upstream.clone().health_check());
         let ca = Arc::new(self.ca);
         let http_handler = Arc::new(self.handler);
         let mitm_filter = Arc::new(MitmFilter::new(self.mitm_filters));
 
         let tcp_listener = TcpListener::bind(self.listen_addr).await?;
         loop {
-            let client = client.clone();
+            let upstream = Arc::clone(&upstream);
             let ca = Arc::clone(&ca);
             let http_handler = Arc::clone(&http_handler);
             let mitm_filter = Arc::clone(&mitm_filter);
@@ -61,10 +63,10 @@ where
                 }
 
                 Ok((tcp_stream, _)) = tcp_listener.accept() => {
-                    tokio::spawn(async move {
+                    crate::context::spawn(async move {
                         let mitm_proxy = MitmProxy {
                             ca: ca.clone(),
-                            client: client.clone(),
+                            client: upstream.next(),
                             http_handler: Arc::clone(&http_handler),
                             mitm_filter: Arc::clone(&mitm_filter),
                         };
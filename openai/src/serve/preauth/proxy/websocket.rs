@@ -0,0 +1,337 @@
+use std::{io, net::SocketAddr, sync::Arc, time::SystemTime};
+
+use base64::Engine;
+use http::{header, uri::Scheme, HeaderName, HeaderValue, Request, Response, StatusCode};
+use hyper::{upgrade::OnUpgrade, Body};
+use log::*;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    rustls::{self, client::ServerCertVerified, Certificate, ServerName},
+    TlsConnector,
+};
+
+use super::error::Error;
+
+/// Maximum size of the upstream handshake response head
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// Check if the request is a WebSocket upgrade
+pub(super) fn is_websocket<B>(req: &Request<B>) -> bool {
+    req.headers()
+        .get(header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+}
+
+/// Stream of the upstream connection, direct or through the upstream proxy
+trait Upstream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Upstream for T {}
+
+/// Forward the WebSocket handshake upstream and tunnel the upgraded connection.
+///
+/// The upstream connection goes through the upstream proxy of the connection, if any.
+pub(super) async fn passthrough(
+    mut req: Request<Body>,
+    proxy: Option<&str>,
+) -> Result<Response<Body>, Error> {
+    let uri = req.uri().clone();
+    let host = uri
+        .host()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "missing host"))?
+        .to_owned();
+    let tls = uri.scheme() == Some(&Scheme::HTTPS);
+    let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+
+    // Serialize the request head, keep the upgrade headers
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let mut head = format!("{} {path} HTTP/1.1\r\nhost: {host}\r\n", req.method());
+    for (name, value) in req.headers().iter().filter(|(k, _)| k.ne(&header::HOST)) {
+        head.push_str(&format!(
+            "{name}: {}\r\n",
+            String::from_utf8_lossy(value.as_bytes())
+        ));
+    }
+    head.push_str("\r\n");
+
+    let client = hyper::upgrade::on(&mut req);
+    let tcp: Box<dyn Upstream> = match proxy {
+        Some(proxy) => connect_proxy(proxy, &host, port).await?,
+        None => Box::new(TcpStream::connect((host.as_str(), port)).await?),
+    };
+
+    if tls {
+        let server_name = ServerName::try_from(host.as_str())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let stream = connector().connect(server_name, tcp).await?;
+        handshake(stream, head, client).await
+    } else {
+        handshake(tcp, head, client).await
+    }
+}
+
+/// Connect to the host through the http(s) or socks5(h) proxy
+async fn connect_proxy(proxy: &str, host: &str, port: u16) -> io::Result<Box<dyn Upstream>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, msg.to_owned());
+    let url = url::Url::parse(proxy).map_err(|_| invalid("invalid upstream proxy"))?;
+    let proxy_host = url
+        .host_str()
+        .ok_or_else(|| invalid("missing upstream proxy host"))?;
+    let proxy_port = url
+        .port_or_known_default()
+        .unwrap_or(if url.scheme().starts_with("socks") {
+            1080
+        } else {
+            80
+        });
+    let credential = (!url.username().is_empty()).then(|| {
+        (
+            percent_decode(url.username()),
+            percent_decode(url.password().unwrap_or_default()),
+        )
+    });
+    let tcp = TcpStream::connect((proxy_host, proxy_port)).await?;
+
+    match url.scheme() {
+        "http" => Ok(Box::new(http_connect(tcp, host, port, credential).await?)),
+        "https" => {
+            let server_name = ServerName::try_from(proxy_host)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            let tls = connector().connect(server_name, tcp).await?;
+            Ok(Box::new(http_connect(tls, host, port, credential).await?))
+        }
+        "socks5" | "socks5h" => {
+            // socks5 resolves the host locally, socks5h leaves it to the proxy
+            let addr = match url.scheme() {
+                "socks5" => tokio::net::lookup_host((host, port)).await?.next(),
+                _ => None,
+            };
+            Ok(Box::new(
+                socks5_connect(tcp, host, port, addr, credential).await?,
+            ))
+        }
+        scheme => Err(invalid(&format!("unsupported upstream proxy: {scheme}"))),
+    }
+}
+
+/// Decode the percent encoded userinfo of the proxy url, a `+` stays as is
+fn percent_decode(s: &str) -> String {
+    url::form_urlencoded::parse(format!("v={}", s.replace('+', "%2B")).as_bytes())
+        .next()
+        .map(|(_, v)| v.into_owned())
+        .unwrap_or_default()
+}
+
+/// Open a tunnel to the host with an HTTP CONNECT request
+async fn http_connect<S>(
+    mut stream: S,
+    host: &str,
+    port: u16,
+    credential: Option<(String, String)>,
+) -> io::Result<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut head = format!("CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n");
+    if let Some((user, password)) = credential {
+        let basic = base64::engine::general_purpose::STANDARD.encode(format!("{user}:{password}"));
+        head.push_str(&format!("Proxy-Authorization: Basic {basic}\r\n"));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+
+    // Read the response head byte by byte, the tunnel data follows it
+    let mut buf = Vec::with_capacity(256);
+    while !buf.ends_with(b"\r\n\r\n") {
+        if buf.len() > MAX_HEAD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "proxy response head too large",
+            ));
+        }
+        buf.push(stream.read_u8().await?);
+    }
+    let status = String::from_utf8_lossy(&buf)
+        .split_whitespace()
+        .nth(1)
+        .map(ToOwned::to_owned)
+        .unwrap_or_default();
+    if status != "200" {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("upstream proxy CONNECT answered {status}"),
+        ));
+    }
+    Ok(stream)
+}
+
+/// Open a tunnel to the host with a SOCKS5 CONNECT, to the resolved address if given
+async fn socks5_connect(
+    mut stream: TcpStream,
+    host: &str,
+    port: u16,
+    addr: Option<SocketAddr>,
+    credential: Option<(String, String)>,
+) -> io::Result<TcpStream> {
+    let failed = |msg: &str| io::Error::new(io::ErrorKind::ConnectionRefused, msg.to_owned());
+
+    // Greeting, no authentication or username/password
+    let method = if credential.is_some() { 0x02 } else { 0x00 };
+    stream.write_all(&[0x05, 0x01, method]).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply != [0x05, method] {
+        return Err(failed(
+            "upstream socks5 proxy refused the authentication method",
+        ));
+    }
+    if let Some((user, password)) = credential {
+        let mut auth = vec![0x01, user.len() as u8];
+        auth.extend_from_slice(user.as_bytes());
+        auth.push(password.len() as u8);
+        auth.extend_from_slice(password.as_bytes());
+        stream.write_all(&auth).await?;
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0x00 {
+            return Err(failed("upstream socks5 proxy refused the credentials"));
+        }
+    }
+
+    // Connect request
+    let mut request = vec![0x05, 0x01, 0x00];
+    match addr {
+        Some(SocketAddr::V4(addr)) => {
+            request.push(0x01);
+            request.extend_from_slice(&addr.ip().octets());
+        }
+        Some(SocketAddr::V6(addr)) => {
+            request.push(0x04);
+            request.extend_from_slice(&addr.ip().octets());
+        }
+        None => {
+            request.extend_from_slice(&[0x03, host.len() as u8]);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0x00 {
+        return Err(failed("upstream socks5 proxy refused the connection"));
+    }
+    // Skip the bound address
+    let len = match reply[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => stream.read_u8().await? as usize,
+        _ => return Err(failed("invalid upstream socks5 reply")),
+    };
+    let mut bound = vec![0u8; len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(stream)
+}
+
+async fn handshake<S>(
+    mut upstream: S,
+    head: String,
+    client: OnUpgrade,
+) -> Result<Response<Body>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    upstream.write_all(head.as_bytes()).await?;
+
+    // Read the response head
+    let mut buf = Vec::with_capacity(1024);
+    let head_len = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if buf.len() > MAX_HEAD_SIZE {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidData, "response head too large").into(),
+            );
+        }
+        let mut chunk = [0u8; 1024];
+        let n = upstream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "upstream closed").into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let (res_head, leftover) = buf.split_at(head_len);
+    let res_head = String::from_utf8_lossy(res_head);
+    let mut lines = res_head.split("\r\n");
+
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid status line"))?;
+
+    let mut builder = Response::builder().status(status);
+    for (name, value) in lines.filter_map(|line| line.split_once(':')) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.trim().as_bytes()),
+            HeaderValue::from_str(value.trim()),
+        ) {
+            builder = builder.header(name, value);
+        }
+    }
+
+    if status != StatusCode::SWITCHING_PROTOCOLS {
+        return Ok(builder.body(Body::from(leftover.to_vec()))?);
+    }
+
+    let leftover = leftover.to_vec();
+    crate::context::spawn(async move {
+        match client.await {
+            Ok(mut client) => {
+                if !leftover.is_empty() {
+                    if let Err(err) = client.write_all(&leftover).await {
+                        debug!("websocket write error: {err}");
+                        return;
+                    }
+                }
+                if let Err(err) = tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+                    debug!("websocket tunnel error: {err}");
+                }
+            }
+            Err(err) => warn!("websocket upgrade error: {err}"),
+        }
+    });
+
+    Ok(builder.body(Body::empty())?)
+}
+
+/// Upstream TLS connector, certificates are not verified like the http client
+fn connector() -> TlsConnector {
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(NoVerifier))
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+struct NoVerifier;
+
+impl rustls::client::ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
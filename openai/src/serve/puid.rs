@@ -4,9 +4,19 @@ use super::error::ResponseError;
 use crate::arkose::GPTModel;
 use crate::{context, URL_CHATGPT_API};
 use moka::sync::Cache;
-use tokio::sync::OnceCell;
 
-static PUID_CACHE: OnceCell<Cache<String, String>> = OnceCell::const_new();
+/// `_puid` cookies of the accounts of an instance
+pub(crate) struct PuidCache(Cache<String, String>);
+
+impl Default for PuidCache {
+    fn default() -> Self {
+        Self(
+            Cache::builder()
+                .time_to_live(std::time::Duration::from_secs(3600 * 24))
+                .build(),
+        )
+    }
+}
 
 pub(super) fn reduce_cache_key(token: &str) -> Result<String, ResponseError> {
     let token_profile = crate::token::check(token)
@@ -17,14 +27,8 @@ pub(super) fn reduce_cache_key(token: &str) -> Result<String, ResponseError> {
     Ok(token_profile.email().to_owned())
 }
 
-async fn puid_cache() -> &'static Cache<String, String> {
-    PUID_CACHE
-        .get_or_init(|| async {
-            Cache::builder()
-                .time_to_live(std::time::Duration::from_secs(3600 * 24))
-                .build()
-        })
-        .await
+fn puid_cache() -> Cache<String, String> {
+    context::get_instance().puid_cache().0.clone()
 }
 
 pub(super) async fn get_or_init_puid(
@@ -35,13 +39,17 @@ pub(super) async fn get_or_init_puid(
     let token = token.trim_start_matches("Bearer ");
     let mut m_puid = None;
     if GPTModel::from_str(model)?.is_gpt4() {
-        let puid_cache = puid_cache().await;
+        let puid_cache = puid_cache();
         if let Some(puid) = puid_cache.get(&cache_id) {
             m_puid = Some(puid);
         } else {
-            let resp = context::get_instance()
-                .client()
-                .get(format!("{URL_CHATGPT_API}/backend-api/models"))
+            let ctx = context::get_instance();
+            let resp = ctx
+                .sticky_client(Some(&cache_id), None)
+                .get(format!(
+                    "{}/backend-api/models",
+                    ctx.upstream_origin(URL_CHATGPT_API)
+                ))
                 .bearer_auth(token)
                 .send()
                 .await
@@ -51,9 +59,15 @@ pub(super) async fn get_or_init_puid(
 
             if let Some(c) = resp.cookies().into_iter().find(|c| c.name().eq("_puid")) {
                 m_puid = Some(c.value().to_owned());
+                context::get_instance().record_puid_refresh(&cache_id);
                 puid_cache.insert(cache_id, m_puid.clone().expect("puid is none"));
             };
         }
     }
     Ok(m_puid)
 }
+
+/// Drop the cached PUID of the account
+pub(super) async fn invalidate_puid(cache_id: &str) {
+    puid_cache().invalidate(cache_id);
+}
@@ -0,0 +1,123 @@
+use moka::sync::Cache;
+
+use crate::arkose::GPTModel;
+use crate::chatgpt::api::ChatGPTBuilder;
+use crate::chatgpt::model::resp::ChatRequirementsResponse;
+use crate::{context, warn, URL_CHATGPT_API};
+
+/// Chat requirements of the accounts of an instance
+pub(crate) struct RequirementsCache(Cache<String, Requirements>);
+
+impl Default for RequirementsCache {
+    fn default() -> Self {
+        Self(
+            Cache::builder()
+                .time_to_live(std::time::Duration::from_secs(3600))
+                .build(),
+        )
+    }
+}
+
+/// Cached chat requirements of an account
+#[derive(Clone, Copy)]
+pub(crate) struct Requirements {
+    pub(super) arkose: bool,
+    pub(super) proofofwork: bool,
+}
+
+impl Requirements {
+    /// Hardcoded fallback when the requirements endpoint can not be probed
+    fn fallback(model: &GPTModel) -> Self {
+        Self {
+            arkose: (context::get_instance().arkose_gpt3_experiment() && model.is_gpt3())
+                || model.is_gpt4(),
+            proofofwork: false,
+        }
+    }
+}
+
+fn requirements_cache() -> Cache<String, Requirements> {
+    context::get_instance().requirements_cache().0.clone()
+}
+
+/// Request chat requirements from upstream, through the sticky client of the account
+pub(super) async fn chat_requirements(token: &str) -> anyhow::Result<ChatRequirementsResponse> {
+    let account = super::puid::reduce_cache_key(token).ok();
+    let ctx = context::get_instance();
+    let api = ChatGPTBuilder::builder()
+        .client(ctx.sticky_client(account.as_deref(), None))
+        .api_prefix(format!(
+            "{}/backend-api",
+            ctx.upstream_origin(URL_CHATGPT_API)
+        ))
+        .access_token(token.trim_start_matches("Bearer ").to_owned())
+        .build();
+    Ok(api.post_chat_requirements().await?)
+}
+
+/// Get or probe the chat requirements of the account, the probe does not depend on the
+/// model so they are cached per account, the model only picks the fallback
+pub(super) async fn get_or_init_requirements(
+    token: &str,
+    model: &GPTModel,
+    cache_id: &str,
+) -> Requirements {
+    let cache = requirements_cache();
+    if let Some(requirements) = cache.get(cache_id) {
+        return requirements;
+    }
+
+    match chat_requirements(token).await {
+        Ok(resp) => {
+            let requirements = Requirements {
+                arkose: resp.arkose.required,
+                proofofwork: resp.proofofwork.required,
+            };
+            cache.insert(cache_id.to_owned(), requirements);
+            requirements
+        }
+        Err(err) => {
+            warn!("Probe chat requirements error: {err}");
+            Requirements::fallback(model)
+        }
+    }
+}
+
+/// Drop the cached requirements of the account, the upstream rejected its tokens
+pub(super) fn invalidate_requirements(cache_id: &str) {
+    requirements_cache().invalidate(cache_id);
+}
+
+/// Sentinel tokens attached to a conversation request
+pub(super) struct SentinelTokens {
+    pub(super) requirements: String,
+    pub(super) proof: Option<String>,
+}
+
+/// Request chat requirements and compute the proof-of-work token if required, the
+/// requirements are cached so the arkose check of the same request does not probe again
+pub(super) async fn sentinel_tokens(
+    token: &str,
+    cache_id: &str,
+    user_agent: &str,
+) -> anyhow::Result<SentinelTokens> {
+    let resp = chat_requirements(token).await?;
+    requirements_cache().insert(
+        cache_id.to_owned(),
+        Requirements {
+            arkose: resp.arkose.required,
+            proofofwork: resp.proofofwork.required,
+        },
+    );
+    let pow = resp.proofofwork;
+    let proof = match (pow.required, pow.seed, pow.difficulty) {
+        (true, Some(seed), Some(difficulty)) => {
+            Some(crate::pow::generate_blocking(seed, difficulty, user_agent.to_owned()).await?)
+        }
+        _ => None,
+    };
+    Ok(SentinelTokens {
+        requirements: resp.token,
+        proof,
+    })
+}
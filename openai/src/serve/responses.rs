@@ -0,0 +1,111 @@
+//! Response cache of the upstream endpoints the clients poll constantly, the model list
+//! and the account check rarely change, so they are answered per account for a short ttl
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use axum::body::{Body, Bytes};
+use axum::http::{header, Method, Response, StatusCode, Uri};
+
+use super::conversations::HEADER_CACHE;
+use super::error::ResponseError;
+use super::extract::{RequestExtractor, SendRequestExt};
+use crate::URL_CHATGPT_API;
+
+/// Account and path with query -> fetched time and body
+pub struct ResponseCache {
+    ttl: Duration,
+    responses: RwLock<HashMap<(String, String), (Instant, Bytes)>>,
+}
+
+impl ResponseCache {
+    pub fn new(ttl: u64) -> Self {
+        Self {
+            ttl: Duration::from_secs(ttl),
+            responses: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Key of a cached request: `GET /backend-api/models` and `GET /backend-api/accounts/check`
+    pub(super) fn key(method: &Method, uri: &Uri) -> Option<String> {
+        if method != Method::GET {
+            return None;
+        }
+        let path = uri.path().trim_end_matches('/');
+        // e.g. `/backend-api/accounts/check/v4-2023-04-27`
+        let cached = path == "/backend-api/models"
+            || path == "/backend-api/accounts/check"
+            || path.starts_with("/backend-api/accounts/check/");
+        cached.then(|| {
+            uri.path_and_query()
+                .map(|v| v.as_str())
+                .unwrap_or(path)
+                .to_owned()
+        })
+    }
+
+    fn get(&self, account: &str, key: &str) -> Option<Bytes> {
+        let responses = self.responses.read().ok()?;
+        responses
+            .get(&(account.to_owned(), key.to_owned()))
+            .filter(|(fetched, _)| fetched.elapsed() < self.ttl)
+            .map(|(_, body)| body.clone())
+    }
+
+    fn insert(&self, account: String, key: String, body: Bytes) {
+        if let Ok(mut responses) = self.responses.write() {
+            responses.insert((account, key), (Instant::now(), body));
+        }
+    }
+
+    /// Drop the responses of the account, returns how many were dropped
+    pub(crate) fn remove(&self, account: &str) -> usize {
+        let Ok(mut responses) = self.responses.write() else {
+            return 0;
+        };
+        let len = responses.len();
+        responses.retain(|(cached, _), _| cached != account);
+        len - responses.len()
+    }
+
+    /// Drop the expired responses, returns how many were dropped
+    pub(crate) fn sweep(&self) -> usize {
+        let Ok(mut responses) = self.responses.write() else {
+            return 0;
+        };
+        let len = responses.len();
+        responses.retain(|_, (fetched, _)| fetched.elapsed() < self.ttl);
+        len - responses.len()
+    }
+}
+
+fn response(status: StatusCode, body: Bytes, cache: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(HEADER_CACHE, cache)
+        .body(Body::from(body))
+        .expect("An error occurred while building the response")
+}
+
+/// Serve the response from the cache within the ttl, otherwise from upstream,
+/// only the successful answers are cached
+pub(super) async fn cached(
+    cache: &ResponseCache,
+    account: String,
+    key: String,
+    client: reqwest::Client,
+    req: RequestExtractor,
+) -> Result<Response<Body>, ResponseError> {
+    if let Some(body) = cache.get(&account, &key) {
+        return Ok(response(StatusCode::OK, body, "hit"));
+    }
+
+    let resp = client.send_request(URL_CHATGPT_API, req).await?;
+    let status = resp.status();
+    let body = resp.bytes().await?;
+    if status.is_success() {
+        cache.insert(account, key, body.clone());
+    }
+    Ok(response(status, body, "miss"))
+}
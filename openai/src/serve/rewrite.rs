@@ -0,0 +1,141 @@
+//! Response body rewrite, event streams line by line, other bodies once buffered
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use axum::body::Bytes;
+use futures_core::Stream;
+
+use crate::warn;
+
+/// Largest body, or event stream line, buffered for a rewrite, a larger one is passed through
+const MAX_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+
+pub(crate) trait BodyRewrite {
+    fn event_stream(&self) -> bool;
+
+    /// Rewrite the data of an event stream `data:` line, `None` drops the line
+    fn event(&mut self, data: &str) -> Option<String>;
+
+    /// Rewrite the whole body of a response that is not an event stream
+    fn body(&mut self, body: &[u8]) -> Bytes;
+}
+
+/// Plugin rewrite of the response, never constructed without the `plugin` feature
+#[cfg(not(feature = "plugin"))]
+pub(crate) enum ResponseRewrite {}
+
+#[cfg(not(feature = "plugin"))]
+impl ResponseRewrite {
+    pub(super) fn start(self, _: u16, _: Option<&str>) -> Option<Self> {
+        match self {}
+    }
+}
+
+#[cfg(not(feature = "plugin"))]
+impl BodyRewrite for ResponseRewrite {
+    fn event_stream(&self) -> bool {
+        match *self {}
+    }
+
+    fn event(&mut self, _: &str) -> Option<String> {
+        match *self {}
+    }
+
+    fn body(&mut self, _: &[u8]) -> Bytes {
+        match *self {}
+    }
+}
+
+/// Response body stream rewritten by `R`
+pub(crate) struct RewriteStream<S, R> {
+    inner: S,
+    rewrite: Option<R>,
+    buf: Vec<u8>,
+    finished: bool,
+}
+
+impl<S, R: BodyRewrite> RewriteStream<S, R> {
+    /// Pass through without rewrite
+    pub(crate) fn new(inner: S, rewrite: Option<R>) -> Self {
+        Self {
+            inner,
+            rewrite,
+            buf: Vec::new(),
+            finished: false,
+        }
+    }
+
+    /// Rewritten complete lines of the event stream
+    fn feed(&mut self, chunk: &[u8]) -> Bytes {
+        if self.buf.len() + chunk.len() > MAX_BUFFER_SIZE {
+            warn!("Response body exceeds {MAX_BUFFER_SIZE} bytes, passed through without rewrite");
+            self.rewrite = None;
+            let mut out = std::mem::take(&mut self.buf);
+            out.extend_from_slice(chunk);
+            return Bytes::from(out);
+        }
+        self.buf.extend_from_slice(chunk);
+        let Some(rewrite) = self.rewrite.as_mut().filter(|r| r.event_stream()) else {
+            return Bytes::new();
+        };
+        let mut out = Vec::with_capacity(self.buf.len());
+        while let Some(pos) = self.buf.iter().position(|b| *b == b'\n') {
+            let line = self.buf.drain(..=pos).collect::<Vec<_>>();
+            let data = line
+                .strip_prefix(b"data: ")
+                .and_then(|data| std::str::from_utf8(data).ok());
+            match data {
+                Some(data) => {
+                    if let Some(data) = rewrite.event(data.trim_end()) {
+                        out.extend_from_slice(format!("data: {data}\n").as_bytes());
+                    }
+                }
+                None => out.extend_from_slice(&line),
+            }
+        }
+        Bytes::from(out)
+    }
+
+    /// The rest of the event stream, or the rewritten body
+    fn finish(&mut self) -> Bytes {
+        let body = std::mem::take(&mut self.buf);
+        match self.rewrite.as_mut() {
+            Some(rewrite) if !rewrite.event_stream() => rewrite.body(&body),
+            _ => Bytes::from(body),
+        }
+    }
+}
+
+impl<S, R, E> Stream for RewriteStream<S, R>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    R: BodyRewrite + Unpin,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if this.rewrite.is_none() {
+            return Pin::new(&mut this.inner).poll_next(cx);
+        }
+        while !this.finished {
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(chunk)) => {
+                    let out = this.feed(&chunk);
+                    if !out.is_empty() {
+                        return Poll::Ready(Some(Ok(out)));
+                    }
+                }
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                None => {
+                    this.finished = true;
+                    let out = this.finish();
+                    if !out.is_empty() {
+                        return Poll::Ready(Some(Ok(out)));
+                    }
+                }
+            }
+        }
+        Poll::Ready(None)
+    }
+}
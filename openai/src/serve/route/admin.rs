@@ -0,0 +1,582 @@
+use std::collections::BTreeMap;
+
+use axum::extract::{Path, Query};
+use axum::response::{Html, IntoResponse};
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+
+use crate::arkose;
+use crate::auth::model::AuthStrategy;
+use crate::context::{
+    self, ContextArgs, Credential, KeyPriority, KeySpec, KeyTarget, TogglesPatch,
+};
+use crate::metrics;
+use crate::serve::clearance::PushedClearance;
+use crate::serve::error::ResponseError;
+use crate::serve::middleware;
+use crate::serve::middleware::access_log::hash_token;
+#[cfg(feature = "limit")]
+use crate::serve::middleware::tokenbucket;
+use crate::serve::usage::{self, UsageStat};
+use crate::serve::websocket;
+
+pub(super) fn config(router: Router, args: &ContextArgs) -> Router {
+    if args.admin_key.is_none() {
+        return router;
+    }
+
+    let admin = Router::new()
+        .route("/admin/har", get(get_har_health))
+        .route("/admin/arkose", get(get_arkose))
+        .route("/admin/accounts", get(get_accounts).post(post_account))
+        .route("/admin/accounts/:id", delete(delete_account))
+        .route("/admin/accounts/:id/stats", get(get_account_stats))
+        .route("/admin/accounts/:id/disable", post(post_disable_account))
+        .route("/admin/accounts/:id/enable", post(post_enable_account))
+        .route("/admin/accounts/:id/login", post(post_login_account))
+        .route("/admin/sessions", get(get_sessions))
+        .route("/admin/data/:user_id", delete(delete_user_data))
+        .route("/admin/keys", get(get_keys).post(post_key))
+        .route("/admin/keys/:key", delete(delete_key))
+        .route("/admin/toggles", get(get_toggles).patch(patch_toggles));
+
+    let admin = match args.usage_store {
+        Some(_) => admin
+            .route("/admin/usage", get(get_usage))
+            .route("/admin/keys/:key/usage", get(get_key_usage)),
+        None => admin,
+    };
+
+    let admin = if args.api_key_pool.is_empty() {
+        admin
+    } else {
+        admin.route("/admin/api-keys", get(get_api_keys))
+    };
+
+    let admin = if args.cookie_store || args.cf_harvester.is_some() {
+        admin.route(
+            "/admin/cf-clearance",
+            get(get_cf_clearance).post(post_cf_clearance),
+        )
+    } else {
+        admin
+    };
+
+    #[cfg(feature = "limit")]
+    let admin = {
+        let limiter = serde_json::json!({
+            "strategy": args.tb_store_strategy,
+            "expired": args.tb_expired,
+            "windows": args.tb_window,
+        });
+        admin.route("/admin/limiter", get(move || get_limiter(limiter)))
+    };
+
+    #[cfg(feature = "preauth")]
+    let admin = admin
+        .route(
+            "/admin/preauth",
+            get(get_preauth_cookies)
+                .post(post_preauth_cookie)
+                .delete(delete_stale_preauth_cookies),
+        )
+        .route("/admin/preauth/:device_id", delete(delete_preauth_cookie));
+
+    let admin = admin.route_layer(axum::middleware::from_fn(
+        middleware::admin_authorization_middleware,
+    ));
+
+    router
+        .route(
+            "/admin",
+            get(get_dashboard).layer(axum::middleware::from_fn(
+                middleware::security_headers_middleware,
+            )),
+        )
+        .merge(admin)
+}
+
+/// GET /admin, the page asks for the admin key and reads the JSON endpoints below
+async fn get_dashboard() -> impl IntoResponse {
+    Html(include_str!("../../../ui/admin.htm"))
+}
+
+/// GET /admin/har
+async fn get_har_health() -> impl IntoResponse {
+    Json(context::get_instance().arkose_har_health())
+}
+
+/// GET /admin/arkose
+async fn get_arkose() -> impl IntoResponse {
+    let ctx = context::get_instance();
+    let pool = ctx.arkose_token_pool().map(|pool| {
+        serde_json::json!({
+            "size": pool.size(),
+            "ready": pool.ready(),
+        })
+    });
+    Json(serde_json::json!({
+        "sources": arkose::metrics::stats(),
+        "pool": pool,
+    }))
+}
+
+/// GET /admin/accounts
+async fn get_accounts() -> impl IntoResponse {
+    Json(context::get_instance().accounts())
+}
+
+#[derive(serde::Deserialize)]
+struct AddAccount {
+    email: Option<String>,
+    password: Option<String>,
+    #[serde(default)]
+    option: AuthStrategy,
+    refresh_token: Option<String>,
+}
+
+/// POST /admin/accounts, log in with the password or refresh token and manage the account
+async fn post_account(Json(body): Json<AddAccount>) -> Result<impl IntoResponse, ResponseError> {
+    let credential = match (body.password, body.refresh_token) {
+        (Some(password), None) => Credential::Password {
+            password,
+            option: body.option,
+        },
+        (None, Some(refresh_token)) => Credential::RefreshToken(refresh_token),
+        _ => {
+            return Err(ResponseError::BadRequest(anyhow::anyhow!(
+                "either password or refresh_token is required"
+            )))
+        }
+    };
+    let email = crate::serve::login_managed(body.email.as_deref(), credential)
+        .await
+        .map_err(ResponseError::BadRequest)?;
+    Ok(Json(context::get_instance().account(&email)))
+}
+
+/// DELETE /admin/accounts/:id, forget the account and its credential
+async fn delete_account(Path(id): Path<String>) -> Result<impl IntoResponse, ResponseError> {
+    if !context::get_instance().remove_account(&id) {
+        return Err(account_not_found(&id));
+    }
+    Ok(axum::http::StatusCode::NO_CONTENT)
+}
+
+/// POST /admin/accounts/:id/disable, requests of the account are rejected
+async fn post_disable_account(Path(id): Path<String>) -> Result<impl IntoResponse, ResponseError> {
+    set_account_disabled(id, true)
+}
+
+/// POST /admin/accounts/:id/enable
+async fn post_enable_account(Path(id): Path<String>) -> Result<impl IntoResponse, ResponseError> {
+    set_account_disabled(id, false)
+}
+
+fn set_account_disabled(id: String, disabled: bool) -> Result<impl IntoResponse, ResponseError> {
+    let ctx = context::get_instance();
+    if !ctx
+        .set_account_disabled(&id, disabled)
+        .map_err(ResponseError::InternalServerError)?
+    {
+        return Err(account_not_found(&id));
+    }
+    Ok(Json(ctx.account(&id)))
+}
+
+/// POST /admin/accounts/:id/login, log in the managed account again with its credential
+async fn post_login_account(Path(id): Path<String>) -> Result<impl IntoResponse, ResponseError> {
+    let ctx = context::get_instance();
+    let managed = ctx
+        .managed_account(&id)
+        .ok_or_else(|| account_not_found(&id))?;
+    crate::serve::login_managed(Some(&id), managed.credential)
+        .await
+        .map_err(ResponseError::BadRequest)?;
+    Ok(Json(ctx.account(&id)))
+}
+
+/// GET /admin/keys, the client keys and their upstream, masked
+async fn get_keys() -> impl IntoResponse {
+    Json(context::get_instance().keys())
+}
+
+#[derive(serde::Deserialize)]
+struct AddKey {
+    /// Client key, generated when missing
+    key: Option<String>,
+    account: Option<String>,
+    token: Option<String>,
+    #[serde(default)]
+    priority: KeyPriority,
+}
+
+/// POST /admin/keys, map a client key to a managed account or an upstream token with
+/// its priority, the answer is the only place a generated key is shown
+async fn post_key(Json(body): Json<AddKey>) -> Result<impl IntoResponse, ResponseError> {
+    let ctx = context::get_instance();
+    let target = match (body.account, body.token) {
+        (Some(account), None) if ctx.managed_account(&account).is_some() => {
+            KeyTarget::Account(account)
+        }
+        (Some(account), None) => return Err(account_not_found(&account)),
+        (None, Some(token)) if !token.is_empty() => KeyTarget::Token(token),
+        _ => {
+            return Err(ResponseError::BadRequest(anyhow::anyhow!(
+                "either account or token is required"
+            )))
+        }
+    };
+    let key = match body.key {
+        Some(key) if key.len() < 16 || key.contains(char::is_whitespace) => {
+            return Err(ResponseError::BadRequest(anyhow::anyhow!(
+                "key must be at least 16 characters without spaces"
+            )))
+        }
+        Some(key) => key,
+        None => {
+            let mut bytes = [0u8; 24];
+            rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut bytes);
+            format!(
+                "nk-{}",
+                base64::Engine::encode(&base64::engine::general_purpose::URL_SAFE_NO_PAD, bytes)
+            )
+        }
+    };
+    let spec = KeySpec {
+        target,
+        priority: body.priority,
+    };
+    ctx.set_key(key.clone(), spec.clone())
+        .map_err(ResponseError::BadRequest)?;
+    Ok(Json(serde_json::json!({
+        "key": key,
+        "target": spec.target,
+        "priority": spec.priority,
+    })))
+}
+
+/// DELETE /admin/keys/:key
+async fn delete_key(Path(key): Path<String>) -> Result<impl IntoResponse, ResponseError> {
+    match context::get_instance().remove_key(&key) {
+        Ok(true) => Ok(axum::http::StatusCode::NO_CONTENT),
+        Ok(false) => Err(ResponseError::NotFound(anyhow::anyhow!("key not found"))),
+        Err(err) => Err(ResponseError::BadRequest(err)),
+    }
+}
+
+/// GET /admin/cf-clearance, the outbound routes and their Cloudflare clearance
+async fn get_cf_clearance() -> Result<impl IntoResponse, ResponseError> {
+    let clearance = clearance()?;
+    Ok(Json(clearance.routes()))
+}
+
+/// POST /admin/cf-clearance, a clearance obtained by an external harvester for a route
+async fn post_cf_clearance(
+    Json(body): Json<PushedClearance>,
+) -> Result<impl IntoResponse, ResponseError> {
+    clearance()?.push(body).map_err(ResponseError::BadRequest)?;
+    Ok(axum::http::StatusCode::NO_CONTENT)
+}
+
+fn clearance() -> Result<std::sync::Arc<crate::serve::clearance::Clearance>, ResponseError> {
+    context::get_instance()
+        .clearance()
+        .cloned()
+        .ok_or_else(|| ResponseError::NotFound(anyhow::anyhow!("cookie store is disabled")))
+}
+
+fn account_not_found(id: &str) -> ResponseError {
+    ResponseError::NotFound(anyhow::anyhow!("account not found: {id}"))
+}
+
+/// GET /admin/accounts/:id/stats, the id is the account email
+async fn get_account_stats(Path(id): Path<String>) -> Result<impl IntoResponse, ResponseError> {
+    let account = context::get_instance()
+        .account(&id)
+        .ok_or_else(|| account_not_found(&id))?;
+    let error_rate = match account.requests {
+        0 => 0.0,
+        requests => account.errors as f64 / requests as f64,
+    };
+    let expires_in = account
+        .expires_at
+        .zip(crate::now_duration().ok())
+        .map(|(expires_at, now)| expires_at - now.as_secs() as i64);
+    Ok(Json(serde_json::json!({
+        "email": account.email,
+        "state": account.state,
+        "requests": account.requests,
+        "errors": account.errors,
+        "error_rate": error_rate,
+        "rate_limited": account.rate_limited,
+        "last_puid_refresh": account.last_puid_refresh,
+        "expires_at": account.expires_at,
+        "expires_in": expires_in,
+        "last_seen": account.last_seen,
+        "last_error": account.last_error,
+    })))
+}
+
+/// GET /admin/sessions
+async fn get_sessions() -> impl IntoResponse {
+    Json(serde_json::json!({
+        "accounts": context::get_instance().active_accounts(),
+        "websocket_connections": metrics::value(websocket::CONNECTIONS, &[]),
+    }))
+}
+
+/// DELETE /admin/data/:user_id, purge what the instance stores of the account email: its
+/// sessions, usage records, cached conversation lists and responses, and content log records
+async fn delete_user_data(Path(user_id): Path<String>) -> Result<impl IntoResponse, ResponseError> {
+    let ctx = context::get_instance();
+    // The WebUI sessions issued so far stop validating
+    ctx.bump_session_generation(&user_id)
+        .map_err(ResponseError::InternalServerError)?;
+    let sessions = ctx.remove_account(&user_id);
+    crate::serve::puid::invalidate_puid(&user_id).await;
+    let usage = match ctx.usage() {
+        Some(store) => store
+            .delete(&user_id)
+            .await
+            .map_err(ResponseError::InternalServerError)?,
+        None => 0,
+    };
+    let conversations = ctx
+        .conversation_cache()
+        .map(|cache| cache.invalidate(&user_id))
+        .unwrap_or_default();
+    let responses = ctx
+        .response_cache()
+        .map(|cache| cache.remove(&user_id))
+        .unwrap_or_default();
+    let content_log = match ctx.content_log() {
+        Some(log) => log
+            .delete(&user_id)
+            .map_err(ResponseError::InternalServerError)?,
+        None => 0,
+    };
+    Ok(Json(serde_json::json!({
+        "user_id": user_id,
+        "sessions": sessions,
+        "usage": usage,
+        "conversations": conversations,
+        "responses": responses,
+        "content_log": content_log,
+    })))
+}
+
+/// GET /admin/toggles
+async fn get_toggles() -> impl IntoResponse {
+    Json(context::get_instance().toggles())
+}
+
+/// PATCH /admin/toggles, the changed settings are kept across restarts
+async fn patch_toggles(
+    Json(patch): Json<TogglesPatch>,
+) -> Result<impl IntoResponse, ResponseError> {
+    let ctx = context::get_instance();
+    let enable_ui = patch.disable_ui == Some(false);
+    let toggles = ctx
+        .update_toggles(patch)
+        .map_err(ResponseError::BadRequest)?;
+    // The build id is only followed once the WebUI is shown
+    if enable_ui {
+        context::spawn(crate::serve::buildid::refresh_task());
+    }
+    Ok(Json(toggles))
+}
+
+/// GET /admin/api-keys, the keys are masked
+async fn get_api_keys() -> impl IntoResponse {
+    let stats = context::get_instance()
+        .api_key_pool()
+        .map(|pool| pool.stats())
+        .unwrap_or_default();
+    Json(stats)
+}
+
+#[derive(serde::Deserialize)]
+struct UsageQuery {
+    /// First date, `yyyy-mm-dd`, default 6 days ago
+    from: Option<String>,
+    /// Last date, `yyyy-mm-dd`, default today
+    to: Option<String>,
+    token: Option<String>,
+    account: Option<String>,
+    model: Option<String>,
+}
+
+fn parse_date(date: Option<String>) -> Result<Option<String>, ResponseError> {
+    match date {
+        Some(date)
+            if date.len() != 10
+                || !date.char_indices().all(|(i, c)| match i {
+                    4 | 7 => c == '-',
+                    _ => c.is_ascii_digit(),
+                }) =>
+        {
+            Err(ResponseError::BadRequest(anyhow::anyhow!(
+                "date must be `yyyy-mm-dd`: {date}"
+            )))
+        }
+        date => Ok(date),
+    }
+}
+
+/// GET /admin/usage, newest first
+async fn get_usage(Query(query): Query<UsageQuery>) -> Result<impl IntoResponse, ResponseError> {
+    let ctx = context::get_instance();
+    let store = ctx
+        .usage()
+        .ok_or_else(|| ResponseError::NotFound(anyhow::anyhow!("usage accounting disabled")))?;
+
+    let to = parse_date(query.to)?.unwrap_or_else(usage::today);
+    let from = parse_date(query.from)?.unwrap_or_else(|| usage::date_before(6).min(to.clone()));
+
+    let mut entries = store
+        .query(&from, &to)
+        .await
+        .map_err(ResponseError::InternalServerError)?;
+    entries.retain(|usage| {
+        query.token.as_ref().map_or(true, |v| usage.key.token.eq(v))
+            && query
+                .account
+                .as_ref()
+                .map_or(true, |v| usage.key.account.as_ref() == Some(v))
+            && query
+                .model
+                .as_ref()
+                .map_or(true, |v| usage.key.model.as_ref() == Some(v))
+    });
+    entries.sort_by(|a, b| {
+        b.key
+            .date
+            .cmp(&a.key.date)
+            .then(b.stat.requests.cmp(&a.stat.requests))
+    });
+
+    let mut total = UsageStat::default();
+    entries.iter().for_each(|usage| total.add(&usage.stat));
+
+    Ok(Json(serde_json::json!({
+        "from": from,
+        "to": to,
+        "total": total,
+        "usage": entries,
+    })))
+}
+
+#[derive(serde::Deserialize)]
+struct KeyUsageQuery {
+    /// First date, `yyyy-mm-dd`, default 6 days ago
+    from: Option<String>,
+    /// Last date, `yyyy-mm-dd`, default today
+    to: Option<String>,
+}
+
+/// GET /admin/keys/:key/usage, the usage of a client key by model and by day
+async fn get_key_usage(
+    Path(key): Path<String>,
+    Query(query): Query<KeyUsageQuery>,
+) -> Result<impl IntoResponse, ResponseError> {
+    let ctx = context::get_instance();
+    let store = ctx
+        .usage()
+        .ok_or_else(|| ResponseError::NotFound(anyhow::anyhow!("usage accounting disabled")))?;
+    if ctx.key_target(&key).is_none() {
+        return Err(ResponseError::NotFound(anyhow::anyhow!("key not found")));
+    }
+
+    let to = parse_date(query.to)?.unwrap_or_else(usage::today);
+    let from = parse_date(query.from)?.unwrap_or_else(|| usage::date_before(6).min(to.clone()));
+
+    let token = hash_token(&key);
+    let entries = store
+        .query(&from, &to)
+        .await
+        .map_err(ResponseError::InternalServerError)?;
+    let mut total = UsageStat::default();
+    let mut models = BTreeMap::<String, UsageStat>::new();
+    let mut days = BTreeMap::<String, UsageStat>::new();
+    for usage in entries.iter().filter(|usage| usage.key.token == token) {
+        total.add(&usage.stat);
+        models
+            .entry(
+                usage
+                    .key
+                    .model
+                    .clone()
+                    .unwrap_or_else(|| "unknown".to_owned()),
+            )
+            .or_default()
+            .add(&usage.stat);
+        days.entry(usage.key.date.clone())
+            .or_default()
+            .add(&usage.stat);
+    }
+
+    Ok(Json(serde_json::json!({
+        "key": context::mask_key(&key),
+        "token": token,
+        "from": from,
+        "to": to,
+        "total": total,
+        "models": models,
+        "days": days,
+        "last_seen": ctx.key_last_seen(&key),
+    })))
+}
+
+/// GET /admin/limiter
+#[cfg(feature = "limit")]
+async fn get_limiter(mut limiter: serde_json::Value) -> impl IntoResponse {
+    let toggles = context::get_instance().toggles();
+    limiter["enable"] = toggles.tb_enable.into();
+    limiter["capacity"] = toggles.tb_capacity.into();
+    limiter["fill_rate"] = toggles.tb_fill_rate.into();
+    limiter["allowed"] = metrics::value(tokenbucket::ALLOWED, &[]).into();
+    limiter["limited"] = metrics::value(tokenbucket::LIMITED, &[]).into();
+    Json(limiter)
+}
+
+#[cfg(feature = "preauth")]
+#[derive(serde::Deserialize)]
+struct PreauthCookieReq {
+    value: String,
+}
+
+/// GET /admin/preauth
+#[cfg(feature = "preauth")]
+async fn get_preauth_cookies() -> impl IntoResponse {
+    Json(context::get_instance().list_preauth_cookies())
+}
+
+/// POST /admin/preauth
+#[cfg(feature = "preauth")]
+async fn post_preauth_cookie(
+    Json(req): Json<PreauthCookieReq>,
+) -> Result<impl IntoResponse, ResponseError> {
+    let cookie = context::get_instance()
+        .insert_preauth_cookie(&req.value)
+        .map_err(ResponseError::BadRequest)?;
+    Ok(Json(cookie))
+}
+
+/// DELETE /admin/preauth
+#[cfg(feature = "preauth")]
+async fn delete_stale_preauth_cookies() -> impl IntoResponse {
+    let removed = context::get_instance().purge_preauth_cookies();
+    Json(serde_json::json!({ "removed": removed }))
+}
+
+/// DELETE /admin/preauth/:device_id
+#[cfg(feature = "preauth")]
+async fn delete_preauth_cookie(
+    Path(device_id): Path<String>,
+) -> Result<impl IntoResponse, ResponseError> {
+    context::get_instance()
+        .remove_preauth_cookie(&device_id)
+        .map(Json)
+        .ok_or_else(|| ResponseError::NotFound(anyhow::anyhow!("device id not found: {device_id}")))
+}
@@ -18,7 +18,60 @@ use axum::{
 };
 use bytes::Bytes;
 use http::response::Builder;
+use moka::sync::Cache;
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Arkose static asset revalidation interval
+const ASSET_REVALIDATE_INTERVAL: Duration = Duration::from_secs(600);
+/// Arkose static asset cache time to live
+const ASSET_CACHE_TTL: Duration = Duration::from_secs(3600 * 24);
+
+/// Arkose static assets of an instance
+pub(crate) struct AssetCache(Cache<String, CachedAsset>);
+
+impl Default for AssetCache {
+    fn default() -> Self {
+        Self(
+            Cache::builder()
+                .max_capacity(1000)
+                .time_to_live(ASSET_CACHE_TTL)
+                .build(),
+        )
+    }
+}
+
+/// Cached enforcement JS / static asset response
+#[derive(Clone)]
+struct CachedAsset {
+    status: StatusCode,
+    headers: HeaderMap,
+    bytes: Bytes,
+    fetched_at: Instant,
+}
+
+impl CachedAsset {
+    fn is_stale(&self) -> bool {
+        self.fetched_at.elapsed() >= ASSET_REVALIDATE_INTERVAL
+    }
+
+    fn to_response(&self) -> Result<Response<Body>, ResponseError> {
+        let mut builder = Response::builder().status(self.status);
+        for (k, v) in self.headers.iter() {
+            builder = builder.header(k, v);
+        }
+        create_response_with_bytes(builder, self.bytes.clone())
+    }
+}
+
+fn asset_cache() -> Cache<String, CachedAsset> {
+    context::get_instance().arkose_assets().0.clone()
+}
+
+/// Only the enforcement JS and static assets are cacheable
+fn is_static_asset(method: &Method, path: &str) -> bool {
+    method.eq(&Method::GET) && (path.starts_with("/cdn/") || path.starts_with("/v2/"))
+}
 
 pub(super) fn config(router: Router, args: &ContextArgs) -> Router {
     if args.arkose_endpoint.is_none() {
@@ -104,6 +157,43 @@ async fn proxy(
     let client = context::get_instance().client();
     let url = format!("https://client-api.arkoselabs.com{}", req_path);
 
+    let cacheable = is_static_asset(&method, req_path);
+    let cached = cacheable.then(|| asset_cache().get(req_path)).flatten();
+
+    if let Some(mut asset) = cached {
+        if !asset.is_stale() {
+            return asset.to_response();
+        }
+
+        // Revalidate the stale asset with a conditional request
+        let mut conditional = headers.clone();
+        if let Some(etag) = asset.headers.get(header::ETAG) {
+            conditional.insert(header::IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = asset.headers.get(header::LAST_MODIFIED) {
+            conditional.insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+        }
+
+        match client.get(&url).headers(conditional).send().await {
+            Ok(resp) if resp.status() == StatusCode::NOT_MODIFIED => {
+                asset.fetched_at = Instant::now();
+                asset_cache().insert(req_path.to_owned(), asset.clone());
+                return asset.to_response();
+            }
+            Ok(resp) if resp.status().is_success() => {
+                return cache_response(req_path, resp).await;
+            }
+            Ok(resp) => {
+                warn!("Arkose asset revalidate status: {}", resp.status());
+                return asset.to_response();
+            }
+            Err(err) => {
+                warn!("Arkose asset revalidate error: {err}");
+                return asset.to_response();
+            }
+        }
+    }
+
     let resp = match body {
         Some(form) => {
             client
@@ -117,6 +207,10 @@ async fn proxy(
     }
     .map_err(ResponseError::InternalServerError)?;
 
+    if cacheable && resp.status().is_success() {
+        return cache_response(req_path, resp).await;
+    }
+
     let mut builder = Response::builder().status(resp.status());
     for ele in resp.headers() {
         builder = builder.header(ele.0, ele.1);
@@ -130,6 +224,31 @@ async fn proxy(
     Ok(create_response_with_bytes(builder, bytes)?)
 }
 
+/// Store the upstream asset response in the cache and return it
+async fn cache_response(
+    req_path: &str,
+    resp: reqwest::Response,
+) -> Result<Response<Body>, ResponseError> {
+    let status = resp.status();
+    // The cookies of the upstream are meant for the client of this request, not for
+    // every client served from the cache
+    let mut headers = resp.headers().clone();
+    headers.remove(header::SET_COOKIE);
+    let bytes = resp
+        .bytes()
+        .await
+        .map_err(ResponseError::InternalServerError)?;
+
+    let asset = CachedAsset {
+        status,
+        headers,
+        bytes,
+        fetched_at: Instant::now(),
+    };
+    asset_cache().insert(req_path.to_owned(), asset.clone());
+    asset.to_response()
+}
+
 fn create_response(
     status: StatusCode,
     content_type: &str,
@@ -1,26 +1,19 @@
-use std::path::PathBuf;
-
 use jsonwebtokens::{encode, Algorithm, AlgorithmID, Verifier};
 use serde_json::json;
-use tokio::sync::OnceCell;
 
 use crate::{
     arkose::{self},
-    context, generate_random_string,
-    homedir::home_dir,
-    now_duration,
+    context, generate_random_string, now_duration,
 };
 
-static TOKEN_SECRET: OnceCell<String> = OnceCell::const_new();
 pub(super) const EXP: u64 = 3600 * 24;
 
-async fn get_or_init_secret() -> &'static String {
-    TOKEN_SECRET
+async fn get_or_init_secret() -> String {
+    let ctx = context::get_instance();
+    ctx.token_secret()
         .get_or_init(|| async {
-            let path = home_dir()
-                .unwrap_or_else(|| PathBuf::from("."))
-                .join(".token_secret");
-            let key = context::get_instance()
+            let path = ctx.instance_path(".token_secret");
+            let key = ctx
                 .arkose_har_upload_key()
                 .cloned()
                 .unwrap_or(generate_random_string(31));
@@ -32,11 +25,12 @@ async fn get_or_init_secret() -> &'static String {
             s
         })
         .await
+        .clone()
 }
 
 pub(super) async fn generate_token() -> anyhow::Result<String> {
     let s = get_or_init_secret().await;
-    let alg = Algorithm::new_hmac(AlgorithmID::HS256, s.to_owned())?;
+    let alg = Algorithm::new_hmac(AlgorithmID::HS256, s)?;
     let header = json!({ "alg": alg.name() });
     let claims = json!({
        "exp": now_duration()?.as_secs() + EXP,
@@ -46,7 +40,7 @@ pub(super) async fn generate_token() -> anyhow::Result<String> {
 
 pub(super) async fn verifier(token_str: &str) -> anyhow::Result<()> {
     let s = get_or_init_secret().await;
-    let alg = Algorithm::new_hmac(AlgorithmID::HS256, s.to_owned())?;
+    let alg = Algorithm::new_hmac(AlgorithmID::HS256, s)?;
     let verifier = Verifier::create().build()?;
     let _ = verifier.verify(&token_str, &alg)?;
     Ok(())
@@ -1,15 +1,22 @@
 use std::collections::HashMap;
-use std::sync::OnceLock;
+#[cfg(feature = "compression")]
+use std::io::Write;
+use std::path::Component;
+use std::sync::{Arc, OnceLock, RwLock};
 
 use axum::http::header;
+use axum::http::HeaderMap;
 use axum::http::StatusCode;
-use axum::{body::Body, extract::Path, http::Response, Router};
+use axum::http::Uri;
+use axum::{body::Body, http::Response, Router};
+use bytes::Bytes;
 
-use crate::context::ContextArgs;
+use crate::context::{self, ContextArgs};
 
 use super::error::ResponseError;
 
-mod arkose;
+mod admin;
+pub(crate) mod arkose;
 mod har;
 pub(super) mod toapi;
 mod ui;
@@ -17,9 +24,18 @@ mod ui;
 include!(concat!(env!("OUT_DIR"), "/generated.rs"));
 
 static STATIC_FILES: OnceLock<HashMap<&'static str, static_files::Resource>> = OnceLock::new();
+/// ETag and compressed variants of the static files, built on the first request
+static ENCODED_FILES: OnceLock<RwLock<HashMap<&'static str, Arc<Encoded>>>> = OnceLock::new();
+
+/// `_next/static` file names are content hashed
+const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+const STATIC_CACHE_CONTROL: &str = "public, max-age=604800";
+/// Files of the WebUI directory may change at any time
+const UI_DIR_CACHE_CONTROL: &str = "no-cache";
 
 pub(super) fn config(router: Router, args: &ContextArgs) -> Router {
     init_static_files();
+    let router = admin::config(router, args);
     let router = arkose::config(router, args);
     let router = har::config(router, args);
     let router = toapi::config(router);
@@ -31,18 +47,227 @@ fn init_static_files() {
     STATIC_FILES.get_or_init(|| generate());
 }
 
-async fn get_static_resource(path: Path<String>) -> Result<Response<Body>, ResponseError> {
-    let path = path.0;
+async fn get_static_resource(
+    uri: Uri,
+    headers: HeaderMap,
+) -> Result<Response<Body>, ResponseError> {
+    let path = uri.path().trim_start_matches('/');
+
+    // Resources in the `static` directory of the WebUI directory take precedence
+    let ctx = context::get_instance();
+    if let Some(ui_dir) = ctx.ui_dir() {
+        let relative = std::path::Path::new(path);
+        if relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            if let Ok(data) = tokio::fs::read(ui_dir.join("static").join(relative)).await {
+                let etag = etag(&data);
+                if not_modified(&headers, &etag) {
+                    return not_modified_response(&etag, UI_DIR_CACHE_CONTROL, false);
+                }
+                return Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .header(header::CONTENT_TYPE, mime_type(path))
+                    .header(header::CACHE_CONTROL, UI_DIR_CACHE_CONTROL)
+                    .header(header::ETAG, etag)
+                    .body(Body::from(data))
+                    .map_err(ResponseError::InternalServerError)?);
+            }
+        }
+    }
+
     let mut static_files = STATIC_FILES.get().expect("static file not init").iter();
-    match static_files.find(|(k, _v)| k.contains(&path)) {
-        Some((_, v)) => Ok(Response::builder()
-            .status(StatusCode::OK)
-            .header(header::CONTENT_TYPE, v.mime_type)
-            .body(Body::from(v.data))
-            .map_err(ResponseError::InternalServerError)?),
-        None => Ok(Response::builder()
+    let Some((key, resource)) = static_files.find(|(k, _v)| k.contains(path)) else {
+        return Ok(Response::builder()
             .status(StatusCode::NOT_FOUND)
             .body(Body::empty())
-            .map_err(ResponseError::InternalServerError)?),
+            .map_err(ResponseError::InternalServerError)?);
+    };
+
+    let cache_control = if path.starts_with("_next/static/") {
+        IMMUTABLE_CACHE_CONTROL
+    } else {
+        STATIC_CACHE_CONTROL
+    };
+    let encoded = encoded(*key, resource).await;
+    if not_modified(&headers, &encoded.etag) {
+        return not_modified_response(&encoded.etag, cache_control, true);
+    }
+
+    let builder = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, resource.mime_type)
+        .header(header::CACHE_CONTROL, cache_control)
+        .header(header::ETAG, &encoded.etag)
+        .header(header::VARY, header::ACCEPT_ENCODING.as_str());
+    let (builder, body) = match (&encoded.br, &encoded.gzip) {
+        (Some(br), _) if accepts_encoding(&headers, "br") => (
+            builder.header(header::CONTENT_ENCODING, "br"),
+            Body::from(br.clone()),
+        ),
+        (_, Some(gzip)) if accepts_encoding(&headers, "gzip") => (
+            builder.header(header::CONTENT_ENCODING, "gzip"),
+            Body::from(gzip.clone()),
+        ),
+        _ => (builder, Body::from(resource.data)),
+    };
+    Ok(builder
+        .body(body)
+        .map_err(ResponseError::InternalServerError)?)
+}
+
+struct Encoded {
+    etag: String,
+    gzip: Option<Bytes>,
+    br: Option<Bytes>,
+}
+
+impl Encoded {
+    fn new(resource: &static_files::Resource) -> Self {
+        let mime_type = resource.mime_type;
+        let compressible = mime_type.starts_with("text/")
+            || ["javascript", "json", "xml", "svg"]
+                .iter()
+                .any(|t| mime_type.contains(t));
+        Self {
+            etag: etag(resource.data),
+            gzip: compressible.then(|| gzip(resource.data)).flatten(),
+            br: compressible.then(|| brotli(resource.data)).flatten(),
+        }
+    }
+}
+
+/// Get the encoded variants of the static file, compress it on the first request
+async fn encoded(key: &'static str, resource: &'static static_files::Resource) -> Arc<Encoded> {
+    let files = ENCODED_FILES.get_or_init(Default::default);
+    if let Some(encoded) = files.read().ok().and_then(|f| f.get(key).cloned()) {
+        return encoded;
+    }
+
+    let encoded = tokio::task::spawn_blocking(move || Encoded::new(resource))
+        .await
+        .unwrap_or_else(|_| Encoded {
+            etag: etag(resource.data),
+            gzip: None,
+            br: None,
+        });
+    let encoded = Arc::new(encoded);
+    if let Ok(mut files) = files.write() {
+        files.insert(key, encoded.clone());
+    }
+    encoded
+}
+
+#[cfg(feature = "compression")]
+fn gzip(data: &[u8]) -> Option<Bytes> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(data).ok()?;
+    let out = encoder.finish().ok()?;
+    (out.len() < data.len()).then(|| Bytes::from(out))
+}
+
+#[cfg(feature = "compression")]
+fn brotli(data: &[u8]) -> Option<Bytes> {
+    let params = brotli::enc::BrotliEncoderParams {
+        quality: 11,
+        lgwin: 22,
+        ..Default::default()
+    };
+    let mut out = Vec::new();
+    brotli::BrotliCompress(&mut &data[..], &mut out, &params).ok()?;
+    (out.len() < data.len()).then(|| Bytes::from(out))
+}
+
+/// Served uncompressed without the `compression` feature
+#[cfg(not(feature = "compression"))]
+fn gzip(_: &[u8]) -> Option<Bytes> {
+    None
+}
+
+#[cfg(not(feature = "compression"))]
+fn brotli(_: &[u8]) -> Option<Bytes> {
+    None
+}
+
+/// Weak ETag, the same content is served with different encodings
+fn etag(data: &[u8]) -> String {
+    format!("W/\"{:x}\"", md5::compute(data))
+}
+
+/// Whether `If-None-Match` matches the ETag
+fn not_modified(headers: &HeaderMap, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| {
+            v.split(',').any(|tag| {
+                let tag = tag.trim();
+                tag == "*" || tag.trim_start_matches("W/") == etag
+            })
+        })
+        .unwrap_or_default()
+}
+
+/// The 304 carries the validator and caching headers of the full response, `Vary` included
+/// for the resources served with several encodings so caches keep the variants apart
+fn not_modified_response(
+    etag: &str,
+    cache_control: &str,
+    vary: bool,
+) -> Result<Response<Body>, ResponseError> {
+    let mut builder = Response::builder()
+        .status(StatusCode::NOT_MODIFIED)
+        .header(header::CACHE_CONTROL, cache_control)
+        .header(header::ETAG, etag);
+    if vary {
+        builder = builder.header(header::VARY, header::ACCEPT_ENCODING.as_str());
+    }
+    Ok(builder
+        .body(Body::empty())
+        .map_err(ResponseError::InternalServerError)?)
+}
+
+/// Whether `Accept-Encoding` allows the encoding, e.g. `gzip, deflate, br;q=0.8`
+fn accepts_encoding(headers: &HeaderMap, encoding: &str) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|item| {
+            let mut parts = item.split(';');
+            parts.next().map(str::trim) == Some(encoding)
+                && parts
+                    .find_map(|p| p.trim().strip_prefix("q="))
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .map_or(true, |q| q > 0.0)
+        })
+}
+
+/// Content type of a resource by its file extension
+fn mime_type(path: &str) -> &'static str {
+    let extension = path
+        .rsplit_once('.')
+        .map(|(_, ext)| ext)
+        .unwrap_or_default();
+    match extension.to_lowercase().as_str() {
+        "htm" | "html" => "text/html; charset=utf-8",
+        "js" | "mjs" => "application/javascript",
+        "css" => "text/css",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        _ => "application/octet-stream",
     }
 }
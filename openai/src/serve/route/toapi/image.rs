@@ -0,0 +1,235 @@
+use axum::{
+    headers::{authorization::Bearer, Authorization},
+    response::{IntoResponse, Response},
+    Extension, Json, TypedHeader,
+};
+use base64::Engine;
+use eventsource_stream::EventStream;
+use futures::StreamExt;
+use futures_core::Stream;
+use http::HeaderMap;
+use serde_json::Value;
+
+use super::{
+    current_timestamp, event_stream, model_mapper, req, resp, send_conversation, status_error,
+    user_agent,
+};
+use crate::{
+    chatgpt::model::{
+        req::{Action, Content, ContentText, Messages, PostConvoRequest},
+        Author, Role,
+    },
+    context, debug,
+    serve::{error::ResponseError, middleware::ClientKey, puid::reduce_cache_key},
+    uuid::uuid,
+    URL_CHATGPT_API,
+};
+
+/// Images generated per request, a conversation turn rarely yields more
+const MAX_IMAGES: usize = 4;
+
+/// Generated image, the file id and the prompt DALL·E actually used
+struct Generated {
+    file_id: String,
+    revised_prompt: Option<String>,
+}
+
+/// OpenAI image generation api to a ChatGPT conversation with the DALL·E tool
+pub(super) async fn images_to_api(
+    TypedHeader(bearer): TypedHeader<Authorization<Bearer>>,
+    client_key: Option<Extension<ClientKey>>,
+    headers: HeaderMap,
+    body: Json<req::ImageReq>,
+) -> Result<Response, ResponseError> {
+    if body.prompt.trim().is_empty() {
+        return Err(ResponseError::BadRequest(anyhow::anyhow!(
+            "prompt is required"
+        )));
+    }
+    if body.n == 0 || body.n > MAX_IMAGES {
+        return Err(ResponseError::BadRequest(anyhow::anyhow!(
+            "n must be between 1 and {MAX_IMAGES}"
+        )));
+    }
+
+    let token = bearer.token();
+    let cache_id = reduce_cache_key(token)?;
+
+    // DALL·E is only available to GPT-4
+    let (model, _, arkose_token, sentinel) =
+        model_mapper(token, "gpt-4", &cache_id, user_agent(&headers)).await?;
+
+    let prompt = instruction(&body);
+    let parent_message_id = uuid();
+    let req = PostConvoRequest::builder()
+        .action(Action::Next)
+        .parent_message_id(&parent_message_id)
+        .messages(vec![Messages::builder()
+            .id(uuid())
+            .author(Author { role: Role::User })
+            .content(
+                Content::builder()
+                    .content_type(ContentText::Text)
+                    .parts(vec![&prompt])
+                    .build(),
+            )
+            .build()])
+        .model(&model)
+        .history_and_training_disabled(true)
+        .arkose_token(&arkose_token)
+        .build();
+
+    let key = client_key
+        .as_ref()
+        .map(|Extension(ClientKey(key))| key.as_str());
+    let resp = send_conversation(token, key, &model, cache_id.clone(), sentinel, &req).await?;
+    let resp = match resp.error_for_status() {
+        Ok(resp) => resp,
+        Err(err) => return status_error(err),
+    };
+
+    let (mut images, reply) = collect_images(event_stream(resp, token, &cache_id)).await;
+    if images.is_empty() {
+        return Err(ResponseError::BadGateway(anyhow::anyhow!(
+            "DALL·E did not generate an image: {}",
+            reply.unwrap_or_default()
+        )));
+    }
+    images.truncate(body.n);
+
+    let client = context::get_instance().sticky_client(Some(&cache_id), None);
+    let mut data = Vec::with_capacity(images.len());
+    for image in images {
+        let url = download_url(&client, token, &image.file_id).await?;
+        let data_item = match body.response_format {
+            req::ImageFormat::Url => resp::ImageData::builder()
+                .url(Some(url))
+                .revised_prompt(image.revised_prompt)
+                .build(),
+            req::ImageFormat::B64Json => {
+                let bytes = client
+                    .get(url)
+                    .send()
+                    .await?
+                    .error_for_status()
+                    .map_err(ResponseError::BadGateway)?
+                    .bytes()
+                    .await?;
+                resp::ImageData::builder()
+                    .b64_json(Some(
+                        base64::engine::general_purpose::STANDARD.encode(bytes),
+                    ))
+                    .revised_prompt(image.revised_prompt)
+                    .build()
+            }
+        };
+        data.push(data_item);
+    }
+
+    Ok(Json(resp::ImageResp {
+        created: current_timestamp(),
+        data,
+    })
+    .into_response())
+}
+
+/// Ask the model to pass the prompt to DALL·E as is
+fn instruction(body: &req::ImageReq) -> String {
+    let size = match body.size.as_deref() {
+        Some("1792x1024") => " in a wide aspect ratio",
+        Some("1024x1792") => " in a tall aspect ratio",
+        _ => " in a square aspect ratio",
+    };
+    let count = match body.n {
+        1 => "an image".to_owned(),
+        n => format!("{n} images"),
+    };
+    format!(
+        "Use DALL·E to create {count}{size} with exactly the prompt below, do not change the prompt and do not ask any question.\n\n{}",
+        body.prompt
+    )
+}
+
+/// Collect the images of the DALL·E tool messages in order,
+/// and the last assistant reply to explain a refusal
+async fn collect_images(
+    mut event_soure: EventStream<
+        impl Stream<Item = Result<bytes::Bytes, reqwest::Error>> + std::marker::Unpin,
+    >,
+) -> (Vec<Generated>, Option<String>) {
+    let mut images: Vec<Generated> = Vec::new();
+    let mut reply = None;
+    while let Some(event_result) = event_soure.next().await {
+        let message = match event_result {
+            Ok(message) => message,
+            Err(err) => {
+                debug!("event-source stream error: {}", err);
+                continue;
+            }
+        };
+        if message.data.eq("[DONE]") {
+            break;
+        }
+        let Ok(value) = serde_json::from_str::<Value>(&message.data) else {
+            continue;
+        };
+        let message = &value["message"];
+        let content = &message["content"];
+        match message["author"]["role"].as_str() {
+            Some("tool") if content["content_type"] == "multimodal_text" => {
+                let parts = content["parts"].as_array().into_iter().flatten();
+                for part in parts.filter(|p| p["content_type"] == "image_asset_pointer") {
+                    // e.g. `file-service://file-xxxx`
+                    let Some(file_id) = part["asset_pointer"]
+                        .as_str()
+                        .map(|p| p.trim_start_matches("file-service://"))
+                    else {
+                        continue;
+                    };
+                    // The message is repeated as the stream goes on
+                    if images.iter().any(|image| image.file_id == file_id) {
+                        continue;
+                    }
+                    images.push(Generated {
+                        file_id: file_id.to_owned(),
+                        revised_prompt: part["metadata"]["dalle"]["prompt"]
+                            .as_str()
+                            .map(ToOwned::to_owned),
+                    });
+                }
+            }
+            Some("assistant") => {
+                if let Some(text) = content["parts"][0].as_str() {
+                    reply = Some(text.to_owned());
+                }
+            }
+            _ => {}
+        }
+    }
+    (images, reply)
+}
+
+/// Get the temporary download url of the generated file
+async fn download_url(
+    client: &reqwest::Client,
+    token: &str,
+    file_id: &str,
+) -> Result<String, ResponseError> {
+    let value = client
+        .get(format!(
+            "{URL_CHATGPT_API}/backend-api/files/{file_id}/download"
+        ))
+        .bearer_auth(token)
+        .send()
+        .await?
+        .error_for_status()
+        .map_err(ResponseError::BadGateway)?
+        .json::<Value>()
+        .await?;
+    value["download_url"]
+        .as_str()
+        .map(ToOwned::to_owned)
+        .ok_or_else(|| {
+            ResponseError::BadGateway(anyhow::anyhow!("no download url of the file {file_id}"))
+        })
+}
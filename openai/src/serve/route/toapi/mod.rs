@@ -1,13 +1,16 @@
 use axum::{
     headers::{authorization::Bearer, Authorization},
-    response::{sse::Event, IntoResponse, Sse},
+    response::{
+        sse::{Event, KeepAlive},
+        IntoResponse, Response, Sse,
+    },
     routing::post,
-    Json, Router, TypedHeader,
+    Extension, Json, Router, TypedHeader,
 };
 use eventsource_stream::{EventStream, Eventsource};
 use futures::StreamExt;
 use futures_core::Stream;
-use http::header;
+use http::{header, HeaderMap};
 use reqwest::StatusCode;
 use serde_json::Value;
 use std::{convert::Infallible, str::FromStr};
@@ -20,11 +23,21 @@ use crate::{
     },
     context,
     serve::{
+        deadline::{self, DeadlineStream, ReadTimeoutStream},
+        drain::TrackedStream,
         error::ResponseError,
+        middleware::{token_authorization_middleware, ClientKey},
         puid::{get_or_init_puid, reduce_cache_key},
+        requirements::{get_or_init_requirements, sentinel_tokens, SentinelTokens},
+        rewrite::RewriteStream,
+        stateless::{CleanupStream, ConversationCleanup},
+        stop::{StopGeneration, StopStream},
     },
 };
-use crate::{chatgpt::model::Role, debug};
+use crate::{
+    chatgpt::{model::Role, SENTINEL_CHAT_REQUIREMENTS_TOKEN, SENTINEL_PROOF_TOKEN},
+    debug, warn, HEADER_UA,
+};
 
 use crate::{
     chatgpt::model::{
@@ -36,36 +49,87 @@ use crate::{
 
 use crate::URL_CHATGPT_API;
 
+mod image;
 mod req;
 mod resp;
+mod tokens;
+mod tool;
 
-/// unofficial api to official api
+/// unofficial api to official api, client keys are swapped for their upstream tokens
+/// as on the proxy
 pub(super) fn config(router: Router) -> Router {
-    router.route("/to/v1/chat/completions", post(chat_to_api))
+    router.merge(
+        Router::new()
+            .route("/to/v1/chat/completions", post(chat_to_api))
+            .route("/to/v1/images/generations", post(image::images_to_api))
+            .route_layer(axum::middleware::from_fn(token_authorization_middleware)),
+    )
 }
 
 async fn chat_to_api(
     TypedHeader(bearer): TypedHeader<Authorization<Bearer>>,
+    client_key: Option<Extension<ClientKey>>,
+    headers: HeaderMap,
     body: Json<req::Req>,
 ) -> Result<impl IntoResponse, ResponseError> {
     // Exstract the token from the Authorization header
     let cache_id = reduce_cache_key(bearer.token())?;
 
+    // System prompt of the client key or the account, before the one of the tools
+    let ctx = context::get_instance();
+    let system_prompt = ctx.system_prompt(
+        client_key
+            .as_ref()
+            .map(|Extension(ClientKey(key))| key.as_str()),
+        bearer.token(),
+        false,
+    );
+
+    // Emulate tool calling with a system prompt, unless the tools are disabled
+    let tools = tool::Tools::new(&body);
+    let tools_prompt = tools.as_ref().map(tool::Tools::prompt);
+    let texts = body
+        .messages
+        .iter()
+        .map(|msg| tool::message_text(msg, &body.messages))
+        .collect::<Vec<_>>();
+
+    // Usage is estimated, the backend api does not report it
+    let prompt_tokens = tokens::prompt_tokens(
+        system_prompt
+            .into_iter()
+            .chain(tools_prompt)
+            .chain(texts.iter().map(String::as_str)),
+    )
+    .await;
+    let include_usage = body
+        .stream_options
+        .as_ref()
+        .is_some_and(|options| options.include_usage);
+
     // Convert to ChatGPT API Message
-    let mut messages = Vec::with_capacity(body.messages.len());
-    for body_msg in body.messages.iter() {
-        let role = if body_msg.role.eq(&Role::System) {
-            Role::Critic
-        } else {
-            body_msg.role
+    let mut messages = Vec::with_capacity(body.messages.len() + 2);
+    let prompts = system_prompt
+        .into_iter()
+        .chain(tools_prompt)
+        .map(|prompt| (Role::Critic, prompt));
+    let history = body.messages.iter().zip(texts.iter()).map(|(msg, text)| {
+        let role = match msg.role {
+            req::MessageRole::System => Role::Critic,
+            req::MessageRole::Assistant => Role::Assistant,
+            // Tool results are told to the model by the user
+            req::MessageRole::User | req::MessageRole::Tool => Role::User,
         };
+        (role, text.as_str())
+    });
+    for (role, text) in prompts.chain(history) {
         let message = Messages::builder()
             .id(uuid())
             .author(Author { role })
             .content(
                 Content::builder()
                     .content_type(ContentText::Text)
-                    .parts(vec![&body_msg.content])
+                    .parts(vec![text])
                     .build(),
             )
             .build();
@@ -73,7 +137,8 @@ async fn chat_to_api(
     }
 
     // OpenAI API to ChatGPT API model mapper
-    let (model, map_model, arkose_token) = model_mapper(&body.model).await?;
+    let (model, map_model, arkose_token, sentinel) =
+        model_mapper(bearer.token(), &body.model, &cache_id, user_agent(&headers)).await?;
 
     // Create request
     let parent_message_id = uuid();
@@ -81,89 +146,238 @@ async fn chat_to_api(
         .action(Action::Next)
         .parent_message_id(&parent_message_id)
         .messages(messages)
-        .model(model)
+        .model(&model)
         .history_and_training_disabled(true)
         .arkose_token(&arkose_token)
         .build();
 
-    let client = context::get_instance().client();
-
-    // Try to get puid from cache
-    let puid = get_or_init_puid(bearer.token(), &body.model, cache_id).await?;
-
-    let mut builder = client
-        .post(format!("{URL_CHATGPT_API}/backend-api/conversation"))
-        .bearer_auth(bearer.token());
-
-    if let Some(puid) = puid {
-        builder = builder.header(header::COOKIE, format!("_puid={puid};"))
-    }
-
-    // Send request
-    let resp = builder
-        .json(&req)
-        .send()
-        .await
-        .map_err(ResponseError::InternalServerError)?;
+    let key = client_key
+        .as_ref()
+        .map(|Extension(ClientKey(key))| key.as_str());
+    let resp = send_conversation(
+        bearer.token(),
+        key,
+        &model,
+        cache_id.clone(),
+        sentinel,
+        &req,
+    )
+    .await?;
 
     match resp.error_for_status() {
         Ok(resp) => {
-            let event_source = resp.bytes_stream().eventsource();
+            let event_source = event_stream(resp, bearer.token(), &cache_id);
+            if let Some(tools) = tools {
+                return tool::handler(
+                    event_source,
+                    map_model.to_owned(),
+                    tools,
+                    body.stream,
+                    prompt_tokens,
+                    include_usage,
+                )
+                .await;
+            }
             match body.stream {
-                true => Ok(
-                    Sse::new(stream_handler(event_source, map_model.to_owned())).into_response()
-                ),
+                true => Ok(sse_response(stream_handler(
+                    event_source,
+                    map_model.to_owned(),
+                    prompt_tokens,
+                    include_usage,
+                ))),
                 false => {
-                    let res = not_stream_handler(event_source, map_model.to_owned())
+                    let res = not_stream_handler(event_source, map_model.to_owned(), prompt_tokens)
                         .await
                         .map_err(ResponseError::InternalServerError)?;
                     Ok(res.into_response())
                 }
             }
         }
-        Err(err) => match err.status() {
-                Some(
-                    status_code
-                    @
-                    // 4xx
-                    (StatusCode::UNAUTHORIZED
-                    | StatusCode::REQUEST_TIMEOUT
-                    | StatusCode::TOO_MANY_REQUESTS
-                    | StatusCode::BAD_REQUEST
-                    | StatusCode::PAYMENT_REQUIRED
-                    | StatusCode::FORBIDDEN
-                    // 5xx
-                    | StatusCode::INTERNAL_SERVER_ERROR
-                    | StatusCode::BAD_GATEWAY
-                    | StatusCode::SERVICE_UNAVAILABLE
-                    | StatusCode::GATEWAY_TIMEOUT),
-                ) => {
-                    if status_code == StatusCode::UNAUTHORIZED {
-                        let body = serde_json::json!({
-                            "error": {
-                                "message": "You didn't provide an API key. You need to provide your API key in an Authorization header using Bearer auth (i.e. Authorization: Bearer YOUR_KEY), or as the password field (with blank username) if you're accessing the API from your browser and are prompted for a username and password. You can obtain an API key from https://platform.openai.com/account/api-keys.",
-                                "type": "invalid_request_error",
-                                "param": null,
-                                "code": null
-                            }
-                        });
-                        return Ok(Json(body).into_response().into_response())
+        Err(err) => status_error(err),
+    }
+}
+
+/// Send the conversation request with the puid cookie and sentinel tokens attached
+async fn send_conversation(
+    token: &str,
+    key: Option<&str>,
+    model: &str,
+    cache_id: String,
+    sentinel: Option<SentinelTokens>,
+    req: &PostConvoRequest<'_>,
+) -> Result<reqwest::Response, ResponseError> {
+    let ctx = context::get_instance();
+    let client = ctx.sticky_client(Some(&cache_id), None);
+
+    // Moderate the prompt
+    let mut body = serde_json::to_value(req)?;
+    if let Some(moderation) = ctx.moderation() {
+        moderation.check_request(&mut body).await?;
+    }
+
+    // Content log of the prompt as it is sent upstream
+    if let Some(log) = ctx.content_log() {
+        log.record_body("/backend-api/conversation", token, key, &body);
+    }
+
+    // Try to get puid from cache
+    let puid = get_or_init_puid(token, model, cache_id).await?;
+
+    let mut builder = client
+        .post(format!(
+            "{}/backend-api/conversation",
+            ctx.upstream_origin(URL_CHATGPT_API)
+        ))
+        .bearer_auth(token);
+
+    if let Some(puid) = puid {
+        builder = builder.header(header::COOKIE, format!("_puid={puid};"))
+    }
+
+    // Attach sentinel tokens
+    if let Some(tokens) = sentinel {
+        builder = builder.header(SENTINEL_CHAT_REQUIREMENTS_TOKEN, tokens.requirements);
+        if let Some(proof) = tokens.proof {
+            builder = builder.header(SENTINEL_PROOF_TOKEN, proof);
+        }
+    }
+
+    // Send request, timed until the response headers as the stream is read apart
+    deadline::stream_response(builder.json(&body).timeout(deadline::NO_TIMEOUT).send()).await
+}
+
+/// Event stream of the conversation, the conversation is hidden once finished in stateless mode,
+/// its generation is stopped if the client disconnects and the output is redacted by the moderation
+fn event_stream(
+    resp: reqwest::Response,
+    token: &str,
+    cache_id: &str,
+) -> EventStream<impl Stream<Item = Result<bytes::Bytes, reqwest::Error>> + std::marker::Unpin> {
+    let ctx = context::get_instance();
+    let client = ctx.sticky_client(Some(cache_id), None);
+    let mut cleanup = ctx
+        .stateless()
+        .then(|| ConversationCleanup::new(client.clone(), token));
+    if let Some(cleanup) = cleanup.as_mut() {
+        cleanup.served_by(resp.url());
+    }
+    let mut stop = StopGeneration::new(client, token);
+    if let Some(stop) = stop.as_mut() {
+        stop.served_by(resp.url());
+    }
+    let redactor = ctx
+        .moderation()
+        .and_then(|moderation| moderation.redactor(Some("text/event-stream")));
+    let body = StopStream::new(CleanupStream::new(resp.bytes_stream(), cleanup), stop);
+    RewriteStream::new(body, redactor).eventsource()
+}
+
+/// SSE response with keepalive pings while the model is quiet, closed with an error
+/// after the maximum duration or once the upstream is quiet for the read timeout
+fn sse_response<S>(stream: S) -> Response
+where
+    S: Stream<Item = Result<Event, Infallible>> + Send + 'static,
+{
+    let ctx = context::get_instance();
+    let error = |message: String| {
+        let error = serde_json::json!({
+            "error": {
+                "message": message,
+                "type": "server_error",
+                "param": null,
+                "code": "stream_timeout"
+            }
+        });
+        Ok(Event::default().data(error.to_string()))
+    };
+    let max = ctx.sse_max_duration();
+    let stream = DeadlineStream::new(Box::pin(stream), max, error(deadline::exceeded(max)));
+    let read = ctx.stream_read_timeout();
+    let stream = ReadTimeoutStream::new(stream, read, Some(error(deadline::idle(read))));
+    let sse = Sse::new(TrackedStream::new(stream, true));
+    match ctx.sse_keepalive() {
+        Some(interval) => sse
+            .keep_alive(KeepAlive::new().interval(interval).text("ping"))
+            .into_response(),
+        None => sse.into_response(),
+    }
+}
+
+/// Map the upstream error status to the official api error
+fn status_error(err: reqwest::Error) -> Result<Response, ResponseError> {
+    match err.status() {
+        Some(
+            status_code
+            @
+            // 4xx
+            (StatusCode::UNAUTHORIZED
+            | StatusCode::REQUEST_TIMEOUT
+            | StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::BAD_REQUEST
+            | StatusCode::PAYMENT_REQUIRED
+            | StatusCode::FORBIDDEN
+            // 5xx
+            | StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT),
+        ) => {
+            if status_code == StatusCode::UNAUTHORIZED {
+                let body = serde_json::json!({
+                    "error": {
+                        "message": "You didn't provide an API key. You need to provide your API key in an Authorization header using Bearer auth (i.e. Authorization: Bearer YOUR_KEY), or as the password field (with blank username) if you're accessing the API from your browser and are prompted for a username and password. You can obtain an API key from https://platform.openai.com/account/api-keys.",
+                        "type": "invalid_request_error",
+                        "param": null,
+                        "code": null
                     }
-                    Err(ResponseError::new(err.to_string(), status_code))
-                },
-                _ => Err(ResponseError::InternalServerError(err)),
-            },
+                });
+                return Ok(Json(body).into_response());
+            }
+            Err(ResponseError::new(err.to_string(), status_code))
+        }
+        _ => Err(ResponseError::InternalServerError(err)),
     }
 }
 
 async fn not_stream_handler(
-    mut event_soure: EventStream<
+    event_soure: EventStream<
         impl Stream<Item = Result<bytes::Bytes, reqwest::Error>> + std::marker::Unpin,
     >,
     model: String,
+    prompt_tokens: i64,
 ) -> anyhow::Result<Json<Value>> {
     let id = generate_id(29);
     let timestamp = current_timestamp();
+    let (previous_message, finish_reason) = collect_reply(event_soure).await;
+    let completion_tokens = tokens::count(&previous_message).await;
+
+    let message = resp::Message::builder()
+        .role(Role::Assistant.to_string())
+        .content(Some(previous_message))
+        .build();
+
+    let resp = resp::Resp::builder()
+        .id(&id)
+        .object("chat.completion.chunk")
+        .created(&timestamp)
+        .model(&model)
+        .choices(vec![resp::Choice::builder()
+            .index(0)
+            .message(Some(message))
+            .finish_reason(finish_reason.as_deref())
+            .build()])
+        .usage(Some(tokens::usage(prompt_tokens, completion_tokens)))
+        .build();
+    let value = serde_json::to_value(&resp)?;
+    Ok(Json(value))
+}
+
+/// Collect the whole assistant reply and the finish reason
+async fn collect_reply(
+    mut event_soure: EventStream<
+        impl Stream<Item = Result<bytes::Bytes, reqwest::Error>> + std::marker::Unpin,
+    >,
+) -> (String, Option<String>) {
     let mut previous_message = String::new();
     let mut finish_reason = None;
     while let Some(event_result) = event_soure.next().await {
@@ -191,33 +405,7 @@ async fn not_stream_handler(
             }
         }
     }
-    drop(event_soure);
-
-    let message = resp::Message::builder()
-        .role(Role::Assistant.to_string())
-        .content(previous_message)
-        .build();
-
-    let resp = resp::Resp::builder()
-        .id(&id)
-        .object("chat.completion.chunk")
-        .created(&timestamp)
-        .model(&model)
-        .choices(vec![resp::Choice::builder()
-            .index(0)
-            .message(Some(message))
-            .finish_reason(finish_reason.as_deref())
-            .build()])
-        .usage(Some(
-            resp::Usage::builder()
-                .prompt_tokens(0)
-                .completion_tokens(0)
-                .total_tokens(0)
-                .build(),
-        ))
-        .build();
-    let value = serde_json::to_value(&resp)?;
-    Ok(Json(value))
+    (previous_message, finish_reason)
 }
 
 fn stream_handler(
@@ -225,9 +413,13 @@ fn stream_handler(
         impl Stream<Item = Result<bytes::Bytes, reqwest::Error>> + std::marker::Unpin,
     >,
     model: String,
+    prompt_tokens: i64,
+    include_usage: bool,
 ) -> impl Stream<Item = Result<Event, Infallible>> {
     let id = generate_id(29);
     let timestamp = current_timestamp();
+    // Without `include_usage` the usage is sent in the finish chunk
+    let finish_usage = (!include_usage).then_some(prompt_tokens);
     async_stream::stream! {
         let mut previous_message = String::new();
         let mut set_role = true;
@@ -236,6 +428,13 @@ fn stream_handler(
             match event_result {
                 Ok(message) =>  {
                     if message.data.eq("[DONE]") {
+                        if include_usage {
+                            let usage = tokens::usage(prompt_tokens, tokens::count(&previous_message).await);
+                            match usage_event(&id, &timestamp, &model, usage) {
+                                Ok(event) => yield Ok(event),
+                                Err(err) => debug!("event source json serialize error: {}", err),
+                            }
+                        }
                         yield Ok(Event::default().data(message.data));
                         break;
                     }
@@ -254,6 +453,7 @@ fn stream_handler(
                                 &model,
                                 &mut previous_message,
                                 &mut set_role,
+                                finish_usage,
                                 convo).await {
                                 Ok(event) => {
                                     if stop == 0 {
@@ -284,6 +484,7 @@ async fn event_convert_handler(
     model: &String,
     previous_message: &mut String,
     set_role: &mut bool,
+    finish_usage: Option<i64>,
     convo: ConvoResponse,
 ) -> anyhow::Result<Event> {
     let messages = convo.messages();
@@ -318,6 +519,11 @@ async fn event_convert_handler(
         .content(return_message)
         .build();
 
+    let usage = match finish_reason.and(finish_usage) {
+        Some(prompt_tokens) => Some(tokens::usage(prompt_tokens, tokens::count(message).await)),
+        None => None,
+    };
+
     let resp = resp::Resp::builder()
         .id(&id)
         .object("chat.completion.chunk")
@@ -328,32 +534,77 @@ async fn event_convert_handler(
             .delta(Some(delta))
             .finish_reason(finish_reason)
             .build()])
+        .usage(usage)
         .build();
     let data = format!(" {}", serde_json::to_string(&resp)?);
     Ok(Event::default().data(data))
 }
 
-async fn model_mapper(model: &str) -> Result<(&str, &str, Option<ArkoseToken>), ResponseError> {
-    let gpt_model = GPTModel::from_str(model)?;
+/// Last chunk without choices carrying the usage, sent with `stream_options.include_usage`
+fn usage_event(
+    id: &str,
+    timestamp: &i64,
+    model: &str,
+    usage: resp::Usage,
+) -> anyhow::Result<Event> {
+    let resp = resp::Resp::builder()
+        .id(id)
+        .object("chat.completion.chunk")
+        .created(timestamp)
+        .model(model)
+        .choices(vec![])
+        .usage(Some(usage))
+        .build();
+    let data = format!(" {}", serde_json::to_string(&resp)?);
+    Ok(Event::default().data(data))
+}
+
+async fn model_mapper<'a>(
+    token: &str,
+    model: &'a str,
+    cache_id: &str,
+    user_agent: &str,
+) -> Result<(String, &'a str, Option<ArkoseToken>, Option<SentinelTokens>), ResponseError> {
+    // Configured aliases take precedence, the response keeps the requested name
+    let (slug, map_model) = match context::get_instance().model_alias().get(model) {
+        Some(slug) => (slug.to_owned(), model),
+        None => match model {
+            model if model.starts_with("gpt-3.5") => {
+                ("text-davinci-002-render-sha".to_owned(), "gpt-3.5-turbo")
+            }
+            model if model.starts_with("gpt-4") => ("gpt-4".to_owned(), "gpt-4"),
+            _ => {
+                return Err(ResponseError::BadRequest(anyhow::anyhow!(
+                    "not support model: {model}"
+                )))
+            }
+        },
+    };
+
+    let gpt_model = GPTModel::from_str(&slug)?;
 
-    let arkose_token = if (context::get_instance().arkose_gpt3_experiment() && gpt_model.is_gpt3())
-        || gpt_model.is_gpt4()
-    {
+    // The sentinel tokens request caches the requirements of the account, read below
+    let sentinel = sentinel_tokens(token, &gpt_model, cache_id, user_agent)
+        .await
+        .map_err(|err| warn!("Get sentinel tokens error: {err}"))
+        .ok();
+    let requirements = get_or_init_requirements(token, &gpt_model, cache_id).await;
+    let arkose_token = if requirements.arkose {
         let arkose_token = ArkoseToken::new_from_context(gpt_model.into()).await?;
         Some(arkose_token)
     } else {
         None
     };
 
-    match model {
-        model if model.starts_with("gpt-3.5") => {
-            Ok(("text-davinci-002-render-sha", "gpt-3.5-turbo", arkose_token))
-        }
-        model if model.starts_with("gpt-4") => Ok(("gpt-4", "gpt-4", arkose_token)),
-        _ => Err(ResponseError::BadRequest(anyhow::anyhow!(
-            "not support model: {model}"
-        ))),
-    }
+    Ok((slug, map_model, arkose_token, sentinel))
+}
+
+/// User-Agent of the client, the proof-of-work token is computed for it
+fn user_agent(headers: &HeaderMap) -> &str {
+    headers
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or(HEADER_UA)
 }
 
 fn generate_id(length: usize) -> String {
@@ -1,6 +1,5 @@
-use serde::Deserialize;
-
-use crate::chatgpt::model::Role;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 #[derive(Deserialize)]
 pub struct Req {
@@ -8,10 +7,106 @@ pub struct Req {
     pub messages: Vec<Message>,
     #[serde(default)]
     pub stream: bool,
+    #[serde(default)]
+    pub tools: Vec<Tool>,
+    #[serde(default)]
+    pub tool_choice: Option<ToolChoice>,
+    #[serde(default)]
+    pub stream_options: Option<StreamOptions>,
+}
+
+#[derive(Deserialize)]
+pub struct StreamOptions {
+    /// Send the usage in a last chunk without choices
+    #[serde(default)]
+    pub include_usage: bool,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageRole {
+    System,
+    User,
+    Assistant,
+    /// `function` is the legacy role of the function call results
+    #[serde(alias = "function")]
+    Tool,
 }
 
 #[derive(Deserialize)]
 pub struct Message {
-    pub role: Role,
-    pub content: String,
+    pub role: MessageRole,
+    #[serde(default)]
+    pub content: Option<String>,
+    /// Tool calls of the assistant message
+    #[serde(default)]
+    pub tool_calls: Vec<ToolCall>,
+    /// Tool call answered by the tool message
+    #[serde(default)]
+    pub tool_call_id: Option<String>,
+    /// Function answered by the legacy function message
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    pub arguments: String,
+}
+
+#[derive(Deserialize)]
+pub struct Tool {
+    pub function: Function,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Function {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<Value>,
+}
+
+/// `none`, `auto`, `required` or `{"type": "function", "function": {"name": "..."}}`
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    Mode(String),
+    Function { function: FunctionName },
+}
+
+#[derive(Deserialize)]
+pub struct FunctionName {
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+pub struct ImageReq {
+    pub prompt: String,
+    #[serde(default = "default_image_n")]
+    pub n: usize,
+    #[serde(default)]
+    pub size: Option<String>,
+    #[serde(default)]
+    pub response_format: ImageFormat,
+}
+
+fn default_image_n() -> usize {
+    1
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageFormat {
+    #[default]
+    Url,
+    B64Json,
 }
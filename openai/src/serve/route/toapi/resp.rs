@@ -38,7 +38,28 @@ pub struct Choice<'a> {
 #[derive(Serialize, TypedBuilder, Clone)]
 pub struct Message {
     pub role: String,
-    pub content: String,
+    pub content: Option<String>,
+    #[builder(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct ToolCall {
+    /// Position of the call, only in stream chunks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub function: FunctionCall,
+}
+
+#[derive(Serialize, Clone)]
+pub struct FunctionCall {
+    pub name: String,
+    /// JSON encoded arguments
+    pub arguments: String,
 }
 
 #[derive(Serialize, TypedBuilder, Clone)]
@@ -49,4 +70,26 @@ pub struct Delta<'a> {
     #[builder(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub content: Option<&'a str>,
+    #[builder(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+}
+
+#[derive(Serialize)]
+pub struct ImageResp {
+    pub created: i64,
+    pub data: Vec<ImageData>,
+}
+
+#[derive(Serialize, TypedBuilder)]
+pub struct ImageData {
+    #[builder(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[builder(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub b64_json: Option<String>,
+    #[builder(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revised_prompt: Option<String>,
 }
@@ -0,0 +1,21 @@
+use super::resp::Usage;
+use crate::serve::tokens;
+
+/// Prompt tokens of the messages
+pub(super) async fn prompt_tokens<'a>(messages: impl IntoIterator<Item = &'a str>) -> i64 {
+    let messages = messages.into_iter().map(ToOwned::to_owned).collect();
+    tokens::count_messages(messages).await as i64
+}
+
+/// Tokens of the reply
+pub(super) async fn count(text: &str) -> i64 {
+    tokens::count(text.to_owned()).await as i64
+}
+
+pub(super) fn usage(prompt_tokens: i64, completion_tokens: i64) -> Usage {
+    Usage::builder()
+        .prompt_tokens(prompt_tokens)
+        .completion_tokens(completion_tokens)
+        .total_tokens(prompt_tokens + completion_tokens)
+        .build()
+}
@@ -0,0 +1,322 @@
+use std::convert::Infallible;
+
+use axum::{
+    response::{sse::Event, IntoResponse, Response},
+    Json,
+};
+use eventsource_stream::EventStream;
+use futures_core::Stream;
+use serde_json::Value;
+
+use super::{
+    collect_reply, current_timestamp, generate_id, req, resp, sse_response, tokens, usage_event,
+};
+use crate::{chatgpt::model::Role, debug, serve::error::ResponseError};
+
+/// Tools of the request, described to the model by a system prompt
+pub(super) struct Tools {
+    names: Vec<String>,
+    prompt: String,
+}
+
+impl Tools {
+    /// `None` if the request has no tools or `tool_choice` is `none`
+    pub(super) fn new(body: &req::Req) -> Option<Self> {
+        let rule = match &body.tool_choice {
+            _ if body.tools.is_empty() => return None,
+            Some(req::ToolChoice::Mode(mode)) if mode == "none" => return None,
+            Some(req::ToolChoice::Mode(mode)) if mode == "required" => {
+                "You must call at least one tool.".to_owned()
+            }
+            Some(req::ToolChoice::Function { function }) => {
+                format!("You must call the tool `{}`.", function.name)
+            }
+            _ => "If no tool is needed, reply to the user normally.".to_owned(),
+        };
+
+        let functions = body
+            .tools
+            .iter()
+            .map(|tool| &tool.function)
+            .collect::<Vec<_>>();
+        let prompt = format!(
+            "You can call the following tools, described as JSON schema:\n{}\n\n\
+            To call tools, reply with only a JSON object and nothing else, in the form:\n\
+            {{\"tool_calls\": [{{\"name\": \"<tool name>\", \"arguments\": {{<arguments>}}}}]}}\n\
+            {rule}\n\
+            The results of the tool calls are sent back to you, use them to answer the user.",
+            serde_json::to_string_pretty(&functions).unwrap_or_default()
+        );
+
+        Some(Self {
+            names: functions.iter().map(|f| f.name.clone()).collect(),
+            prompt,
+        })
+    }
+
+    pub(super) fn prompt(&self) -> &str {
+        &self.prompt
+    }
+
+    /// Parse the tool calls of the reply, `None` if the reply is a normal answer
+    fn parse(&self, reply: &str) -> Option<Vec<resp::ToolCall>> {
+        // The JSON may be wrapped by a markdown code block
+        let start = reply.find('{')?;
+        let end = reply.rfind('}')?;
+        let value = serde_json::from_str::<Value>(reply.get(start..=end)?).ok()?;
+
+        let calls = value["tool_calls"]
+            .as_array()?
+            .iter()
+            .filter_map(|call| {
+                let name = call["name"].as_str()?;
+                if !self.names.iter().any(|n| n == name) {
+                    return None;
+                }
+                let arguments = match &call["arguments"] {
+                    Value::String(arguments) => arguments.to_owned(),
+                    Value::Null => "{}".to_owned(),
+                    arguments => arguments.to_string(),
+                };
+                Some(resp::ToolCall {
+                    index: None,
+                    id: format!("call_{}", crate::generate_random_string(24)),
+                    kind: "function",
+                    function: resp::FunctionCall {
+                        name: name.to_owned(),
+                        arguments,
+                    },
+                })
+            })
+            .collect::<Vec<_>>();
+        (!calls.is_empty()).then_some(calls)
+    }
+}
+
+/// Text of the message for the model, tool calls and results are written in the prompt format
+pub(super) fn message_text(msg: &req::Message, messages: &[req::Message]) -> String {
+    let content = msg.content.clone().unwrap_or_default();
+    match msg.role {
+        req::MessageRole::Assistant if !msg.tool_calls.is_empty() => {
+            let calls = msg
+                .tool_calls
+                .iter()
+                .map(|call| {
+                    let arguments = serde_json::from_str::<Value>(&call.function.arguments)
+                        .unwrap_or_else(|_| Value::String(call.function.arguments.clone()));
+                    serde_json::json!({ "name": call.function.name, "arguments": arguments })
+                })
+                .collect::<Vec<_>>();
+            serde_json::json!({ "tool_calls": calls }).to_string()
+        }
+        req::MessageRole::Tool => {
+            let id = msg.tool_call_id.as_deref().unwrap_or_default();
+            let name = messages
+                .iter()
+                .flat_map(|m| m.tool_calls.iter())
+                .find(|call| call.id == id)
+                .map(|call| call.function.name.as_str())
+                .or(msg.name.as_deref())
+                .unwrap_or("tool");
+            format!("Result of the tool `{name}`:\n{content}")
+        }
+        _ => content,
+    }
+}
+
+/// The reply is buffered to tell tool calls from text, so it is streamed in one chunk
+pub(super) async fn handler(
+    event_soure: EventStream<
+        impl Stream<Item = Result<bytes::Bytes, reqwest::Error>> + std::marker::Unpin,
+    >,
+    model: String,
+    tools: Tools,
+    stream: bool,
+    prompt_tokens: i64,
+    include_usage: bool,
+) -> Result<Response, ResponseError> {
+    let id = generate_id(29);
+    let timestamp = current_timestamp();
+
+    if stream {
+        // Keepalive pings are sent while the reply is buffered
+        let events = async_stream::stream! {
+            let (reply, finish_reason) = collect_reply(event_soure).await;
+            let answer = Answer::new(&tools, reply, finish_reason, prompt_tokens).await;
+            for event in answer.events(&id, &timestamp, &model, include_usage) {
+                yield Ok::<_, Infallible>(event);
+            }
+        };
+        return Ok(sse_response(events));
+    }
+
+    let (reply, finish_reason) = collect_reply(event_soure).await;
+    let answer = Answer::new(&tools, reply, finish_reason, prompt_tokens).await;
+    let message = resp::Message::builder()
+        .role(Role::Assistant.to_string())
+        .content(answer.content)
+        .tool_calls(answer.tool_calls)
+        .build();
+    let resp = resp::Resp::builder()
+        .id(&id)
+        .object("chat.completion")
+        .created(&timestamp)
+        .model(&model)
+        .choices(vec![resp::Choice::builder()
+            .index(0)
+            .message(Some(message))
+            .finish_reason(Some(answer.finish_reason.as_str()))
+            .build()])
+        .usage(Some(answer.usage))
+        .build();
+    Ok(Json(resp).into_response())
+}
+
+/// The reply as either text or tool calls
+struct Answer {
+    content: Option<String>,
+    tool_calls: Option<Vec<resp::ToolCall>>,
+    finish_reason: String,
+    usage: resp::Usage,
+}
+
+impl Answer {
+    async fn new(
+        tools: &Tools,
+        reply: String,
+        finish_reason: Option<String>,
+        prompt_tokens: i64,
+    ) -> Self {
+        let usage = tokens::usage(prompt_tokens, tokens::count(&reply).await);
+        match tools.parse(&reply) {
+            Some(calls) => Self {
+                content: None,
+                tool_calls: Some(calls),
+                finish_reason: "tool_calls".to_owned(),
+                usage,
+            },
+            None => Self {
+                content: Some(reply),
+                tool_calls: None,
+                finish_reason: finish_reason.unwrap_or_else(|| "stop".to_owned()),
+                usage,
+            },
+        }
+    }
+
+    /// Chunks of the role with the content or tool calls, the finish reason,
+    /// the usage with `include_usage` and `[DONE]`
+    fn events(self, id: &str, timestamp: &i64, model: &str, include_usage: bool) -> Vec<Event> {
+        let tool_calls = self.tool_calls.map(|calls| {
+            calls
+                .into_iter()
+                .enumerate()
+                .map(|(index, call)| resp::ToolCall {
+                    index: Some(index),
+                    ..call
+                })
+                .collect()
+        });
+        let role = Role::Assistant;
+        let deltas = [
+            (
+                resp::Delta::builder()
+                    .role(Some(&role))
+                    .content(self.content.as_deref())
+                    .tool_calls(tool_calls)
+                    .build(),
+                None,
+            ),
+            (
+                resp::Delta::builder().build(),
+                Some(self.finish_reason.as_str()),
+            ),
+        ];
+
+        let mut events = Vec::with_capacity(deltas.len() + 2);
+        for (delta, finish_reason) in deltas {
+            // Without `include_usage` the usage is sent in the finish chunk
+            let usage = (finish_reason.is_some() && !include_usage).then(|| self.usage.clone());
+            let resp = resp::Resp::builder()
+                .id(id)
+                .object("chat.completion.chunk")
+                .created(timestamp)
+                .model(model)
+                .choices(vec![resp::Choice::builder()
+                    .index(0)
+                    .delta(Some(delta))
+                    .finish_reason(finish_reason)
+                    .build()])
+                .usage(usage)
+                .build();
+            match serde_json::to_string(&resp) {
+                Ok(data) => events.push(Event::default().data(format!(" {data}"))),
+                Err(err) => debug!("event source json serialize error: {}", err),
+            }
+        }
+        if include_usage {
+            match usage_event(id, timestamp, model, self.usage) {
+                Ok(event) => events.push(event),
+                Err(err) => debug!("event source json serialize error: {}", err),
+            }
+        }
+        events.push(Event::default().data("[DONE]"));
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tools() -> Tools {
+        Tools {
+            names: vec!["get_weather".to_owned()],
+            prompt: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_tools_parse() {
+        let tools = tools();
+
+        let calls = tools
+            .parse(r#"{"tool_calls": [{"name": "get_weather", "arguments": {"city": "Paris"}}]}"#)
+            .expect("tool call");
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].function.name, "get_weather");
+        assert_eq!(calls[0].function.arguments, r#"{"city":"Paris"}"#);
+        assert!(calls[0].id.starts_with("call_"));
+
+        // Wrapped by a markdown code block, arguments as a string or missing
+        let reply = "```json\n{\"tool_calls\": [\
+            {\"name\": \"get_weather\", \"arguments\": \"{\\\"city\\\": \\\"Oslo\\\"}\"},\
+            {\"name\": \"get_weather\"}]}\n```";
+        let calls = tools.parse(reply).expect("tool calls");
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].function.arguments, r#"{"city": "Oslo"}"#);
+        assert_eq!(calls[1].function.arguments, "{}");
+
+        // Unknown tools and normal answers
+        assert!(tools
+            .parse(r#"{"tool_calls": [{"name": "rm_rf", "arguments": {}}]}"#)
+            .is_none());
+        assert!(tools.parse("It is sunny in Paris.").is_none());
+        assert!(tools
+            .parse(r#"Use {"city": "Paris"} as the input"#)
+            .is_none());
+    }
+
+    #[test]
+    fn test_legacy_function_message() {
+        let messages = serde_json::from_str::<Vec<req::Message>>(
+            r#"[{"role": "function", "name": "get_weather", "content": "sunny"}]"#,
+        )
+        .expect("legacy function message");
+        assert!(messages[0].role == req::MessageRole::Tool);
+        assert_eq!(
+            message_text(&messages[0], &messages),
+            "Result of the tool `get_weather`:\nsunny"
+        );
+    }
+}
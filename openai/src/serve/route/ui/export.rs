@@ -0,0 +1,167 @@
+use serde::Serialize;
+use serde_json::Value;
+
+/// Conversation export format, `md`, `json`, `html` or `pdf`
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub(super) enum Format {
+    #[default]
+    Markdown,
+    Json,
+    Html,
+    Pdf,
+}
+
+impl Format {
+    pub(super) fn parse(s: Option<&str>) -> Option<Self> {
+        match s {
+            None | Some("md") | Some("markdown") => Some(Format::Markdown),
+            Some("json") => Some(Format::Json),
+            Some("html") => Some(Format::Html),
+            Some("pdf") => Some(Format::Pdf),
+            _ => None,
+        }
+    }
+
+    pub(super) fn extension(&self) -> &'static str {
+        match self {
+            Format::Markdown => "md",
+            Format::Json => "json",
+            Format::Html => "html",
+            Format::Pdf => "pdf",
+        }
+    }
+
+    pub(super) fn content_type(&self) -> &'static str {
+        match self {
+            Format::Markdown => "text/markdown; charset=utf-8",
+            Format::Json => "application/json",
+            Format::Html => "text/html; charset=utf-8",
+            Format::Pdf => "application/pdf",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(super) struct ExportMessage {
+    pub role: String,
+    pub content: String,
+    pub create_time: Option<f64>,
+    pub model: Option<String>,
+}
+
+/// The displayed thread of a conversation
+#[derive(Serialize)]
+pub(super) struct Thread {
+    pub id: String,
+    pub title: String,
+    pub create_time: Option<f64>,
+    pub update_time: Option<f64>,
+    pub messages: Vec<ExportMessage>,
+}
+
+impl Thread {
+    /// Walk the conversation tree from the current node back to the root
+    pub(super) fn from_conversation(id: String, convo: &Value) -> Self {
+        let mapping = &convo["mapping"];
+        // A broken tree must not loop forever
+        let max_depth = mapping.as_object().map(|m| m.len()).unwrap_or_default();
+
+        let mut messages = Vec::new();
+        let mut node = convo["current_node"].as_str();
+        for _ in 0..max_depth {
+            let Some(id) = node else { break };
+            let item = &mapping[id];
+            if let Some(message) = export_message(&item["message"]) {
+                messages.push(message);
+            }
+            node = item["parent"].as_str();
+        }
+        messages.reverse();
+
+        Self {
+            id,
+            title: convo["title"].as_str().unwrap_or("ChatGPT").to_owned(),
+            create_time: convo["create_time"].as_f64(),
+            update_time: convo["update_time"].as_f64(),
+            messages,
+        }
+    }
+
+    /// The shared thread, the share data lists its messages in order
+    pub(super) fn from_share(id: String, share: &Value) -> Self {
+        let Some(items) = share["linear_conversation"].as_array() else {
+            return Self::from_conversation(id, share);
+        };
+        Self {
+            id,
+            title: share["title"].as_str().unwrap_or("ChatGPT").to_owned(),
+            create_time: share["create_time"].as_f64(),
+            update_time: share["update_time"].as_f64(),
+            messages: items
+                .iter()
+                .filter_map(|item| export_message(&item["message"]))
+                .collect(),
+        }
+    }
+
+    pub(super) fn to_markdown(&self) -> String {
+        let mut md = format!("# {}\n\n", self.title);
+        for message in &self.messages {
+            md.push_str(&format!(
+                "## {}\n\n{}\n\n",
+                role_name(&message.role),
+                message.content
+            ));
+        }
+        md
+    }
+}
+
+/// Visible text of a message, `None` for system, hidden and empty messages
+fn export_message(message: &Value) -> Option<ExportMessage> {
+    let role = message["author"]["role"].as_str()?;
+    if role == "system"
+        || message["metadata"]["is_visually_hidden_from_conversation"]
+            .as_bool()
+            .unwrap_or_default()
+    {
+        return None;
+    }
+
+    let content = &message["content"];
+    let text = match content["content_type"].as_str() {
+        Some("code") => format!("```\n{}\n```", content["text"].as_str().unwrap_or_default()),
+        _ => match content["parts"].as_array() {
+            Some(parts) => parts
+                .iter()
+                .map(|part| match part.as_str() {
+                    Some(text) => text.to_owned(),
+                    None => "[attachment]".to_owned(),
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+            None => content["text"].as_str().unwrap_or_default().to_owned(),
+        },
+    };
+    if text.trim().is_empty() {
+        return None;
+    }
+
+    Some(ExportMessage {
+        role: role.to_owned(),
+        content: text,
+        create_time: message["create_time"].as_f64(),
+        model: message["metadata"]["model_slug"]
+            .as_str()
+            .map(ToOwned::to_owned),
+    })
+}
+
+pub(super) fn role_name(role: &str) -> &str {
+    match role {
+        "user" => "User",
+        "assistant" => "ChatGPT",
+        "tool" => "Tool",
+        _ => role,
+    }
+}
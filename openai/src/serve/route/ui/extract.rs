@@ -8,6 +8,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     auth::API_AUTH_SESSION_COOKIE_KEY,
+    context, now_duration,
     serve::{error::ResponseError, route::ui::LOGIN_INDEX, route::ui::SESSION_ID},
     token::model::AuthenticateToken,
 };
@@ -21,20 +22,66 @@ pub(super) struct Session {
     pub user_id: String,
     pub email: String,
     pub expires: i64,
+    /// Keep the cookie until the token expires
+    #[serde(default)]
+    pub remember: bool,
+    /// Unix seconds of the login, kept across session refreshes, 0 for older cookies
+    #[serde(default)]
+    pub created: i64,
+    /// Session generation of the account at the login, see `Context::session_generation`
+    #[serde(default)]
+    pub generation: u64,
+    /// Email of the OIDC user signed in to the pooled account of the session
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sso: Option<String>,
+    /// Id of the session kept on the server, `None` for new and legacy cookie sessions
+    #[serde(skip)]
+    pub id: Option<String>,
 }
 
-impl ToString for Session {
-    fn to_string(&self) -> String {
-        let json = serde_json::to_string(self)
-            .expect("An error occurred during the internal serialization session");
-        base64::engine::general_purpose::STANDARD.encode(json.as_bytes())
+impl Session {
+    /// End of the absolute session lifetime, `None` without a limit
+    pub fn deadline(&self) -> Option<i64> {
+        let max_age = context::get_instance().session_max_age();
+        (max_age > 0 && self.created > 0).then(|| self.created + max_age as i64)
+    }
+
+    /// The signed in user, the OIDC user of a pooled account or the account itself
+    pub fn user(&self) -> &str {
+        self.sso.as_deref().unwrap_or(&self.email)
+    }
+
+    /// Whether the user logged out everywhere since the session was issued
+    pub fn revoked(&self) -> bool {
+        self.generation < context::get_instance().session_generation(self.user())
+    }
+
+    /// Keep the session on the server, returns the id its cookie holds
+    pub fn save(&self) -> anyhow::Result<String> {
+        let id = self
+            .id
+            .clone()
+            .unwrap_or_else(|| crate::generate_random_string(SESSION_ID_LEN));
+        context::get_instance().web_sessions().insert(
+            &id,
+            serde_json::to_string(self)?,
+            self.expires,
+        )?;
+        Ok(id)
     }
 }
 
 impl FromStr for Session {
     type Err = ResponseError;
 
+    /// Session of the id kept on the server, or of a legacy cookie holding the whole session
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(data) = context::get_instance().web_sessions().get(s) {
+            let mut session =
+                serde_json::from_str::<Session>(&data).map_err(ResponseError::Unauthorized)?;
+            session.id = Some(s.to_owned());
+            return Ok(session);
+        }
         let data = base64::engine::general_purpose::STANDARD
             .decode(s)
             .map_err(ResponseError::Unauthorized)?;
@@ -51,12 +98,73 @@ impl From<AuthenticateToken> for Session {
             expires: value.expires(),
             refresh_token: value.refresh_token().map(|v| v.to_owned()),
             auth_session: value.auth_session().map(|v| v.to_owned()),
+            remember: false,
+            created: now_duration()
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or_default(),
+            generation: context::get_instance().session_generation(value.email()),
+            sso: None,
+            id: None,
         }
     }
 }
 
+/// Maximum number of accounts a browser can hold
+pub(super) const MAX_ACCOUNTS: usize = 5;
+
+/// Length of the random session ids
+const SESSION_ID_LEN: usize = 32;
+
+/// Active account slot of the browser, an index beyond the slots is ignored
+fn active_index(jar: &CookieJar) -> Option<usize> {
+    jar.get(SESSION_ID)?
+        .value()
+        .parse()
+        .ok()
+        .filter(|index| *index < MAX_ACCOUNTS)
+}
+
+/// Cookie holding the session of the account slot
+pub(super) fn slot_cookie_name(index: usize) -> String {
+    format!("{SESSION_ID}_{index}")
+}
+
+/// All account sessions held by the browser, by slot index
+pub(super) fn sessions(jar: &CookieJar) -> Vec<(usize, Session)> {
+    (0..MAX_ACCOUNTS)
+        .filter_map(|index| {
+            let cookie = jar.get(&slot_cookie_name(index))?;
+            Session::from_str(cookie.value())
+                .ok()
+                .filter(|session| !session.revoked())
+                .map(|session| (index, session))
+        })
+        .collect()
+}
+
+/// Slot for a new login, the slot of the same account, a free slot, or the active slot when full
+pub(super) fn login_slot(jar: &CookieJar, email: &str) -> usize {
+    let sessions = sessions(jar);
+    sessions
+        .iter()
+        .find(|(_, session)| session.email == email)
+        .map(|(index, _)| *index)
+        .or_else(|| (0..MAX_ACCOUNTS).find(|i| !sessions.iter().any(|(index, _)| index == i)))
+        .or_else(|| active_index(jar))
+        .unwrap_or_default()
+}
+
+/// Session of the active account slot
+pub(super) fn active_session(jar: &CookieJar) -> Option<(usize, Session)> {
+    let index = active_index(jar)?;
+    let session = Session::from_str(jar.get(&slot_cookie_name(index))?.value()).ok()?;
+    (!session.revoked()).then_some((index, session))
+}
+
 pub(super) struct SessionExtractor {
     pub session: Session,
+    /// Account slot index, `None` for a legacy single session cookie
+    pub index: Option<usize>,
     pub session_token: Option<String>,
     pub headers: HeaderMap,
     pub jar: CookieJar,
@@ -73,7 +181,22 @@ where
     async fn from_request(req: Request<B>, _: &S) -> Result<Self, Self::Rejection> {
         let (parts, _) = req.into_parts().into();
         let jar = CookieJar::from_headers(&parts.headers);
-        match jar.get(SESSION_ID) {
+
+        // `SESSION_ID` stores the active account slot, or the whole session of legacy cookies,
+        // without a valid slot the first held account is used
+        let (index, value) = match jar.get(SESSION_ID).map(|c| c.value().to_owned()) {
+            Some(value) if value.parse::<usize>().is_err() => (None, jar.get(SESSION_ID)),
+            _ => {
+                let index =
+                    active_index(&jar).or_else(|| sessions(&jar).first().map(|(index, _)| *index));
+                (
+                    index,
+                    index.and_then(|index| jar.get(&slot_cookie_name(index))),
+                )
+            }
+        };
+
+        match value {
             Some(c) => {
                 let session = extract_session(c.value())?;
                 let session_token = jar
@@ -81,6 +204,7 @@ where
                     .map(|c| c.value().to_owned());
                 Ok(SessionExtractor {
                     session,
+                    index,
                     session_token,
                     jar,
                     headers: parts.headers.clone(),
@@ -92,12 +216,24 @@ where
 }
 
 fn extract_session(cookie_value: &str) -> Result<Session, ResponseError> {
-    Session::from_str(cookie_value)
+    let session = Session::from_str(cookie_value)
         .map_err(|_| ResponseError::Unauthorized(anyhow::anyhow!("invalid session")))
         .and_then(|session| match check_token(&session.access_token) {
             Ok(_) => Ok(session),
             Err(err) => Err(err),
-        })
+        })?;
+
+    // The absolute lifetime is over, a refreshed token does not extend it, or the
+    // account logged out everywhere
+    let now = now_duration().map_err(ResponseError::InternalServerError)?;
+    if session.revoked()
+        || session
+            .deadline()
+            .is_some_and(|deadline| deadline <= now.as_secs() as i64)
+    {
+        return Err(ResponseError::TempporaryRedirect(LOGIN_INDEX));
+    }
+    Ok(session)
 }
 
 fn check_token(token: &str) -> Result<(), ResponseError> {
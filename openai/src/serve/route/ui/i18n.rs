@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+
+use axum::http::{header, HeaderMap};
+use axum_extra::extract::CookieJar;
+
+/// Cookie overriding the `Accept-Language` header, `en` or `zh`
+pub(super) const LANG_COOKIE: &str = "ninja_lang";
+
+const EN: &[(&str, &str)] = &[
+    ("welcome_back", "Welcome Back"),
+    ("welcome", "Welcome"),
+    ("email_address", "Email address"),
+    ("username", "Username"),
+    ("password", "Password"),
+    ("show_password", "Show password"),
+    ("hide_password", "Hide password"),
+    ("option", "Option"),
+    ("optional", "Optional"),
+    ("mfa_code", "MFA Code"),
+    ("continue", "Continue"),
+    ("need_access_token", "Need an access token?"),
+    ("go_get_it", "Go get it"),
+    ("or", "Or"),
+    ("continue_with_access_token", "Continue with Access Token"),
+    ("continue_with_sso", "Continue with SSO"),
+    ("ninja_on_github", "Ninja on GitHub"),
+    ("remember_me", "Remember me"),
+    ("add_account", "Add account"),
+    ("export", "Export"),
+    ("error_rate_limited_title", "Too many requests"),
+    (
+        "error_rate_limited",
+        "You are sending requests too quickly, please slow down.",
+    ),
+    ("error_upstream_title", "Something went wrong"),
+    (
+        "error_upstream",
+        "The service is temporarily unavailable, please try again later.",
+    ),
+    ("retry_after_prefix", "Retrying in "),
+    ("retry_after_suffix", " seconds"),
+    ("retry", "Retry"),
+    ("logout_all_title", "Log out everywhere"),
+    (
+        "logout_all",
+        "Sign this account out of every browser and device it is logged in on.",
+    ),
+    ("enter_email", "Enter your email address"),
+    ("enter_password", "Enter password"),
+    ("enter_mfa_code", "Enter MFA code (optional)"),
+    (
+        "auth_intro",
+        "This service helps users refused by ChatGPT to get an Access Token.",
+    ),
+    (
+        "auth_no_account",
+        "If you do not have a ChatGPT account, this service is of no use to you.",
+    ),
+    ("auth_expires_prefix", "The Access Token is valid for "),
+    ("auth_expires_suffix", " days."),
+    ("get_access_token", "Get Access Token"),
+    ("click_to_copy", "Click the text box to copy"),
+    ("full_data", "Full data"),
+    (
+        "auth_disclaimer",
+        "Disclaimer: this is a personal service, not an official OpenAI service!",
+    ),
+];
+
+const ZH: &[(&str, &str)] = &[
+    ("welcome_back", "欢迎回来"),
+    ("welcome", "欢迎"),
+    ("email_address", "邮箱"),
+    ("username", "用户名"),
+    ("password", "密码"),
+    ("show_password", "显示密码"),
+    ("hide_password", "隐藏密码"),
+    ("option", "选项"),
+    ("optional", "可选"),
+    ("mfa_code", "MFA 验证码"),
+    ("continue", "继续"),
+    ("need_access_token", "需要 Access Token？"),
+    ("go_get_it", "去获取"),
+    ("or", "或"),
+    ("continue_with_access_token", "使用 Access Token 登录"),
+    ("continue_with_sso", "使用单点登录"),
+    ("ninja_on_github", "GitHub 上的 Ninja"),
+    ("remember_me", "记住我"),
+    ("add_account", "添加账号"),
+    ("export", "导出"),
+    ("error_rate_limited_title", "请求过于频繁"),
+    ("error_rate_limited", "你的请求速度过快，请稍后再试。"),
+    ("error_upstream_title", "出错了"),
+    ("error_upstream", "服务暂时不可用，请稍后再试。"),
+    ("retry_after_prefix", "将在 "),
+    ("retry_after_suffix", " 秒后重试"),
+    ("retry", "重试"),
+    ("logout_all_title", "退出所有设备"),
+    ("logout_all", "将此账号从所有已登录的浏览器和设备退出。"),
+    ("enter_email", "请输入邮箱"),
+    ("enter_password", "请输入密码"),
+    ("enter_mfa_code", "请输入 MFA 验证码（可选）"),
+    (
+        "auth_intro",
+        "本服务可帮助ChatGPT被拒用户获取Access Token。",
+    ),
+    ("auth_no_account", "如果你没有ChatGPT账号，本服务对你无用。"),
+    ("auth_expires_prefix", "获取到的Access Token有效期为"),
+    ("auth_expires_suffix", "天。"),
+    ("get_access_token", "获取Access Token"),
+    ("click_to_copy", "点击文本框即可复制"),
+    ("full_data", "完整数据"),
+    (
+        "auth_disclaimer",
+        "由于一些你懂的原因，特申明：这是个人服务，非OpenAI的官方服务！",
+    ),
+];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(super) enum Lang {
+    En,
+    Zh,
+}
+
+impl Lang {
+    fn parse(s: &str) -> Option<Self> {
+        let s = s.trim().to_lowercase();
+        if s.starts_with("zh") {
+            Some(Lang::Zh)
+        } else if s.starts_with("en") {
+            Some(Lang::En)
+        } else {
+            None
+        }
+    }
+
+    /// Select from the `ninja_lang` cookie, then the `Accept-Language` header, default English
+    pub(super) fn from_headers(headers: &HeaderMap) -> Self {
+        let jar = CookieJar::from_headers(headers);
+        if let Some(lang) = jar.get(LANG_COOKIE).and_then(|c| Lang::parse(c.value())) {
+            return lang;
+        }
+
+        let accept = headers
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+
+        // e.g. `zh-CN,zh;q=0.9,en;q=0.8`, pick the supported language with the highest weight
+        let mut langs = accept
+            .split(',')
+            .filter_map(|item| {
+                let mut parts = item.split(';');
+                let lang = Lang::parse(parts.next()?)?;
+                let q = parts
+                    .find_map(|p| p.trim().strip_prefix("q="))
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                Some((lang, q))
+            })
+            .collect::<Vec<_>>();
+        langs.sort_by(|a, b| b.1.total_cmp(&a.1));
+        langs.first().map(|(lang, _)| *lang).unwrap_or(Lang::En)
+    }
+
+    pub(super) fn code(&self) -> &'static str {
+        match self {
+            Lang::En => "en",
+            Lang::Zh => "zh",
+        }
+    }
+
+    /// Template strings of the language
+    pub(super) fn strings(&self) -> HashMap<&'static str, &'static str> {
+        match self {
+            Lang::En => EN,
+            Lang::Zh => ZH,
+        }
+        .iter()
+        .copied()
+        .collect()
+    }
+}
@@ -1,4 +1,9 @@
+mod export;
 mod extract;
+mod i18n;
+mod oidc;
+mod pdf;
+mod theme;
 
 use anyhow::anyhow;
 use axum::body;
@@ -22,13 +27,14 @@ use axum_csrf::CsrfConfig;
 use axum_csrf::CsrfLayer;
 use axum_csrf::CsrfToken;
 use axum_csrf::Key;
+use axum_csrf::SameSite;
 use axum_extra::extract::cookie;
 use axum_extra::extract::CookieJar;
 use http::response::Builder;
 use serde_json::{json, Value};
+use sha2::{Digest, Sha512};
 use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::sync::OnceLock;
+use std::net::{IpAddr, SocketAddr};
 use time::format_description::well_known::Rfc3339;
 use tower::ServiceBuilder;
 use tower_http::ServiceBuilderExt;
@@ -41,10 +47,13 @@ use crate::debug;
 use crate::info;
 use crate::now_duration;
 use crate::serve;
+use crate::serve::buildid::build_id;
+use crate::serve::captcha;
 use crate::serve::convert::header_convert;
 use crate::serve::error::ResponseError;
-use crate::serve::route::ui::extract::SessionExtractor;
-use crate::serve::turnstile;
+use crate::serve::route::ui::extract::{
+    active_session, login_slot, sessions, slot_cookie_name, SessionExtractor,
+};
 use crate::serve::EMPTY;
 use crate::{
     auth::{model::AuthAccount, provide::AuthProvider},
@@ -52,7 +61,10 @@ use crate::{
     URL_CHATGPT_API,
 };
 
+use self::export::{Format, Thread};
 use self::extract::Session;
+use self::i18n::Lang;
+use self::theme::Theme;
 
 use super::get_static_resource;
 
@@ -60,15 +72,20 @@ const DEFAULT_INDEX: &str = "/";
 const LOGIN_INDEX: &str = "/auth/login";
 const SESSION_ID: &str = "ninja_session";
 const PUID_ID: &str = "_puid";
-const BUILD_ID: &str = "eFlZtDCQUjuHAccnRY3au";
 const TEMP_404: &str = "404.htm";
 const TEMP_AUTH: &str = "auth.htm";
 const TEMP_CHAT: &str = "chat.htm";
 const TEMP_DETAIL: &str = "detail.htm";
 const TEMP_LOGIN: &str = "login.htm";
 const TEMP_SHARE: &str = "share.htm";
-
-static TEMPLATE: OnceLock<tera::Tera> = OnceLock::new();
+const TEMP_THEME: &str = "theme.htm";
+const TEMP_BRAND: &str = "brand.htm";
+const TEMP_CAPTCHA: &str = "captcha.htm";
+const TEMP_ACCOUNTS: &str = "accounts.htm";
+const TEMP_EXPORT: &str = "export.htm";
+const TEMP_EXPORT_BUTTON: &str = "export_button.htm";
+const TEMP_ERROR: &str = "error.htm";
+const TEMP_LOGOUT_ALL: &str = "logout_all.htm";
 
 // this function could be located in a different module
 pub(super) fn config(router: Router, args: &ContextArgs) -> Router {
@@ -77,29 +94,30 @@ pub(super) fn config(router: Router, args: &ContextArgs) -> Router {
             info!("WebUI site use Arkose endpoint: {endpoint}")
         }
 
-        let mut tera = tera::Tera::default();
-        tera.add_raw_templates(vec![
-            (TEMP_404, include_str!("../../../../ui/404.htm")),
-            (TEMP_AUTH, include_str!("../../../../ui/auth.htm")),
-            (TEMP_LOGIN, include_str!("../../../../ui/login.htm")),
-            (TEMP_CHAT, include_str!("../../../../ui/chat.htm")),
-            (TEMP_DETAIL, include_str!("../../../../ui/detail.htm")),
-            (TEMP_SHARE, include_str!("../../../../ui/share.htm")),
-        ])
-        .expect("The static template failed to load");
+        if let Some(ui_dir) = args.ui_dir.as_ref() {
+            info!("WebUI site use directory: {}", ui_dir.display())
+        }
 
-        let _ = TEMPLATE.set(tera);
+        let tera = load_templates(args.ui_dir.as_deref()).expect("The template failed to load");
+        let _ = context::get_instance().templates().set(tera);
 
-        let cookie_key = Key::generate();
-        let config = CsrfConfig::default().with_key(Some(cookie_key));
+        let config = csrf_config(args);
 
-        let router = if context::get_instance().auth_key().is_some() {
-            router
+        let ui = Router::new();
+        let ui = if context::get_instance().auth_key().is_some() {
+            ui
         } else {
-            router.route("/auth", get(get_auth))
+            ui.route("/auth", get(get_auth))
         };
 
-        router
+        let ui = if context::get_instance().oidc().is_some() {
+            ui.route("/auth/oidc", get(oidc::get_oidc))
+                .route("/auth/oidc/callback", get(oidc::get_oidc_callback))
+        } else {
+            ui
+        };
+
+        let ui = ui
             .route(
                 "/auth/login",
                 post(post_login).layer(ServiceBuilder::new().map_request_body(body::boxed).layer(
@@ -107,14 +125,31 @@ pub(super) fn config(router: Router, args: &ContextArgs) -> Router {
                 )),
             )
             .route("/auth/login", get(get_login))
+            .route(
+                "/auth/logout_all",
+                post(post_logout_all).layer(
+                    ServiceBuilder::new().map_request_body(body::boxed).layer(
+                        axum::middleware::from_fn(serve::middleware::csrf::auth_middleware),
+                    ),
+                ),
+            )
+            .route("/auth/logout_all", get(get_logout_all))
+            .layer(axum::middleware::from_fn(
+                serve::middleware::csrf::rotate_middleware,
+            ))
             .layer(CsrfLayer::new(config))
             .route("/auth/login/token", post(post_login_token))
             .route("/auth/logout", get(get_logout))
+            .route("/auth/switch/:index", get(get_switch))
             .route("/auth/session", get(get_session))
             .route("/auth/me", get(get_auth_me))
             .route("/", get(get_chat))
             .route("/c", get(get_chat))
             .route("/c/:conversation_id", get(get_chat))
+            .route("/export/:conversation_id", get(get_export))
+            .route("/gpts", get(get_gpts))
+            .route("/gpts/*path", get(get_gpts))
+            .route("/g/:slug", get(get_gizmo))
             .route(
                 "/chat",
                 any(|| async {
@@ -135,78 +170,124 @@ pub(super) fn config(router: Router, args: &ContextArgs) -> Router {
                         .expect("An error occurred while redirecting")
                 }),
             )
-            .route("/share/e/:share_id", get(get_share_chat))
-            .route("/share/:share_id", get(get_share_chat))
-            .route("/share/:share_id/continue", get(get_share_chat_continue))
-            .route(
-                &format!("/_next/data/{BUILD_ID}/index.json"),
-                get(get_chat_info),
-            )
+            .route("/_next/data/:build_id/index.json", get(get_chat_info))
             .route(
                 // {conversation_id}.json
-                &format!("/_next/data/{BUILD_ID}/c/:conversation_id"),
+                "/_next/data/:build_id/c/:conversation_id",
                 get(get_chat_info),
             )
+            .route("/_next/data/:build_id/gpts.json", get(get_chat_info))
             .route(
-                // {share_id}.json
-                &format!("/_next/data/{BUILD_ID}/share/:share_id"),
-                get(get_share_chat_info),
-            )
-            .route(
-                &format!("/_next/data/{BUILD_ID}/share/:share_id/continue.json"),
-                get(get_share_chat_continue_info),
-            )
+                // {slug}.json
+                "/_next/data/:build_id/g/:slug",
+                get(get_gizmo_info),
+            );
+
+        let ui = if args.disable_share {
+            ui
+        } else {
+            ui.route("/share/e/:share_id", get(get_share_chat))
+                .route("/share/:share_id", get(get_share_chat))
+                .route("/share/:share_id/continue", get(get_share_chat_continue))
+                .route("/share/:share_id/export.pdf", get(get_share_export_pdf))
+                .route("/share/:share_id/export.html", get(get_share_export_html))
+                .route(
+                    // {share_id}.json
+                    "/_next/data/:build_id/share/:share_id",
+                    get(get_share_chat_info),
+                )
+                .route(
+                    "/_next/data/:build_id/share/:share_id/continue.json",
+                    get(get_share_chat_continue_info),
+                )
+        };
+
+        let ui = ui
+            .layer(axum::middleware::from_fn(sliding_session_middleware))
             // static resource endpoints
             .route("/resources/*path", get(get_static_resource))
             .route("/_next/static/*path", get(get_static_resource))
             .route("/fonts/*path", get(get_static_resource))
             .route("/ulp/*path", get(get_static_resource))
             .route("/sweetalert2/*path", get(get_static_resource))
+            .route_layer(axum::middleware::from_fn(
+                serve::middleware::webui_toggle_middleware,
+            ))
             // 404 endpoint
             .fallback(error_404)
+            .layer(axum::middleware::from_fn(
+                serve::middleware::security_headers_middleware,
+            ))
+            // The error pages are for the browsers of the WebUI, not the API clients
+            .layer(axum::middleware::from_fn(error_page_middleware));
+
+        router.merge(ui)
     } else {
         router
     }
 }
 
-async fn get_auth(token: CsrfToken) -> Result<impl IntoResponse, ResponseError> {
+async fn get_auth(
+    addr: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    token: CsrfToken,
+) -> Result<impl IntoResponse, ResponseError> {
     let mut ctx = tera::Context::new();
     ctx.insert("csrf_token", &token.authenticity_token()?);
-    settings_template_data(&mut ctx);
+    settings_template_data(&mut ctx, &headers);
+    captcha_template_data(
+        &mut ctx,
+        &captcha::client_ip(addr.as_ref().map(|ConnectInfo(addr)| addr), &headers),
+    );
     let tm = render_template(TEMP_AUTH, &ctx)?;
     Ok((token, tm))
 }
 
-async fn get_login(token: CsrfToken) -> Result<impl IntoResponse, ResponseError> {
+async fn get_login(
+    addr: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    token: CsrfToken,
+) -> Result<impl IntoResponse, ResponseError> {
     let mut ctx = tera::Context::new();
     ctx.insert("csrf_token", &token.authenticity_token()?);
     ctx.insert("error", "");
     ctx.insert("username", "");
-    settings_template_data(&mut ctx);
+    settings_template_data(&mut ctx, &headers);
+    captcha_template_data(
+        &mut ctx,
+        &captcha::client_ip(addr.as_ref().map(|ConnectInfo(addr)| addr), &headers),
+    );
     let tm = render_template(TEMP_LOGIN, &ctx)?;
     Ok((token, tm))
 }
 
 async fn post_login(
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    addr: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    jar: CookieJar,
     token: CsrfToken,
-    mut account: axum::Form<AuthAccount>,
+    account: axum::Form<AuthAccount>,
 ) -> Result<impl IntoResponse, ResponseError> {
-    turnstile::cf_turnstile_check(&addr.ip(), account.cf_turnstile_response.as_deref()).await?;
-
-    match serve::try_login(&mut account).await {
-        Ok(access_token) => {
-            let authentication_token = AuthenticateToken::try_from(access_token)
-                .map_err(ResponseError::InternalServerError)?;
-            let session = Session::from(authentication_token);
-
-            let cookie = cookie::Cookie::build(SESSION_ID, session.to_string())
-                .path(DEFAULT_INDEX)
-                .same_site(cookie::SameSite::Lax)
-                .expires(time::OffsetDateTime::from_unix_timestamp(session.expires)?)
-                .secure(false)
-                .http_only(false)
-                .finish();
+    let client_ip = captcha::client_ip(addr.as_ref().map(|ConnectInfo(addr)| addr), &headers);
+    captcha::captcha_check(&client_ip, account.captcha_response()).await?;
+
+    // Configured only with the `ldap` feature, the start refuses the LDAP url otherwise
+    #[cfg(feature = "ldap")]
+    let result = match context::get_instance().ldap().is_some() {
+        true => {
+            serve::ldap::throttle(&account.username, &client_ip)?;
+            ldap_session(&account, &client_ip).await
+        }
+        false => password_session(&account).await,
+    };
+    #[cfg(not(feature = "ldap"))]
+    let result = password_session(&account).await;
+    match result {
+        Ok(mut session) => {
+            captcha::record_pass(&client_ip);
+            session.remember = account.remember;
+            let index = login_slot(&jar, &session.email);
+            let cookie = session_cookie(index, &session)?;
 
             let mut builder = Response::builder()
                 .status(StatusCode::SEE_OTHER)
@@ -226,6 +307,7 @@ async fn post_login(
 
             Ok(builder
                 .header(header::SET_COOKIE, cookie.to_string())
+                .header(header::SET_COOKIE, active_cookie(index).to_string())
                 .body(Body::empty())
                 .map_err(ResponseError::InternalServerError)?
                 .into_response())
@@ -234,15 +316,35 @@ async fn post_login(
             let mut ctx = tera::Context::new();
             ctx.insert("csrf_token", &token.authenticity_token()?);
             ctx.insert("username", &account.username);
+            ctx.insert("remember", &account.remember);
             ctx.insert("error", &err.to_string());
+            settings_template_data(&mut ctx, &headers);
+            captcha_template_data(&mut ctx, &client_ip);
             let tm = render_template(TEMP_LOGIN, &ctx)?;
             Ok((token, tm).into_response())
         }
     }
 }
 
+/// Session of the OpenAI account of the login form
+async fn password_session(account: &axum::Form<AuthAccount>) -> anyhow::Result<Session> {
+    if !context::get_instance().login_allowed(&account.username) {
+        anyhow::bail!("{} is not allowed to log in", account.username)
+    }
+    let access_token = serve::try_login(account).await?;
+    Ok(Session::from(AuthenticateToken::try_from(access_token)?))
+}
+
+/// Session of the pooled account of the directory user of the login form
+#[cfg(feature = "ldap")]
+async fn ldap_session(account: &axum::Form<AuthAccount>, addr: &IpAddr) -> anyhow::Result<Session> {
+    let (user, token) = serve::ldap::login(&account.username, &account.password, addr).await?;
+    Ok(oidc::sso_session(token, user))
+}
+
 async fn post_login_token(
     TypedHeader(bearer): TypedHeader<Authorization<Bearer>>,
+    jar: CookieJar,
 ) -> Result<Response<Body>, ResponseError> {
     let access_token = bearer.token();
 
@@ -256,6 +358,13 @@ async fn post_login_token(
             "Get Profile Erorr"
         )))?;
 
+    if !context::get_instance().login_allowed(profile.email()) {
+        return Err(ResponseError::Forbidden(anyhow!(
+            "{} is not allowed to log in",
+            profile.email()
+        )));
+    }
+
     let session = Session {
         access_token: access_token.to_owned(),
         user_id: profile.user_id().to_owned(),
@@ -263,60 +372,144 @@ async fn post_login_token(
         expires: profile.expires(),
         refresh_token: None,
         auth_session: None,
+        remember: true,
+        created: now_duration()?.as_secs() as i64,
+        generation: context::get_instance().session_generation(profile.email()),
+        sso: None,
+        id: None,
     };
-
-    let cookie = cookie::Cookie::build(SESSION_ID, session.to_string())
-        .path(DEFAULT_INDEX)
-        .same_site(cookie::SameSite::Lax)
-        .expires(time::OffsetDateTime::from_unix_timestamp(session.expires)?)
-        .secure(false)
-        .http_only(false)
-        .finish();
+    let index = login_slot(&jar, &session.email);
+    let cookie = session_cookie(index, &session)?;
 
     return Ok(Response::builder()
         .status(StatusCode::OK)
         .header(header::LOCATION, DEFAULT_INDEX)
         .header(header::SET_COOKIE, cookie.to_string())
+        .header(header::SET_COOKIE, active_cookie(index).to_string())
         .body(Body::empty())
         .map_err(ResponseError::InternalServerError)?);
 }
 
 async fn get_logout(extract: SessionExtractor) -> Result<Response<Body>, ResponseError> {
     // If the session is empty, then redirect to the login page
-    if let Some(refresh_token) = extract.session.refresh_token {
+    if let Some(refresh_token) = extract.session.refresh_token.as_ref() {
         let ctx = context::get_instance();
-        let _a = ctx.auth_client().do_revoke_token(&refresh_token).await;
+        let _a = ctx.auth_client().do_revoke_token(refresh_token).await;
     }
 
-    // Clear session
-    let session_cookie = cookie::Cookie::build(SESSION_ID, EMPTY)
-        .path(DEFAULT_INDEX)
-        .same_site(cookie::SameSite::Lax)
-        .max_age(time::Duration::seconds(0))
-        .secure(false)
-        .http_only(false)
-        .finish();
+    logout_response(extract)
+}
+
+/// GET /auth/logout_all, the confirmation form of the signed in account
+async fn get_logout_all(
+    headers: HeaderMap,
+    token: CsrfToken,
+    extract: SessionExtractor,
+) -> Result<impl IntoResponse, ResponseError> {
+    let mut ctx = tera::Context::new();
+    ctx.insert("csrf_token", &token.authenticity_token()?);
+    ctx.insert("email", extract.session.user());
+    settings_template_data(&mut ctx, &headers);
+    let tm = render_template(TEMP_LOGOUT_ALL, &ctx)?;
+    Ok((token, tm))
+}
+
+/// POST /auth/logout_all, log the account out of every browser: the refresh token is revoked,
+/// the state kept for the account is dropped and the sessions issued so far stop validating
+async fn post_logout_all(extract: SessionExtractor) -> Result<Response<Body>, ResponseError> {
+    let ctx = context::get_instance();
+    let user = extract.session.user();
+    if let Some(refresh_token) = extract.session.refresh_token.as_ref() {
+        if let Err(err) = ctx.auth_client().do_revoke_token(refresh_token).await {
+            debug!("Revoke token error: {}", err);
+        }
+    }
+    ctx.bump_session_generation(user)
+        .map_err(ResponseError::InternalServerError)?;
+    // A pooled account is shared by the OIDC users, its state stays
+    if extract.session.sso.is_none() {
+        if let Some(cache) = ctx.conversation_cache() {
+            cache.invalidate(user);
+        }
+        serve::puid::invalidate_puid(user).await;
+    }
+    info!("WebUI sessions of {user} revoked");
+
+    logout_response(extract)
+}
+
+/// Clear the session of the browser, then switch to another held account or go to the login page
+fn logout_response(extract: SessionExtractor) -> Result<Response<Body>, ResponseError> {
+    if let Some(id) = extract.session.id.as_deref() {
+        context::get_instance().web_sessions().remove(id)?;
+    }
+
+    // Clear the account slot, or the legacy session
+    let session_cookie = clear_cookie(
+        extract
+            .index
+            .map(slot_cookie_name)
+            .unwrap_or(SESSION_ID.to_owned()),
+    );
 
     // Clear puid
-    let puid_cookie = cookie::Cookie::build(PUID_ID, EMPTY)
+    let puid_cookie = clear_cookie(PUID_ID.to_owned());
+
+    // Switch to another held account, otherwise redirect to login page
+    let next = sessions(&extract.jar)
+        .into_iter()
+        .map(|(index, _)| index)
+        .find(|index| Some(*index) != extract.index);
+    let (location, active_cookie) = match next {
+        Some(index) => (DEFAULT_INDEX, active_cookie(index)),
+        None => (LOGIN_INDEX, clear_cookie(SESSION_ID.to_owned())),
+    };
+
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, location)
+        .header(header::SET_COOKIE, session_cookie.to_string())
+        .header(header::SET_COOKIE, puid_cookie.to_string())
+        .header(header::SET_COOKIE, active_cookie.to_string())
+        .body(Body::empty())
+        .map_err(ResponseError::InternalServerError)?)
+}
+
+/// GET /auth/switch/:index, switch the active account
+async fn get_switch(
+    Path(index): Path<usize>,
+    jar: CookieJar,
+) -> Result<Response<Body>, ResponseError> {
+    if !sessions(&jar).iter().any(|(i, _)| *i == index) {
+        return Err(ResponseError::TempporaryRedirect(LOGIN_INDEX));
+    }
+
+    // The puid and auth session belong to the previous account
+    let auth_session_cookie = cookie::Cookie::build(API_AUTH_SESSION_COOKIE_KEY, EMPTY)
         .path(DEFAULT_INDEX)
         .same_site(cookie::SameSite::Lax)
         .max_age(time::Duration::seconds(0))
-        .secure(false)
+        .secure(true)
         .http_only(false)
         .finish();
 
-    // Redirect to login page
     Ok(Response::builder()
         .status(StatusCode::FOUND)
-        .header(header::LOCATION, LOGIN_INDEX)
-        .header(header::SET_COOKIE, session_cookie.to_string())
-        .header(header::SET_COOKIE, puid_cookie.to_string())
+        .header(header::LOCATION, DEFAULT_INDEX)
+        .header(header::SET_COOKIE, active_cookie(index).to_string())
+        .header(
+            header::SET_COOKIE,
+            clear_cookie(PUID_ID.to_owned()).to_string(),
+        )
+        .header(header::SET_COOKIE, auth_session_cookie.to_string())
         .body(Body::empty())
         .map_err(ResponseError::InternalServerError)?)
 }
 
 async fn get_session(extract: SessionExtractor) -> Result<Response<Body>, ResponseError> {
+    // Legacy session cookies are migrated to the first account slot
+    let index = extract.index.unwrap_or(0);
+
     // Compare the current timestamp with the expiration time of the session
     let current_timestamp = now_duration()?.as_secs() as i64;
     if extract.session.expires < current_timestamp {
@@ -324,7 +517,8 @@ async fn get_session(extract: SessionExtractor) -> Result<Response<Body>, Respon
     }
 
     // Refresh session
-    if extract.session.expires - current_timestamp <= 21600 {
+    let refresh_window = context::get_instance().session_refresh_window() as i64;
+    if extract.session.expires - current_timestamp <= refresh_window {
         let ctx = context::get_instance();
         let new_session = if let Some(c) = extract.session_token {
             match ctx.auth_client().do_session(&c).await {
@@ -338,6 +532,15 @@ async fn get_session(extract: SessionExtractor) -> Result<Response<Body>, Respon
                     None
                 }
             }
+        } else if let Some(user) = extract.session.sso.as_ref() {
+            // The pooled account is refreshed by ninja with its credential
+            match serve::pooled::pooled_token(&extract.session.email).await {
+                Ok(token) => Some(oidc::sso_session(token, user.to_owned())),
+                Err(err) => {
+                    debug!("Pooled account token error: {}", err);
+                    None
+                }
+            }
         } else if let Some(refresh_token) = extract.session.refresh_token.as_ref() {
             match ctx.auth_client().do_refresh_token(&refresh_token).await {
                 Ok(new_refresh_token) => {
@@ -353,25 +556,89 @@ async fn get_session(extract: SessionExtractor) -> Result<Response<Body>, Respon
             None
         };
 
-        if let Some(new_session) = new_session {
-            return create_response_from_session(&new_session);
+        if let Some(mut new_session) = new_session {
+            new_session.remember = extract.session.remember;
+            new_session.created = extract.session.created;
+            new_session.id = extract.session.id.clone();
+            return create_response_from_session(index, &new_session);
         }
     }
 
-    create_response_from_session(&extract.session)
+    create_response_from_session(index, &extract.session)
 }
 
-fn create_response_from_session(session: &Session) -> Result<Response<Body>, ResponseError> {
+fn create_response_from_session(
+    index: usize,
+    session: &Session,
+) -> Result<Response<Body>, ResponseError> {
     let body = session_to_body(session)?;
     Ok(Response::builder()
         .status(StatusCode::OK)
         .header(header::LOCATION, LOGIN_INDEX)
-        .header(header::SET_COOKIE, session.to_string()) // Note: This might not be what you want
+        .header(
+            header::SET_COOKIE,
+            session_cookie(index, session)?.to_string(),
+        )
+        .header(header::SET_COOKIE, active_cookie(index).to_string())
         .header(header::CONTENT_TYPE, "application/json")
         .body(Body::from(body))
         .map_err(ResponseError::InternalServerError)?)
 }
 
+/// Build the account slot cookie holding the id of the session kept on the server, remembered
+/// sessions last until the token expires, others until the browser is closed or the configured
+/// session lifetime if any
+fn session_cookie(
+    index: usize,
+    session: &Session,
+) -> Result<cookie::Cookie<'static>, ResponseError> {
+    let builder = cookie::Cookie::build(slot_cookie_name(index), session.save()?)
+        .path(DEFAULT_INDEX)
+        .same_site(cookie::SameSite::Lax)
+        .secure(false)
+        .http_only(false);
+
+    let lifetime = context::get_instance().session_lifetime();
+    let deadline = session.deadline().unwrap_or(i64::MAX);
+    let expires = match lifetime.filter(|_| !session.remember) {
+        None => Some(session.expires),
+        Some(0) => None,
+        // Sliding expiry, extended on every session refresh, or every request in sliding mode
+        Some(lifetime) => {
+            Some((now_duration()?.as_secs() + lifetime).min(session.expires as u64) as i64)
+        }
+    };
+    let builder = match expires.map(|expires| expires.min(deadline)) {
+        Some(expires) => builder.expires(time::OffsetDateTime::from_unix_timestamp(expires)?),
+        // A browser session cookie still ends with the absolute lifetime
+        None if deadline < i64::MAX => {
+            builder.expires(time::OffsetDateTime::from_unix_timestamp(deadline)?)
+        }
+        None => builder,
+    };
+    Ok(builder.finish())
+}
+
+/// Build the cookie selecting the active account slot
+fn active_cookie(index: usize) -> cookie::Cookie<'static> {
+    cookie::Cookie::build(SESSION_ID, index.to_string())
+        .path(DEFAULT_INDEX)
+        .same_site(cookie::SameSite::Lax)
+        .secure(false)
+        .http_only(false)
+        .finish()
+}
+
+fn clear_cookie(name: String) -> cookie::Cookie<'static> {
+    cookie::Cookie::build(name, EMPTY)
+        .path(DEFAULT_INDEX)
+        .same_site(cookie::SameSite::Lax)
+        .max_age(time::Duration::seconds(0))
+        .secure(false)
+        .http_only(false)
+        .finish()
+}
+
 fn session_to_body(session: &Session) -> anyhow::Result<String> {
     let expires = time::OffsetDateTime::from_unix_timestamp(session.expires)
         .map(|v| v.format(&Rfc3339))??;
@@ -440,31 +707,117 @@ async fn get_chat(
         }
         None => TEMP_CHAT,
     };
+    render_chat(template_name, query.0, page_props(&extract), &extract)
+}
+
+/// GET /gpts and /gpts/*path, the GPT store
+async fn get_gpts(
+    mut query: Query<HashMap<String, String>>,
+    extract: SessionExtractor,
+) -> Result<Response<Body>, ResponseError> {
+    query.insert("default".to_string(), "[gpts]".to_owned());
+    render_chat(TEMP_CHAT, query.0, page_props(&extract), &extract)
+}
+
+/// GET /g/:slug, a custom GPT of the store
+async fn get_gizmo(
+    Path(slug): Path<String>,
+    mut query: Query<HashMap<String, String>>,
+    extract: SessionExtractor,
+) -> Result<Response<Body>, ResponseError> {
+    let Some(gizmo) = gizmo_data(&slug, &extract).await? else {
+        return error_404(extract.headers).await;
+    };
+    let mut props = page_props(&extract);
+    props["gizmo"] = gizmo;
+    query.insert("default".to_string(), format!("[g, {slug}]"));
+    render_chat(TEMP_CHAT, query.0, props, &extract)
+}
+
+/// GET /_next/data/:build_id/g/:slug
+async fn get_gizmo_info(
+    Path((_, slug)): Path<(String, String)>,
+    extract: SessionExtractor,
+) -> Result<Response<Body>, ResponseError> {
+    let slug = slug.trim_end_matches(".json");
+    let body = match gizmo_data(slug, &extract).await? {
+        Some(gizmo) => {
+            let mut props = page_props(&extract);
+            props["gizmo"] = gizmo;
+            json!({ "pageProps": props, "__N_SSP": true })
+        }
+        None => json!({ "notFound": true }),
+    };
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .map_err(ResponseError::InternalServerError)?)
+}
+
+/// Gizmo of the slug from the upstream with the session of the user, `None` if it is not found
+async fn gizmo_data(
+    slug: &str,
+    extract: &SessionExtractor,
+) -> Result<Option<Value>, ResponseError> {
+    if slug.is_empty() || !slug.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Ok(None);
+    }
+    let resp = context::get_instance()
+        .sticky_client(Some(&extract.session.email), None)
+        .get(format!("{URL_CHATGPT_API}/backend-api/gizmos/{slug}"))
+        .headers(header_convert(
+            &extract.headers,
+            &extract.jar,
+            URL_CHATGPT_API,
+        )?)
+        .bearer_auth(&extract.session.access_token)
+        .send()
+        .await
+        .map_err(ResponseError::InternalServerError)?;
+    if !resp.status().is_success() {
+        return Ok(None);
+    }
+    Ok(resp.json::<Value>().await.ok())
+}
+
+/// Page props of the signed in user
+fn page_props(extract: &SessionExtractor) -> Value {
+    json!({
+        "user": {
+            "id": extract.session.user_id,
+            "name": extract.session.email,
+            "email": extract.session.email,
+            "image": null,
+            "picture": null,
+            "groups": [],
+        },
+        "serviceStatus": {},
+        "userCountry": "US",
+        "geoOk": true,
+        "serviceAnnouncement": {
+            "paid": {},
+            "public": {}
+        },
+        "isUserInCanPayGroup": true
+    })
+}
+
+/// Chat application page, the client side router shows the page of the `default` query
+fn render_chat(
+    template_name: &str,
+    query: HashMap<String, String>,
+    page_props: Value,
+    extract: &SessionExtractor,
+) -> Result<Response<Body>, ResponseError> {
     let props = serde_json::json!({
         "props": {
-            "pageProps": {
-                "user": {
-                    "id": extract.session.user_id,
-                    "name": extract.session.email,
-                    "email": extract.session.email,
-                    "image": null,
-                    "picture": null,
-                    "groups": [],
-                },
-                "serviceStatus": {},
-                "userCountry": "US",
-                "geoOk": true,
-                "serviceAnnouncement": {
-                    "paid": {},
-                    "public": {}
-                },
-                "isUserInCanPayGroup": true
-            },
+            "pageProps": page_props,
             "__N_SSP": true
         },
         "page": "/[[...default]]",
-        "query": query.0,
-        "buildId": BUILD_ID,
+        "query": query,
+        "buildId": build_id(),
         "assetPrefix": "https://cdn.oaistatic.com",
         "isFallback": false,
         "gssp": true,
@@ -475,30 +828,130 @@ async fn get_chat(
         "props",
         &serde_json::to_string(&props).map_err(ResponseError::InternalServerError)?,
     );
-    settings_template_data(&mut ctx);
-    return render_template(template_name, &ctx);
+    let accounts = sessions(&extract.jar)
+        .into_iter()
+        .map(|(index, session)| {
+            json!({
+                "index": index,
+                "email": session.email,
+                "active": Some(index) == extract.index,
+            })
+        })
+        .collect::<Vec<_>>();
+    ctx.insert("accounts", &accounts);
+    settings_template_data(&mut ctx, &extract.headers);
+    render_template(template_name, &ctx)
+}
+
+/// GET /export/:conversation_id?format=md|json|html|pdf
+async fn get_export(
+    Path(conversation_id): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+    extract: SessionExtractor,
+) -> Result<Response<Body>, ResponseError> {
+    let format = Format::parse(query.get("format").map(String::as_str)).ok_or(
+        ResponseError::BadRequest(anyhow!("format must be one of md, json, html")),
+    )?;
+    if !conversation_id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return Err(ResponseError::BadRequest(anyhow!(
+            "invalid conversation id"
+        )));
+    }
+
+    let convo = context::get_instance()
+        .sticky_client(Some(&extract.session.email), None)
+        .get(format!(
+            "{URL_CHATGPT_API}/backend-api/conversation/{conversation_id}"
+        ))
+        .headers(header_convert(
+            &extract.headers,
+            &extract.jar,
+            URL_CHATGPT_API,
+        )?)
+        .bearer_auth(&extract.session.access_token)
+        .send()
+        .await
+        .map_err(ResponseError::InternalServerError)?
+        .error_for_status()
+        .map_err(ResponseError::BadRequest)?
+        .json::<Value>()
+        .await
+        .map_err(ResponseError::InternalServerError)?;
+
+    let thread = Thread::from_conversation(conversation_id, &convo);
+    export_response(&thread, format, &extract.headers)
+}
+
+/// GET /share/:share_id/export.pdf
+async fn get_share_export_pdf(
+    Path(share_id): Path<String>,
+    extract: SessionExtractor,
+) -> Result<Response<Body>, ResponseError> {
+    share_export(share_id, Format::Pdf, extract).await
+}
+
+/// GET /share/:share_id/export.html
+async fn get_share_export_html(
+    Path(share_id): Path<String>,
+    extract: SessionExtractor,
+) -> Result<Response<Body>, ResponseError> {
+    share_export(share_id, Format::Html, extract).await
+}
+
+/// The shared conversation as a standalone document
+async fn share_export(
+    share_id: String,
+    format: Format,
+    extract: SessionExtractor,
+) -> Result<Response<Body>, ResponseError> {
+    let share = share_data(&share_id, &extract)
+        .await?
+        .filter(|share| share["linear_conversation"].is_array() || share["mapping"].is_object())
+        .ok_or(ResponseError::NotFound(anyhow!("share not found")))?;
+    let thread = Thread::from_share(share_id, &share);
+    export_response(&thread, format, &extract.headers)
+}
+
+fn export_response(
+    thread: &Thread,
+    format: Format,
+    headers: &HeaderMap,
+) -> Result<Response<Body>, ResponseError> {
+    let body = match format {
+        Format::Markdown => thread.to_markdown().into_bytes(),
+        Format::Json => {
+            serde_json::to_vec_pretty(thread).map_err(ResponseError::InternalServerError)?
+        }
+        Format::Html => {
+            let mut ctx = tera::Context::new();
+            ctx.insert("thread", thread);
+            settings_template_data(&mut ctx, headers);
+            render(TEMP_EXPORT, &ctx)?.into_bytes()
+        }
+        Format::Pdf => pdf::render(thread),
+    };
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, format.content_type())
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"{}.{}\"",
+                thread.id,
+                format.extension()
+            ),
+        )
+        .body(Body::from(body))
+        .map_err(ResponseError::InternalServerError)?)
 }
 
 async fn get_chat_info(extract: SessionExtractor) -> Result<Response<Body>, ResponseError> {
     let body = serde_json::json!({
-        "pageProps": {
-            "user": {
-                "id": extract.session.user_id,
-                "name": extract.session.email,
-                "email": extract.session.email,
-                "image": null,
-                "picture": null,
-                "groups": [],
-            },
-            "serviceStatus": {},
-            "userCountry": "US",
-            "geoOk": true,
-            "serviceAnnouncement": {
-                "paid": {},
-                "public": {}
-            },
-            "isUserInCanPayGroup": true
-        },
+        "pageProps": page_props(&extract),
         "__N_SSP": true
     });
 
@@ -509,12 +962,17 @@ async fn get_chat_info(extract: SessionExtractor) -> Result<Response<Body>, Resp
         .map_err(ResponseError::InternalServerError)?)
 }
 
-async fn get_share_chat(
-    share_id: Path<String>,
-    extract: SessionExtractor,
-) -> Result<Response<Body>, ResponseError> {
-    let share_id = share_id.0;
-    let resp = context::get_instance()
+/// Share data of the share pages, the successful ones are cached for the share cache ttl
+async fn share_data(
+    share_id: &str,
+    extract: &SessionExtractor,
+) -> Result<Option<Value>, ResponseError> {
+    let ctx = context::get_instance();
+    if let Some(share_data) = ctx.share_cache().and_then(|cache| cache.get(share_id)) {
+        return Ok(Some(share_data));
+    }
+
+    let resp = ctx
         .client()
         .get(format!("{URL_CHATGPT_API}/backend-api/share/{share_id}"))
         .headers(header_convert(
@@ -525,19 +983,36 @@ async fn get_share_chat(
         .send()
         .await
         .map_err(ResponseError::InternalServerError)?;
+    let status = resp.status();
+    let Ok(mut share_data) = resp.json::<Value>().await else {
+        return Ok(None);
+    };
+    if let Some(replace) = share_data
+        .get_mut("continue_conversation_url")
+        .and_then(|v| v.as_str())
+    {
+        let new_value = replace.replace("https://chat.openai.com", "");
+        share_data
+            .as_object_mut()
+            .and_then(|data| data.insert("continue_conversation_url".to_owned(), json!(new_value)));
+    }
 
-    return match resp.json::<Value>().await {
-        Ok(mut share_data) => {
-            if let Some(replace) = share_data
-                .get_mut("continue_conversation_url")
-                .and_then(|v| v.as_str())
-            {
-                let new_value = replace.replace("https://chat.openai.com", "");
-                share_data.as_object_mut().and_then(|data| {
-                    data.insert("continue_conversation_url".to_owned(), json!(new_value))
-                });
-            }
+    if status.is_success() {
+        if let Some(cache) = ctx.share_cache() {
+            cache.insert(share_id, share_data.clone());
+        }
+    }
+    Ok(Some(share_data))
+}
+
+async fn get_share_chat(
+    share_id: Path<String>,
+    extract: SessionExtractor,
+) -> Result<Response<Body>, ResponseError> {
+    let share_id = share_id.0;
 
+    return match share_data(&share_id, &extract).await? {
+        Some(share_data) => {
             let props = serde_json::json!({
                         "props": {
                             "pageProps": {
@@ -556,7 +1031,7 @@ async fn get_share_chat(
                         "query": {
                             "shareParams": vec![share_id]
                         },
-                        "buildId": BUILD_ID,
+                        "buildId": build_id(),
                         "assetPrefix": "https://cdn.oaistatic.com",
                         "isFallback": false,
                         "gssp": true,
@@ -568,17 +1043,17 @@ async fn get_share_chat(
                 "props",
                 &serde_json::to_string(&props).map_err(ResponseError::InternalServerError)?,
             );
-            settings_template_data(&mut ctx);
+            settings_template_data(&mut ctx, &extract.headers);
             render_template(TEMP_SHARE, &ctx)
         }
-        Err(_) => {
+        None => {
             let props = serde_json::json!({
                 "props": {
                     "pageProps": {"statusCode": 404}
                 },
                 "page": "/_error",
                 "query": {},
-                "buildId": BUILD_ID,
+                "buildId": build_id(),
                 "assetPrefix": "https://cdn.oaistatic.com",
                 "nextExport": true,
                 "isFallback": false,
@@ -591,41 +1066,20 @@ async fn get_share_chat(
                 "props",
                 &serde_json::to_string(&props).map_err(ResponseError::InternalServerError)?,
             );
-            settings_template_data(&mut ctx);
+            settings_template_data(&mut ctx, &extract.headers);
             render_template(TEMP_404, &ctx)
         }
     };
 }
 
 async fn get_share_chat_info(
-    share_id: Path<String>,
+    Path((_, share_id)): Path<(String, String)>,
     extract: SessionExtractor,
 ) -> Result<Response<Body>, ResponseError> {
-    let share_id = share_id.0.replace(".json", "");
-    let resp = context::get_instance()
-        .client()
-        .get(format!("{URL_CHATGPT_API}/backend-api/share/{share_id}"))
-        .headers(header_convert(
-            &extract.headers,
-            &extract.jar,
-            URL_CHATGPT_API,
-        )?)
-        .send()
-        .await
-        .map_err(ResponseError::InternalServerError)?;
-
-    return match resp.json::<Value>().await {
-        Ok(mut share_data) => {
-            if let Some(replace) = share_data
-                .get_mut("continue_conversation_url")
-                .and_then(|v| v.as_str())
-            {
-                let new_value = replace.replace("https://chat.openai.com", "");
-                share_data.as_object_mut().and_then(|data| {
-                    data.insert("continue_conversation_url".to_owned(), json!(new_value))
-                });
-            }
+    let share_id = share_id.replace(".json", "");
 
+    return match share_data(&share_id, &extract).await? {
+        Some(share_data) => {
             let props = serde_json::json!({
                 "pageProps": {
                     "sharedConversationId": share_id,
@@ -648,7 +1102,7 @@ async fn get_share_chat_info(
                 ))
                 .map_err(ResponseError::InternalServerError)?)
         }
-        Err(_) => Ok(Response::builder()
+        None => Ok(Response::builder()
             .status(StatusCode::OK)
             .header(header::CONTENT_TYPE, "application/json")
             .body(Body::from(
@@ -668,34 +1122,11 @@ async fn get_share_chat_continue(share_id: Path<String>) -> Result<Response<Body
 }
 
 async fn get_share_chat_continue_info(
-    share_id: Path<String>,
+    Path((_, share_id)): Path<(String, String)>,
     extract: SessionExtractor,
 ) -> Result<Response<Body>, ResponseError> {
-    let resp = context::get_instance()
-        .client()
-        .get(format!(
-            "{URL_CHATGPT_API}/backend-api/share/{}",
-            share_id.0
-        ))
-        .headers(header_convert(
-            &extract.headers,
-            &extract.jar,
-            URL_CHATGPT_API,
-        )?)
-        .send()
-        .await
-        .map_err(ResponseError::InternalServerError)?;
-    match resp.json::<Value>().await {
-        Ok(mut share_data) => {
-            if let Some(replace) = share_data
-                .get_mut("continue_conversation_url")
-                .and_then(|v| v.as_str())
-            {
-                let new_value = replace.replace("https://chat.openai.com", "");
-                share_data.as_object_mut().and_then(|data| {
-                    data.insert("continue_conversation_url".to_owned(), json!(new_value))
-                });
-            }
+    match share_data(&share_id, &extract).await? {
+        Some(share_data) => {
             let props = serde_json::json!({
                 "pageProps": {
                     "user": {
@@ -714,7 +1145,7 @@ async fn get_share_chat_continue_info(
                         "public": {}
                     },
                     "isUserInCanPayGroup": true,
-                    "sharedConversationId": share_id.0,
+                    "sharedConversationId": share_id,
                     "serverResponse": {
                         "type": "data",
                         "data": share_data,
@@ -750,7 +1181,7 @@ async fn get_share_chat_continue_info(
                 ))
                 .map_err(ResponseError::InternalServerError)?)
         }
-        Err(_) => Ok(Response::builder()
+        None => Ok(Response::builder()
             .status(StatusCode::OK)
             .header(header::CONTENT_TYPE, "same-origin")
             .body(Body::from(
@@ -761,7 +1192,7 @@ async fn get_share_chat_continue_info(
     }
 }
 
-async fn error_404() -> Result<Response<Body>, ResponseError> {
+async fn error_404(headers: HeaderMap) -> Result<Response<Body>, ResponseError> {
     let mut ctx = tera::Context::new();
     let props = json!(
         {
@@ -770,7 +1201,7 @@ async fn error_404() -> Result<Response<Body>, ResponseError> {
             },
             "page": "/_error",
             "query": {},
-            "buildId": BUILD_ID,
+            "buildId": build_id(),
             "assetPrefix": "https://cdn.oaistatic.com",
             "nextExport": true,
             "isFallback": false,
@@ -782,15 +1213,150 @@ async fn error_404() -> Result<Response<Body>, ResponseError> {
         "props",
         &serde_json::to_string(&props).map_err(ResponseError::InternalServerError)?,
     );
+    settings_template_data(&mut ctx, &headers);
     render_template(TEMP_404, &ctx)
 }
 
+/// Render the error page for WebUI navigation requests failed with 429 or 5xx,
+/// instead of the raw JSON or empty body
+/// Sliding mode: every request of a signed in browser extends the session cookie
+/// of the active account, unless the response sets it already
+async fn sliding_session_middleware<B>(
+    request: http::Request<B>,
+    next: axum::middleware::Next<B>,
+) -> axum::response::Response {
+    let ctx = context::get_instance();
+    if !ctx.session_sliding() || !ctx.session_lifetime().is_some_and(|lifetime| lifetime > 0) {
+        return next.run(request).await;
+    }
+
+    let active = active_session(&CookieJar::from_headers(request.headers()));
+    let mut resp = next.run(request).await;
+    let Some((index, session)) = active.filter(|(_, session)| !session.remember) else {
+        return resp;
+    };
+    let now = now_duration()
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    if session.expires <= now || session.deadline().is_some_and(|deadline| deadline <= now) {
+        return resp;
+    }
+    let name = slot_cookie_name(index);
+    let sets_session = resp
+        .headers()
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.split('=').next() == Some(name.as_str()));
+    if !sets_session {
+        if let Some(value) = session_cookie(index, &session)
+            .ok()
+            .and_then(|cookie| header::HeaderValue::from_str(&cookie.to_string()).ok())
+        {
+            resp.headers_mut().append(header::SET_COOKIE, value);
+        }
+    }
+    resp
+}
+
+async fn error_page_middleware<B>(
+    request: http::Request<B>,
+    next: axum::middleware::Next<B>,
+) -> axum::response::Response {
+    let headers = request.headers().clone();
+    let navigation = request.method() == http::Method::GET
+        && match headers.get("Sec-Fetch-Mode") {
+            Some(mode) => mode == "navigate",
+            None => headers
+                .get(header::ACCEPT)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.contains("text/html"))
+                .unwrap_or_default(),
+        };
+
+    let resp = next.run(request).await;
+    let status = resp.status();
+    let html = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with("text/html"))
+        .unwrap_or_default();
+    if !navigation || html || !(status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error())
+    {
+        return resp;
+    }
+
+    let mut ctx = tera::Context::new();
+    ctx.insert("status", &status.as_u16());
+    ctx.insert("rate_limited", &(status == StatusCode::TOO_MANY_REQUESTS));
+    if let Some(retry_after) = resp
+        .headers()
+        .get(header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+    {
+        ctx.insert("retry_after", &retry_after);
+    }
+    settings_template_data(&mut ctx, &headers);
+
+    match render(TEMP_ERROR, &ctx) {
+        Ok(page) => {
+            // Keep the status and headers such as `Retry-After`, replace the body
+            let (mut parts, _) = resp.into_parts();
+            parts.headers.remove(header::CONTENT_LENGTH);
+            parts.headers.insert(
+                header::CONTENT_TYPE,
+                header::HeaderValue::from_static("text/html; charset=utf-8"),
+            );
+            axum::response::Response::from_parts(parts, body::boxed(Body::from(page)))
+        }
+        Err(_) => resp,
+    }
+}
+
+/// Load the built-in templates, overridden by the same named files in the WebUI directory
+fn load_templates(ui_dir: Option<&std::path::Path>) -> anyhow::Result<tera::Tera> {
+    let builtin = [
+        (TEMP_404, include_str!("../../../../ui/404.htm")),
+        (TEMP_AUTH, include_str!("../../../../ui/auth.htm")),
+        (TEMP_LOGIN, include_str!("../../../../ui/login.htm")),
+        (TEMP_CHAT, include_str!("../../../../ui/chat.htm")),
+        (TEMP_DETAIL, include_str!("../../../../ui/detail.htm")),
+        (TEMP_SHARE, include_str!("../../../../ui/share.htm")),
+        (TEMP_THEME, include_str!("../../../../ui/theme.htm")),
+        (TEMP_BRAND, include_str!("../../../../ui/brand.htm")),
+        (TEMP_CAPTCHA, include_str!("../../../../ui/captcha.htm")),
+        (TEMP_ACCOUNTS, include_str!("../../../../ui/accounts.htm")),
+        (TEMP_EXPORT, include_str!("../../../../ui/export.htm")),
+        (TEMP_ERROR, include_str!("../../../../ui/error.htm")),
+        (
+            TEMP_LOGOUT_ALL,
+            include_str!("../../../../ui/logout_all.htm"),
+        ),
+        (
+            TEMP_EXPORT_BUTTON,
+            include_str!("../../../../ui/export_button.htm"),
+        ),
+    ];
+
+    let mut templates = Vec::with_capacity(builtin.len());
+    for (name, content) in builtin {
+        let content = match ui_dir.map(|dir| dir.join(name)).filter(|p| p.is_file()) {
+            Some(path) => std::fs::read_to_string(&path)
+                .map_err(|err| anyhow!("Failed to read {}: {err}", path.display()))?,
+            None => content.to_owned(),
+        };
+        templates.push((name, content));
+    }
+
+    let mut tera = tera::Tera::default();
+    tera.add_raw_templates(templates)?;
+    Ok(tera)
+}
+
 fn render_template(name: &str, context: &tera::Context) -> Result<Response<Body>, ResponseError> {
-    let tm = TEMPLATE
-        .get()
-        .expect("template not init")
-        .render(name, context)
-        .map_err(ResponseError::InternalServerError)?;
+    let tm = render(name, context)?;
 
     Ok(Response::builder()
         .status(StatusCode::OK)
@@ -799,21 +1365,85 @@ fn render_template(name: &str, context: &tera::Context) -> Result<Response<Body>
         .map_err(ResponseError::InternalServerError)?)
 }
 
-fn settings_template_data(ctx: &mut tera::Context) {
+fn render(name: &str, context: &tera::Context) -> Result<String, ResponseError> {
+    // Debug builds reload the WebUI directory templates on every render
+    let ui_dir = context::get_instance().ui_dir().map(|p| p.to_path_buf());
+    let tm: anyhow::Result<String> = match ui_dir {
+        Some(ui_dir) if cfg!(debug_assertions) => {
+            load_templates(Some(&ui_dir)).and_then(|tera| Ok(tera.render(name, context)?))
+        }
+        _ => context::get_instance()
+            .templates()
+            .get()
+            .expect("template not init")
+            .render(name, context)
+            .map_err(Into::into),
+    };
+    tm.map_err(ResponseError::InternalServerError)
+}
+
+/// CSRF cookie of the login forms, the key derived from the configured one is the same
+/// across restarts and replicas
+fn csrf_config(args: &ContextArgs) -> CsrfConfig {
+    let key = match args.csrf_key.as_deref() {
+        Some(key) => Key::from(&Sha512::digest(key.as_bytes())),
+        None => Key::generate(),
+    };
+    let config = CsrfConfig::default()
+        .with_cookie_name(serve::middleware::csrf::CSRF_COOKIE)
+        .with_key(Some(key));
+    match args.csrf_strict {
+        true => config.with_same_site(SameSite::Strict),
+        false => config,
+    }
+}
+
+fn settings_template_data(ctx: &mut tera::Context, headers: &HeaderMap) {
     let g_ctx = context::get_instance();
 
+    let lang = Lang::from_headers(headers);
+    ctx.insert("url_prefix", g_ctx.url_prefix());
+    ctx.insert("lang", lang.code());
+    ctx.insert("i18n", &lang.strings());
+    ctx.insert("theme", Theme::from_headers(headers).code());
+
     if g_ctx.auth_key().is_none() {
         ctx.insert("auth_key", "false");
     }
     if g_ctx.pop_preauth_cookie().is_some() {
         ctx.insert("support_apple", "true");
     }
-    if let Some(site_key) = g_ctx.cf_turnstile() {
-        ctx.insert("site_key", &site_key.site_key);
+    if g_ctx.oidc().is_some() {
+        ctx.insert("oidc", "true");
+    }
+    if g_ctx.ldap().is_some() {
+        ctx.insert("ldap", "true");
+    }
+    if let Some(captcha) = g_ctx.captcha() {
+        ctx.insert("site_key", captcha.site_key());
+        ctx.insert("captcha", captcha.provider().as_str());
     }
     if let Some(arkose_endpoint) = g_ctx.arkose_endpoint() {
         ctx.insert("arkose_endpoint", arkose_endpoint)
     }
+
+    let branding = g_ctx.branding();
+    if let Some(site_title) = branding.site_title.as_ref() {
+        ctx.insert("site_title", site_title);
+    }
+    if let Some(logo_url) = branding.logo_url.as_ref() {
+        ctx.insert("logo_url", logo_url);
+    }
+    if let Some(footer_text) = branding.footer_text.as_ref() {
+        ctx.insert("footer_text", footer_text);
+    }
+}
+
+/// The client ip passed the captcha recently, the login page renders without the widget
+fn captcha_template_data(ctx: &mut tera::Context, addr: &IpAddr) {
+    if captcha::passed(addr) {
+        ctx.insert("site_key", "");
+    }
 }
 
 #[allow(dead_code)]
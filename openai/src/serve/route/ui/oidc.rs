@@ -0,0 +1,240 @@
+//! OIDC single sign-on of the WebUI, an authorization code flow with PKCE against the
+//! identity provider of the operator. The signed in user gets a session of one of the
+//! accounts managed through the admin API, picked by the user so it stays the same,
+//! and never sees the OpenAI credentials
+use anyhow::anyhow;
+use axum::body::Body;
+use axum::extract::Query;
+use axum::http::{header, HeaderMap, Response, StatusCode};
+use axum_extra::extract::{cookie, CookieJar};
+use base64::Engine;
+use rand::RngCore;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use super::extract::{login_slot, Session};
+use super::{active_cookie, session_cookie, DEFAULT_INDEX, LOGIN_INDEX};
+use crate::context::{self, Oidc, OidcDiscovery};
+use crate::serve::error::ResponseError;
+use crate::serve::pooled::{pooled_account, pooled_token};
+use crate::token::model::AuthenticateToken;
+use crate::{info, warn};
+
+/// Cookie holding the state and the PKCE verifier of a sign-in in progress
+const OIDC_STATE_COOKIE: &str = "ninja_oidc_state";
+
+/// Seconds the identity provider has to send the user back
+const STATE_MAX_AGE: i64 = 600;
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct UserInfo {
+    sub: String,
+    email: Option<String>,
+    email_verified: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub(super) struct Callback {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+/// Endpoints of the issuer, fetched once from its discovery document
+async fn discovery(oidc: &Oidc) -> anyhow::Result<&OidcDiscovery> {
+    oidc.discovery
+        .get_or_try_init(|| async {
+            let discovery = context::get_instance()
+                .client()
+                .get(format!("{}/.well-known/openid-configuration", oidc.issuer))
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<OidcDiscovery>()
+                .await?;
+            Ok(discovery)
+        })
+        .await
+}
+
+/// Url the identity provider sends the user back to
+fn redirect_uri(headers: &HeaderMap) -> Result<String, ResponseError> {
+    let origin = crate::serve::share::origin(headers)
+        .ok_or_else(|| ResponseError::BadRequest(anyhow!("Missing Host header")))?;
+    Ok(format!(
+        "{origin}{}/auth/oidc/callback",
+        context::get_instance().url_prefix()
+    ))
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn state_cookie(value: String, max_age: i64) -> cookie::Cookie<'static> {
+    cookie::Cookie::build(OIDC_STATE_COOKIE, value)
+        .path(DEFAULT_INDEX)
+        .same_site(cookie::SameSite::Lax)
+        .max_age(time::Duration::seconds(max_age))
+        .secure(false)
+        .http_only(true)
+        .finish()
+}
+
+/// GET /auth/oidc, send the user to the identity provider
+pub(super) async fn get_oidc(headers: HeaderMap) -> Result<Response<Body>, ResponseError> {
+    let ctx = context::get_instance();
+    let oidc = ctx
+        .oidc()
+        .ok_or_else(|| ResponseError::NotFound(anyhow!("OIDC is not configured")))?;
+    let discovery = discovery(oidc).await.map_err(ResponseError::BadGateway)?;
+
+    let state = random_token();
+    let verifier = random_token();
+    let challenge = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .encode(Sha256::digest(verifier.as_bytes()));
+    let url = reqwest::Url::parse_with_params(
+        &discovery.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", &oidc.client_id),
+            ("redirect_uri", &redirect_uri(&headers)?),
+            ("scope", &oidc.scopes),
+            ("state", &state),
+            ("code_challenge", &challenge),
+            ("code_challenge_method", "S256"),
+        ],
+    )
+    .map_err(ResponseError::InternalServerError)?;
+
+    Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, url.as_str())
+        .header(
+            header::SET_COOKIE,
+            state_cookie(format!("{state}.{verifier}"), STATE_MAX_AGE).to_string(),
+        )
+        .body(Body::empty())
+        .map_err(ResponseError::InternalServerError)
+}
+
+/// GET /auth/oidc/callback, the identity provider sent the user back with a code
+pub(super) async fn get_oidc_callback(
+    headers: HeaderMap,
+    jar: CookieJar,
+    Query(callback): Query<Callback>,
+) -> Result<Response<Body>, ResponseError> {
+    if let Some(error) = callback.error {
+        warn!("OIDC sign-in error: {error}");
+        return Err(ResponseError::TempporaryRedirect(LOGIN_INDEX));
+    }
+    let (state, verifier) = jar
+        .get(OIDC_STATE_COOKIE)
+        .and_then(|cookie| {
+            let (state, verifier) = cookie.value().split_once('.')?;
+            Some((state.to_owned(), verifier.to_owned()))
+        })
+        .ok_or_else(|| ResponseError::BadRequest(anyhow!("OIDC sign-in expired")))?;
+    if callback.state.as_deref() != Some(state.as_str()) {
+        return Err(ResponseError::BadRequest(anyhow!("OIDC state mismatch")));
+    }
+    let code = callback
+        .code
+        .ok_or_else(|| ResponseError::BadRequest(anyhow!("Missing OIDC code")))?;
+
+    let user = user_info(&code, &verifier, &redirect_uri(&headers)?)
+        .await
+        .map_err(ResponseError::Unauthorized)?;
+    let ctx = context::get_instance();
+    if !ctx.login_allowed(&user) {
+        return Err(ResponseError::Forbidden(anyhow!(
+            "{user} is not allowed to log in"
+        )));
+    }
+
+    let account = pooled_account(&user, None)
+        .ok_or_else(|| ResponseError::ServiceUnavailable(anyhow!("No pooled account available")))?;
+    let token = pooled_token(&account)
+        .await
+        .map_err(ResponseError::BadGateway)?;
+    let session = sso_session(token, user);
+    info!(
+        "OIDC user {} signed in to {}",
+        session.user(),
+        session.email
+    );
+
+    let index = login_slot(&jar, &session.email);
+    Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(header::LOCATION, DEFAULT_INDEX)
+        .header(
+            header::SET_COOKIE,
+            session_cookie(index, &session)?.to_string(),
+        )
+        .header(header::SET_COOKIE, active_cookie(index).to_string())
+        .header(
+            header::SET_COOKIE,
+            state_cookie(String::new(), 0).to_string(),
+        )
+        .body(Body::empty())
+        .map_err(ResponseError::InternalServerError)
+}
+
+/// Exchange the code and read the email of the user, through the back channel so the
+/// answers come from the identity provider itself
+async fn user_info(code: &str, verifier: &str, redirect_uri: &str) -> anyhow::Result<String> {
+    let ctx = context::get_instance();
+    let oidc = ctx
+        .oidc()
+        .ok_or_else(|| anyhow!("OIDC is not configured"))?;
+    let discovery = discovery(oidc).await?;
+    let token = ctx
+        .client()
+        .post(&discovery.token_endpoint)
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("client_id", &oidc.client_id),
+            ("client_secret", &oidc.client_secret),
+            ("code_verifier", verifier),
+        ])
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<TokenResponse>()
+        .await?;
+    let user = ctx
+        .client()
+        .get(&discovery.userinfo_endpoint)
+        .bearer_auth(token.access_token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<UserInfo>()
+        .await?;
+
+    match (user.email, user.email_verified) {
+        (Some(email), Some(true) | None) => Ok(email.to_lowercase()),
+        (Some(_), Some(false)) => anyhow::bail!("the email of {} is not verified", user.sub),
+        (None, _) => anyhow::bail!("no email for {}, request the `email` scope", user.sub),
+    }
+}
+
+/// Session of the pooled account for the user, without the upstream refresh token
+pub(super) fn sso_session(token: AuthenticateToken, user: String) -> Session {
+    let mut session = Session::from(token);
+    session.refresh_token = None;
+    session.auth_session = None;
+    session.generation = context::get_instance().session_generation(&user);
+    session.sso = Some(user);
+    session
+}
@@ -0,0 +1,180 @@
+//! Minimal PDF writer of the exported threads, the text is set in the standard Helvetica
+//! fonts so no font is embedded, characters outside of WinAnsi are replaced by `?`
+use super::export::{role_name, Thread};
+
+/// A4 in points
+const PAGE_WIDTH: f32 = 595.0;
+const PAGE_HEIGHT: f32 = 842.0;
+const MARGIN: f32 = 50.0;
+
+const REGULAR: &str = "F1";
+const BOLD: &str = "F2";
+
+struct Line {
+    font: &'static str,
+    size: f32,
+    /// WinAnsi encoded text, empty for a blank line
+    text: Vec<u8>,
+}
+
+pub(super) fn render(thread: &Thread) -> Vec<u8> {
+    let mut lines = Vec::new();
+    push_text(&mut lines, BOLD, 16.0, &thread.title);
+    push_text(&mut lines, REGULAR, 10.0, "");
+    for message in &thread.messages {
+        push_text(&mut lines, BOLD, 11.0, role_name(&message.role));
+        push_text(&mut lines, REGULAR, 10.0, &message.content);
+        push_text(&mut lines, REGULAR, 10.0, "");
+    }
+    write(paginate(lines))
+}
+
+/// Wrap the paragraphs of the text at the spaces, Helvetica is about half an em wide on average
+fn push_text(lines: &mut Vec<Line>, font: &'static str, size: f32, text: &str) {
+    let width = ((PAGE_WIDTH - 2.0 * MARGIN) / (size * 0.55)) as usize;
+    let paragraphs = match text.is_empty() {
+        true => vec![""],
+        false => text.lines().collect(),
+    };
+    for paragraph in paragraphs {
+        let encoded = paragraph
+            .replace('\t', "    ")
+            .chars()
+            .map(win_ansi)
+            .collect::<Vec<_>>();
+        if encoded.is_empty() {
+            lines.push(Line {
+                font,
+                size,
+                text: Vec::new(),
+            });
+            continue;
+        }
+
+        let mut rest = &encoded[..];
+        while !rest.is_empty() {
+            let end = match rest.len() <= width {
+                true => rest.len(),
+                false => rest[..=width]
+                    .iter()
+                    .rposition(|b| *b == b' ')
+                    .filter(|end| *end > 0)
+                    .unwrap_or(width),
+            };
+            lines.push(Line {
+                font,
+                size,
+                text: rest[..end].to_vec(),
+            });
+            rest = &rest[end..];
+            // The space the line was broken at does not start the next line
+            if rest.first() == Some(&b' ') {
+                rest = &rest[1..];
+            }
+        }
+    }
+}
+
+/// Content streams of the pages
+fn paginate(lines: Vec<Line>) -> Vec<Vec<u8>> {
+    let mut pages = Vec::new();
+    let mut page = Vec::new();
+    let mut y = PAGE_HEIGHT - MARGIN;
+    for line in lines {
+        let leading = line.size * 1.4;
+        if y - leading < MARGIN && !page.is_empty() {
+            pages.push(std::mem::take(&mut page));
+            y = PAGE_HEIGHT - MARGIN;
+        }
+        y -= leading;
+        if line.text.is_empty() {
+            continue;
+        }
+        page.extend_from_slice(
+            format!("BT /{} {} Tf {MARGIN} {y:.1} Td (", line.font, line.size).as_bytes(),
+        );
+        for byte in line.text {
+            if matches!(byte, b'(' | b')' | b'\\') {
+                page.push(b'\\');
+            }
+            page.push(byte);
+        }
+        page.extend_from_slice(b") Tj ET\n");
+    }
+    pages.push(page);
+    pages
+}
+
+/// Objects: the catalog, the page tree, the two fonts, then the page and its content of each page
+fn write(pages: Vec<Vec<u8>>) -> Vec<u8> {
+    let kids = (0..pages.len())
+        .map(|index| format!("{} 0 R", 5 + index * 2))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let font = |name: &str| {
+        format!("<< /Type /Font /Subtype /Type1 /BaseFont /{name} /Encoding /WinAnsiEncoding >>")
+            .into_bytes()
+    };
+    let mut objects = vec![
+        b"<< /Type /Catalog /Pages 2 0 R >>".to_vec(),
+        format!("<< /Type /Pages /Kids [{kids}] /Count {} >>", pages.len()).into_bytes(),
+        font("Helvetica"),
+        font("Helvetica-Bold"),
+    ];
+    for (index, content) in pages.into_iter().enumerate() {
+        objects.push(
+            format!(
+                "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {PAGE_WIDTH} {PAGE_HEIGHT}] \
+                 /Resources << /Font << /{REGULAR} 3 0 R /{BOLD} 4 0 R >> >> /Contents {} 0 R >>",
+                6 + index * 2
+            )
+            .into_bytes(),
+        );
+        let mut stream = format!("<< /Length {} >>\nstream\n", content.len()).into_bytes();
+        stream.extend(content);
+        stream.extend_from_slice(b"\nendstream");
+        objects.push(stream);
+    }
+
+    let mut pdf = b"%PDF-1.4\n".to_vec();
+    let mut offsets = Vec::with_capacity(objects.len());
+    for (index, object) in objects.iter().enumerate() {
+        offsets.push(pdf.len());
+        pdf.extend_from_slice(format!("{} 0 obj\n", index + 1).as_bytes());
+        pdf.extend_from_slice(object);
+        pdf.extend_from_slice(b"\nendobj\n");
+    }
+    let xref = pdf.len();
+    pdf.extend_from_slice(
+        format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes(),
+    );
+    for offset in offsets {
+        pdf.extend_from_slice(format!("{offset:010} 00000 n \n").as_bytes());
+    }
+    pdf.extend_from_slice(
+        format!(
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{xref}\n%%EOF\n",
+            objects.len() + 1
+        )
+        .as_bytes(),
+    );
+    pdf
+}
+
+fn win_ansi(c: char) -> u8 {
+    match c {
+        ' '..='~' => c as u8,
+        '\u{a0}'..='\u{ff}' => c as u32 as u8,
+        '€' => 0x80,
+        '…' => 0x85,
+        '‘' => 0x91,
+        '’' => 0x92,
+        '“' => 0x93,
+        '”' => 0x94,
+        '•' => 0x95,
+        '–' => 0x96,
+        '—' => 0x97,
+        '™' => 0x99,
+        _ => b'?',
+    }
+}
@@ -0,0 +1,34 @@
+use axum::http::HeaderMap;
+use axum_extra::extract::CookieJar;
+
+/// Cookie storing the theme preference, `light`, `dark` or `system`
+pub(super) const THEME_COOKIE: &str = "ninja_theme";
+
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub(super) enum Theme {
+    Light,
+    Dark,
+    /// Follow `prefers-color-scheme`
+    #[default]
+    System,
+}
+
+impl Theme {
+    /// Select from the `ninja_theme` cookie, default follow the system
+    pub(super) fn from_headers(headers: &HeaderMap) -> Self {
+        let jar = CookieJar::from_headers(headers);
+        match jar.get(THEME_COOKIE).map(|c| c.value()) {
+            Some("light") => Theme::Light,
+            Some("dark") => Theme::Dark,
+            _ => Theme::System,
+        }
+    }
+
+    pub(super) fn code(&self) -> &'static str {
+        match self {
+            Theme::Light => "light",
+            Theme::Dark => "dark",
+            Theme::System => "system",
+        }
+    }
+}
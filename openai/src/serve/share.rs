@@ -0,0 +1,138 @@
+//! Share links created and changed through the proxy, the link of a share points
+//! at the share pages of this instance when it serves them instead of chat.openai.com,
+//! and the share page data cache, the pages are the same for every anonymous visitor
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use axum::http::{header, HeaderMap, Method};
+use serde_json::Value;
+
+use super::convert::rebuild_response;
+use super::error::ResponseError;
+use crate::context;
+
+/// Write to the share links: `POST /backend-api/share/create`, `PATCH` and `DELETE /backend-api/share/:share_id`
+pub(super) struct ShareWrite {
+    /// Share of the request, `None` when it is created
+    share_id: Option<String>,
+    /// Origin the client reached the proxy through
+    origin: Option<String>,
+}
+
+impl ShareWrite {
+    pub(super) fn of(method: &Method, path: &str, headers: &HeaderMap) -> Option<Self> {
+        let id = path
+            .trim_end_matches('/')
+            .strip_prefix("/backend-api/share/")
+            .filter(|id| !id.is_empty() && !id.contains('/'))?;
+        let share_id = match (method, id) {
+            (&Method::POST, "create") => None,
+            (&Method::PATCH | &Method::DELETE, _) => Some(id.to_owned()),
+            _ => return None,
+        };
+        Some(Self {
+            share_id,
+            origin: origin(headers),
+        })
+    }
+
+    /// Drop the cached page data of the changed or deleted share
+    pub(super) fn invalidate(&self) {
+        let cache = context::get_instance().share_cache();
+        if let Some((cache, share_id)) = cache.zip(self.share_id.as_deref()) {
+            cache.invalidate(share_id);
+        }
+    }
+
+    /// Point the `share_url` of the response at the share page of this instance
+    pub(super) async fn rewrite(
+        &self,
+        resp: reqwest::Response,
+    ) -> Result<reqwest::Response, ResponseError> {
+        let ctx = context::get_instance();
+        let Some(origin) = self.origin.as_deref().filter(|_| ctx.share_pages()) else {
+            return Ok(resp);
+        };
+        if !resp.status().is_success() {
+            return Ok(resp);
+        }
+
+        let status = resp.status();
+        let headers = resp.headers().clone();
+        let body = resp.bytes().await?;
+        let body = match serde_json::from_slice::<Value>(&body) {
+            Ok(mut value) => match value["share_id"].as_str().map(ToOwned::to_owned) {
+                Some(share_id) if value["share_url"].is_string() => {
+                    value["share_url"] =
+                        Value::String(format!("{origin}{}/share/{share_id}", ctx.url_prefix()));
+                    serde_json::to_vec(&value)?.into()
+                }
+                _ => body,
+            },
+            Err(_) => body,
+        };
+
+        rebuild_response(status, &headers, body)
+    }
+}
+
+/// Public origin of the proxy, as seen by the client behind a reverse proxy
+pub(super) fn origin(headers: &HeaderMap) -> Option<String> {
+    let value = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+    };
+    let host = value("x-forwarded-host").or_else(|| value(header::HOST.as_str()))?;
+    let scheme = value("x-forwarded-proto").unwrap_or("http");
+    Some(format!("{scheme}://{host}"))
+}
+
+/// Share page data by share id
+pub struct ShareCache {
+    ttl: Duration,
+    shares: RwLock<HashMap<String, (Instant, Value)>>,
+}
+
+impl ShareCache {
+    pub fn new(ttl: u64) -> Self {
+        Self {
+            ttl: Duration::from_secs(ttl),
+            shares: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn get(&self, share_id: &str) -> Option<Value> {
+        let shares = self.shares.read().ok()?;
+        shares
+            .get(share_id)
+            .filter(|(fetched, _)| fetched.elapsed() < self.ttl)
+            .map(|(_, data)| data.clone())
+    }
+
+    pub(crate) fn insert(&self, share_id: &str, data: Value) {
+        if let Ok(mut shares) = self.shares.write() {
+            shares.insert(share_id.to_owned(), (Instant::now(), data));
+        }
+    }
+
+    fn invalidate(&self, share_id: &str) {
+        if let Ok(mut shares) = self.shares.write() {
+            shares.remove(share_id);
+        }
+    }
+
+    /// Drop the expired share data, returns how many were dropped
+    pub(crate) fn sweep(&self) -> usize {
+        let Ok(mut shares) = self.shares.write() else {
+            return 0;
+        };
+        let len = shares.len();
+        shares.retain(|_, (fetched, _)| fetched.elapsed() < self.ttl);
+        len - shares.len()
+    }
+}
@@ -2,54 +2,91 @@ use axum_server::Handle;
 use std::time::Duration;
 #[cfg(target_family = "unix")]
 use tokio::signal::unix::{signal, SignalKind};
+#[cfg(target_family = "windows")]
+use tokio::signal::windows;
 use tokio::time::sleep;
 
-use crate::info;
+use crate::{context, info, warn};
 
-pub(super) async fn graceful_shutdown(handle: Handle) {
-    #[cfg(target_family = "windows")]
-    {
-        tokio::signal::ctrl_c()
-            .await
-            .expect("Ctrl+C signal hanlde error");
-        sending_graceful_shutdown_signal(handle, "SIGINT").await;
+pub(super) async fn graceful_shutdown(
+    handle: Handle,
+    drain_timeout: Duration,
+    shutdown_timeout: Duration,
+) {
+    let signal = shutdown_signal().await;
+    sending_graceful_shutdown_signal(handle, drain_timeout, shutdown_timeout, signal).await;
+}
+
+/// Wait for the first shutdown signal, the name is logged
+#[cfg(target_family = "windows")]
+async fn shutdown_signal() -> &'static str {
+    let mut ctrl_break = windows::ctrl_break().expect("Ctrl+Break signal hanlde error");
+    let mut ctrl_close = windows::ctrl_close().expect("Ctrl+Close signal hanlde error");
+    let mut ctrl_shutdown = windows::ctrl_shutdown().expect("Ctrl+Shutdown signal hanlde error");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = ctrl_break.recv() => "SIGBREAK",
+        _ = ctrl_close.recv() => "CTRL_CLOSE",
+        _ = ctrl_shutdown.recv() => "CTRL_SHUTDOWN",
     }
+}
 
-    #[cfg(target_family = "unix")]
-    {
-        let mut sigterm = signal(SignalKind::terminate()).expect("SIGTERM signal hanlde error");
-        let mut sigquit = signal(SignalKind::quit()).expect("SIGQUIT signal hanlde error");
-        let mut sigchld = signal(SignalKind::child()).expect("SIGCHLD signal hanlde error");
-        let mut sighup = signal(SignalKind::hangup()).expect("SIGHUP signal hanlde error");
-        tokio::select! {
-            _ = sigterm.recv() => {
-                sending_graceful_shutdown_signal(handle, "SIGTERM").await;
-            },
-            _ = sigquit.recv() => {
-                sending_graceful_shutdown_signal(handle, "SIGQUIT").await;
-            },
-            _ = sigchld.recv() => {
-                sending_graceful_shutdown_signal(handle, "SIGCHLD").await;
-            },
-            _ = sighup.recv() => {
-                sending_graceful_shutdown_signal(handle, "SIGHUP").await;
-            },
-            _ = tokio::signal::ctrl_c() => {
-                sending_graceful_shutdown_signal(handle, "SIGINT").await;
-            }
-        };
+/// Wait for the first shutdown signal, the name is logged
+#[cfg(target_family = "unix")]
+async fn shutdown_signal() -> &'static str {
+    let mut sigterm = signal(SignalKind::terminate()).expect("SIGTERM signal hanlde error");
+    let mut sigquit = signal(SignalKind::quit()).expect("SIGQUIT signal hanlde error");
+    let mut sigchld = signal(SignalKind::child()).expect("SIGCHLD signal handle error");
+    let mut sighup = signal(SignalKind::hangup()).expect("SIGHUP signal handle error");
+    tokio::select! {
+        _ = sigterm.recv() => "SIGTERM",
+        _ = sigquit.recv() => "SIGQUIT",
+        _ = sigchld.recv() => "SIGCHLD",
+        _ = sighup.recv() => "SIGHUP",
+        _ = tokio::signal::ctrl_c() => "SIGINT",
     }
 }
 
-async fn sending_graceful_shutdown_signal(handle: Handle, signal: &'static str) {
+async fn sending_graceful_shutdown_signal(
+    handle: Handle,
+    drain_timeout: Duration,
+    shutdown_timeout: Duration,
+    signal: &'static str,
+) {
     info!("{signal} received: starting graceful shutdown");
+    let ctx = context::get_instance();
+
+    // Answer the new requests with 503, let the in-flight event streams and
+    // conversations finish before closing the connections.
+    let drain = ctx.drain();
+    drain.start();
+    let active = drain.active();
+    if active > 0 && !drain_timeout.is_zero() {
+        info!("Draining {active} event streams");
+        if drain.drain(drain_timeout).await {
+            info!("Event streams drained");
+        } else {
+            warn!(
+                "Drain timeout elapsed, {} event streams will be cut",
+                drain.active()
+            );
+        }
+    }
+
+    // Keep the upstream cookies (cf_clearance, _puid, sessions) for the next start.
+    ctx.save_cookies();
 
     // Signal the server to shutdown using Handle.
-    handle.graceful_shutdown(Some(Duration::from_secs(30)));
+    handle.graceful_shutdown(Some(shutdown_timeout));
 
-    // Print alive connection count every second.
+    // Print in-flight connection and event stream count every second.
     loop {
         sleep(Duration::from_secs(1)).await;
-        info!("Alive connections: {}", handle.connection_count());
+        info!(
+            "Draining: {} alive connections, {} event streams, shutdown timeout {}s",
+            handle.connection_count(),
+            drain.active(),
+            shutdown_timeout.as_secs()
+        );
     }
 }
@@ -0,0 +1,124 @@
+//! Stateless mode, hides the upstream conversation once the response is finished
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use axum::body::Bytes;
+use futures_core::Stream;
+use reqwest::Url;
+use serde_json::Value;
+
+use super::upstream;
+use crate::{context, debug, warn, URL_CHATGPT_API};
+
+/// Hides the conversation of the response once dropped
+pub(crate) struct ConversationCleanup {
+    client: reqwest::Client,
+    token: String,
+    /// Upstream endpoint which served the conversation
+    origin: String,
+    conversation_id: Option<String>,
+    buf: Vec<u8>,
+}
+
+impl ConversationCleanup {
+    pub(crate) fn new(client: reqwest::Client, token: &str) -> Self {
+        Self {
+            client,
+            token: token.trim_start_matches("Bearer ").to_owned(),
+            origin: context::get_instance()
+                .upstream_origin(URL_CHATGPT_API)
+                .to_owned(),
+            conversation_id: None,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Hide through the upstream endpoint which served the conversation at `url`
+    pub(crate) fn served_by(&mut self, url: &Url) {
+        if let Some(origin) = upstream::endpoint_of(url, upstream::CONVERSATION_PATH) {
+            self.origin = origin;
+        }
+    }
+
+    /// Find the conversation id in the event stream
+    fn feed(&mut self, chunk: &[u8]) {
+        if self.conversation_id.is_some() {
+            return;
+        }
+
+        self.buf.extend_from_slice(chunk);
+        while let Some(pos) = self.buf.iter().position(|b| *b == b'\n') {
+            let line = self.buf.drain(..=pos).collect::<Vec<_>>();
+            let Some(data) = line.strip_prefix(b"data: ") else {
+                continue;
+            };
+            if let Ok(value) = serde_json::from_slice::<Value>(data) {
+                if let Some(id) = value["conversation_id"].as_str() {
+                    self.conversation_id = Some(id.to_owned());
+                    self.buf.clear();
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ConversationCleanup {
+    fn drop(&mut self) {
+        let Some(conversation_id) = self.conversation_id.take() else {
+            return;
+        };
+        let client = self.client.clone();
+        let token = std::mem::take(&mut self.token);
+        let url = format!("{}/backend-api/conversation/{conversation_id}", self.origin);
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move {
+                let result = client
+                    .patch(url)
+                    .bearer_auth(token)
+                    .json(&serde_json::json!({ "is_visible": false }))
+                    .send()
+                    .await
+                    .and_then(|resp| resp.error_for_status());
+                match result {
+                    Ok(_) => debug!("Stateless mode hid conversation {conversation_id}"),
+                    Err(err) => warn!("Failed to hide conversation {conversation_id}: {err}"),
+                }
+            });
+        }
+    }
+}
+
+/// Response body stream hiding the conversation when finished
+pub(crate) struct CleanupStream<S> {
+    inner: S,
+    cleanup: Option<ConversationCleanup>,
+}
+
+impl<S> CleanupStream<S> {
+    pub(crate) fn new(inner: S, cleanup: Option<ConversationCleanup>) -> Self {
+        Self { inner, cleanup }
+    }
+}
+
+impl<S, E> Stream for CleanupStream<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => {
+                if let Some(cleanup) = self.cleanup.as_mut() {
+                    cleanup.feed(chunk);
+                }
+            }
+            // Hide as soon as the body is finished
+            Poll::Ready(None) => self.cleanup = None,
+            _ => {}
+        }
+        poll
+    }
+}
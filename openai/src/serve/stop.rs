@@ -0,0 +1,195 @@
+//! Client disconnects in the middle of a conversation stream. The upstream stream goes with
+//! the response body, and the generation is stopped upstream when a stop path is set, so
+//! the account quota is not spent on answers nobody reads
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use axum::body::Bytes;
+use futures_core::Stream;
+use reqwest::Url;
+use serde_json::Value;
+
+use super::upstream;
+use crate::{context, debug, metrics, warn, URL_CHATGPT_API};
+
+const DISCONNECTED: &str = "ninja_stream_disconnects_total";
+
+/// Stops the generation of the response once dropped before its end
+pub(crate) struct StopGeneration {
+    /// Instance of the request, the body is dropped outside of its scope
+    ctx: Arc<context::Context>,
+    client: reqwest::Client,
+    token: String,
+    /// Upstream endpoint which served the conversation
+    origin: String,
+    path: String,
+    conversation_id: Option<String>,
+    message_id: Option<String>,
+    buf: Vec<u8>,
+    finished: bool,
+}
+
+impl StopGeneration {
+    /// `None` without a stop path
+    pub(crate) fn new(client: reqwest::Client, token: &str) -> Option<Self> {
+        let ctx = context::get_instance();
+        let path = ctx.stop_generation_path()?.to_owned();
+        let origin = ctx.upstream_origin(URL_CHATGPT_API).to_owned();
+        Some(Self {
+            ctx,
+            client,
+            token: token.trim_start_matches("Bearer ").to_owned(),
+            origin,
+            path,
+            conversation_id: None,
+            message_id: None,
+            buf: Vec::new(),
+            finished: false,
+        })
+    }
+
+    /// Stop through the upstream endpoint which served the conversation at `url`
+    pub(crate) fn served_by(&mut self, url: &Url) {
+        if let Some(origin) = upstream::endpoint_of(url, upstream::CONVERSATION_PATH) {
+            self.origin = origin;
+        }
+    }
+
+    /// Follow the conversation and the assistant message in the event stream
+    fn feed(&mut self, chunk: &[u8]) {
+        self.buf.extend_from_slice(chunk);
+        while let Some(pos) = self.buf.iter().position(|b| *b == b'\n') {
+            let line = self.buf.drain(..=pos).collect::<Vec<_>>();
+            let Some(data) = line.strip_prefix(b"data: ") else {
+                continue;
+            };
+            if data.starts_with(b"[DONE]") {
+                self.finished = true;
+                continue;
+            }
+            let Ok(value) = serde_json::from_slice::<Value>(data) else {
+                continue;
+            };
+            if let Some(id) = value["conversation_id"].as_str() {
+                self.conversation_id = Some(id.to_owned());
+            }
+            if value["message"]["author"]["role"] == "assistant" {
+                if let Some(id) = value["message"]["id"].as_str() {
+                    self.message_id = Some(id.to_owned());
+                }
+            }
+        }
+    }
+}
+
+impl Drop for StopGeneration {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        // Counted by the instance of the request, not the scope the body is dropped in
+        context::sync_scope(self.ctx.clone(), || {
+            metrics::inc(
+                DISCONNECTED,
+                "Conversation streams dropped by the client before their end",
+                &[],
+                1,
+            )
+        });
+        let Some(conversation_id) = self.conversation_id.take() else {
+            return;
+        };
+        let body = serde_json::json!({
+            "conversation_id": conversation_id,
+            "message_id": self.message_id.take(),
+        });
+        let url = format!("{}{}", self.origin, self.path);
+        let client = self.client.clone();
+        let token = std::mem::take(&mut self.token);
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(context::scope(self.ctx.clone(), async move {
+                let result = client
+                    .post(url)
+                    .bearer_auth(token)
+                    .json(&body)
+                    .send()
+                    .await
+                    .and_then(|resp| resp.error_for_status());
+                match result {
+                    Ok(_) => debug!("Client disconnected, stopped conversation {conversation_id}"),
+                    Err(err) => warn!("Failed to stop conversation {conversation_id}: {err}"),
+                }
+            }));
+        }
+    }
+}
+
+/// Response body stream stopping the generation when dropped before its end
+pub(crate) struct StopStream<S> {
+    inner: S,
+    stop: Option<StopGeneration>,
+}
+
+impl<S> StopStream<S> {
+    pub(crate) fn new(inner: S, stop: Option<StopGeneration>) -> Self {
+        Self { inner, stop }
+    }
+}
+
+impl<S, E> Stream for StopStream<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => {
+                if let Some(stop) = self.stop.as_mut() {
+                    stop.feed(chunk);
+                }
+            }
+            // The upstream finished or failed on its own
+            Poll::Ready(None) | Poll::Ready(Some(Err(_))) => {
+                if let Some(mut stop) = self.stop.take() {
+                    stop.finished = true;
+                }
+            }
+            Poll::Pending => {}
+        }
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::{Context as InstanceContext, ContextArgs};
+
+    #[test]
+    fn test_disconnect_counted_by_instance() {
+        let args = ContextArgs::builder()
+            .stop_generation_path("/backend-api/stop_conversation")
+            .build();
+        let ctx = Arc::new(InstanceContext::new(args));
+        let stop = context::sync_scope(ctx.clone(), || {
+            StopGeneration::new(reqwest::Client::new(), "Bearer token")
+        })
+        .expect("Stop path is set");
+        let mut stream = StopStream::new(
+            futures::stream::iter(Vec::<Result<Bytes, ()>>::new()),
+            Some(stop),
+        );
+        if let Some(stop) = stream.stop.as_mut() {
+            stop.feed(b"data: {\"conversation_id\":\"c1\"}\n");
+        }
+        // Dropped by the server outside of the instance scope
+        drop(stream);
+
+        let value = context::sync_scope(ctx, || metrics::value(DISCONNECTED, &[]));
+        assert_eq!(value, 1);
+        assert_eq!(metrics::value(DISCONNECTED, &[]), 0);
+    }
+}
@@ -0,0 +1,51 @@
+//! Event stream filter, drops the events of the proxied ChatGPT stream that programmatic
+//! consumers have no use for, e.g. `moderation`, `conversation_detail_metadata` and
+//! `title_generation`, by the `type` of their data
+use axum::body::Bytes;
+use axum::http::HeaderMap;
+use serde_json::Value;
+
+use super::rewrite::BodyRewrite;
+use crate::context;
+
+/// Event types stripped from the response of the request, in place of `--sse-strip`,
+/// empty to strip nothing
+pub(super) const HEADER_STRIP: &str = "X-Ninja-Strip-Events";
+
+pub(crate) struct EventStrip {
+    types: Vec<String>,
+}
+
+impl EventStrip {
+    /// Types of the request header or the config, `None` when nothing is stripped
+    pub(super) fn of(headers: &HeaderMap) -> Option<Self> {
+        let types = match headers.get(HEADER_STRIP).and_then(|v| v.to_str().ok()) {
+            Some(value) => value
+                .split(',')
+                .map(str::trim)
+                .filter(|t| !t.is_empty())
+                .map(ToOwned::to_owned)
+                .collect::<Vec<_>>(),
+            None => context::get_instance().sse_strip().to_vec(),
+        };
+        (!types.is_empty()).then_some(Self { types })
+    }
+}
+
+impl BodyRewrite for EventStrip {
+    fn event_stream(&self) -> bool {
+        true
+    }
+
+    fn event(&mut self, data: &str) -> Option<String> {
+        let stripped = serde_json::from_str::<Value>(data)
+            .ok()
+            .and_then(|value| value["type"].as_str().map(ToOwned::to_owned))
+            .is_some_and(|t| self.types.contains(&t));
+        (!stripped).then(|| data.to_owned())
+    }
+
+    fn body(&mut self, body: &[u8]) -> Bytes {
+        Bytes::copy_from_slice(body)
+    }
+}
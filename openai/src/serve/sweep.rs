@@ -0,0 +1,93 @@
+//! Expired entry cleanup, the in-memory stores otherwise only drop entries as they are touched
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::middleware::tokenbucket::{TokenBucket, TokenBucketLimitContext};
+use super::usage;
+use crate::{context, debug, metrics, warn};
+
+const SWEPT: &str = "ninja_sweep_removed_total";
+
+/// Periodically drop the expired arkose tokens, preauth cookies, sessions, conversation lists,
+/// share page data, cached responses, captcha passes, failed LDAP logins, limiter buckets,
+/// and the usage records and content log files past the retention, and save the upstream cookies
+pub(super) async fn sweep_task(interval: Duration, limit: Arc<TokenBucketLimitContext>) {
+    loop {
+        tokio::time::sleep(interval).await;
+
+        let ctx = context::get_instance();
+        ctx.save_cookies();
+        let mut swept = vec![
+            (
+                "arkose_token",
+                ctx.arkose_token_pool()
+                    .map(|pool| pool.sweep())
+                    .unwrap_or_default(),
+            ),
+            ("session", ctx.sweep_accounts()),
+            ("web_session", ctx.sweep_web_sessions()),
+            (
+                "conversation_list",
+                ctx.conversation_cache()
+                    .map(|cache| cache.sweep())
+                    .unwrap_or_default(),
+            ),
+            (
+                "share_page",
+                ctx.share_cache()
+                    .map(|cache| cache.sweep())
+                    .unwrap_or_default(),
+            ),
+            (
+                "response",
+                ctx.response_cache()
+                    .map(|cache| cache.sweep())
+                    .unwrap_or_default(),
+            ),
+            (
+                "captcha_pass",
+                ctx.captcha_passes()
+                    .map(|passes| passes.sweep())
+                    .unwrap_or_default(),
+            ),
+        ];
+        #[cfg(feature = "ldap")]
+        swept.push((
+            "ldap_failure",
+            ctx.ldap()
+                .map(|ldap| ldap.failures.sweep())
+                .unwrap_or_default(),
+        ));
+        #[cfg(feature = "preauth")]
+        swept.push(("preauth_cookie", ctx.purge_preauth_cookies()));
+        // Records older than the retention of the stored user data
+        if let (Some(days), Some(store)) = (ctx.data_retention(), ctx.usage()) {
+            match store.purge(&usage::date_before(days)).await {
+                Ok(removed) => swept.push(("usage", removed)),
+                Err(err) => warn!("Sweep usage records error: {err}"),
+            }
+        }
+        if let Some(log) = ctx.content_log() {
+            let max = ctx
+                .data_retention()
+                .map(|days| Duration::from_secs(days * 86400));
+            swept.push(("content_log", log.purge(max)));
+        }
+        match limit.sweep().await {
+            Ok(removed) => swept.push(("limiter_bucket", removed)),
+            Err(err) => warn!("Sweep limiter buckets error: {err}"),
+        }
+
+        for (store, removed) in swept {
+            if removed > 0 {
+                debug!("Sweep {store}: {removed} expired entries removed");
+                metrics::inc(
+                    SWEPT,
+                    "Expired entries removed by the sweep",
+                    &[("store", store)],
+                    removed as u64,
+                );
+            }
+        }
+    }
+}
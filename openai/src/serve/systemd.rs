@@ -0,0 +1,78 @@
+//! systemd `Type=notify` integration, no-op when not started by systemd
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use axum_server::Handle;
+use sd_notify::NotifyState;
+
+use crate::{info, warn};
+
+/// Send `READY=1` once the server is listening, then `WATCHDOG=1` every half
+/// `WatchdogSec` as long as the server answers the health check
+pub(super) async fn notify_task(handle: Handle, tls: bool) {
+    let Some(addr) = handle.listening().await else {
+        return;
+    };
+
+    if let Err(err) = sd_notify::notify(false, &[NotifyState::Ready]) {
+        warn!("Failed to notify systemd ready: {err}");
+        return;
+    }
+
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) || usec == 0 {
+        return;
+    }
+
+    let interval = Duration::from_micros(usec / 2);
+    info!("systemd watchdog enabled, interval: {interval:?}");
+
+    let client = match reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .no_proxy()
+        .timeout(interval)
+        .build()
+    {
+        Ok(client) => client,
+        Err(err) => {
+            warn!("Failed to build watchdog client: {err}");
+            return;
+        }
+    };
+    let url = health_url(addr, tls);
+
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        // Any HTTP response means the server is still serving requests,
+        // otherwise skip the ping and let systemd restart the hung instance
+        match client.get(&url).send().await {
+            Ok(_) => {
+                if let Err(err) = sd_notify::notify(false, &[NotifyState::Watchdog]) {
+                    warn!("Failed to notify systemd watchdog: {err}");
+                }
+            }
+            Err(err) => warn!("Health check failed, skip watchdog ping: {err}"),
+        }
+    }
+}
+
+/// Send `STOPPING=1` when the server is shutting down
+pub(super) fn stopping() {
+    if let Err(err) = sd_notify::notify(false, &[NotifyState::Stopping]) {
+        warn!("Failed to notify systemd stopping: {err}");
+    }
+}
+
+/// The health check url of the listening address, unspecified addresses are probed via loopback
+fn health_url(addr: SocketAddr, tls: bool) -> String {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    let scheme = if tls { "https" } else { "http" };
+    format!("{scheme}://{}/", SocketAddr::new(ip, addr.port()))
+}
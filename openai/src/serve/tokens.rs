@@ -0,0 +1,51 @@
+//! Token estimate of the prompts and replies, the backend api does not report the usage
+#[cfg(feature = "tiktoken")]
+use std::sync::OnceLock;
+
+#[cfg(feature = "tiktoken")]
+use tiktoken_rs::CoreBPE;
+
+/// `cl100k_base` encoding of gpt-3.5 and gpt-4, shared without a lock as encoding only reads it
+#[cfg(feature = "tiktoken")]
+fn encoding() -> &'static CoreBPE {
+    static ENCODING: OnceLock<CoreBPE> = OnceLock::new();
+    ENCODING.get_or_init(|| tiktoken_rs::cl100k_base().expect("Failed to load cl100k_base"))
+}
+
+#[cfg(feature = "tiktoken")]
+fn encode(text: &str) -> u64 {
+    encoding().encode_with_special_tokens(text).len() as u64
+}
+
+/// Without the `tiktoken` feature a token is estimated as 4 characters
+#[cfg(not(feature = "tiktoken"))]
+fn encode(text: &str) -> u64 {
+    text.chars().count() as u64 / 4
+}
+
+/// Tokens of the text, encoded on the blocking threads as a long text takes a while
+pub(crate) async fn count(text: String) -> u64 {
+    if text.is_empty() {
+        return 0;
+    }
+    tokio::task::spawn_blocking(move || encode(&text))
+        .await
+        .unwrap_or_default()
+}
+
+/// Prompt tokens of the messages, every message is framed by 3 tokens
+/// and the reply is primed with 3 tokens
+pub(crate) async fn count_messages(messages: Vec<String>) -> u64 {
+    if messages.is_empty() {
+        return 0;
+    }
+    tokio::task::spawn_blocking(move || {
+        messages
+            .iter()
+            .map(|message| 3 + encode(message))
+            .sum::<u64>()
+            + 3
+    })
+    .await
+    .unwrap_or_default()
+}
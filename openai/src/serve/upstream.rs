@@ -0,0 +1,206 @@
+//! Alternate upstream endpoints (mirrors/gateways) of the ChatGPT and platform APIs,
+//! an endpoint failing repeatedly is skipped for a while and the requests fail over to the next one
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use reqwest::{Client, Request, Response, Url};
+
+use crate::{metrics, now_duration, warn, URL_CHATGPT_API, URL_PLATFORM_API};
+
+const UPSTREAM_UP: &str = "ninja_upstream_endpoint_up";
+const FAILOVER: &str = "ninja_upstream_failover_total";
+
+/// Path of the conversation requests, the stream of their response is followed
+pub(crate) const CONVERSATION_PATH: &str = "/backend-api/conversation";
+
+/// Consecutive failures marking an endpoint down
+const MAX_FAILURES: u32 = 3;
+
+/// Seconds a down endpoint is skipped, it is then tried again
+const COOLDOWN: u64 = 30;
+
+struct Endpoint {
+    url: String,
+    failures: AtomicU32,
+    /// Unix seconds until which the endpoint is skipped
+    down_until: AtomicU64,
+}
+
+impl Endpoint {
+    fn new(url: String) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_owned(),
+            failures: AtomicU32::new(0),
+            down_until: AtomicU64::new(0),
+        }
+    }
+
+    fn healthy(&self, now: u64) -> bool {
+        self.down_until.load(Ordering::Relaxed) <= now
+    }
+
+    /// Connection errors and gateway errors count as failures
+    fn record(&self, result: &reqwest::Result<Response>) {
+        let failed = match result {
+            Ok(resp) => matches!(resp.status().as_u16(), 502..=504),
+            Err(err) => err.is_connect() || err.is_timeout(),
+        };
+        if !failed {
+            if self.failures.swap(0, Ordering::Relaxed) >= MAX_FAILURES {
+                self.set_up(true);
+            }
+            return;
+        }
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= MAX_FAILURES {
+            if failures == MAX_FAILURES {
+                warn!(
+                    "Upstream {} failed {MAX_FAILURES} times, skipped for {COOLDOWN}s",
+                    self.url
+                );
+                self.set_up(false);
+            }
+            let now = now_duration().map(|d| d.as_secs()).unwrap_or_default();
+            self.down_until.store(now + COOLDOWN, Ordering::Relaxed);
+        }
+    }
+
+    fn set_up(&self, up: bool) {
+        metrics::set(
+            UPSTREAM_UP,
+            "Whether the upstream endpoint is used",
+            &[("endpoint", self.url.as_str())],
+            up as u64,
+        );
+    }
+}
+
+/// Endpoints of one upstream origin, in the order of preference
+pub(crate) struct UpstreamSet {
+    endpoints: Vec<Endpoint>,
+}
+
+impl UpstreamSet {
+    fn new(origin: &str, alternates: Vec<String>) -> Self {
+        Self {
+            endpoints: std::iter::once(origin.to_owned())
+                .chain(alternates)
+                .map(Endpoint::new)
+                .collect(),
+        }
+    }
+
+    /// Healthy endpoints first, the down ones are the last resort
+    fn candidates(&self) -> impl Iterator<Item = &Endpoint> {
+        let now = now_duration().map(|d| d.as_secs()).unwrap_or_default();
+        let (healthy, down): (Vec<_>, Vec<_>) = self.endpoints.iter().partition(|e| e.healthy(now));
+        healthy.into_iter().chain(down)
+    }
+
+    /// Base url of direct requests, the first healthy endpoint
+    pub(crate) fn origin(&self) -> &str {
+        self.candidates()
+            .next()
+            .map(|endpoint| endpoint.url.as_str())
+            .expect("The upstream has no endpoint")
+    }
+
+    /// Send the request to the first healthy endpoint, failing over to the
+    /// next one when the connection fails, the request was then not received
+    pub(crate) async fn execute(
+        &self,
+        client: &Client,
+        request: Request,
+    ) -> reqwest::Result<Response> {
+        if self.endpoints.len() == 1 {
+            let result = client.execute(request).await;
+            self.endpoints[0].record(&result);
+            return result;
+        }
+
+        let mut candidates = self.candidates().peekable();
+        let mut request = request;
+        loop {
+            let endpoint = candidates.next().expect("The upstream has no endpoint");
+            // Keep a copy for the next endpoint, streamed bodies can not be sent again
+            let next = candidates.peek().and_then(|_| request.try_clone());
+            let query = request
+                .url()
+                .query()
+                .map(|query| format!("?{query}"))
+                .unwrap_or_default();
+            if let Ok(url) = Url::parse(&format!("{}{}{query}", endpoint.url, request.url().path()))
+            {
+                *request.url_mut() = url;
+            }
+
+            let result = client.execute(request).await;
+            endpoint.record(&result);
+            match (result, next) {
+                (Err(err), Some(next)) if err.is_connect() => {
+                    metrics::inc(
+                        FAILOVER,
+                        "Requests failed over to the next upstream endpoint",
+                        &[("endpoint", endpoint.url.as_str())],
+                        1,
+                    );
+                    request = next;
+                }
+                (result, _) => return result,
+            }
+        }
+    }
+}
+
+/// Endpoint which served the request of `path` at the response `url`, the url less the path
+pub(crate) fn endpoint_of(url: &Url, path: &str) -> Option<String> {
+    let prefix = url
+        .path()
+        .trim_end_matches('/')
+        .strip_suffix(path.trim_end_matches('/'))?;
+    Some(format!("{}{prefix}", url.origin().ascii_serialization()))
+}
+
+/// Upstream endpoints of the ChatGPT and platform APIs
+pub(crate) struct Upstreams {
+    chatgpt: UpstreamSet,
+    platform: UpstreamSet,
+}
+
+impl Upstreams {
+    pub(crate) fn new(chatgpt: Vec<String>, platform: Vec<String>) -> Self {
+        Self {
+            chatgpt: UpstreamSet::new(URL_CHATGPT_API, chatgpt),
+            platform: UpstreamSet::new(URL_PLATFORM_API, platform),
+        }
+    }
+
+    /// Endpoints of the origin, `None` for other origins
+    pub(crate) fn of(&self, origin: &str) -> Option<&UpstreamSet> {
+        match origin {
+            URL_CHATGPT_API => Some(&self.chatgpt),
+            URL_PLATFORM_API => Some(&self.platform),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_endpoint_of() {
+        let url = Url::parse("https://gw.example.com/chatgpt/backend-api/conversation").unwrap();
+        assert_eq!(
+            endpoint_of(&url, CONVERSATION_PATH).as_deref(),
+            Some("https://gw.example.com/chatgpt")
+        );
+        let url = Url::parse("http://127.0.0.1:8080/backend-api/conversation/").unwrap();
+        assert_eq!(
+            endpoint_of(&url, CONVERSATION_PATH).as_deref(),
+            Some("http://127.0.0.1:8080")
+        );
+        let url = Url::parse("https://chat.openai.com/auth/login").unwrap();
+        assert_eq!(endpoint_of(&url, CONVERSATION_PATH), None);
+    }
+}
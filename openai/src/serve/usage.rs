@@ -0,0 +1,509 @@
+//! Usage accounting, requests and estimated tokens per day, token, account and model
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures_core::Stream;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::middleware::access_log::hash_token;
+use super::tokens;
+use crate::warn;
+
+/// Buffered body size of non-streamed responses to read the usage from
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageStrategy {
+    #[default]
+    Mem,
+    #[cfg(feature = "limit")]
+    Redis,
+}
+
+impl std::str::FromStr for UsageStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mem" => Ok(Self::Mem),
+            #[cfg(feature = "limit")]
+            "redis" => Ok(Self::Redis),
+            _ => anyhow::bail!("usage store: {} is not supported", s),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct UsageKey {
+    /// UTC date, `yyyy-mm-dd`
+    pub date: String,
+    /// Hash prefix of the client key or the bearer token, same as the access log
+    pub token: String,
+    pub account: Option<String>,
+    pub model: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct UsageStat {
+    pub requests: u64,
+    /// Estimated unless reported by the upstream
+    pub prompt_tokens: u64,
+    /// Estimated from the streamed content unless reported by the upstream
+    pub completion_tokens: u64,
+}
+
+impl UsageStat {
+    pub(crate) fn add(&mut self, other: &UsageStat) {
+        self.requests += other.requests;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Usage {
+    #[serde(flatten)]
+    pub key: UsageKey,
+    #[serde(flatten)]
+    pub stat: UsageStat,
+}
+
+#[async_trait::async_trait]
+pub trait UsageStore: Send + Sync {
+    /// Add the stat to the usage of the key
+    async fn record(&self, key: UsageKey, stat: UsageStat) -> anyhow::Result<()>;
+
+    /// Usage between the dates, inclusive
+    async fn query(&self, from: &str, to: &str) -> anyhow::Result<Vec<Usage>>;
+
+    /// Delete the usage of the account, returns how many records were deleted
+    async fn delete(&self, account: &str) -> anyhow::Result<usize>;
+
+    /// Delete the usage of the dates before `date`, returns how many records were deleted
+    async fn purge(&self, date: &str) -> anyhow::Result<usize>;
+}
+
+/// UTC date of `days` ago, `yyyy-mm-dd`
+pub(crate) fn date_before(days: u64) -> String {
+    (time::OffsetDateTime::now_utc() - time::Duration::days(days as i64))
+        .date()
+        .to_string()
+}
+
+/// UTC date of today, `yyyy-mm-dd`
+pub fn today() -> String {
+    date_before(0)
+}
+
+/// Usage store from the strategy, kept for `retention` days
+#[cfg_attr(not(feature = "limit"), allow(unused_variables))]
+pub fn new_store(
+    strategy: UsageStrategy,
+    redis_url: &str,
+    retention: u64,
+) -> anyhow::Result<Arc<dyn UsageStore>> {
+    Ok(match strategy {
+        UsageStrategy::Mem => Arc::new(MemUsageStore::new(retention)),
+        #[cfg(feature = "limit")]
+        UsageStrategy::Redis => Arc::new(RedisUsageStore::new(redis_url, retention)?),
+    })
+}
+
+pub struct MemUsageStore {
+    retention: u64,
+    usage: RwLock<HashMap<UsageKey, UsageStat>>,
+}
+
+impl MemUsageStore {
+    pub fn new(retention: u64) -> Self {
+        Self {
+            retention,
+            usage: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl UsageStore for MemUsageStore {
+    async fn record(&self, key: UsageKey, stat: UsageStat) -> anyhow::Result<()> {
+        let cutoff = date_before(self.retention);
+        let mut usage = self
+            .usage
+            .write()
+            .map_err(|_| anyhow::anyhow!("usage store poisoned"))?;
+        usage.retain(|key, _| key.date >= cutoff);
+        usage.entry(key).or_default().add(&stat);
+        Ok(())
+    }
+
+    async fn query(&self, from: &str, to: &str) -> anyhow::Result<Vec<Usage>> {
+        let usage = self
+            .usage
+            .read()
+            .map_err(|_| anyhow::anyhow!("usage store poisoned"))?;
+        Ok(usage
+            .iter()
+            .filter(|(key, _)| key.date.as_str() >= from && key.date.as_str() <= to)
+            .map(|(key, stat)| Usage {
+                key: key.clone(),
+                stat: *stat,
+            })
+            .collect())
+    }
+
+    async fn delete(&self, account: &str) -> anyhow::Result<usize> {
+        self.remove(|key| key.account.as_deref() == Some(account))
+    }
+
+    async fn purge(&self, date: &str) -> anyhow::Result<usize> {
+        self.remove(|key| key.date.as_str() < date)
+    }
+}
+
+impl MemUsageStore {
+    fn remove(&self, f: impl Fn(&UsageKey) -> bool) -> anyhow::Result<usize> {
+        let mut usage = self
+            .usage
+            .write()
+            .map_err(|_| anyhow::anyhow!("usage store poisoned"))?;
+        let len = usage.len();
+        usage.retain(|key, _| !f(key));
+        Ok(len - usage.len())
+    }
+}
+
+/// Usage in redis hashes `ninja_usage|<date>|<token>|<account>|<model>`, expired after the retention
+#[cfg(feature = "limit")]
+pub struct RedisUsageStore {
+    retention: u64,
+    client: redis::Client,
+}
+
+#[cfg(feature = "limit")]
+const REDIS_KEY_PREFIX: &str = "ninja_usage";
+
+#[cfg(feature = "limit")]
+impl RedisUsageStore {
+    pub fn new(url: &str, retention: u64) -> redis::RedisResult<Self> {
+        Ok(Self {
+            retention,
+            client: redis::Client::open(url)?,
+        })
+    }
+}
+
+#[cfg(feature = "limit")]
+#[async_trait::async_trait]
+impl UsageStore for RedisUsageStore {
+    async fn record(&self, key: UsageKey, stat: UsageStat) -> anyhow::Result<()> {
+        let mut con = self.client.get_async_connection().await?;
+        let name = format!(
+            "{REDIS_KEY_PREFIX}|{}|{}|{}|{}",
+            key.date,
+            key.token,
+            key.account.unwrap_or_default(),
+            key.model.unwrap_or_default()
+        );
+        redis::pipe()
+            .hincr(&name, "requests", stat.requests)
+            .hincr(&name, "prompt_tokens", stat.prompt_tokens)
+            .hincr(&name, "completion_tokens", stat.completion_tokens)
+            .expire(&name, (self.retention * 86400) as usize)
+            .query_async::<_, ()>(&mut con)
+            .await?;
+        Ok(())
+    }
+
+    async fn query(&self, from: &str, to: &str) -> anyhow::Result<Vec<Usage>> {
+        use redis::AsyncCommands;
+
+        let mut con = self.client.get_async_connection().await?;
+        let mut usage = Vec::new();
+        for (name, key) in scan(&mut con).await? {
+            if key.date.as_str() < from || key.date.as_str() > to {
+                continue;
+            }
+
+            let stat: HashMap<String, u64> = con.hgetall(&name).await?;
+            let field = |name: &str| stat.get(name).copied().unwrap_or_default();
+            usage.push(Usage {
+                key,
+                stat: UsageStat {
+                    requests: field("requests"),
+                    prompt_tokens: field("prompt_tokens"),
+                    completion_tokens: field("completion_tokens"),
+                },
+            });
+        }
+        Ok(usage)
+    }
+
+    async fn delete(&self, account: &str) -> anyhow::Result<usize> {
+        self.remove(|key| key.account.as_deref() == Some(account))
+            .await
+    }
+
+    async fn purge(&self, date: &str) -> anyhow::Result<usize> {
+        self.remove(|key| key.date.as_str() < date).await
+    }
+}
+
+#[cfg(feature = "limit")]
+impl RedisUsageStore {
+    async fn remove(&self, f: impl Fn(&UsageKey) -> bool) -> anyhow::Result<usize> {
+        let mut con = self.client.get_async_connection().await?;
+        let names = scan(&mut con)
+            .await?
+            .into_iter()
+            .filter(|(_, key)| f(key))
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        if !names.is_empty() {
+            redis::cmd("DEL")
+                .arg(&names)
+                .query_async::<_, ()>(&mut con)
+                .await?;
+        }
+        Ok(names.len())
+    }
+}
+
+/// Names of the usage hashes and their keys
+#[cfg(feature = "limit")]
+async fn scan(con: &mut redis::aio::Connection) -> anyhow::Result<Vec<(String, UsageKey)>> {
+    use redis::AsyncCommands;
+
+    let mut iter = con
+        .scan_match::<_, String>(format!("{REDIS_KEY_PREFIX}|*"))
+        .await?;
+    let mut names = Vec::new();
+    while let Some(name) = iter.next_item().await {
+        let mut parts = name.splitn(5, '|').skip(1);
+        let (Some(date), Some(token), Some(account), Some(model)) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            continue;
+        };
+        let key = UsageKey {
+            date: date.to_owned(),
+            token: token.to_owned(),
+            account: (!account.is_empty()).then(|| account.to_owned()),
+            model: (!model.is_empty()).then(|| model.to_owned()),
+        };
+        names.push((name, key));
+    }
+    Ok(names)
+}
+
+/// Text of the message `content` of the official api or `content.parts` of the backend api
+fn content_text(message: &Value) -> String {
+    match &message["content"] {
+        Value::String(content) => content.to_owned(),
+        content => content["parts"]
+            .as_array()
+            .map(|parts| {
+                parts
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .collect::<Vec<_>>()
+                    .concat()
+            })
+            .unwrap_or_default(),
+    }
+}
+
+/// Texts of the prompt messages
+fn prompt_texts(body: &Value) -> Vec<String> {
+    body["messages"]
+        .as_array()
+        .map(|messages| messages.iter().map(content_text).collect())
+        .unwrap_or_default()
+}
+
+/// Usage of a proxied request, recorded when the response body is finished or dropped
+pub(crate) struct UsageTracker {
+    store: Arc<dyn UsageStore>,
+    key: UsageKey,
+    prompt_tokens: Option<u64>,
+    prompt: Vec<String>,
+    completion_tokens: Option<u64>,
+    /// Streamed deltas of the official api
+    delta: String,
+    /// Cumulative assistant message of the backend api
+    message: String,
+    event_stream: bool,
+    buf: Vec<u8>,
+}
+
+impl UsageTracker {
+    /// The usage of a request with a client key is accounted to the key
+    pub(crate) fn new(
+        store: Arc<dyn UsageStore>,
+        token: &str,
+        client_key: Option<&str>,
+        body: Option<&[u8]>,
+    ) -> Self {
+        let account = crate::token::check_for_u8(token.as_bytes())
+            .ok()
+            .flatten()
+            .map(|profile| profile.email().to_owned());
+        let body = body.and_then(|body| serde_json::from_slice::<Value>(body).ok());
+
+        Self {
+            store,
+            key: UsageKey {
+                date: today(),
+                token: hash_token(client_key.unwrap_or(token)),
+                account,
+                model: body
+                    .as_ref()
+                    .and_then(|body| body["model"].as_str())
+                    .map(ToOwned::to_owned),
+            },
+            prompt_tokens: None,
+            prompt: body.as_ref().map(prompt_texts).unwrap_or_default(),
+            completion_tokens: None,
+            delta: String::new(),
+            message: String::new(),
+            event_stream: false,
+            buf: Vec::new(),
+        }
+    }
+
+    fn feed(&mut self, chunk: &[u8]) {
+        if !self.event_stream {
+            if self.buf.len() + chunk.len() <= MAX_BODY_SIZE {
+                self.buf.extend_from_slice(chunk);
+            }
+            return;
+        }
+
+        self.buf.extend_from_slice(chunk);
+        while let Some(pos) = self.buf.iter().position(|b| *b == b'\n') {
+            let line = self.buf.drain(..=pos).collect::<Vec<_>>();
+            if let Some(data) = line.strip_prefix(b"data: ") {
+                if let Ok(value) = serde_json::from_slice::<Value>(data) {
+                    self.observe(&value);
+                }
+            }
+        }
+    }
+
+    fn observe(&mut self, value: &Value) {
+        if let Some(usage) = value.get("usage") {
+            self.prompt_tokens = usage["prompt_tokens"].as_u64().or(self.prompt_tokens);
+            self.completion_tokens = usage["completion_tokens"]
+                .as_u64()
+                .or(self.completion_tokens);
+        }
+
+        if let Some(choices) = value["choices"].as_array() {
+            for choice in choices {
+                if let Some(content) = choice
+                    .pointer("/delta/content")
+                    .or_else(|| choice.pointer("/message/content"))
+                    .and_then(Value::as_str)
+                {
+                    self.delta.push_str(content);
+                }
+            }
+        }
+
+        if let Some(message) = value.get("message") {
+            if message.pointer("/author/role").and_then(Value::as_str) == Some("assistant") {
+                self.message = content_text(message);
+                if let Some(model) = message
+                    .pointer("/metadata/model_slug")
+                    .and_then(Value::as_str)
+                {
+                    self.key.model = Some(model.to_owned());
+                }
+            }
+        }
+    }
+}
+
+impl Drop for UsageTracker {
+    fn drop(&mut self) {
+        if !self.event_stream && !self.buf.is_empty() {
+            if let Ok(value) = serde_json::from_slice::<Value>(&self.buf) {
+                self.observe(&value);
+            }
+        }
+
+        // The tokens not reported by the upstream are estimated like the compat api does
+        let prompt_tokens = self.prompt_tokens;
+        let prompt = std::mem::take(&mut self.prompt);
+        let completion_tokens = self.completion_tokens;
+        let completion = std::mem::take(&mut self.delta) + &self.message;
+        let store = self.store.clone();
+        let key = std::mem::take(&mut self.key);
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move {
+                let stat = UsageStat {
+                    requests: 1,
+                    prompt_tokens: match prompt_tokens {
+                        Some(tokens) => tokens,
+                        None => tokens::count_messages(prompt).await,
+                    },
+                    completion_tokens: match completion_tokens {
+                        Some(tokens) => tokens,
+                        None => tokens::count(completion).await,
+                    },
+                };
+                if let Err(err) = store.record(key, stat).await {
+                    warn!("Failed to record usage: {err}");
+                }
+            });
+        }
+    }
+}
+
+/// Response body stream feeding the usage tracker
+pub(crate) struct UsageStream<S> {
+    inner: S,
+    tracker: Option<UsageTracker>,
+}
+
+impl<S> UsageStream<S> {
+    /// Track the response body of the content type, pass through without tracker
+    pub(crate) fn new(
+        inner: S,
+        content_type: Option<&str>,
+        mut tracker: Option<UsageTracker>,
+    ) -> Self {
+        if let Some(tracker) = tracker.as_mut() {
+            tracker.event_stream = content_type
+                .map(|v| v.starts_with("text/event-stream"))
+                .unwrap_or_default();
+        }
+        Self { inner, tracker }
+    }
+}
+
+impl<S, E> Stream for UsageStream<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => {
+                if let Some(tracker) = self.tracker.as_mut() {
+                    tracker.feed(chunk);
+                }
+            }
+            // Record as soon as the body is finished
+            Poll::Ready(None) => self.tracker = None,
+            _ => {}
+        }
+        poll
+    }
+}
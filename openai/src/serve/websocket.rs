@@ -0,0 +1,227 @@
+use axum::{
+    body::Bytes,
+    extract::ws::{Message, WebSocket, WebSocketUpgrade},
+    http::{header, HeaderMap, HeaderValue, Method, Uri},
+    response::Response,
+};
+use eventsource_stream::Eventsource;
+use futures::StreamExt;
+use serde_json::json;
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+
+use crate::{
+    chatgpt::model::{resp::PostConvoResponse, Role},
+    context, debug, metrics, URL_CHATGPT_API,
+};
+
+use super::{
+    deadline,
+    error::ResponseError,
+    extract::{RequestExtractor, SendRequestExt},
+    stateless::CleanupStream,
+    stop::{StopGeneration, StopStream},
+};
+
+pub(super) const CONNECTIONS: &str = "ninja_websocket_connections";
+
+/// `/ws/conversation`, each text frame is a `/backend-api/conversation` request body,
+/// the upstream SSE is converted to `delta` frames followed by a `done` frame
+pub(super) async fn ws_conversation(
+    ws: WebSocketUpgrade,
+    headers: HeaderMap,
+) -> Result<Response, ResponseError> {
+    // Checked by the token authorization, browsers can not set headers on a WebSocket
+    // handshake, the `access_token` query is handed on as the header with `--query-token`
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .or_else(|| headers.get("X-Authorization"))
+        .and_then(|v| v.to_str().ok())
+        .ok_or(ResponseError::Unauthorized(anyhow::anyhow!(
+            "access_token is required!"
+        )))?;
+    let token = format!("Bearer {}", token.trim_start_matches("Bearer "));
+
+    // The upgraded socket is driven outside of the request, keep the context
+    let ctx = context::get_instance();
+    Ok(ws.on_upgrade(move |socket| context::scope(ctx, handle_socket(socket, token))))
+}
+
+async fn handle_socket(mut socket: WebSocket, token: String) {
+    let connections = metrics::gauge(CONNECTIONS, "Open conversation WebSocket connections", &[]);
+    connections.fetch_add(1, Ordering::Relaxed);
+    // Requests sent while a conversation is streaming
+    let mut queued = VecDeque::new();
+    loop {
+        let message = match queued.pop_front() {
+            Some(message) => message,
+            None => match socket.recv().await {
+                Some(Ok(message)) => message,
+                _ => break,
+            },
+        };
+        let body = match message {
+            Message::Text(text) => Bytes::from(text),
+            Message::Binary(data) => Bytes::from(data),
+            Message::Close(_) => break,
+            _ => continue,
+        };
+
+        if let Err(err) = conversation(&mut socket, &mut queued, &token, body).await {
+            let frame = json!({
+                "type": "error",
+                "code": err.code().as_u16(),
+                "msg": err.msg(),
+            });
+            if socket.send(Message::Text(frame.to_string())).await.is_err() {
+                break;
+            }
+        }
+    }
+    connections.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Stream the conversation as frames, the upstream is dropped as soon as the client goes away
+async fn conversation(
+    socket: &mut WebSocket,
+    queued: &mut VecDeque<Message>,
+    token: &str,
+    body: Bytes,
+) -> Result<(), ResponseError> {
+    // Counted like the event streams, no new conversation once the shutdown started
+    let drain = context::get_instance().drain().clone();
+    if drain.draining() {
+        return Err(ResponseError::ServiceUnavailable(anyhow::anyhow!(
+            "Server is shutting down, please try again later"
+        )));
+    }
+    let _tracked = drain.track();
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::AUTHORIZATION,
+        HeaderValue::from_str(token).map_err(ResponseError::BadRequest)?,
+    );
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    headers.insert(
+        header::ACCEPT,
+        HeaderValue::from_static("text/event-stream"),
+    );
+
+    let mut req = RequestExtractor::new(
+        Uri::from_static("/backend-api/conversation"),
+        Method::POST,
+        headers,
+        Some(body),
+    );
+    let (account, session) = req.sticky_keys();
+    let (client, route) =
+        context::get_instance().sticky_client_route(account.as_deref(), session.as_deref());
+    req.set_route(route);
+    req.inject_system_prompt();
+    // Hidden once finished or aborted in stateless mode, like the event streams
+    let mut cleanup = req.conversation_cleanup(&client);
+    let mut stop = StopGeneration::new(client.clone(), token);
+    let resp = client.send_request(URL_CHATGPT_API, req).await?;
+
+    let status = resp.status();
+    if !status.is_success() {
+        let msg = resp.text().await.map_err(ResponseError::BadGateway)?;
+        return Err(ResponseError::new(msg, status));
+    }
+
+    if let Some(cleanup) = cleanup.as_mut() {
+        cleanup.served_by(resp.url());
+    }
+    if let Some(stop) = stop.as_mut() {
+        stop.served_by(resp.url());
+    }
+
+    let mut event_source =
+        StopStream::new(CleanupStream::new(resp.bytes_stream(), cleanup), stop).eventsource();
+    let read = context::get_instance().stream_read_timeout();
+    let wait = read.unwrap_or(deadline::NO_TIMEOUT);
+    let mut previous_message = String::new();
+    loop {
+        let event = tokio::select! {
+            event = tokio::time::timeout(wait, event_source.next()) => match event {
+                Ok(Some(event)) => event,
+                Ok(None) => break,
+                Err(_) => {
+                    let err = anyhow::anyhow!(deadline::idle(read));
+                    return Err(ResponseError::GatewayTimeout(err));
+                }
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                    debug!("WebSocket client disconnected, conversation aborted");
+                    return Ok(());
+                }
+                Some(Ok(message)) => {
+                    queued.push_back(message);
+                    continue;
+                }
+            },
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(err) => {
+                debug!("event-source stream error: {}", err);
+                continue;
+            }
+        };
+
+        if event.data.eq("[DONE]") {
+            break;
+        }
+
+        let convo = match serde_json::from_str::<PostConvoResponse>(&event.data) {
+            Ok(PostConvoResponse::Conversation(convo)) => convo,
+            _ => continue,
+        };
+
+        if convo.role().ne(&Role::Assistant) {
+            continue;
+        }
+
+        let message = match convo.messages().into_iter().next() {
+            Some(message) => message,
+            None => continue,
+        };
+        // The whole message when the upstream rewrote the text sent so far
+        let delta = message
+            .strip_prefix(previous_message.as_str())
+            .unwrap_or(&message);
+        let finish_reason = convo
+            .end_turn()
+            .filter(|&end| end)
+            .map(|_| convo.metadata_finish_details_type());
+
+        if delta.is_empty() && finish_reason.is_none() {
+            continue;
+        }
+
+        let frame = json!({
+            "type": "delta",
+            "conversation_id": convo.conversation_id,
+            "message_id": convo.message.id,
+            "delta": delta,
+            "finish_reason": finish_reason,
+        });
+        socket
+            .send(Message::Text(frame.to_string()))
+            .await
+            .map_err(ResponseError::InternalServerError)?;
+
+        previous_message = message;
+    }
+
+    socket
+        .send(Message::Text(json!({ "type": "done" }).to_string()))
+        .await
+        .map_err(ResponseError::InternalServerError)?;
+    Ok(())
+}
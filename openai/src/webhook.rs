@@ -0,0 +1,145 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::{context, error, now_duration, warn};
+
+/// Signature header, `sha256=<hex hmac of the body>`
+const HEADER_SIGNATURE: &str = "X-Ninja-Signature";
+const HEADER_EVENT: &str = "X-Ninja-Event";
+
+/// Rate limited requests within `STORM_WINDOW` that count as a storm
+const STORM_THRESHOLD: usize = 100;
+const STORM_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    ServerStart,
+    ServerStop,
+    AccountDeactivated,
+    RefreshFailed,
+    HarExhausted,
+    RateLimitStorm,
+    ApiKeyRemoved,
+}
+
+impl ToString for Event {
+    fn to_string(&self) -> String {
+        match self {
+            Event::ServerStart => "server_start",
+            Event::ServerStop => "server_stop",
+            Event::AccountDeactivated => "account_deactivated",
+            Event::RefreshFailed => "refresh_failed",
+            Event::HarExhausted => "har_exhausted",
+            Event::RateLimitStorm => "rate_limit_storm",
+            Event::ApiKeyRemoved => "api_key_removed",
+        }
+        .to_owned()
+    }
+}
+
+pub struct Webhook {
+    urls: Vec<String>,
+    secret: Option<String>,
+    /// Rate limited requests in the current window
+    rate_limited: Mutex<(Instant, usize)>,
+    /// Whether the storm event has fired in the current window
+    storm_alerted: AtomicBool,
+}
+
+impl Webhook {
+    pub fn new(urls: Vec<String>, secret: Option<String>) -> Self {
+        Self {
+            urls,
+            secret,
+            rate_limited: Mutex::new((Instant::now(), 0)),
+            storm_alerted: AtomicBool::new(false),
+        }
+    }
+
+    /// Send the event to all webhooks
+    pub async fn send(&self, event: Event, data: Value) {
+        let body = serde_json::json!({
+            "event": event,
+            "timestamp": now_duration().map(|d| d.as_secs()).unwrap_or_default(),
+            "data": data,
+        })
+        .to_string();
+
+        for url in &self.urls {
+            let mut builder = context::get_instance()
+                .client()
+                .post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(HEADER_EVENT, event.to_string());
+            if let Some(ref secret) = self.secret {
+                builder =
+                    builder.header(HEADER_SIGNATURE, format!("sha256={}", sign(secret, &body)));
+            }
+            if let Err(err) = builder
+                .body(body.clone())
+                .send()
+                .await
+                .and_then(|resp| resp.error_for_status())
+            {
+                error!("Failed to send webhook {}: {err}", event.to_string());
+            }
+        }
+    }
+
+    /// Count a rate limited request, fire `RateLimitStorm` once per window above the threshold
+    pub fn record_rate_limited(&self) {
+        let count = match self.rate_limited.lock() {
+            Ok(mut window) => {
+                if window.0.elapsed() > STORM_WINDOW {
+                    *window = (Instant::now(), 0);
+                    self.storm_alerted.store(false, Ordering::Relaxed);
+                }
+                window.1 += 1;
+                window.1
+            }
+            Err(_) => return,
+        };
+
+        if count >= STORM_THRESHOLD && !self.storm_alerted.swap(true, Ordering::Relaxed) {
+            warn!("Rate limit storm: {count} requests limited");
+            fire(
+                Event::RateLimitStorm,
+                serde_json::json!({ "count": count, "window": STORM_WINDOW.as_secs() }),
+            );
+        }
+    }
+}
+
+/// Fire the event in the background if webhooks are configured
+pub fn fire(event: Event, data: Value) {
+    if context::get_instance().webhook().is_some() {
+        context::spawn(async move {
+            if let Some(webhook) = context::get_instance().webhook() {
+                webhook.send(event, data).await
+            }
+        });
+    }
+}
+
+/// HMAC-SHA256 hex digest of the body
+fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(body.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
@@ -1,6 +1,18 @@
 use crate::parse;
 use clap::{Args, Subcommand};
 use openai::arkose::funcaptcha::Solver;
+use openai::balancer::Ipv6Strategy;
+use openai::chatgpt::model::alias::ModelAlias;
+use openai::dns::DohEndpoint;
+use openai::impersonate::{ImpersonateProfiles, UserAgentRotation};
+use openai::serve::captcha::CaptchaProvider;
+use openai::serve::cassette::CassetteMode;
+use openai::serve::logfile::LogRotation;
+use openai::serve::moderation::ModerationAction;
+use openai::serve::preauth::cagen::CaFormat;
+use openai::serve::usage::UsageStrategy;
+#[cfg(feature = "limit")]
+use openai::serve::RateWindow;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
@@ -35,7 +47,35 @@ pub mod cmd {
         #[clap(subcommand)]
         Serve(ServeSubcommand),
         /// Terminal interaction
-        Terminal,
+        Terminal(super::TerminalArgs),
+    }
+}
+
+#[cfg(feature = "terminal")]
+#[derive(Args, Debug, Default)]
+pub struct TerminalArgs {
+    /// Encrypt the cached tokens with a key derived from the passphrase
+    #[clap(long, env = "STORE_PASSPHRASE", conflicts_with = "store_key_file")]
+    pub(super) store_passphrase: Option<String>,
+
+    /// Encrypt the cached tokens with a key derived from the key file
+    #[clap(long, env = "STORE_KEY_FILE", value_parser = parse::parse_file_path)]
+    pub(super) store_key_file: Option<PathBuf>,
+}
+
+#[cfg(feature = "terminal")]
+impl TerminalArgs {
+    /// Cipher of the account store, `None` keeps the tokens in plaintext
+    pub(super) fn store_cipher(&self) -> anyhow::Result<Option<crate::store::cipher::StoreCipher>> {
+        let secret = match (self.store_passphrase.as_ref(), self.store_key_file.as_ref()) {
+            (Some(passphrase), _) => passphrase.as_bytes().to_vec(),
+            (None, Some(path)) => std::fs::read(path)?,
+            (None, None) => return Ok(None),
+        };
+        if secret.is_empty() {
+            anyhow::bail!("The store passphrase or key file is empty")
+        }
+        Ok(Some(crate::store::cipher::StoreCipher::new(secret)))
     }
 }
 
@@ -59,23 +99,266 @@ pub enum ServeSubcommand {
     #[cfg(target_family = "unix")]
     Log,
     /// Generate MITM CA certificate
-    Genca,
+    Genca {
+        /// CA certificate and private key output directory
+        #[clap(short, long, default_value = "ca")]
+        dir: PathBuf,
+        /// Replace the existing CA, the old files are kept as backup
+        #[clap(short, long)]
+        rotate: bool,
+    },
+    /// Export MITM CA certificate (pem/der/mobileconfig)
+    Exportca {
+        /// CA certificate file path
+        #[clap(short, long, default_value = "ca/cert.crt")]
+        cert: PathBuf,
+        /// Export format (pem/der/mobileconfig)
+        #[clap(short, long, default_value = "pem", value_parser = parse::parse_ca_format)]
+        format: CaFormat,
+        /// Output file path
+        #[clap(short, long)]
+        out: Option<PathBuf>,
+    },
     /// Generate config template file (toml format file)
     GT {
         /// Configuration template output to file (toml format file)
         #[clap(short, long, group = "gt")]
         out: Option<PathBuf>,
     },
+    /// Generate or validate the configuration
+    #[clap(subcommand)]
+    Config(ConfigSubcommand),
     /// Update the application
     Update,
 }
 
+#[derive(Subcommand)]
+pub enum ConfigSubcommand {
+    /// Generate a fully commented config template (toml format file)
+    Init {
+        /// Configuration template output to file, print to stdout if not set
+        #[clap(short, long)]
+        out: Option<PathBuf>,
+        /// Overwrite the existing file
+        #[clap(short, long)]
+        force: bool,
+    },
+    /// Validate the configuration (TLS files, redis, solver key, ...) without starting the server
+    Check(ServeArgs),
+}
+
 #[derive(Args, Debug, Default, Serialize, Deserialize)]
 pub struct ServeArgs {
     /// Log level (info/debug/warn/trace/error)
     #[clap(short = 'L', long, global = true, env = "LOG", default_value = "info")]
     pub(super) level: String,
 
+    /// Log file path, log to stdout if not set
+    #[clap(long, env = "LOG_FILE")]
+    pub(super) log_file: Option<PathBuf>,
+
+    /// Log file rotation by time in UTC (hourly/daily/never)
+    #[clap(long, default_value = "daily")]
+    #[serde(default)]
+    pub(super) log_rotation: LogRotation,
+
+    /// Log file rotation by size (MB), 0 to disable
+    #[clap(long, default_value = "0")]
+    #[serde(default)]
+    pub(super) log_max_size: u64,
+
+    /// Rotated log files to keep, 0 to keep all
+    #[clap(long, default_value = "7")]
+    #[serde(default = "defaults::log_max_files")]
+    pub(super) log_max_files: usize,
+
+    /// Access log file path, one JSON line per request, rotated like the log file
+    #[clap(long, env = "ACCESS_LOG")]
+    pub(super) access_log: Option<PathBuf>,
+
+    /// Usage accounting store (mem/redis), disabled if not set
+    #[clap(long, env = "USAGE_STORE")]
+    pub(super) usage_store: Option<UsageStrategy>,
+
+    /// Usage accounting redis connection url
+    #[clap(long, default_value = "redis://127.0.0.1:6379", requires = "usage_store", value_parser = parse::parse_url)]
+    #[serde(default = "defaults::usage_redis_url")]
+    pub(super) usage_redis_url: String,
+
+    /// Usage accounting retention (days)
+    #[clap(long, default_value = "90", requires = "usage_store")]
+    #[serde(default = "defaults::usage_retention")]
+    pub(super) usage_retention: u64,
+
+    /// Conversation list cache ttl (seconds), 0 to disable
+    #[clap(long, default_value = "0")]
+    #[serde(default)]
+    pub(super) conversation_cache_ttl: u64,
+
+    /// Wait for upstream before serving the stale conversation list (seconds)
+    #[clap(long, default_value = "3")]
+    #[serde(default = "defaults::conversation_cache_timeout")]
+    pub(super) conversation_cache_timeout: u64,
+
+    /// Share page data cache ttl (seconds), 0 to disable
+    #[clap(long, default_value = "0")]
+    #[serde(default)]
+    pub(super) share_cache_ttl: u64,
+
+    /// Model list and account check cache ttl (seconds), 0 to disable
+    #[clap(long, default_value = "0")]
+    #[serde(default)]
+    pub(super) response_cache_ttl: u64,
+
+    /// Expired entry sweep interval (seconds), 0 to disable
+    #[clap(long, default_value = "300")]
+    #[serde(default = "defaults::sweep_interval")]
+    pub(super) sweep_interval: u64,
+
+    /// Retention of the stored user data (days), the sweep deletes the older usage records and content log files, 0 to disable
+    #[clap(long, env = "DATA_RETENTION", default_value = "0")]
+    #[serde(default)]
+    pub(super) data_retention: u64,
+
+    /// Wait for the in-flight event streams on shutdown (seconds), 0 to disable
+    #[clap(long, default_value = "60")]
+    #[serde(default = "defaults::shutdown_drain_timeout")]
+    pub(super) shutdown_drain_timeout: u64,
+
+    /// Graceful shutdown timeout (seconds), the connections still open are then closed
+    #[clap(long, default_value = "30")]
+    #[serde(default = "defaults::shutdown_timeout")]
+    pub(super) shutdown_timeout: u64,
+
+    /// Platform API key pool file, one `sk-` key per line, serves /v1 requests without their own key
+    #[clap(long, env = "API_KEY_POOL", value_parser = parse::parse_file_path)]
+    pub(super) api_key_pool: Option<PathBuf>,
+
+    /// Client key map file (toml), client-facing keys mapped to a managed account or an upstream token
+    #[clap(long, env = "KEY_MAP", value_parser = parse::parse_file_path)]
+    pub(super) key_map: Option<PathBuf>,
+
+    /// Accept the token of the `?api_key=` query (`?access_token=` of /ws/conversation), for the clients that can't set headers
+    #[clap(long, env = "QUERY_TOKEN")]
+    #[serde(default)]
+    pub(super) query_token: bool,
+
+    /// System prompts file (toml), prompts prepended to the new conversations by client key (`[keys]`) or account email (`[accounts]`)
+    #[clap(long, env = "SYSTEM_PROMPTS", value_parser = parse::parse_file_path)]
+    pub(super) system_prompts: Option<PathBuf>,
+
+    /// Prepend the system prompts to the conversations proxied through /backend-api too, not only the /to/v1 ones
+    #[clap(long, env = "SYSTEM_PROMPT_PROXY", requires = "system_prompts")]
+    #[serde(default)]
+    pub(super) system_prompt_proxy: bool,
+
+    /// Rewrite plugins file (toml), Rhai scripts changing the proxied request and response bodies per route
+    #[clap(long, env = "PLUGINS", value_parser = parse::parse_file_path)]
+    pub(super) plugins: Option<PathBuf>,
+
+    /// Moderation keyword rules file, one regex per line, matched in prompts and outputs
+    #[clap(long, env = "MODERATION_RULES", value_parser = parse::parse_file_path)]
+    pub(super) moderation_rules: Option<PathBuf>,
+
+    /// Moderation endpoint (OpenAI compatible), rejects flagged prompts, e.g. https://api.openai.com/v1/moderations
+    #[clap(long, env = "MODERATION_ENDPOINT", value_parser = parse::parse_url)]
+    pub(super) moderation_endpoint: Option<String>,
+
+    /// Moderation endpoint API key
+    #[clap(long, env = "MODERATION_KEY", requires = "moderation_endpoint")]
+    pub(super) moderation_key: Option<String>,
+
+    /// Moderation action of the keyword rules on prompts, reject / redact
+    #[clap(long, env = "MODERATION_ACTION", default_value = "reject")]
+    #[serde(default)]
+    pub(super) moderation_action: ModerationAction,
+
+    /// Shadow traffic target, a http(s) upstream origin or a local sink file, e.g. https://staging.example.com
+    #[clap(long, env = "MIRROR")]
+    pub(super) mirror: Option<String>,
+
+    /// Mirrored share of the proxied requests (0.0 ~ 1.0)
+    #[clap(long, default_value = "0.1", requires = "mirror", value_parser = parse::parse_ratio)]
+    #[serde(default = "defaults::mirror_ratio")]
+    pub(super) mirror_ratio: f64,
+
+    /// Mirror the non-idempotent requests too (e.g. the POST of the conversations), only the idempotent ones by default
+    #[clap(long, env = "MIRROR_ALL_METHODS", requires = "mirror")]
+    #[serde(default)]
+    pub(super) mirror_all_methods: bool,
+
+    /// Cassette directory, records the upstream exchanges or replays them without requesting the upstream
+    #[clap(long, env = "CASSETTE")]
+    pub(super) cassette: Option<PathBuf>,
+
+    /// Cassette mode, record / replay
+    #[clap(
+        long,
+        env = "CASSETTE_MODE",
+        default_value = "replay",
+        requires = "cassette"
+    )]
+    #[serde(default)]
+    pub(super) cassette_mode: CassetteMode,
+
+    /// Replay the latest recording of the path to a request whose body changed, instead of answering 502
+    #[clap(long, env = "CASSETTE_FALLBACK", requires = "cassette")]
+    #[serde(default)]
+    pub(super) cassette_fallback: bool,
+
+    /// Content log directory, audit trail of the prompts sent through the instance in daily files (jsonl)
+    #[clap(long, env = "CONTENT_LOG")]
+    pub(super) content_log: Option<PathBuf>,
+
+    /// Content log retention (days), 0 to keep the files
+    #[clap(long, default_value = "30", requires = "content_log")]
+    #[serde(default = "defaults::content_log_retention")]
+    pub(super) content_log_retention: u64,
+
+    /// Content log redaction rules file (toml), `[[rule]]` regex patterns redacted from the record fields besides the emails and keys of the prompts
+    #[clap(long, env = "CONTENT_LOG_REDACT", value_parser = parse::parse_file_path, requires = "content_log")]
+    pub(super) content_log_redact: Option<PathBuf>,
+
+    /// Alternate ChatGPT API endpoints (mirrors/gateways), used when the official one fails, multiple separated by `,`
+    #[clap(long, env = "CHATGPT_UPSTREAM", value_parser = parse::parse_url, value_delimiter = ',')]
+    pub(super) chatgpt_upstream: Option<Vec<String>>,
+
+    /// Alternate platform API endpoints (mirrors/gateways), used when the official one fails, multiple separated by `,`
+    #[clap(long, env = "PLATFORM_UPSTREAM", value_parser = parse::parse_url, value_delimiter = ',')]
+    pub(super) platform_upstream: Option<Vec<String>>,
+
+    /// Stateless mode, hide the upstream conversations once the response is finished
+    #[clap(long, env = "STATELESS")]
+    #[serde(default)]
+    pub(super) stateless: bool,
+
+    /// Share one upstream call among identical concurrent GET requests (same path, account and authorization)
+    #[clap(long, env = "COALESCE_REQUESTS")]
+    #[serde(default)]
+    pub(super) coalesce_requests: bool,
+
+    /// Model alias, requested model names mapped to backend slugs, e.g. `gpt-4o=gpt-4`, multiple separated by `,`
+    #[clap(long, env = "MODEL_ALIAS")]
+    pub(super) model_alias: Option<ModelAlias>,
+
+    /// SSE keepalive ping interval (seconds) while the upstream is quiet, 0 to disable
+    #[clap(long, default_value = "15")]
+    #[serde(default = "defaults::sse_keepalive")]
+    pub(super) sse_keepalive: u64,
+
+    /// Maximum duration of a streamed conversation (seconds), closed with a final error event, 0 to disable
+    #[clap(long, default_value = "0")]
+    #[serde(default)]
+    pub(super) sse_max_duration: u64,
+
+    /// Upstream path called with the conversation and message ids to stop the generation when the client disconnects mid-stream, e.g. `/backend-api/stop`
+    #[clap(long, env = "STOP_GENERATION_PATH", value_parser = parse::parse_url_path)]
+    pub(super) stop_generation_path: Option<String>,
+
+    /// Event types stripped from the proxied ChatGPT event streams, e.g. `moderation,conversation_detail_metadata,title_generation`, multiple separated by `,`
+    #[clap(long, env = "SSE_STRIP", value_delimiter = ',')]
+    pub(super) sse_strip: Option<Vec<String>>,
+
     /// Configuration file path (toml format file)
     #[clap(short = 'C', long, env = "CONFIG", value_parser = parse::parse_file_path)]
     pub(super) config: Option<PathBuf>,
@@ -92,7 +375,17 @@ pub struct ServeArgs {
     #[clap(long, default_value = "1024")]
     pub(super) concurrent_limit: usize,
 
-    /// Server proxies pool, Only support http/https/socks5 protocol
+    /// Requests waiting for the concurrency limit at most, the others get a 429 (0 for unbounded)
+    #[clap(long, env = "QUEUE_DEPTH", default_value = "0")]
+    #[serde(default)]
+    pub(super) queue_depth: usize,
+
+    /// Wait for the concurrency limit at most (seconds), then a 429 (0 waits until served)
+    #[clap(long, env = "QUEUE_TIMEOUT", default_value = "0")]
+    #[serde(default)]
+    pub(super) queue_timeout: u64,
+
+    /// Server proxies pool, Only support http/https/socks5/socks5h protocol
     #[clap(short = 'x',long, env = "PROXIES", value_parser = parse::parse_proxies_url, group = "proxy")]
     pub(super) proxies: Option<std::vec::Vec<String>>,
 
@@ -104,6 +397,19 @@ pub struct ServeArgs {
     #[clap(long, short = 'I', env = "IPV6_SUBNET", value_parser = parse::parse_ipv6_subnet, group = "proxy")]
     pub(super) ipv6_subnet: Option<(std::net::Ipv6Addr, u8)>,
 
+    /// IPv6 subnet rotation strategy, random / session / account
+    #[clap(long, env = "IPV6_STRATEGY", default_value = "random")]
+    #[serde(default)]
+    pub(super) ipv6_strategy: Ipv6Strategy,
+
+    /// Per-account outbound binding file (toml), pin an account to an interface, proxy or IPv6 address
+    #[clap(long, env = "ACCOUNT_BINDING", value_parser = parse::parse_file_path)]
+    pub(super) account_binding: Option<PathBuf>,
+
+    /// Resolve upstream hostnames via DNS-over-HTTPS, cloudflare / google / custom JSON API url, looked up through the proxies and interface
+    #[clap(long, env = "DOH")]
+    pub(super) doh: Option<DohEndpoint>,
+
     /// Disable direct connection
     #[clap(long, env = "DISABLE_DIRECT")]
     pub(super) disable_direct: bool,
@@ -112,7 +418,7 @@ pub struct ServeArgs {
     #[clap(long, env = "COOKIE_STORE")]
     pub(super) cookie_store: bool,
 
-    /// Client timeout (seconds)
+    /// Client timeout (seconds), of the whole upstream request apart from the event streams
     #[clap(long, default_value = "360")]
     pub(super) timeout: usize,
 
@@ -120,6 +426,16 @@ pub struct ServeArgs {
     #[clap(long, default_value = "20")]
     pub(super) connect_timeout: usize,
 
+    /// Timeout of the upstream event stream requests until the response headers (seconds), 0 to use the client timeout
+    #[clap(long, env = "STREAM_TIMEOUT", default_value = "0")]
+    #[serde(default)]
+    pub(super) stream_timeout: u64,
+
+    /// Longest wait for the next chunk of an upstream event stream (seconds), then it is closed with a final error event, 0 to disable
+    #[clap(long, env = "STREAM_READ_TIMEOUT", default_value = "0")]
+    #[serde(default)]
+    pub(super) stream_read_timeout: u64,
+
     /// TCP keepalive (seconds)
     #[clap(long, default_value = "60")]
     pub(super) tcp_keepalive: usize,
@@ -128,6 +444,44 @@ pub struct ServeArgs {
     #[clap(long, default_value = "90")]
     pub(super) pool_idle_timeout: usize,
 
+    /// Maximum idle connections per host kept in the pool, unlimited if unset
+    #[clap(long)]
+    pub(super) pool_max_idle_per_host: Option<usize>,
+
+    /// Clients per outbound route (direct or proxy), each with its own connection pool
+    #[clap(long, default_value = "1")]
+    #[serde(default = "defaults::pool_size")]
+    pub(super) pool_size: usize,
+
+    /// Disable TCP_NODELAY of the upstream sockets
+    #[clap(long)]
+    #[serde(default)]
+    pub(super) disable_tcp_nodelay: bool,
+
+    /// TLS fingerprint profiles of the API clients, one is picked per client, multiple separated by `,`, e.g. chrome120,safari16_5
+    #[clap(long, env = "IMPERSONATE")]
+    pub(super) impersonate: Option<ImpersonateProfiles>,
+
+    /// TLS fingerprint profiles of the auth clients, multiple separated by `,`
+    #[clap(long, env = "AUTH_IMPERSONATE")]
+    pub(super) auth_impersonate: Option<ImpersonateProfiles>,
+
+    /// TLS fingerprint profiles of the arkose clients, the API clients are used if unset, multiple separated by `,`
+    #[clap(long, env = "ARKOSE_IMPERSONATE")]
+    pub(super) arkose_impersonate: Option<ImpersonateProfiles>,
+
+    /// User-Agents file, one per line, each is only used with a fingerprint profile of the same browser
+    #[clap(long, env = "USER_AGENTS", value_parser = parse::parse_file_path)]
+    pub(super) user_agents: Option<PathBuf>,
+
+    /// User-Agent rotation, client / account / request, the User-Agents are generated from the profiles without a file
+    #[clap(long, env = "USER_AGENT_ROTATION")]
+    pub(super) user_agent_rotation: Option<UserAgentRotation>,
+
+    /// Cloudflare clearance harvester (FlareSolverr compatible) url, asked for a `cf_clearance` of the outbound route when a challenge is detected
+    #[clap(long, env = "CF_HARVESTER", value_parser = parse::parse_url)]
+    pub(super) cf_harvester: Option<String>,
+
     /// TLS certificate file path
     #[clap(long, env = "TLS_CERT", requires = "tls_key")]
     pub(super) tls_cert: Option<PathBuf>,
@@ -140,18 +494,196 @@ pub struct ServeArgs {
     #[clap(short = 'A', long, env = "AUTH_KEY")]
     pub(super) auth_key: Option<String>,
 
+    /// Admin API key
+    #[clap(long, env = "ADMIN_KEY")]
+    pub(super) admin_key: Option<String>,
+
+    /// HTTP Basic auth of the whole server, `user:password` pairs, multiple separated by `,`
+    #[clap(long, env = "BASIC_AUTH", value_parser = parse::parse_basic_auth, value_delimiter = ',')]
+    pub(super) basic_auth: Option<Vec<String>>,
+
+    /// Paths passing without the HTTP Basic auth, by prefix, multiple separated by `,`, e.g. `/v1,/backend-api`
+    #[clap(
+        long,
+        env = "BASIC_AUTH_EXEMPT",
+        value_delimiter = ',',
+        requires = "basic_auth"
+    )]
+    pub(super) basic_auth_exempt: Option<Vec<String>>,
+
+    /// Event webhook urls, multiple separated by `,`
+    #[clap(long, env = "WEBHOOK", value_parser = parse::parse_url, value_delimiter = ',')]
+    pub(super) webhook: Option<Vec<String>>,
+
+    /// Webhook HMAC-SHA256 signing secret, sent as `X-Ninja-Signature`
+    #[clap(long, env = "WEBHOOK_SECRET", requires = "webhook")]
+    pub(super) webhook_secret: Option<String>,
+
     /// Disable WebUI
     #[clap(short = 'D', long, env = "DISABLE_WEBUI")]
     pub(super) disable_webui: bool,
 
-    /// Cloudflare turnstile captcha site key
+    /// Disable WebUI share pages
+    #[clap(long, env = "DISABLE_SHARE")]
+    #[serde(default)]
+    pub(super) disable_share: bool,
+
+    /// Mount all routes under the url prefix, Example: /ninja
+    #[clap(long, env = "URL_PREFIX", value_parser = parse::parse_url_prefix)]
+    pub(super) url_prefix: Option<String>,
+
+    /// WebUI directory overriding the built-in templates and `static` resources
+    #[clap(long, env = "UI_DIR", value_parser = parse::parse_dir_path)]
+    pub(super) ui_dir: Option<PathBuf>,
+
+    /// WebUI session lifetime (seconds) without "remember me", 0 until the browser is closed [default: until the token expires]
+    #[clap(long, env = "SESSION_LIFETIME")]
+    pub(super) session_lifetime: Option<u64>,
+
+    /// Refresh the WebUI session when the token expires within (seconds)
+    #[clap(long, env = "SESSION_REFRESH_WINDOW", default_value = "21600")]
+    #[serde(default = "defaults::session_refresh_window")]
+    pub(super) session_refresh_window: u64,
+
+    /// Absolute WebUI session lifetime (seconds) since the login, refreshes do not extend it, 0 for no limit
+    #[clap(long, env = "SESSION_MAX_AGE", default_value = "0")]
+    #[serde(default)]
+    pub(super) session_max_age: u64,
+
+    /// Extend the WebUI session cookie on every request instead of on session refresh only
+    #[clap(long, env = "SESSION_SLIDING")]
+    #[serde(default)]
+    pub(super) session_sliding: bool,
+
+    /// Login page captcha site key (Cloudflare turnstile, hCaptcha or reCAPTCHA v3)
     #[clap(long, env = "CF_SECRET_KEY", requires = "cf_secret_key")]
     pub(super) cf_site_key: Option<String>,
 
-    /// Cloudflare turnstile captcha secret key
+    /// Login page captcha secret key (Cloudflare turnstile, hCaptcha or reCAPTCHA v3)
     #[clap(long, env = "CF_SITE_KEY", requires = "cf_site_key")]
     pub(super) cf_secret_key: Option<String>,
 
+    /// Login page captcha provider, turnstile / hcaptcha / recaptcha (v3)
+    #[clap(long, env = "CAPTCHA_PROVIDER", default_value = "turnstile")]
+    #[serde(default)]
+    pub(super) captcha_provider: CaptchaProvider,
+
+    /// Minimum reCAPTCHA v3 score of the login (0.0 ~ 1.0)
+    #[clap(long, default_value = "0.5")]
+    #[serde(default = "defaults::recaptcha_min_score")]
+    pub(super) recaptcha_min_score: f64,
+
+    /// Skip the login captcha of a client ip that passed it within (seconds), 0 to disable
+    #[clap(long, default_value = "0")]
+    #[serde(default)]
+    pub(super) captcha_cache_ttl: u64,
+
+    /// Header of the client ip set by the reverse proxy in front, e.g. X-Forwarded-For or CF-Connecting-IP (connection address if unset)
+    #[clap(long, env = "REAL_IP_HEADER")]
+    pub(super) real_ip_header: Option<String>,
+
+    /// WebUI CSRF cookie key, the tokens survive restarts and work across replicas sharing it (random if unset)
+    #[clap(long, env = "CSRF_KEY")]
+    pub(super) csrf_key: Option<String>,
+
+    /// WebUI CSRF cookie with SameSite=Strict instead of Lax
+    #[clap(long, env = "CSRF_STRICT")]
+    #[serde(default)]
+    pub(super) csrf_strict: bool,
+
+    /// Issue a new WebUI CSRF token for every rendered form, a token is good for one submission
+    #[clap(long, env = "CSRF_ROTATE")]
+    #[serde(default)]
+    pub(super) csrf_rotate: bool,
+
+    /// WebUI login allowed emails or domains, multiple separated by `,`, supports `*.` wildcard, e.g. `example.com,bob@example.org`
+    #[clap(long, env = "LOGIN_ALLOW", value_delimiter = ',')]
+    pub(super) login_allow: Option<Vec<String>>,
+
+    /// WebUI login denied emails or domains, multiple separated by `,`, supports `*.` wildcard, over the allowed ones
+    #[clap(long, env = "LOGIN_DENY", value_delimiter = ',')]
+    pub(super) login_deny: Option<Vec<String>>,
+
+    /// WebUI OIDC single sign-on issuer url, the signed in users are mapped to the accounts managed through the admin API
+    #[clap(long, env = "OIDC_ISSUER", value_parser = parse::parse_url, requires = "oidc_client_id", requires = "oidc_client_secret")]
+    pub(super) oidc_issuer: Option<String>,
+
+    /// WebUI OIDC client id
+    #[clap(long, env = "OIDC_CLIENT_ID", requires = "oidc_issuer")]
+    pub(super) oidc_client_id: Option<String>,
+
+    /// WebUI OIDC client secret
+    #[clap(long, env = "OIDC_CLIENT_SECRET", requires = "oidc_issuer")]
+    pub(super) oidc_client_secret: Option<String>,
+
+    /// WebUI OIDC scopes, separated by spaces
+    #[clap(long, env = "OIDC_SCOPES", default_value = "openid email profile")]
+    #[serde(default = "defaults::oidc_scopes")]
+    pub(super) oidc_scopes: String,
+
+    /// WebUI LDAP / Active Directory login url, `ldap://` or `ldaps://`
+    #[clap(long, env = "LDAP_URL", value_parser = parse::parse_ldap_url, requires = "ldap_user_dn")]
+    pub(super) ldap_url: Option<String>,
+
+    /// WebUI LDAP bind DN of the user, `{username}` is the login name, e.g. `uid={username},ou=people,dc=example,dc=com` or `{username}@example.com`
+    #[clap(long, env = "LDAP_USER_DN", requires = "ldap_url")]
+    pub(super) ldap_user_dn: Option<String>,
+
+    /// WebUI LDAP StartTLS upgrade of an `ldap://` url, required unless the host is a loopback address
+    #[clap(long, env = "LDAP_STARTTLS", requires = "ldap_url")]
+    #[serde(default)]
+    pub(super) ldap_starttls: bool,
+
+    /// WebUI LDAP search base of the user entry, read for its `mail` and `memberOf`
+    #[clap(long, env = "LDAP_BASE_DN", requires = "ldap_url")]
+    pub(super) ldap_base_dn: Option<String>,
+
+    /// WebUI LDAP filter of the user entry, `{username}` is the login name
+    #[clap(
+        long,
+        env = "LDAP_USER_FILTER",
+        default_value = "(|(uid={username})(sAMAccountName={username})(userPrincipalName={username}))"
+    )]
+    #[serde(default = "defaults::ldap_user_filter")]
+    pub(super) ldap_user_filter: String,
+
+    /// WebUI LDAP group mapping file (toml), group DN to the pooled accounts of its members
+    #[clap(long, env = "LDAP_GROUPS", value_parser = parse::parse_file_path, requires = "ldap_base_dn")]
+    pub(super) ldap_groups: Option<PathBuf>,
+
+    /// WebUI site title
+    #[clap(long, env = "SITE_TITLE")]
+    pub(super) site_title: Option<String>,
+
+    /// WebUI logo url
+    #[clap(long, env = "LOGO_URL")]
+    pub(super) logo_url: Option<String>,
+
+    /// WebUI footer text
+    #[clap(long, env = "FOOTER_TEXT")]
+    pub(super) footer_text: Option<String>,
+
+    /// Disable WebUI security headers
+    #[clap(long, env = "DISABLE_SECURITY_HEADERS")]
+    #[serde(default)]
+    pub(super) disable_security_headers: bool,
+
+    /// WebUI Content-Security-Policy, empty to omit the header
+    #[clap(long, env = "CSP")]
+    pub(super) csp: Option<String>,
+
+    /// WebUI X-Frame-Options, empty to omit the header
+    #[clap(long, env = "FRAME_OPTIONS")]
+    pub(super) frame_options: Option<String>,
+
+    /// WebUI Referrer-Policy, empty to omit the header
+    #[clap(long, env = "REFERRER_POLICY")]
+    pub(super) referrer_policy: Option<String>,
+
+    /// CSP frame-ancestors of the embedded share pages (`/share/e/*`), which are served without X-Frame-Options, any site by default
+    #[clap(long, env = "SHARE_FRAME_ANCESTORS")]
+    pub(super) share_frame_ancestors: Option<String>,
+
     /// Arkose endpoint, Example: https://client-api.arkoselabs.com
     #[clap(long, value_parser = parse::parse_url)]
     pub(super) arkose_endpoint: Option<String>,
@@ -180,6 +712,21 @@ pub struct ServeArgs {
     #[clap(short = 'K', long)]
     pub(super) arkose_har_upload_key: Option<String>,
 
+    /// Disable a HAR file after consecutive failures (0 means never)
+    #[clap(long, default_value = "5")]
+    #[serde(default = "defaults::arkose_har_max_failures")]
+    pub(super) arkose_har_max_failures: usize,
+
+    /// Arkose token warm pool size per type (0 means disabled)
+    #[clap(long, default_value = "0")]
+    #[serde(default)]
+    pub(super) arkose_pool_size: usize,
+
+    /// Arkose token warm pool token lifetime (seconds)
+    #[clap(long, default_value = "120", value_parser = parse::parse_nonzero_secs)]
+    #[serde(default = "defaults::arkose_pool_ttl")]
+    pub(super) arkose_pool_ttl: u64,
+
     /// About ArkoseLabs solver platform
     #[clap(
         short = 's',
@@ -193,6 +740,11 @@ pub struct ServeArgs {
     /// About the solver client key by ArkoseLabs
     pub(super) arkose_solver_key: Option<String>,
 
+    /// Arkose solver/HAR success rate alert threshold over the latest 50 attempts (0.0 ~ 1.0)
+    #[clap(long, default_value = "0.5", value_parser = parse::parse_ratio)]
+    #[serde(default = "defaults::arkose_alert_threshold")]
+    pub(super) arkose_alert_threshold: f64,
+
     /// Enable token bucket flow limitation
     #[clap(short = 'T', long)]
     #[cfg(feature = "limit")]
@@ -223,6 +775,23 @@ pub struct ServeArgs {
     #[cfg(feature = "limit")]
     pub(super) tb_expired: u32,
 
+    /// Token bucket windows limited together with the capacity and fill rate, `<requests>/<period>`, e.g. `10/min,200/hour`, multiple separated by `,`
+    #[clap(long, requires = "tb_enable", value_delimiter = ',')]
+    #[cfg(feature = "limit")]
+    pub(super) tb_window: Option<Vec<RateWindow>>,
+
+    /// Forward CONNECT proxy bind address, tunnels to OpenAI hosts only
+    #[clap(long, env = "FORWARD_BIND", value_parser = parse::parse_socket_addr)]
+    pub(super) fbind: Option<std::net::SocketAddr>,
+
+    /// Forward CONNECT proxy allowed hostnames, supports `*.` wildcard [default: openai.com,*.openai.com,*.oaistatic.com,*.oaiusercontent.com]
+    #[clap(long, value_delimiter = ',', requires = "fbind")]
+    pub(super) fhosts: Option<Vec<String>>,
+
+    /// Forward CONNECT proxy `Proxy-Authorization` credentials, `user:password` pairs, multiple separated by `,`, required unless it binds a loopback address
+    #[clap(long, env = "FORWARD_AUTH", value_parser = parse::parse_basic_auth, value_delimiter = ',', requires = "fbind")]
+    pub(super) fauth: Option<Vec<String>>,
+
     /// Preauth MITM server bind address
     #[clap(
         short = 'B',
@@ -232,15 +801,29 @@ pub struct ServeArgs {
     )]
     pub(super) pbind: Option<std::net::SocketAddr>,
 
-    /// Preauth MITM server upstream proxy, Only support http/https/socks5 protocol
+    /// Preauth MITM server upstream proxies, rotated per connection, Only support http/https/socks5/socks5h protocol
     #[clap(
         short = 'X',
         long,
         env = "PREAUTH_UPSTREAM",
-        value_parser = parse::parse_url,
+        value_parser = parse::parse_proxies_url,
         requires = "pbind"
     )]
-    pub(super) pupstream: Option<String>,
+    #[serde(default, deserialize_with = "proxy_list")]
+    pub(super) pupstream: Option<std::vec::Vec<String>>,
+
+    /// Preauth MITM server intercept hostnames, supports wildcard, others are tunneled [default: ios.chat.openai.com]
+    #[clap(long, value_delimiter = ',', requires = "pbind")]
+    pub(super) pfilter: Option<Vec<String>>,
+
+    /// Preauth cookie pool low webhook url
+    #[clap(long, value_parser = parse::parse_url, requires = "pbind")]
+    pub(super) preauth_webhook: Option<String>,
+
+    /// Fire the preauth webhook when the cookie pool size drops below
+    #[clap(long, default_value = "1", requires = "pbind")]
+    #[serde(default = "defaults::preauth_webhook_threshold")]
+    pub(super) preauth_webhook_threshold: usize,
 
     /// Preauth MITM server CA certificate file path
     #[clap(long, default_value = "ca/cert.crt", requires = "pbind")]
@@ -250,3 +833,105 @@ pub struct ServeArgs {
     #[clap(long, default_value = "ca/key.pem", requires = "pbind")]
     pub(super) pkey: PathBuf,
 }
+
+/// Proxies of the config file, a list or a comma separated string as the earlier releases took
+fn proxy_list<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Proxies {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    let proxies = match Option::<Proxies>::deserialize(deserializer)? {
+        Some(Proxies::One(proxies)) => proxies,
+        Some(Proxies::Many(proxies)) => proxies.join(","),
+        None => return Ok(None),
+    };
+    parse::parse_proxies_url(&proxies)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// Defaults of the config file fields added since the first release, the same as the command
+/// line ones, so the existing config files still load
+mod defaults {
+    pub(super) fn log_max_files() -> usize {
+        7
+    }
+
+    pub(super) fn usage_redis_url() -> String {
+        "redis://127.0.0.1:6379".to_owned()
+    }
+
+    pub(super) fn usage_retention() -> u64 {
+        90
+    }
+
+    pub(super) fn conversation_cache_timeout() -> u64 {
+        3
+    }
+
+    pub(super) fn sweep_interval() -> u64 {
+        300
+    }
+
+    pub(super) fn shutdown_drain_timeout() -> u64 {
+        60
+    }
+
+    pub(super) fn shutdown_timeout() -> u64 {
+        30
+    }
+
+    pub(super) fn mirror_ratio() -> f64 {
+        0.1
+    }
+
+    pub(super) fn content_log_retention() -> u64 {
+        30
+    }
+
+    pub(super) fn sse_keepalive() -> u64 {
+        15
+    }
+
+    pub(super) fn pool_size() -> usize {
+        1
+    }
+
+    pub(super) fn session_refresh_window() -> u64 {
+        21600
+    }
+
+    pub(super) fn recaptcha_min_score() -> f64 {
+        0.5
+    }
+
+    pub(super) fn oidc_scopes() -> String {
+        "openid email profile".to_owned()
+    }
+
+    pub(super) fn ldap_user_filter() -> String {
+        "(|(uid={username})(sAMAccountName={username})(userPrincipalName={username}))".to_owned()
+    }
+
+    pub(super) fn arkose_har_max_failures() -> usize {
+        5
+    }
+
+    pub(super) fn arkose_pool_ttl() -> u64 {
+        120
+    }
+
+    pub(super) fn arkose_alert_threshold() -> f64 {
+        0.5
+    }
+
+    pub(super) fn preauth_webhook_threshold() -> usize {
+        1
+    }
+}
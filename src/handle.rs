@@ -1,10 +1,25 @@
-use std::{ops::Not, path::PathBuf};
+use std::{collections::HashMap, fmt::Write, ops::Not, path::PathBuf, sync::Arc};
+
+use clap::{Args, CommandFactory};
+use openai::{
+    arkose::funcaptcha::ArkoseSolver,
+    balancer::AccountBinding,
+    context::{ContextArgs, KeySpec, SystemPrompts},
+    serve::{
+        captcha::CaptchaProvider,
+        cassette::{Cassette, CassetteMode},
+        contentlog::{ContentLog, RedactRules},
+        mirror::Mirror,
+        moderation::{Moderation, ModerationAction},
+        Serve,
+    },
+};
 
-use clap::CommandFactory;
-use openai::{arkose::funcaptcha::ArkoseSolver, context::ContextArgs, serve::Serve};
+#[cfg(feature = "plugin")]
+use openai::serve::plugin::{PluginConfig, Plugins};
 
 use crate::{
-    args::{self, ServeArgs},
+    args::{self, ConfigSubcommand, ServeArgs},
     utils::unix::fix_relative_path,
 };
 
@@ -16,11 +31,7 @@ pub(super) fn serve(mut args: ServeArgs, relative_path: bool) -> anyhow::Result<
         fix_relative_path(&mut args);
     }
 
-    if let Some(config_path) = args.config {
-        let bytes = std::fs::read(config_path)?;
-        let data = String::from_utf8(bytes)?;
-        args = toml::from_str::<ServeArgs>(&data)?;
-    }
+    args = load_config(args)?;
 
     #[cfg(target_os = "linux")]
     utils::unix::sysctl_route_add_ipv6_subnet(args.ipv6_subnet);
@@ -45,6 +56,113 @@ pub(super) fn serve(mut args: ServeArgs, relative_path: bool) -> anyhow::Result<
         None => None,
     };
 
+    // Per-account outbound binding, e.g. `["user@example.com"]` table with `interface`, `proxy` or `ipv6`
+    let account_bindings = match args.account_binding.as_ref() {
+        Some(path) => {
+            toml::from_str::<HashMap<String, AccountBinding>>(&std::fs::read_to_string(path)?)?
+        }
+        None => HashMap::new(),
+    };
+
+    // Platform API key pool, one key per line, `#` comments
+    let api_key_pool = read_api_key_pool(&args)?;
+
+    // Client keys, `"nk-team-a" = { account = "team-a@example.com" }` or `{ token = "..." }`
+    let key_map = match args.key_map.as_ref() {
+        Some(path) => toml::from_str::<HashMap<String, KeySpec>>(&std::fs::read_to_string(path)?)?,
+        None => HashMap::new(),
+    };
+
+    // System prompts, `[keys]` and `[accounts]` tables of prompts
+    let system_prompts = match args.system_prompts.as_ref() {
+        Some(path) => Some(toml::from_str::<SystemPrompts>(&std::fs::read_to_string(
+            path,
+        )?)?),
+        None => None,
+    };
+
+    // Content log redaction rules, `[[rule]]` tables with `pattern`
+    let content_log_redact = match args.content_log_redact.as_ref() {
+        Some(path) => toml::from_str::<RedactRules>(&std::fs::read_to_string(path)?)?.rule,
+        None => Vec::new(),
+    };
+    let content_log = match args.content_log.as_ref() {
+        Some(dir) => Some(Arc::new(ContentLog::new(
+            dir.to_owned(),
+            args.content_log_retention,
+            content_log_redact,
+        )?)),
+        None => None,
+    };
+
+    // Rewrite plugins, `[[plugin]]` tables with `route` and `script`, a script
+    // that does not compile stops the start like `config check`
+    #[cfg(feature = "plugin")]
+    let plugins = match args.plugins.as_ref() {
+        Some(path) => Some(Plugins::new(&read_plugins(path)?)?)
+            .filter(|plugins| !plugins.is_empty())
+            .map(Arc::new),
+        None => None,
+    };
+    #[cfg(not(feature = "plugin"))]
+    check_plugins(&args)?;
+
+    // Moderation keyword rules, one regex per line, compiled before the start
+    let moderation_rules = match args.moderation_rules.as_ref() {
+        Some(path) => read_moderation_rules(path)?,
+        None => Vec::new(),
+    };
+    let moderation = match !moderation_rules.is_empty() || args.moderation_endpoint.is_some() {
+        true => Some(Arc::new(Moderation::new(
+            &moderation_rules,
+            args.moderation_endpoint.clone(),
+            args.moderation_key.clone(),
+            args.moderation_action,
+        )?)),
+        false => None,
+    };
+
+    // Shadow traffic mirror, a file url that is not a path stops the start
+    let mirror = match args.mirror.as_ref() {
+        Some(target) => Some(Arc::new(Mirror::new(
+            target,
+            args.mirror_ratio,
+            args.mirror_all_methods,
+        )?)),
+        None => None,
+    };
+
+    // Cassette of the upstream exchanges, the replay directory must exist
+    let cassette = match args.cassette.as_ref() {
+        Some(dir) => Some(Arc::new(Cassette::new(
+            dir.to_owned(),
+            args.cassette_mode,
+            args.cassette_fallback,
+        )?)),
+        None => None,
+    };
+
+    check_ldap_url(&args)?;
+    check_forward_bind(&args)?;
+    // The config file skips the command line parsers
+    if args.arkose_pool_ttl == 0 {
+        anyhow::bail!("arkose_pool_ttl must be greater than 0")
+    }
+
+    // LDAP group mapping, `"cn=group,dc=example,dc=com" = ["account@example.com"]`
+    let ldap_groups = match args.ldap_groups.as_ref() {
+        Some(path) => {
+            toml::from_str::<HashMap<String, Vec<String>>>(&std::fs::read_to_string(path)?)?
+        }
+        None => HashMap::new(),
+    };
+
+    // User-Agents, one per line
+    let user_agents = match args.user_agents.as_ref() {
+        Some(path) => read_user_agents(path)?,
+        None => Vec::new(),
+    };
+
     // Set the log level
     std::env::set_var("RUST_LOG", args.level);
 
@@ -52,21 +170,110 @@ pub(super) fn serve(mut args: ServeArgs, relative_path: bool) -> anyhow::Result<
         .bind(args.bind)
         .interface(args.interface)
         .ipv6_subnet(args.ipv6_subnet)
+        .ipv6_strategy(args.ipv6_strategy)
+        .account_bindings(account_bindings)
+        .doh(args.doh)
         .proxies(args.proxies.unwrap_or_default())
         .disable_direct(args.disable_direct)
         .cookie_store(args.cookie_store)
         .tcp_keepalive(args.tcp_keepalive)
         .pool_idle_timeout(args.pool_idle_timeout)
+        .pool_max_idle_per_host(args.pool_max_idle_per_host)
+        .pool_size(args.pool_size)
+        .disable_tcp_nodelay(args.disable_tcp_nodelay)
+        .impersonate(args.impersonate.unwrap_or_default())
+        .auth_impersonate(args.auth_impersonate.unwrap_or_default())
+        .arkose_impersonate(args.arkose_impersonate.unwrap_or_default())
+        .user_agents(user_agents)
+        .user_agent_rotation(args.user_agent_rotation)
+        .cf_harvester(args.cf_harvester)
         .timeout(args.timeout)
         .connect_timeout(args.connect_timeout)
+        .stream_timeout(args.stream_timeout)
+        .stream_read_timeout(args.stream_read_timeout)
         .workers(args.workers)
+        .log_file(args.log_file)
+        .log_rotation(args.log_rotation)
+        .log_max_size(args.log_max_size)
+        .log_max_files(args.log_max_files)
+        .access_log(args.access_log)
+        .usage_store(args.usage_store)
+        .usage_redis_url(args.usage_redis_url)
+        .usage_retention(args.usage_retention)
+        .conversation_cache_ttl(args.conversation_cache_ttl)
+        .conversation_cache_timeout(args.conversation_cache_timeout)
+        .share_cache_ttl(args.share_cache_ttl)
+        .response_cache_ttl(args.response_cache_ttl)
+        .sweep_interval(args.sweep_interval)
+        .data_retention(args.data_retention)
+        .shutdown_drain_timeout(args.shutdown_drain_timeout)
+        .shutdown_timeout(args.shutdown_timeout)
+        .api_key_pool(api_key_pool)
+        .key_map(key_map)
+        .query_token(args.query_token)
+        .moderation(moderation)
+        .mirror(mirror)
+        .cassette(cassette)
+        .content_log(content_log)
+        .chatgpt_upstream(args.chatgpt_upstream.unwrap_or_default())
+        .platform_upstream(args.platform_upstream.unwrap_or_default())
+        .stateless(args.stateless)
+        .coalesce_requests(args.coalesce_requests)
+        .model_alias(args.model_alias.unwrap_or_default())
+        .sse_keepalive(args.sse_keepalive)
+        .sse_max_duration(args.sse_max_duration)
+        .stop_generation_path(args.stop_generation_path)
+        .sse_strip(args.sse_strip.unwrap_or_default())
         .concurrent_limit(args.concurrent_limit)
+        .queue_depth(args.queue_depth)
+        .queue_timeout(args.queue_timeout)
         .tls_cert(args.tls_cert)
         .tls_key(args.tls_key)
         .auth_key(args.auth_key)
+        .admin_key(args.admin_key)
+        .basic_auth(args.basic_auth.unwrap_or_default())
+        .basic_auth_exempt(args.basic_auth_exempt.unwrap_or_default())
+        .system_prompts(system_prompts)
+        .system_prompt_proxy(args.system_prompt_proxy)
+        .webhook(args.webhook.unwrap_or_default())
+        .webhook_secret(args.webhook_secret)
         .cf_site_key(args.cf_site_key)
         .cf_secret_key(args.cf_secret_key)
+        .captcha_provider(args.captcha_provider)
+        .recaptcha_min_score(args.recaptcha_min_score)
+        .captcha_cache_ttl(args.captcha_cache_ttl)
+        .real_ip_header(args.real_ip_header)
+        .csrf_key(args.csrf_key)
+        .csrf_strict(args.csrf_strict)
+        .csrf_rotate(args.csrf_rotate)
+        .login_allow(args.login_allow.unwrap_or_default())
+        .login_deny(args.login_deny.unwrap_or_default())
+        .oidc_issuer(args.oidc_issuer)
+        .oidc_client_id(args.oidc_client_id)
+        .oidc_client_secret(args.oidc_client_secret)
+        .oidc_scopes(args.oidc_scopes)
+        .ldap_url(args.ldap_url)
+        .ldap_user_dn(args.ldap_user_dn)
+        .ldap_starttls(args.ldap_starttls)
+        .ldap_base_dn(args.ldap_base_dn)
+        .ldap_user_filter(args.ldap_user_filter)
+        .ldap_groups(ldap_groups)
         .disable_ui(args.disable_webui)
+        .disable_share(args.disable_share)
+        .url_prefix(args.url_prefix)
+        .ui_dir(args.ui_dir)
+        .session_lifetime(args.session_lifetime)
+        .session_refresh_window(args.session_refresh_window)
+        .session_max_age(args.session_max_age)
+        .session_sliding(args.session_sliding)
+        .site_title(args.site_title)
+        .logo_url(args.logo_url)
+        .footer_text(args.footer_text)
+        .disable_security_headers(args.disable_security_headers)
+        .csp(args.csp)
+        .frame_options(args.frame_options)
+        .referrer_policy(args.referrer_policy)
+        .share_frame_ancestors(args.share_frame_ancestors)
         .arkose_endpoint(args.arkose_endpoint)
         .arkose_gpt3_har_dir(args.arkose_gpt3_har_dir)
         .arkose_gpt4_har_dir(args.arkose_gpt4_har_dir)
@@ -74,12 +281,35 @@ pub(super) fn serve(mut args: ServeArgs, relative_path: bool) -> anyhow::Result<
         .arkose_platform_har_dir(args.arkose_platform_har_dir)
         .arkose_gpt3_experiment(args.arkose_gpt3_experiment)
         .arkose_har_upload_key(args.arkose_har_upload_key)
+        .arkose_har_max_failures(args.arkose_har_max_failures)
         .arkose_solver(arkose_solver)
+        .arkose_alert_threshold(args.arkose_alert_threshold)
+        .arkose_pool_size(args.arkose_pool_size)
+        .arkose_pool_ttl(args.arkose_pool_ttl)
+        .fbind(args.fbind)
+        .fhosts(
+            args.fhosts.unwrap_or(
+                openai::context::DEFAULT_FORWARD_HOSTS
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect(),
+            ),
+        )
+        .fauth(args.fauth.unwrap_or_default())
         .pbind(args.pbind)
-        .pupstream(args.pupstream)
+        .pupstream(args.pupstream.unwrap_or_default())
+        .pfilter(
+            args.pfilter
+                .unwrap_or(vec![String::from("ios.chat.openai.com")]),
+        )
+        .preauth_webhook(args.preauth_webhook)
+        .preauth_webhook_threshold(args.preauth_webhook_threshold)
         .pcert(args.pcert)
         .pkey(args.pkey);
 
+    #[cfg(feature = "plugin")]
+    let builder = builder.plugins(plugins);
+
     #[cfg(feature = "limit")]
     let builder = builder
         .tb_enable(args.tb_enable)
@@ -87,7 +317,8 @@ pub(super) fn serve(mut args: ServeArgs, relative_path: bool) -> anyhow::Result<
         .tb_redis_url(args.tb_redis_url)
         .tb_capacity(args.tb_capacity)
         .tb_fill_rate(args.tb_fill_rate)
-        .tb_expired(args.tb_expired);
+        .tb_expired(args.tb_expired)
+        .tb_window(args.tb_window.unwrap_or_default());
 
     Serve::new(builder.build()).run()
 }
@@ -247,7 +478,43 @@ pub(super) fn generate_template(out: Option<PathBuf>) -> anyhow::Result<()> {
         std::env::current_dir()?.join("serve.toml")
     };
 
-    let args = args::ServeArgs {
+    let args = template_args()?;
+
+    let write = |out: PathBuf, args: ServeArgs| -> anyhow::Result<()> {
+        #[cfg(target_family = "unix")]
+        {
+            use std::fs::Permissions;
+            use std::os::unix::prelude::PermissionsExt;
+            std::fs::File::create(&out)?.set_permissions(Permissions::from_mode(0o755))?;
+        }
+
+        #[cfg(target_family = "windows")]
+        std::fs::File::create(&out)?;
+
+        Ok(std::fs::write(out, toml::to_string_pretty(&args)?)?)
+    };
+
+    if !out.exists() {
+        write(out, args)?;
+    }
+    Ok(())
+}
+
+/// Replace the arguments with the config file if set
+fn load_config(args: ServeArgs) -> anyhow::Result<ServeArgs> {
+    match args.config {
+        Some(config_path) => {
+            let bytes = std::fs::read(config_path)?;
+            let data = String::from_utf8(bytes)?;
+            Ok(toml::from_str::<ServeArgs>(&data)?)
+        }
+        None => Ok(args),
+    }
+}
+
+/// Arguments of the config template, with the command line defaults
+fn template_args() -> anyhow::Result<ServeArgs> {
+    Ok(args::ServeArgs {
         bind: Some("0.0.0.0:7999".parse()?),
         workers: 1,
         concurrent_limit: 65535,
@@ -262,29 +529,417 @@ pub(super) fn generate_template(out: Option<PathBuf>) -> anyhow::Result<()> {
         tb_expired: 86400,
         cookie_store: true,
         pool_idle_timeout: 90,
+        pool_size: 1,
         level: "info".to_owned(),
+        log_max_files: 7,
+        usage_redis_url: "redis://127.0.0.1:6379".to_string(),
+        usage_retention: 90,
+        conversation_cache_ttl: 0,
+        conversation_cache_timeout: 3,
+        share_cache_ttl: 0,
+        response_cache_ttl: 0,
+        sweep_interval: 300,
+        data_retention: 0,
+        shutdown_drain_timeout: 60,
+        shutdown_timeout: 30,
+        sse_keepalive: 15,
+        sse_max_duration: 0,
+        moderation_action: ModerationAction::Reject,
+        mirror_ratio: 0.1,
+        cassette_mode: CassetteMode::Replay,
+        cassette_fallback: false,
+        content_log_retention: 30,
+        captcha_provider: CaptchaProvider::Turnstile,
+        recaptcha_min_score: 0.5,
+        captcha_cache_ttl: 0,
+        oidc_scopes: "openid email profile".to_owned(),
+        ldap_user_filter:
+            "(|(uid={username})(sAMAccountName={username})(userPrincipalName={username}))"
+                .to_owned(),
+        preauth_webhook_threshold: 1,
         pcert: PathBuf::from("ca/cert.crt"),
         pkey: PathBuf::from("ca/key.pem"),
         arkose_gpt3_experiment: false,
+        arkose_alert_threshold: 0.5,
+        arkose_har_max_failures: 5,
+        arkose_pool_size: 0,
+        arkose_pool_ttl: 120,
         ..args::ServeArgs::default()
+    })
+}
+
+pub(super) fn config(command: ConfigSubcommand) -> anyhow::Result<()> {
+    match command {
+        ConfigSubcommand::Init { out, force } => config_init(out, force),
+        ConfigSubcommand::Check(args) => config_check(args),
+    }
+}
+
+fn config_init(out: Option<PathBuf>, force: bool) -> anyhow::Result<()> {
+    let template = commented_template()?;
+    match out {
+        Some(out) => {
+            if out.is_dir() {
+                anyhow::bail!("{} not a file", out.display())
+            }
+            if out.exists() && !force {
+                anyhow::bail!(
+                    "{} already exists, use `--force` to overwrite",
+                    out.display()
+                )
+            }
+            if let Some(parent) = out.parent() {
+                if parent.as_os_str().is_empty().not() && parent.exists().not() {
+                    std::fs::create_dir_all(parent)?;
+                }
+            }
+            std::fs::write(&out, template)?;
+            println!("Config template written to {}", out.display());
+        }
+        None => print!("{template}"),
+    }
+    Ok(())
+}
+
+/// Config template with every option, documented by its command line help.
+/// Options without a default are commented out.
+fn commented_template() -> anyhow::Result<String> {
+    let values = toml::Value::try_from(template_args()?)?;
+    let cmd = ServeArgs::augment_args(clap::Command::new("run"));
+
+    let mut template = String::from("# Ninja configuration, `ninja run --config <FILE>`\n");
+    for arg in cmd.get_arguments() {
+        let id = arg.get_id().as_str();
+        if id == "config" || arg.is_hide_set() {
+            continue;
+        }
+
+        template.push('\n');
+        if let Some(help) = arg.get_help() {
+            for line in help.to_string().lines() {
+                writeln!(template, "# {line}")?;
+            }
+        }
+        match values.get(id) {
+            Some(value) => writeln!(template, "{id} = {value}")?,
+            None => writeln!(template, "# {id} =")?,
+        }
+    }
+    Ok(template)
+}
+
+/// Validate the configuration, report every check and fail if any of them failed
+fn config_check(args: ServeArgs) -> anyhow::Result<()> {
+    let args = load_config(args)?;
+    let mut checks: Vec<(String, anyhow::Result<()>)> = Vec::new();
+
+    checks.push((
+        "Direct connection and proxies".to_owned(),
+        if args.disable_direct && args.proxies.as_ref().map_or(true, |p| p.is_empty()) {
+            Err(anyhow::anyhow!(
+                "Cannot disable direct connection and not set proxies"
+            ))
+        } else {
+            Ok(())
+        },
+    ));
+
+    if let (Some(cert), Some(key)) = (args.tls_cert.as_ref(), args.tls_key.as_ref()) {
+        checks.push((
+            format!("TLS certificate {}", cert.display()),
+            check_pem(cert, "CERTIFICATE"),
+        ));
+        checks.push((
+            format!("TLS private key {}", key.display()),
+            check_pem(key, "PRIVATE KEY"),
+        ));
+    }
+
+    if args.pbind.is_some() {
+        checks.push((
+            format!("PreAuth CA certificate {}", args.pcert.display()),
+            check_pem(&args.pcert, "CERTIFICATE"),
+        ));
+        checks.push((
+            format!("PreAuth CA private key {}", args.pkey.display()),
+            check_pem(&args.pkey, "PRIVATE KEY"),
+        ));
+    }
+
+    if let Some(path) = args.account_binding.as_ref() {
+        checks.push((
+            format!("Account binding {}", path.display()),
+            std::fs::read_to_string(path)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| {
+                    toml::from_str::<HashMap<String, AccountBinding>>(&data)
+                        .map_err(anyhow::Error::from)
+                })
+                .map(|_| ()),
+        ));
+    }
+
+    if let Some(url) = args.ldap_url.as_ref() {
+        checks.push((format!("LDAP url {url}"), check_ldap_url(&args)));
+    }
+
+    if let Some(fbind) = args.fbind {
+        checks.push((format!("Forward proxy {fbind}"), check_forward_bind(&args)));
+    }
+
+    if let Some(path) = args.ldap_groups.as_ref() {
+        checks.push((
+            format!("LDAP groups {}", path.display()),
+            std::fs::read_to_string(path)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| {
+                    toml::from_str::<HashMap<String, Vec<String>>>(&data)
+                        .map_err(anyhow::Error::from)
+                })
+                .map(|_| ()),
+        ));
+    }
+
+    if let Some(path) = args.api_key_pool.as_ref() {
+        checks.push((
+            format!("API key pool {}", path.display()),
+            read_api_key_pool(&args).map(|_| ()),
+        ));
+    }
+
+    if let Some(path) = args.key_map.as_ref() {
+        checks.push((
+            format!("Key map {}", path.display()),
+            std::fs::read_to_string(path)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| {
+                    toml::from_str::<HashMap<String, KeySpec>>(&data).map_err(anyhow::Error::from)
+                })
+                .map(|_| ()),
+        ));
+    }
+
+    if let Some(path) = args.system_prompts.as_ref() {
+        checks.push((
+            format!("System prompts {}", path.display()),
+            std::fs::read_to_string(path)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| {
+                    toml::from_str::<SystemPrompts>(&data).map_err(anyhow::Error::from)
+                })
+                .map(|_| ()),
+        ));
+    }
+
+    if let Some(path) = args.content_log_redact.as_ref() {
+        checks.push((
+            format!("Content log redaction rules {}", path.display()),
+            std::fs::read_to_string(path)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| toml::from_str::<RedactRules>(&data).map_err(anyhow::Error::from))
+                .and_then(|rules| ContentLog::check(&rules.rule)),
+        ));
+    }
+
+    if let Some(path) = args.user_agents.as_ref() {
+        checks.push((
+            format!("User-Agents {}", path.display()),
+            read_user_agents(path).map(|_| ()),
+        ));
+    }
+
+    if let Some(path) = args.plugins.as_ref() {
+        #[cfg(feature = "plugin")]
+        let result = read_plugins(path).and_then(|plugins| Plugins::new(&plugins).map(|_| ()));
+        #[cfg(not(feature = "plugin"))]
+        let result = check_plugins(&args);
+        checks.push((format!("Plugins {}", path.display()), result));
+    }
+
+    if let Some(path) = args.moderation_rules.as_ref() {
+        checks.push((
+            format!("Moderation rules {}", path.display()),
+            read_moderation_rules(path).and_then(|rules| {
+                Moderation::new(&rules, None, None, args.moderation_action).map(|_| ())
+            }),
+        ));
+    }
+
+    for dir in [
+        args.arkose_gpt3_har_dir.as_ref(),
+        args.arkose_gpt4_har_dir.as_ref(),
+        args.arkose_auth_har_dir.as_ref(),
+        args.arkose_platform_har_dir.as_ref(),
+        args.ui_dir.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        checks.push((
+            format!("Directory {}", dir.display()),
+            match dir.is_dir() {
+                true => Ok(()),
+                false => Err(anyhow::anyhow!("not a readable directory")),
+            },
+        ));
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    #[cfg(feature = "limit")]
+    if args.tb_enable && args.tb_store_strategy == "redis" {
+        checks.push((
+            format!("Token bucket redis {}", args.tb_redis_url),
+            runtime.block_on(openai::serve::check::redis(&args.tb_redis_url)),
+        ));
+    }
+
+    if let Some(key) = args.arkose_solver_key.as_ref() {
+        let solver = ArkoseSolver::new(args.arkose_solver.clone(), key.clone());
+        let name = format!("Arkose solver {} key", solver.solver.to_string());
+        match runtime.block_on(openai::serve::check::solver(&solver)) {
+            Ok(balance) => checks.push((format!("{name} (balance: {balance})"), Ok(()))),
+            Err(err) => checks.push((name, Err(err))),
+        }
+    }
+
+    let mut failed = 0;
+    for (name, result) in checks {
+        match result {
+            Ok(_) => println!("[ok] {name}"),
+            Err(err) => {
+                failed += 1;
+                println!("[failed] {name}: {err}")
+            }
+        }
+    }
+
+    if failed > 0 {
+        anyhow::bail!("{failed} check(s) failed")
+    }
+    println!("Configuration is valid");
+    Ok(())
+}
+
+/// Refuse an `ldap://` url without StartTLS unless its host is a loopback address,
+/// the directory passwords would cross the network in clear text
+fn check_ldap_url(args: &ServeArgs) -> anyhow::Result<()> {
+    let Some(url) = args.ldap_url.as_deref() else {
+        return Ok(());
+    };
+    if cfg!(not(feature = "ldap")) {
+        anyhow::bail!("--ldap-url requires ninja built with the `ldap` feature")
+    }
+    let url = url::Url::parse(url)?;
+    let loopback = match url.host() {
+        Some(url::Host::Domain(domain)) => domain.eq_ignore_ascii_case("localhost"),
+        Some(url::Host::Ipv4(ip)) => ip.is_loopback(),
+        Some(url::Host::Ipv6(ip)) => ip.is_loopback(),
+        None => false,
     };
+    if url.scheme() == "ldap" && !args.ldap_starttls && !loopback {
+        anyhow::bail!("ldap:// sends the passwords in clear text, use ldaps:// or --ldap-starttls")
+    }
+    Ok(())
+}
 
-    let write = |out: PathBuf, args: ServeArgs| -> anyhow::Result<()> {
-        #[cfg(target_family = "unix")]
+/// Refuse a forward proxy reachable from other hosts without credentials, it would
+/// tunnel anyone to the allowed hosts
+fn check_forward_bind(args: &ServeArgs) -> anyhow::Result<()> {
+    match args.fbind {
+        Some(fbind)
+            if !fbind.ip().is_loopback() && args.fauth.as_ref().map_or(true, Vec::is_empty) =>
         {
-            use std::fs::Permissions;
-            use std::os::unix::prelude::PermissionsExt;
-            std::fs::File::create(&out)?.set_permissions(Permissions::from_mode(0o755))?;
+            anyhow::bail!("the forward proxy binds {fbind}, set --fauth or bind a loopback address")
         }
+        _ => Ok(()),
+    }
+}
 
-        #[cfg(target_family = "windows")]
-        std::fs::File::create(&out)?;
+/// Check the file is readable and contains the PEM block
+fn check_pem(path: &std::path::Path, label: &str) -> anyhow::Result<()> {
+    let data = std::fs::read_to_string(path)?;
+    match data.contains("-----BEGIN") && data.contains(label) {
+        true => Ok(()),
+        false => anyhow::bail!("no PEM {label} found"),
+    }
+}
 
-        Ok(std::fs::write(out, toml::to_string_pretty(&args)?)?)
+/// Read the API key pool file, one `sk-` key per line, blank lines and `#` comments are skipped,
+/// no keys without the file
+fn read_api_key_pool(args: &ServeArgs) -> anyhow::Result<Vec<String>> {
+    let Some(path) = args.api_key_pool.as_ref() else {
+        return Ok(Vec::new());
     };
+    let keys = std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(ToOwned::to_owned)
+        .collect::<Vec<_>>();
+    if let Some(key) = keys.iter().find(|key| !key.starts_with("sk-")) {
+        anyhow::bail!("not an API key: {}", openai::context::mask_key(key))
+    }
+    if keys.is_empty() {
+        anyhow::bail!("no API key found")
+    }
+    Ok(keys)
+}
 
-    if !out.exists() {
-        write(out, args)?;
+/// Refuse the plugins file, ninja is built without the `plugin` feature
+#[cfg(not(feature = "plugin"))]
+fn check_plugins(args: &ServeArgs) -> anyhow::Result<()> {
+    if args.plugins.is_some() {
+        anyhow::bail!("--plugins requires ninja built with the `plugin` feature")
     }
     Ok(())
 }
+
+/// Read the plugins file, the scripts are relative to the file
+#[cfg(feature = "plugin")]
+fn read_plugins(path: &std::path::Path) -> anyhow::Result<Vec<PluginConfig>> {
+    #[derive(serde::Deserialize)]
+    struct PluginsFile {
+        #[serde(default)]
+        plugin: Vec<PluginConfig>,
+    }
+
+    let file = toml::from_str::<PluginsFile>(&std::fs::read_to_string(path)?)?;
+    let dir = path.parent().unwrap_or(std::path::Path::new("."));
+    Ok(file
+        .plugin
+        .into_iter()
+        .map(|mut plugin| {
+            if plugin.script.is_relative() {
+                plugin.script = dir.join(&plugin.script);
+            }
+            plugin
+        })
+        .collect())
+}
+
+/// Read the moderation rules file, one regex per line, blank lines and `#` comments are skipped
+fn read_moderation_rules(path: &std::path::Path) -> anyhow::Result<Vec<String>> {
+    Ok(std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(ToOwned::to_owned)
+        .collect())
+}
+
+/// Read the User-Agents file, one per line, blank lines and `#` comments are skipped
+fn read_user_agents(path: &std::path::Path) -> anyhow::Result<Vec<String>> {
+    let agents = std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(ToOwned::to_owned)
+        .collect::<Vec<_>>();
+    if agents.is_empty() {
+        anyhow::bail!("no User-Agent found")
+    }
+    Ok(agents)
+}
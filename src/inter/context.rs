@@ -1,5 +1,5 @@
 use crate::store::conf::Conf;
-use crate::store::{account::AccountStore, conf::ConfFileStore, Store};
+use crate::store::{account::AccountStore, cipher::StoreCipher, conf::ConfFileStore, Store};
 use anyhow::anyhow;
 use openai::arkose::funcaptcha::ArkoseSolver;
 use openai::auth::{AuthClient, AuthClientBuilder};
@@ -103,10 +103,18 @@ impl Context {
             .await
     }
 
+    // Initialize the account store, encrypted with the cipher if any
+    pub async fn init_account_store(cipher: Option<StoreCipher>) -> anyhow::Result<()> {
+        let store = ACCOUNT_STORE
+            .get_or_init(|| async { AccountStore::new(cipher) })
+            .await;
+        tokio::task::spawn_blocking(move || store.derive_key()).await?
+    }
+
     pub async fn get_account_store() -> &'static AccountStore {
         ACCOUNT_STORE
-            .get_or_init(|| async { AccountStore::new() })
-            .await
+            .get()
+            .expect("The account store is not initialized")
     }
 
     pub async fn get_auth_client() -> AuthClient {
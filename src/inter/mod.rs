@@ -8,7 +8,7 @@ mod valid;
 
 use crate::{
     inter::conversation::{api, chatgpt},
-    store::Store,
+    store::{cipher::StoreCipher, Store},
 };
 use indicatif::{ProgressBar, ProgressStyle};
 use inquire::{
@@ -30,7 +30,8 @@ use tokio::task;
 
 use self::context::Context;
 
-pub async fn prompt() -> anyhow::Result<()> {
+pub async fn prompt(cipher: Option<StoreCipher>) -> anyhow::Result<()> {
+    Context::init_account_store(cipher).await?;
     Context::init_openai_context().await?;
     check_authorization().await?;
     print_boot_message().await;
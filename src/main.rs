@@ -30,10 +30,14 @@ fn main() -> anyhow::Result<()> {
             args::ServeSubcommand::Status => handle::serve_status()?,
             #[cfg(target_family = "unix")]
             args::ServeSubcommand::Log => handle::serve_log()?,
-            args::ServeSubcommand::Genca => {
-                let _ = openai::serve::preauth::cagen::gen_ca();
+            args::ServeSubcommand::Genca { dir, rotate } => {
+                openai::serve::preauth::cagen::gen_ca(&dir, rotate)?;
+            }
+            args::ServeSubcommand::Exportca { cert, format, out } => {
+                openai::serve::preauth::cagen::export_ca(&cert, format, out)?;
             }
             args::ServeSubcommand::GT { out } => handle::generate_template(out)?,
+            args::ServeSubcommand::Config(command) => handle::config(command)?,
             args::ServeSubcommand::Update => update::update()?,
         }
     }
@@ -54,20 +58,25 @@ fn main() -> anyhow::Result<()> {
                 args::ServeSubcommand::Status => handle::serve_status()?,
                 #[cfg(target_family = "unix")]
                 args::ServeSubcommand::Log => handle::serve_log()?,
-                args::ServeSubcommand::Genca => {
-                    let _ = openai::serve::preauth::cagen::gen_ca();
+                args::ServeSubcommand::Genca { dir, rotate } => {
+                    openai::serve::preauth::cagen::gen_ca(&dir, rotate)?;
+                }
+                args::ServeSubcommand::Exportca { cert, format, out } => {
+                    openai::serve::preauth::cagen::export_ca(&cert, format, out)?;
                 }
                 args::ServeSubcommand::GT { out } => handle::generate_template(out)?,
+                args::ServeSubcommand::Config(command) => handle::config(command)?,
                 args::ServeSubcommand::Update => update::update()?,
             },
-            SubCommands::Terminal => {
+            SubCommands::Terminal(args) => {
+                let cipher = args.store_cipher()?;
                 let runtime = tokio::runtime::Builder::new_multi_thread()
                     .enable_all()
                     .worker_threads(1)
                     .max_blocking_threads(1)
                     .build()?;
 
-                runtime.block_on(inter::prompt())?;
+                runtime.block_on(inter::prompt(cipher))?;
             }
         }
     }
@@ -24,7 +24,7 @@ pub fn parse_url(s: &str) -> anyhow::Result<String> {
         .context("The Proxy Url format must be `protocol://user:pass@ip:port`")?;
     let protocol = url.scheme().to_string();
     match protocol.as_str() {
-        "http" | "https" | "socks5" | "redis" | "rediss" => Ok(s.to_string()),
+        "http" | "https" | "socks5" | "socks5h" | "redis" | "rediss" => Ok(s.to_string()),
         _ => anyhow::bail!("Unsupported protocol: {}", protocol),
     }
 }
@@ -38,6 +38,26 @@ pub fn parse_ipv6_subnet(s: &str) -> anyhow::Result<(std::net::Ipv6Addr, u8)> {
     }
 }
 
+// ratio parse
+pub fn parse_ratio(s: &str) -> anyhow::Result<f64> {
+    let ratio = s
+        .parse::<f64>()
+        .map_err(|_| anyhow::anyhow!(format!("`{}` isn't a number", s)))?;
+    if !(0.0..=1.0).contains(&ratio) {
+        anyhow::bail!(format!("`{}` must be between 0.0 and 1.0", s))
+    }
+    Ok(ratio)
+}
+
+// non zero seconds parse
+pub fn parse_nonzero_secs(s: &str) -> anyhow::Result<u64> {
+    match s.parse::<u64>() {
+        Ok(0) => anyhow::bail!(format!("`{}` must be greater than 0", s)),
+        Ok(secs) => Ok(secs),
+        Err(_) => anyhow::bail!(format!("`{}` isn't a number", s)),
+    }
+}
+
 // proxy proto
 pub fn parse_proxies_url(s: &str) -> anyhow::Result<Vec<String>> {
     let split = s.split(',');
@@ -47,7 +67,9 @@ pub fn parse_proxies_url(s: &str) -> anyhow::Result<Vec<String>> {
             .context("The Proxy Url format must be `protocol://user:pass@ip:port`")?;
         let protocol = url.scheme().to_string();
         match protocol.as_str() {
-            "http" | "https" | "socks5" | "redis" | "rediss" => proxies.push(ele.to_string()),
+            "http" | "https" | "socks5" | "socks5h" | "redis" | "rediss" => {
+                proxies.push(ele.to_string())
+            }
             _ => anyhow::bail!("Unsupported protocol: {}", protocol),
         };
     }
@@ -70,6 +92,43 @@ pub fn parse_file_path(s: &str) -> anyhow::Result<PathBuf> {
     Ok(path)
 }
 
+// parse ldap url, e.g. `ldaps://ldap.example.com:636`
+pub fn parse_ldap_url(s: &str) -> anyhow::Result<String> {
+    let url = url::Url::parse(s).context("The LDAP Url format must be `ldap://host:port`")?;
+    match url.scheme() {
+        "ldap" | "ldaps" => Ok(s.to_string()),
+        protocol => anyhow::bail!("Unsupported protocol: {}", protocol),
+    }
+}
+
+// parse basic auth credential, e.g. `user:password`
+pub fn parse_basic_auth(s: &str) -> anyhow::Result<String> {
+    match s.split_once(':') {
+        Some((user, password)) if !user.is_empty() && !password.is_empty() => Ok(s.to_string()),
+        _ => anyhow::bail!(format!("`{}` isn't a `user:password` pair", s)),
+    }
+}
+
+// parse url prefix, e.g. `/ninja`
+pub fn parse_url_prefix(s: &str) -> anyhow::Result<String> {
+    let prefix = s.trim_matches('/');
+    if prefix.is_empty() {
+        anyhow::bail!("The url prefix can't be empty")
+    }
+    if !prefix
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "-._~/".contains(c))
+    {
+        anyhow::bail!(format!("`{}` isn't a valid url prefix", s))
+    }
+    Ok(format!("/{prefix}"))
+}
+
+// parse url path, e.g. `/backend-api/stop`
+pub fn parse_url_path(s: &str) -> anyhow::Result<String> {
+    parse_url_prefix(s).map_err(|_| anyhow::anyhow!("`{s}` isn't a valid url path"))
+}
+
 // parse directory path
 pub fn parse_dir_path(s: &str) -> anyhow::Result<PathBuf> {
     let path =
@@ -85,3 +144,8 @@ pub fn parse_dir_path(s: &str) -> anyhow::Result<PathBuf> {
 
     Ok(path)
 }
+
+// parse MITM CA export format
+pub fn parse_ca_format(s: &str) -> anyhow::Result<openai::serve::preauth::cagen::CaFormat> {
+    s.parse()
+}
@@ -1,13 +1,21 @@
-use super::{Store, StoreId, StoreResult};
+use super::{
+    cipher::StoreCipher,
+    file::{self, FileLock},
+    Store, StoreId, StoreResult,
+};
 use openai::homedir::home_dir;
 use openai::{auth::model::AuthStrategy, token::model::AuthenticateToken};
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, ops::Not, path::PathBuf};
 
-pub struct AccountStore(PathBuf);
+pub struct AccountStore {
+    path: PathBuf,
+    /// Tokens are encrypted at rest with the cipher
+    cipher: Option<StoreCipher>,
+}
 
 impl AccountStore {
-    pub fn new() -> Self {
+    pub fn new(cipher: Option<StoreCipher>) -> Self {
         let path = match home_dir() {
             Some(home_dir) => home_dir.join(".ninja_accounts"),
             None => PathBuf::from(".ninja_accounts"),
@@ -22,19 +30,51 @@ impl AccountStore {
             std::fs::File::create(&path)
                 .unwrap_or_else(|_| panic!("Unable to create file: {}", path.display()));
         }
-        AccountStore(path)
+        AccountStore { path, cipher }
+    }
+
+    fn read_bytes(&self) -> StoreResult<Vec<u8>> {
+        let bytes = std::fs::read(&self.path)?;
+        match self.cipher.as_ref() {
+            Some(cipher) => cipher.decrypt(&bytes),
+            None if StoreCipher::is_encrypted(&bytes) => anyhow::bail!(
+                "{} is encrypted, set --store-passphrase or --store-key-file",
+                self.path.display()
+            ),
+            None => Ok(bytes),
+        }
+    }
+
+    /// Derive the key of the cipher, slow on purpose so it is run on the blocking threads
+    pub fn derive_key(&self) -> StoreResult<()> {
+        match self.cipher.as_ref() {
+            Some(cipher) => {
+                let _lock = FileLock::shared(&self.path)?;
+                cipher.derive(&std::fs::read(&self.path)?)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn write_bytes(&self, json: String) -> StoreResult<()> {
+        let bytes = match self.cipher.as_ref() {
+            Some(cipher) => cipher.encrypt(json.as_bytes())?,
+            None => json.into_bytes(),
+        };
+        file::write_atomic(&self.path, &bytes)
     }
 }
 
 impl Default for AccountStore {
     fn default() -> Self {
-        Self::new()
+        Self::new(None)
     }
 }
 
 impl Store<Account> for AccountStore {
     fn store(&self, target: Account) -> StoreResult<Option<Self::Obj>> {
-        let bytes = std::fs::read(&self.0)?;
+        let _lock = FileLock::exclusive(&self.path)?;
+        let bytes = self.read_bytes()?;
         let mut data: HashMap<String, Account> = if bytes.is_empty() {
             HashMap::new()
         } else {
@@ -42,12 +82,13 @@ impl Store<Account> for AccountStore {
         };
         let v = data.insert(target.email.to_string(), target);
         let json = serde_json::to_string_pretty(&data)?;
-        std::fs::write(&self.0, json.as_bytes())?;
+        self.write_bytes(json)?;
         Ok(v)
     }
 
     fn read(&self, target: Account) -> StoreResult<Option<Self::Obj>> {
-        let bytes = std::fs::read(&self.0)?;
+        let _lock = FileLock::shared(&self.path)?;
+        let bytes = self.read_bytes()?;
         if bytes.is_empty() {
             return Ok(None);
         }
@@ -57,7 +98,8 @@ impl Store<Account> for AccountStore {
     }
 
     fn remove(&self, target: Account) -> StoreResult<Option<Self::Obj>> {
-        let bytes = std::fs::read(&self.0)?;
+        let _lock = FileLock::exclusive(&self.path)?;
+        let bytes = self.read_bytes()?;
         if bytes.is_empty() {
             return Ok(None);
         }
@@ -65,12 +107,13 @@ impl Store<Account> for AccountStore {
             serde_json::from_slice(&bytes).map_err(|e| anyhow::anyhow!(e))?;
         let v = data.remove(&target.id());
         let json = serde_json::to_string_pretty(&data)?;
-        std::fs::write(&self.0, json)?;
+        self.write_bytes(json)?;
         Ok(v)
     }
 
     fn list(&self) -> StoreResult<Vec<Self::Obj>> {
-        let bytes = std::fs::read(&self.0)?;
+        let _lock = FileLock::shared(&self.path)?;
+        let bytes = self.read_bytes()?;
         if bytes.is_empty() {
             return Ok(vec![]);
         }
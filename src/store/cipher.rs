@@ -0,0 +1,143 @@
+use std::sync::Mutex;
+
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
+};
+use base64::{engine::general_purpose, Engine};
+
+/// Encrypted files start with the prefix, followed by `<salt>:<nonce>:<ciphertext>` in base64
+const PREFIX: &str = "ninja-enc:v1:";
+
+/// PBKDF2-HMAC-SHA256 rounds deriving the key
+const ROUNDS: u32 = 600_000;
+
+/// Encryption of a store file at rest, AES-256-GCM with a key derived from a passphrase or key file
+pub struct StoreCipher {
+    secret: Vec<u8>,
+    /// The key of the last salt, deriving is slow on purpose
+    derived: Mutex<Option<([u8; 16], [u8; 32])>>,
+}
+
+impl StoreCipher {
+    pub fn new(secret: Vec<u8>) -> Self {
+        Self {
+            secret,
+            derived: Mutex::new(None),
+        }
+    }
+
+    fn key(&self, salt: Option<[u8; 16]>) -> ([u8; 16], [u8; 32]) {
+        let mut derived = self.derived.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((cached_salt, key)) = *derived {
+            if salt.map_or(true, |salt| salt == cached_salt) {
+                return (cached_salt, key);
+            }
+        }
+        let salt = salt.unwrap_or_else(|| {
+            let mut salt = [0u8; 16];
+            OsRng.fill_bytes(&mut salt);
+            salt
+        });
+        let mut key = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<sha2::Sha256>(&self.secret, &salt, ROUNDS, &mut key);
+        *derived = Some((salt, key));
+        (salt, key)
+    }
+
+    pub fn encrypt(&self, plain: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let (salt, key) = self.key(None);
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = Aes256Gcm::new(&key.into())
+            .encrypt(&nonce, plain)
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt the store"))?;
+        let engine = general_purpose::STANDARD;
+        Ok(format!(
+            "{PREFIX}{}:{}:{}",
+            engine.encode(salt),
+            engine.encode(nonce),
+            engine.encode(ciphertext)
+        )
+        .into_bytes())
+    }
+
+    /// Plaintext files are passed through, they are encrypted on the next write
+    pub fn decrypt(&self, bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let Some(encrypted) = bytes.strip_prefix(PREFIX.as_bytes()) else {
+            return Ok(bytes.to_vec());
+        };
+        let (salt, nonce, ciphertext) = Self::parse(encrypted)?;
+        let (_, key) = self.key(Some(salt));
+        Aes256Gcm::new(&key.into())
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| {
+                anyhow::anyhow!("Failed to decrypt the store, wrong passphrase or key file")
+            })
+    }
+
+    /// Derive the key of the file content ahead, the reads and writes reuse it
+    pub fn derive(&self, bytes: &[u8]) -> anyhow::Result<()> {
+        let salt = match bytes.strip_prefix(PREFIX.as_bytes()) {
+            Some(encrypted) => Some(Self::parse(encrypted)?.0),
+            None => None,
+        };
+        self.key(salt);
+        Ok(())
+    }
+
+    fn parse(encrypted: &[u8]) -> anyhow::Result<([u8; 16], Vec<u8>, Vec<u8>)> {
+        let engine = general_purpose::STANDARD;
+        let parts = std::str::from_utf8(encrypted)?
+            .trim()
+            .split(':')
+            .map(|part| engine.decode(part))
+            .collect::<Result<Vec<_>, _>>()?;
+        let [salt, nonce, ciphertext] = parts.as_slice() else {
+            anyhow::bail!("Malformed encrypted store")
+        };
+        let salt = <[u8; 16]>::try_from(salt.as_slice())?;
+        if nonce.len() != 12 {
+            anyhow::bail!("Malformed encrypted store")
+        }
+        Ok((salt, nonce.to_owned(), ciphertext.to_owned()))
+    }
+
+    /// Whether the file content is encrypted
+    pub fn is_encrypted(bytes: &[u8]) -> bool {
+        bytes.starts_with(PREFIX.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let cipher = StoreCipher::new(b"passphrase".to_vec());
+        let encrypted = cipher.encrypt(b"{\"token\":1}").unwrap();
+        assert!(StoreCipher::is_encrypted(&encrypted));
+        assert_eq!(cipher.decrypt(&encrypted).unwrap(), b"{\"token\":1}");
+
+        // A fresh cipher derives the key from the stored salt
+        let cipher = StoreCipher::new(b"passphrase".to_vec());
+        assert_eq!(cipher.decrypt(&encrypted).unwrap(), b"{\"token\":1}");
+    }
+
+    #[test]
+    fn test_wrong_passphrase() {
+        let encrypted = StoreCipher::new(b"passphrase".to_vec())
+            .encrypt(b"{}")
+            .unwrap();
+        let cipher = StoreCipher::new(b"other".to_vec());
+        assert!(cipher.decrypt(&encrypted).is_err());
+    }
+
+    #[test]
+    fn test_plaintext_pass_through() {
+        let cipher = StoreCipher::new(b"passphrase".to_vec());
+        assert!(!StoreCipher::is_encrypted(b"{}"));
+        assert_eq!(cipher.decrypt(b"{}").unwrap(), b"{}");
+        assert!(cipher.decrypt(format!("{PREFIX}a:b").as_bytes()).is_err());
+    }
+}
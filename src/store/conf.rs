@@ -1,4 +1,7 @@
-use super::{Store, StoreId, StoreResult};
+use super::{
+    file::{self, FileLock},
+    Store, StoreId, StoreResult,
+};
 use openai::{arkose::funcaptcha::Solver, homedir::home_dir};
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, ops::Not, path::PathBuf};
@@ -81,6 +84,7 @@ impl Default for ConfFileStore {
 
 impl Store<Conf> for ConfFileStore {
     fn store(&self, target: Conf) -> StoreResult<Option<Self::Obj>> {
+        let _lock = FileLock::exclusive(&self.0)?;
         let bytes = std::fs::read(&self.0)?;
         let mut data: HashMap<String, Conf> = if bytes.is_empty() {
             HashMap::new()
@@ -89,11 +93,12 @@ impl Store<Conf> for ConfFileStore {
         };
         let v = data.insert(target.id(), target);
         let json = serde_json::to_string_pretty(&data)?;
-        std::fs::write(&self.0, json.as_bytes())?;
+        file::write_atomic(&self.0, json.as_bytes())?;
         Ok(v)
     }
 
     fn read(&self, target: Conf) -> StoreResult<Option<Self::Obj>> {
+        let _lock = FileLock::shared(&self.0)?;
         let bytes = std::fs::read(&self.0)?;
         if bytes.is_empty() {
             return Ok(None);
@@ -104,6 +109,7 @@ impl Store<Conf> for ConfFileStore {
     }
 
     fn remove(&self, target: Conf) -> StoreResult<Option<Self::Obj>> {
+        let _lock = FileLock::exclusive(&self.0)?;
         let bytes = std::fs::read(&self.0)?;
         if bytes.is_empty() {
             return Ok(None);
@@ -112,11 +118,12 @@ impl Store<Conf> for ConfFileStore {
             serde_json::from_slice(&bytes).map_err(|e| anyhow::anyhow!(e))?;
         let v = data.remove(&target.id());
         let json = serde_json::to_string_pretty(&data)?;
-        std::fs::write(&self.0, json)?;
+        file::write_atomic(&self.0, json.as_bytes())?;
         Ok(v)
     }
 
     fn list(&self) -> StoreResult<Vec<Self::Obj>> {
+        let _lock = FileLock::shared(&self.0)?;
         let bytes = std::fs::read(&self.0)?;
         if bytes.is_empty() {
             return Ok(vec![]);
@@ -0,0 +1,69 @@
+//! Store file access shared by processes, an advisory lock on a sidecar `.lock` file
+//! serializes them and writes go through a temp file renamed over the store file
+use super::StoreResult;
+use fs2::FileExt;
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Advisory lock of a store file, released on drop
+pub struct FileLock(File);
+
+impl FileLock {
+    /// Lock for reading, other readers are allowed
+    pub fn shared(path: &Path) -> StoreResult<Self> {
+        let file = Self::open(path)?;
+        file.lock_shared()?;
+        Ok(Self(file))
+    }
+
+    /// Lock for read-modify-write
+    pub fn exclusive(path: &Path) -> StoreResult<Self> {
+        let file = Self::open(path)?;
+        file.lock_exclusive()?;
+        Ok(Self(file))
+    }
+
+    fn open(path: &Path) -> StoreResult<File> {
+        Ok(OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .open(sidecar(path, "lock"))?)
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = self.0.unlock();
+    }
+}
+
+/// Replace the file content, readers never see a partially written file
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> StoreResult<()> {
+    let tmp = sidecar(path, &format!("{}.tmp", std::process::id()));
+    let write = || -> StoreResult<()> {
+        let mut file = File::create(&tmp)?;
+        if let Ok(metadata) = std::fs::metadata(path) {
+            file.set_permissions(metadata.permissions())?;
+        }
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    };
+    write().map_err(|err| {
+        let _ = std::fs::remove_file(&tmp);
+        err
+    })
+}
+
+/// `<path>.<ext>` next to the store file
+fn sidecar(path: &Path, ext: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(ext);
+    path.with_file_name(name)
+}
@@ -1,5 +1,7 @@
 pub mod account;
+pub mod cipher;
 pub mod conf;
+pub mod file;
 
 use serde::de::DeserializeOwned;
 
@@ -92,4 +92,41 @@ pub(crate) fn fix_relative_path(args: &mut ServeArgs) {
             )
         }
     }
+
+    for c in [
+        args.log_file.as_mut(),
+        args.access_log.as_mut(),
+        args.cassette.as_mut(),
+        args.content_log.as_mut(),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        if c.is_relative() {
+            *c = std::env::current_dir()
+                .expect("cannot get current exe")
+                .join(&*c)
+        }
+    }
+
+    for c in [
+        args.account_binding.as_mut(),
+        args.api_key_pool.as_mut(),
+        args.key_map.as_mut(),
+        args.system_prompts.as_mut(),
+        args.content_log_redact.as_mut(),
+        args.ldap_groups.as_mut(),
+        args.plugins.as_mut(),
+        args.moderation_rules.as_mut(),
+        args.user_agents.as_mut(),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        if c.is_relative() {
+            *c = std::env::current_dir()
+                .expect("cannot get current exe")
+                .join(&*c)
+        }
+    }
 }